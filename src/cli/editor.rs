@@ -0,0 +1,792 @@
+//! Line Editor
+//!
+//! Reads REPL input in raw mode: cursor keys and the usual Emacs bindings,
+//! history kept across sessions in the oxide cache directory, reverse
+//! search with Ctrl+R, and Tab completion of slash commands and file paths.
+//! Alt+Enter starts a new line, as does Enter inside a block opened with
+//! `"""` and closed by another `"""`. Pasted text is inserted as is, newlines
+//! included. When stdin or stdout is not a terminal, lines (and `"""`
+//! blocks) are read from stdin without editing.
+//!
+//! [`LineEditor::read_block`] reads a multi-line block instead: Enter starts
+//! a new line and Ctrl+D ends the block.
+
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use crossterm::{
+    cursor::{MoveDown, MoveToColumn, MoveUp},
+    event::{
+        self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent, KeyEventKind,
+        KeyModifiers,
+    },
+    execute, queue,
+    style::{Attribute, Print, ResetColor, SetAttribute, SetForegroundColor},
+    terminal::{self, Clear, ClearType},
+};
+
+use super::theme::Theme;
+use crate::model::cache::cache_dir;
+
+const HISTORY_FILE: &str = "history";
+const HISTORY_LIMIT: usize = 1000;
+const BLOCK_DELIMITER: &str = "\"\"\"";
+const PROMPT_WIDTH: usize = 2;
+const CONTINUATION: &str = "  ";
+
+/// Result of [`LineEditor::read_line`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadLine {
+    Line(String),
+    /// Ctrl+C on an empty line.
+    Interrupted,
+    /// Ctrl+D on an empty line, or the end of piped input.
+    Eof,
+}
+
+/// Previous inputs, oldest first, one per line in the history file with
+/// newlines and backslashes escaped.
+#[derive(Debug, Default)]
+struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl History {
+    fn load(path: Option<PathBuf>) -> Self {
+        let entries = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(unescape).collect())
+            .unwrap_or_default();
+        Self { entries, path }
+    }
+
+    fn add(&mut self, entry: &str) {
+        if entry.trim().is_empty() || self.entries.last().is_some_and(|last| last == entry) {
+            return;
+        }
+        self.entries.push(entry.to_string());
+        if self.entries.len() > HISTORY_LIMIT {
+            self.entries.drain(..self.entries.len() - HISTORY_LIMIT);
+        }
+        if let Err(e) = self.save() {
+            tracing::warn!("Failed to save REPL history: {}", e);
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut text = String::new();
+        for entry in &self.entries {
+            text.push_str(&escape(entry));
+            text.push('\n');
+        }
+        fs::write(path, text)
+    }
+
+    /// Index of the newest entry before `before` that contains `query`.
+    fn search(&self, query: &str, before: usize) -> Option<usize> {
+        self.entries[..before.min(self.entries.len())]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+}
+
+fn escape(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// An in-progress Ctrl+R search.
+struct Search {
+    query: String,
+    /// History index of the current match.
+    found: Option<usize>,
+    /// The input to restore if the search is cancelled.
+    original: (Vec<char>, usize),
+}
+
+enum Prompt<'a> {
+    Input,
+    Search(&'a Search),
+}
+
+impl Prompt<'_> {
+    fn width(&self) -> usize {
+        match self {
+            Prompt::Input => PROMPT_WIDTH,
+            Prompt::Search(search) => search_label(search).chars().count(),
+        }
+    }
+}
+
+fn search_label(search: &Search) -> String {
+    let state = if search.found.is_none() && !search.query.is_empty() {
+        "failed search"
+    } else {
+        "search"
+    };
+    format!("({}) `{}': ", state, search.query)
+}
+
+pub struct LineEditor {
+    history: History,
+    commands: Vec<String>,
+    /// Rows between the first input line and the cursor at the last draw.
+    cursor_row: usize,
+}
+
+impl LineEditor {
+    /// An editor that completes `commands` and keeps its history in the
+    /// oxide cache directory.
+    pub fn new(commands: &[&str]) -> Self {
+        Self::with_history(commands, Some(cache_dir().join(HISTORY_FILE)))
+    }
+
+    /// An editor with its history in `path`, or only in memory for `None`.
+    pub fn with_history(commands: &[&str], path: Option<PathBuf>) -> Self {
+        Self {
+            history: History::load(path),
+            commands: commands.iter().map(|c| c.to_string()).collect(),
+            cursor_row: 0,
+        }
+    }
+
+    /// Show the prompt and read one input.
+    pub fn read_line(&mut self) -> Result<ReadLine> {
+        self.read_line_with("")
+    }
+
+    /// Like [`read_line`](Self::read_line), with `initial` already typed.
+    pub fn read_line_with(&mut self, initial: &str) -> Result<ReadLine> {
+        let mut stdout = io::stdout();
+        execute!(stdout, Print("\n"))?;
+        if !io::stdin().is_terminal() || !stdout.is_terminal() {
+            draw_prompt(&mut stdout, &Prompt::Input)?;
+            stdout.flush()?;
+            return read_piped();
+        }
+
+        let _raw = RawMode::enable()?;
+        self.edit(initial, false)
+    }
+
+    /// Read a block of any number of lines, ended by Ctrl+D. Blocks are
+    /// not added to the history. Piped input is read up to a `"""` line or
+    /// the end of input.
+    pub fn read_block(&mut self) -> Result<ReadLine> {
+        let mut stdout = io::stdout();
+        execute!(stdout, Print("\n"))?;
+        if !io::stdin().is_terminal() || !stdout.is_terminal() {
+            draw_prompt(&mut stdout, &Prompt::Input)?;
+            stdout.flush()?;
+            return read_piped_block();
+        }
+
+        let _raw = RawMode::enable()?;
+        self.edit("", true)
+    }
+
+    /// Edit `initial`. In a `block`, Enter inserts a newline and Ctrl+D
+    /// submits.
+    fn edit(&mut self, initial: &str, block: bool) -> Result<ReadLine> {
+        let mut text: Vec<char> = initial.chars().collect();
+        let mut cursor = text.len();
+        let mut browse = self.history.entries.len();
+        let mut draft = Vec::new();
+        let mut search: Option<Search> = None;
+
+        self.cursor_row = 0;
+        self.draw(&Prompt::Input, &text, cursor)?;
+        loop {
+            let key = match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => key,
+                Event::Paste(pasted) => {
+                    let pasted: Vec<char> = pasted
+                        .replace("\r\n", "\n")
+                        .replace('\r', "\n")
+                        .chars()
+                        .collect();
+                    let len = pasted.len();
+                    text.splice(cursor..cursor, pasted);
+                    cursor += len;
+                    self.draw(&Prompt::Input, &text, cursor)?;
+                    continue;
+                }
+                Event::Resize(..) => {
+                    self.draw(&Prompt::Input, &text, cursor)?;
+                    continue;
+                }
+                _ => continue,
+            };
+            let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+            let alt = key.modifiers.contains(KeyModifiers::ALT);
+
+            if let Some(active) = search.as_mut() {
+                if let Some(done) = self.search_key(active, key, &mut text, &mut cursor) {
+                    if !done {
+                        let (original, at) = active.original.clone();
+                        text = original;
+                        cursor = at;
+                    }
+                    search = None;
+                    self.draw(&Prompt::Input, &text, cursor)?;
+                } else {
+                    self.draw(&Prompt::Search(active), &text, cursor)?;
+                }
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Enter
+                    if block
+                        || alt
+                        || open_block(&text.iter().collect::<String>())
+                        || event::poll(Duration::ZERO)? =>
+                {
+                    // A key already waiting after Enter means pasted text
+                    // from a terminal without bracketed paste.
+                    text.insert(cursor, '\n');
+                    cursor += 1;
+                }
+                KeyCode::Enter => {
+                    self.finish(&text)?;
+                    let line: String = text.iter().collect();
+                    self.history.add(line.trim());
+                    return Ok(ReadLine::Line(strip_block(&line)));
+                }
+                KeyCode::Char('c') if ctrl => {
+                    if text.is_empty() {
+                        self.finish(&text)?;
+                        return Ok(ReadLine::Interrupted);
+                    }
+                    text.clear();
+                    cursor = 0;
+                }
+                KeyCode::Char('d') if ctrl && block && !text.is_empty() => {
+                    self.finish(&text)?;
+                    return Ok(ReadLine::Line(text.iter().collect()));
+                }
+                KeyCode::Char('d') if ctrl => {
+                    if text.is_empty() {
+                        self.finish(&text)?;
+                        return Ok(ReadLine::Eof);
+                    }
+                    if cursor < text.len() {
+                        text.remove(cursor);
+                    }
+                }
+                KeyCode::Char('a') if ctrl => cursor = line_start(&text, cursor),
+                KeyCode::Home => cursor = line_start(&text, cursor),
+                KeyCode::Char('e') if ctrl => cursor = line_end(&text, cursor),
+                KeyCode::End => cursor = line_end(&text, cursor),
+                KeyCode::Char('u') if ctrl => {
+                    let start = line_start(&text, cursor);
+                    text.drain(start..cursor);
+                    cursor = start;
+                }
+                KeyCode::Char('k') if ctrl => {
+                    let end = line_end(&text, cursor);
+                    text.drain(cursor..end);
+                }
+                KeyCode::Char('w') if ctrl => {
+                    let start = word_start(&text, cursor);
+                    text.drain(start..cursor);
+                    cursor = start;
+                }
+                KeyCode::Char('r') if ctrl => {
+                    let active = Search {
+                        query: String::new(),
+                        found: None,
+                        original: (text.clone(), cursor),
+                    };
+                    self.draw(&Prompt::Search(&active), &text, cursor)?;
+                    search = Some(active);
+                    continue;
+                }
+                KeyCode::Char(c) if !ctrl && !alt => {
+                    text.insert(cursor, c);
+                    cursor += 1;
+                }
+                KeyCode::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    text.remove(cursor);
+                }
+                KeyCode::Delete if cursor < text.len() => {
+                    text.remove(cursor);
+                }
+                KeyCode::Left if cursor > 0 => cursor -= 1,
+                KeyCode::Right if cursor < text.len() => cursor += 1,
+                KeyCode::Up => {
+                    let start = line_start(&text, cursor);
+                    if start > 0 {
+                        let above = line_start(&text, start - 1);
+                        cursor = (above + cursor - start).min(start - 1);
+                    } else if browse > 0 {
+                        if browse == self.history.entries.len() {
+                            draft = text.clone();
+                        }
+                        browse -= 1;
+                        text = self.history.entries[browse].chars().collect();
+                        cursor = text.len();
+                    }
+                }
+                KeyCode::Down => {
+                    let end = line_end(&text, cursor);
+                    if end < text.len() {
+                        let column = cursor - line_start(&text, cursor);
+                        cursor = (end + 1 + column).min(line_end(&text, end + 1));
+                    } else if browse < self.history.entries.len() {
+                        browse += 1;
+                        text = match self.history.entries.get(browse) {
+                            Some(entry) => entry.chars().collect(),
+                            None => draft.clone(),
+                        };
+                        cursor = text.len();
+                    }
+                }
+                KeyCode::Tab => self.complete(&mut text, &mut cursor)?,
+                _ => {}
+            }
+            self.draw(&Prompt::Input, &text, cursor)?;
+        }
+    }
+
+    /// Handle a key during a Ctrl+R search. Returns `Some(true)` to keep
+    /// the match for editing, `Some(false)` to cancel, and `None` while
+    /// searching.
+    fn search_key(
+        &self,
+        search: &mut Search,
+        key: KeyEvent,
+        text: &mut Vec<char>,
+        cursor: &mut usize,
+    ) -> Option<bool> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let entries = self.history.entries.len();
+        let from = match key.code {
+            KeyCode::Char('r') if ctrl => search.found.unwrap_or(entries),
+            KeyCode::Char('c') | KeyCode::Char('g') if ctrl => return Some(false),
+            KeyCode::Esc => return Some(false),
+            KeyCode::Char(c) if !ctrl => {
+                search.query.push(c);
+                search.found.map_or(entries, |i| i + 1)
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                entries
+            }
+            _ => return Some(true),
+        };
+
+        search.found = self.history.search(&search.query, from);
+        if let Some(index) = search.found {
+            let entry = &self.history.entries[index];
+            *text = entry.chars().collect();
+            *cursor = entry
+                .find(&search.query)
+                .map_or(text.len(), |at| entry[..at].chars().count());
+        }
+        None
+    }
+
+    /// Complete the word before the cursor: a slash command at the start
+    /// of the input, a file path anywhere else. Several matches are
+    /// completed to their common prefix, and listed if that adds nothing.
+    fn complete(&mut self, text: &mut Vec<char>, cursor: &mut usize) -> Result<()> {
+        let start = text[..*cursor]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map_or(0, |i| i + 1);
+        let word: String = text[start..*cursor].iter().collect();
+        let command = start == 0 && word.starts_with('/');
+
+        let candidates = if command {
+            self.commands
+                .iter()
+                .filter(|c| c.starts_with(&word))
+                .cloned()
+                .collect()
+        } else {
+            path_completions(&word)
+        };
+
+        let completion = match candidates.as_slice() {
+            [] => return Ok(()),
+            [only] if command => format!("{} ", only),
+            [only] => only.clone(),
+            _ => common_prefix(&candidates),
+        };
+        if completion.chars().count() > word.chars().count() {
+            let completion: Vec<char> = completion.chars().collect();
+            let len = completion.len();
+            text.splice(start..*cursor, completion);
+            *cursor = start + len;
+            return Ok(());
+        }
+
+        let names: Vec<&str> = candidates
+            .iter()
+            .map(|c| {
+                let trimmed = c.trim_end_matches('/');
+                match trimmed.rfind('/') {
+                    Some(i) => &c[i + 1..],
+                    None => c.as_str(),
+                }
+            })
+            .collect();
+        self.finish(text)?;
+        let mut stdout = io::stdout();
+        queue!(
+            stdout,
+            SetForegroundColor(Theme::TEXT_SECONDARY),
+            Print(names.join("  ")),
+            ResetColor,
+            Print("\r\n\r\n")
+        )?;
+        self.cursor_row = 0;
+        Ok(())
+    }
+
+    /// Redraw the prompt and input, leaving the terminal cursor at `cursor`.
+    fn draw(&mut self, prompt: &Prompt, text: &[char], cursor: usize) -> Result<()> {
+        let width = terminal_width();
+        let mut stdout = io::stdout();
+        if self.cursor_row > 0 {
+            queue!(stdout, MoveUp(self.cursor_row as u16))?;
+        }
+        queue!(stdout, MoveToColumn(0), Clear(ClearType::FromCursorDown))?;
+        draw_prompt(&mut stdout, prompt)?;
+        let first = prompt.width();
+        let mut col = first % width;
+        for &c in text {
+            if c == '\n' {
+                queue!(stdout, Print("\r\n"), Print(CONTINUATION))?;
+                col = CONTINUATION.len();
+                continue;
+            }
+            queue!(stdout, Print(c))?;
+            col += 1;
+            if col == width {
+                // Leave the terminal's pending wrap after a full row, so
+                // the cursor is where `position` puts it.
+                queue!(stdout, Print(" \r"))?;
+                col = 0;
+            }
+        }
+
+        let end = position(text, text.len(), first, width);
+        let at = position(text, cursor, first, width);
+        if end.0 > at.0 {
+            queue!(stdout, MoveUp((end.0 - at.0) as u16))?;
+        }
+        queue!(stdout, MoveToColumn(at.1 as u16))?;
+        stdout.flush()?;
+        self.cursor_row = at.0;
+        Ok(())
+    }
+
+    /// Move below the input, ready for output.
+    fn finish(&mut self, text: &[char]) -> Result<()> {
+        let width = terminal_width();
+        let end = position(text, text.len(), PROMPT_WIDTH, width);
+        let mut stdout = io::stdout();
+        if end.0 > self.cursor_row {
+            queue!(stdout, MoveDown((end.0 - self.cursor_row) as u16))?;
+        }
+        queue!(stdout, Print("\r\n"))?;
+        stdout.flush()?;
+        self.cursor_row = 0;
+        Ok(())
+    }
+}
+
+/// Columns of the terminal, or 80 if it does not say.
+fn terminal_width() -> usize {
+    match terminal::size() {
+        Ok((width, _)) if width > 0 => width as usize,
+        _ => 80,
+    }
+}
+
+/// Raw mode and bracketed paste for the lifetime of the guard.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnableBracketedPaste).ok();
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        execute!(io::stdout(), DisableBracketedPaste).ok();
+        terminal::disable_raw_mode().ok();
+    }
+}
+
+fn draw_prompt(stdout: &mut io::Stdout, prompt: &Prompt) -> Result<()> {
+    match prompt {
+        Prompt::Input => queue!(
+            stdout,
+            SetForegroundColor(Theme::RUST_ORANGE),
+            SetAttribute(Attribute::Bold),
+            Print("▸"),
+            SetAttribute(Attribute::Reset),
+            ResetColor,
+            Print(" ")
+        )?,
+        Prompt::Search(search) => queue!(
+            stdout,
+            SetForegroundColor(Theme::TEXT_SECONDARY),
+            Print(search_label(search)),
+            ResetColor
+        )?,
+    }
+    Ok(())
+}
+
+/// Read a line, or a `"""` block, from non-interactive stdin.
+fn read_piped() -> Result<ReadLine> {
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    let mut line = String::new();
+    if stdin.read_line(&mut line)? == 0 {
+        return Ok(ReadLine::Eof);
+    }
+    if line.trim() != BLOCK_DELIMITER {
+        return Ok(ReadLine::Line(
+            line.trim_end_matches(['\r', '\n']).to_string(),
+        ));
+    }
+
+    let mut block = Vec::new();
+    loop {
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 || line.trim() == BLOCK_DELIMITER {
+            break;
+        }
+        block.push(line.trim_end_matches(['\r', '\n']).to_string());
+    }
+    Ok(ReadLine::Line(block.join("\n")))
+}
+
+/// Read lines from non-interactive stdin up to a `"""` line or the end.
+fn read_piped_block() -> Result<ReadLine> {
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    let mut block = Vec::new();
+    loop {
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            if block.is_empty() {
+                return Ok(ReadLine::Eof);
+            }
+            break;
+        }
+        if line.trim() == BLOCK_DELIMITER {
+            break;
+        }
+        block.push(line.trim_end_matches(['\r', '\n']).to_string());
+    }
+    Ok(ReadLine::Line(block.join("\n")))
+}
+
+/// Whether `text` opens a `"""` block without closing it.
+fn open_block(text: &str) -> bool {
+    match text.trim_start().strip_prefix(BLOCK_DELIMITER) {
+        Some(rest) => !rest.trim_end().ends_with(BLOCK_DELIMITER),
+        None => false,
+    }
+}
+
+/// The contents of a `"""` block, or `text` if it is not one.
+fn strip_block(text: &str) -> String {
+    text.trim()
+        .strip_prefix(BLOCK_DELIMITER)
+        .and_then(|rest| rest.strip_suffix(BLOCK_DELIMITER))
+        .map_or(text, |inner| inner.trim_matches('\n'))
+        .to_string()
+}
+
+fn line_start(text: &[char], cursor: usize) -> usize {
+    text[..cursor]
+        .iter()
+        .rposition(|&c| c == '\n')
+        .map_or(0, |i| i + 1)
+}
+
+fn line_end(text: &[char], cursor: usize) -> usize {
+    text[cursor..]
+        .iter()
+        .position(|&c| c == '\n')
+        .map_or(text.len(), |i| cursor + i)
+}
+
+/// Start of the word before `cursor`, skipping whitespace first.
+fn word_start(text: &[char], cursor: usize) -> usize {
+    let mut i = cursor;
+    while i > 0 && text[i - 1].is_whitespace() {
+        i -= 1;
+    }
+    while i > 0 && !text[i - 1].is_whitespace() {
+        i -= 1;
+    }
+    i
+}
+
+/// Row and column of character `index`, with the first line after a
+/// prompt `first` columns wide, later lines after [`CONTINUATION`], and
+/// long lines wrapped at `width`.
+fn position(text: &[char], index: usize, first: usize, width: usize) -> (usize, usize) {
+    let (mut row, mut col) = (first / width, first % width);
+    for &c in &text[..index] {
+        if c == '\n' {
+            row += 1;
+            col = CONTINUATION.len();
+        } else {
+            col += 1;
+            if col == width {
+                row += 1;
+                col = 0;
+            }
+        }
+    }
+    (row, col)
+}
+
+/// Paths starting with `word`, directories with a trailing `/`. A leading
+/// `~/` stands for the home directory.
+fn path_completions(word: &str) -> Vec<String> {
+    let (dir_part, prefix) = match word.rfind('/') {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+    let dir = match dir_part.strip_prefix("~/") {
+        Some(rest) => std::env::var_os("HOME").map_or(PathBuf::from(dir_part), |home| {
+            PathBuf::from(home).join(rest)
+        }),
+        None if dir_part.is_empty() => PathBuf::from("."),
+        None => PathBuf::from(dir_part),
+    };
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+
+    let mut paths: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir_part, name, slash))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn common_prefix(words: &[String]) -> String {
+    let Some(first) = words.first() else {
+        return String::new();
+    };
+    let mut prefix: Vec<char> = first.chars().collect();
+    for word in &words[1..] {
+        let shared = prefix
+            .iter()
+            .zip(word.chars())
+            .take_while(|(a, b)| **a == *b)
+            .count();
+        prefix.truncate(shared);
+    }
+    prefix.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_escapes_round_trip() {
+        let path = std::env::temp_dir().join(format!("oxide-history-{}", std::process::id()));
+        let mut history = History::load(Some(path.clone()));
+        history.add("fn main() {\n    println!(\"a\\n\");\n}");
+        history.add("second");
+        history.add("second");
+        history.add("  ");
+
+        let loaded = History::load(Some(path.clone()));
+        assert_eq!(loaded.entries, history.entries);
+        assert_eq!(loaded.entries.len(), 2);
+        assert_eq!(loaded.search("main", 2), Some(0));
+        assert_eq!(loaded.search("sec", 1), None);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_blocks() {
+        assert!(open_block("\"\"\""));
+        assert!(open_block("\"\"\"\nfn main() {}"));
+        assert!(!open_block("\"\"\"\nfn main() {}\n\"\"\""));
+        assert!(!open_block("plain"));
+        assert_eq!(
+            strip_block("\"\"\"\nline 1\nline 2\n\"\"\""),
+            "line 1\nline 2"
+        );
+        assert_eq!(strip_block("\"\"\"inline\"\"\""), "inline");
+        assert_eq!(strip_block("no block"), "no block");
+    }
+
+    #[test]
+    fn test_cursor_position_wraps_and_continues() {
+        let text: Vec<char> = "abcdefgh\nxy".chars().collect();
+        // 2-column prompt in a 5-column terminal: "▸ abc" / "defgh" / "  xy"
+        assert_eq!(position(&text, 0, 2, 5), (0, 2));
+        assert_eq!(position(&text, 3, 2, 5), (1, 0));
+        assert_eq!(position(&text, 8, 2, 5), (2, 0));
+        assert_eq!(position(&text, 11, 2, 5), (3, 4));
+        assert_eq!(line_start(&text, 10), 9);
+        assert_eq!(line_end(&text, 2), 8);
+        assert_eq!(word_start(&"ab cd  ".chars().collect::<Vec<_>>(), 7), 3);
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let words = vec!["/retry".to_string(), "/reset".to_string()];
+        assert_eq!(common_prefix(&words), "/re");
+        assert_eq!(common_prefix(&[]), "");
+    }
+}
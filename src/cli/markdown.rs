@@ -0,0 +1,803 @@
+//! Markdown Rendering
+//!
+//! Styles a reply for the terminal as it streams in: headings, bold,
+//! italic, inline code, lists, quotes and rules, with fenced code blocks
+//! highlighted by language in the [`Theme`] palette. Text is printed as
+//! soon as its markup is known: the start of a line is held back until its
+//! block marker is unambiguous, emphasis markers until the next character,
+//! and code lines until they end.
+
+use std::io::{self, Write};
+
+use crossterm::{
+    queue,
+    style::{Attribute, Color, Print, SetAttribute, SetForegroundColor},
+};
+
+use super::theme::Theme;
+
+const RULE_WIDTH: usize = 40;
+
+/// Keywords, line comment and string quotes of a language.
+struct Syntax {
+    names: &'static [&'static str],
+    keywords: &'static [&'static str],
+    comment: &'static str,
+    quotes: &'static [char],
+    /// Keywords match in any case, as in SQL.
+    any_case: bool,
+}
+
+const SYNTAXES: &[Syntax] = &[
+    Syntax {
+        names: &["rust", "rs"],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+            "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+            "trait", "true", "type", "unsafe", "use", "where", "while",
+        ],
+        comment: "//",
+        quotes: &['"'],
+        any_case: false,
+    },
+    Syntax {
+        names: &["python", "py"],
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "False", "finally", "for", "from", "global", "if", "import",
+            "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return",
+            "True", "try", "while", "with", "yield",
+        ],
+        comment: "#",
+        quotes: &['"', '\''],
+        any_case: false,
+    },
+    Syntax {
+        names: &[
+            "javascript",
+            "js",
+            "jsx",
+            "typescript",
+            "ts",
+            "tsx",
+            "mjs",
+            "node",
+        ],
+        keywords: &[
+            "async",
+            "await",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "const",
+            "continue",
+            "default",
+            "delete",
+            "do",
+            "else",
+            "enum",
+            "export",
+            "extends",
+            "false",
+            "finally",
+            "for",
+            "function",
+            "if",
+            "implements",
+            "import",
+            "in",
+            "instanceof",
+            "interface",
+            "let",
+            "new",
+            "null",
+            "return",
+            "static",
+            "super",
+            "switch",
+            "this",
+            "throw",
+            "true",
+            "try",
+            "type",
+            "typeof",
+            "undefined",
+            "var",
+            "void",
+            "while",
+            "yield",
+        ],
+        comment: "//",
+        quotes: &['"', '\'', '`'],
+        any_case: false,
+    },
+    Syntax {
+        names: &["go", "golang"],
+        keywords: &[
+            "break",
+            "case",
+            "chan",
+            "const",
+            "continue",
+            "default",
+            "defer",
+            "else",
+            "false",
+            "fallthrough",
+            "for",
+            "func",
+            "go",
+            "goto",
+            "if",
+            "import",
+            "interface",
+            "map",
+            "nil",
+            "package",
+            "range",
+            "return",
+            "select",
+            "struct",
+            "switch",
+            "true",
+            "type",
+            "var",
+        ],
+        comment: "//",
+        quotes: &['"', '\'', '`'],
+        any_case: false,
+    },
+    Syntax {
+        names: &[
+            "c", "h", "cpp", "c++", "cc", "hpp", "java", "cs", "csharp", "kotlin", "kt", "swift",
+        ],
+        keywords: &[
+            "auto",
+            "bool",
+            "break",
+            "case",
+            "catch",
+            "char",
+            "class",
+            "const",
+            "continue",
+            "default",
+            "delete",
+            "do",
+            "double",
+            "else",
+            "enum",
+            "extends",
+            "extern",
+            "false",
+            "final",
+            "float",
+            "for",
+            "fun",
+            "func",
+            "if",
+            "implements",
+            "import",
+            "int",
+            "interface",
+            "let",
+            "long",
+            "namespace",
+            "new",
+            "null",
+            "nullptr",
+            "package",
+            "private",
+            "protected",
+            "public",
+            "return",
+            "short",
+            "signed",
+            "sizeof",
+            "static",
+            "struct",
+            "switch",
+            "template",
+            "this",
+            "throw",
+            "true",
+            "try",
+            "typedef",
+            "union",
+            "unsigned",
+            "using",
+            "val",
+            "var",
+            "virtual",
+            "void",
+            "volatile",
+            "while",
+        ],
+        comment: "//",
+        quotes: &['"', '\''],
+        any_case: false,
+    },
+    Syntax {
+        names: &["sh", "bash", "shell", "zsh", "console"],
+        keywords: &[
+            "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+            "in", "local", "return", "then", "while",
+        ],
+        comment: "#",
+        quotes: &['"', '\''],
+        any_case: false,
+    },
+    Syntax {
+        names: &["toml", "yaml", "yml", "ini"],
+        keywords: &["true", "false"],
+        comment: "#",
+        quotes: &['"', '\''],
+        any_case: false,
+    },
+    Syntax {
+        names: &["json", "jsonl"],
+        keywords: &["true", "false", "null"],
+        comment: "",
+        quotes: &['"'],
+        any_case: false,
+    },
+    Syntax {
+        names: &["sql"],
+        keywords: &[
+            "and", "as", "by", "create", "delete", "from", "group", "having", "insert", "into",
+            "join", "left", "limit", "not", "null", "on", "or", "order", "select", "set", "table",
+            "update", "values", "where",
+        ],
+        comment: "--",
+        quotes: &['\''],
+        any_case: true,
+    },
+];
+
+fn syntax_for(lang: &str) -> Option<&'static Syntax> {
+    let lang = lang.to_ascii_lowercase();
+    SYNTAXES.iter().find(|s| s.names.contains(&lang.as_str()))
+}
+
+/// Terminal style of a run of text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Style {
+    color: Option<Color>,
+    bold: bool,
+    italic: bool,
+    dim: bool,
+}
+
+impl Style {
+    fn color(color: Color) -> Self {
+        Self {
+            color: Some(color),
+            ..Default::default()
+        }
+    }
+}
+
+/// Block kind of the line being printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Paragraph,
+    Heading(usize),
+    Quote,
+}
+
+/// What the start of a line turned out to be.
+#[derive(Debug, PartialEq, Eq)]
+enum LineStart<'a> {
+    /// Could still become several things; wait for more text.
+    Undecided,
+    Plain,
+    Rule,
+    /// A code fence with its marker (e.g. "```") and language.
+    Fence(&'a str, &'a str),
+    /// A block marker, its replacement, and the text after it.
+    Block(Block, String, &'a str),
+}
+
+/// Classify the start of a line, `line` holding everything received
+/// since the last newline (and the newline, if it has arrived).
+fn classify(line: &str) -> LineStart<'_> {
+    let done = line.ends_with('\n');
+    let body = line.trim_end_matches('\n');
+    let text = body.trim_start();
+    let indent = &body[..body.len() - text.len()];
+    let undecided = |more_possible: bool| {
+        if more_possible && !done {
+            LineStart::Undecided
+        } else {
+            LineStart::Plain
+        }
+    };
+
+    let Some(first) = text.chars().next() else {
+        return undecided(true);
+    };
+    for fence in ["```", "~~~"] {
+        if let Some(lang) = text.strip_prefix(fence) {
+            return match done {
+                true => LineStart::Fence(fence, lang.trim()),
+                false => LineStart::Undecided,
+            };
+        }
+        if fence.starts_with(text) {
+            return undecided(true);
+        }
+    }
+    let after = |marker_len: usize| &line[indent.len() + marker_len..];
+
+    match first {
+        '#' => {
+            let level = text.chars().take_while(|&c| c == '#').count();
+            if level == text.len() {
+                return undecided(level <= 6);
+            }
+            match level <= 6 && text[level..].starts_with(' ') {
+                true => LineStart::Block(Block::Heading(level), String::new(), after(level + 1)),
+                false => LineStart::Plain,
+            }
+        }
+        '-' | '*' | '+' if text[1..].starts_with(' ') => {
+            LineStart::Block(Block::Paragraph, format!("{}• ", indent), after(2))
+        }
+        '-' | '*' | '_' if text.chars().all(|c| c == first) => match done {
+            true if text.len() >= 3 => LineStart::Rule,
+            _ => undecided(true),
+        },
+        '+' if text.len() == 1 => undecided(true),
+        '0'..='9' => {
+            let digits = text.chars().take_while(char::is_ascii_digit).count();
+            let rest = &text[digits..];
+            if rest.is_empty() || rest == "." || rest == ")" {
+                return undecided(digits < 10);
+            }
+            match rest.starts_with(". ") || rest.starts_with(") ") {
+                true => LineStart::Block(
+                    Block::Paragraph,
+                    format!("{}{} ", indent, &text[..digits + 1]),
+                    after(digits + 2),
+                ),
+                false => LineStart::Plain,
+            }
+        }
+        '>' => {
+            if text.len() == 1 {
+                return undecided(true);
+            }
+            let marker = if text[1..].starts_with(' ') { 2 } else { 1 };
+            LineStart::Block(Block::Quote, "│ ".to_string(), after(marker))
+        }
+        _ => LineStart::Plain,
+    }
+}
+
+/// Renders streamed markdown to a terminal.
+pub struct MarkdownRenderer<W: Write> {
+    out: W,
+    /// Text of the current line while its block kind is undecided.
+    pending: Option<String>,
+    block: Block,
+    /// Marker and language of the open code block.
+    fence: Option<(String, String)>,
+    code_line: String,
+    bold: bool,
+    italic: bool,
+    code: bool,
+    /// A run of `*` or `_` waiting for the character after it.
+    delimiter: Option<(char, usize)>,
+    previous: Option<char>,
+    applied: Style,
+}
+
+impl MarkdownRenderer<io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl<W: Write> MarkdownRenderer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            pending: Some(String::new()),
+            block: Block::Paragraph,
+            fence: None,
+            code_line: String::new(),
+            bold: false,
+            italic: false,
+            code: false,
+            delimiter: None,
+            previous: None,
+            applied: Style::default(),
+        }
+    }
+
+    /// Render the next chunk of the reply.
+    pub fn push(&mut self, text: &str) -> io::Result<()> {
+        for c in text.chars() {
+            self.push_char(c)?;
+        }
+        self.out.flush()
+    }
+
+    /// Print whatever is held back and reset the terminal style.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some((_, lang)) = self.fence.take() {
+            let line = std::mem::take(&mut self.code_line);
+            self.write_code(&line, &lang)?;
+        }
+        if let Some(pending) = self.pending.take() {
+            for c in pending.chars() {
+                self.inline_char(c)?;
+            }
+        }
+        self.resolve_delimiter(None)?;
+        self.set_style(Style::default())?;
+        self.pending = Some(String::new());
+        self.reset_inline();
+        self.out.flush()
+    }
+
+    fn push_char(&mut self, c: char) -> io::Result<()> {
+        if let Some((marker, lang)) = &self.fence {
+            if c != '\n' {
+                self.code_line.push(c);
+                return Ok(());
+            }
+            let line = std::mem::take(&mut self.code_line);
+            if line.trim() == marker.as_str() {
+                self.fence = None;
+                self.write_styled(&line, Style::color(Theme::IRON_GRAY))?;
+            } else {
+                let lang = lang.clone();
+                self.write_code(&line, &lang)?;
+            }
+            return self.write_styled("\n", Style::default());
+        }
+
+        let Some(pending) = self.pending.as_mut() else {
+            return self.inline_char(c);
+        };
+        pending.push(c);
+        let line = pending.clone();
+        match classify(&line) {
+            LineStart::Undecided => Ok(()),
+            LineStart::Plain => {
+                self.pending = None;
+                for c in line.chars() {
+                    self.inline_char(c)?;
+                }
+                Ok(())
+            }
+            LineStart::Rule => {
+                self.pending = Some(String::new());
+                let rule = "─".repeat(RULE_WIDTH);
+                self.write_styled(&rule, Style::color(Theme::IRON_GRAY))?;
+                self.write_styled("\n", Style::default())
+            }
+            LineStart::Fence(marker, lang) => {
+                self.pending = Some(String::new());
+                self.fence = Some((marker.to_string(), lang.to_string()));
+                self.write_styled(line.trim_end_matches('\n'), Style::color(Theme::IRON_GRAY))?;
+                self.write_styled("\n", Style::default())
+            }
+            LineStart::Block(block, prefix, rest) => {
+                self.pending = None;
+                self.block = block;
+                let marker = match block {
+                    Block::Quote => Style::color(Theme::IRON_GRAY),
+                    _ => Style {
+                        bold: true,
+                        ..Style::color(Theme::RUST_ORANGE)
+                    },
+                };
+                self.write_styled(&prefix, marker)?;
+                for c in rest.to_string().chars() {
+                    self.inline_char(c)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn inline_char(&mut self, c: char) -> io::Result<()> {
+        if c == '\n' {
+            self.resolve_delimiter(None)?;
+            self.reset_inline();
+            self.write_styled("\n", Style::default())?;
+            self.pending = Some(String::new());
+            return Ok(());
+        }
+        if self.code {
+            if c == '`' {
+                self.code = false;
+            } else {
+                self.write_text(c)?;
+            }
+            self.previous = Some(c);
+            return Ok(());
+        }
+        if let Some((delimiter, count)) = self.delimiter {
+            if c == delimiter {
+                self.delimiter = Some((delimiter, count + 1));
+                return Ok(());
+            }
+            self.resolve_delimiter(Some(c))?;
+        }
+        match c {
+            '`' => self.code = true,
+            '*' | '_' => {
+                self.delimiter = Some((c, 1));
+                return Ok(());
+            }
+            _ => self.write_text(c)?,
+        }
+        self.previous = Some(c);
+        Ok(())
+    }
+
+    /// Decide what a run of `*` or `_` does now that the character after
+    /// it (`None` at the end of a line) is known: close open emphasis, open
+    /// new emphasis, or print as text. `_` inside a word is always text.
+    fn resolve_delimiter(&mut self, next: Option<char>) -> io::Result<()> {
+        let Some((delimiter, count)) = self.delimiter.take() else {
+            return Ok(());
+        };
+        let space_before = self.previous.map_or(true, |c| c.is_whitespace());
+        let space_after = next.map_or(true, char::is_whitespace);
+        let in_word =
+            delimiter == '_' && !space_before && next.is_some_and(|c| c.is_alphanumeric());
+        let can_close = !space_before && !in_word;
+        let can_open = !space_after
+            && !in_word
+            && (delimiter == '*' || self.previous.map_or(true, |c| !c.is_alphanumeric()));
+
+        let mut left = count;
+        if left >= 2 && self.bold && can_close {
+            self.bold = false;
+            left -= 2;
+        }
+        if left >= 1 && self.italic && can_close {
+            self.italic = false;
+            left -= 1;
+        }
+        if left >= 2 && !self.bold && can_open {
+            self.bold = true;
+            left -= 2;
+        }
+        if left >= 1 && !self.italic && can_open {
+            self.italic = true;
+            left -= 1;
+        }
+        for _ in 0..left {
+            self.write_text(delimiter)?;
+        }
+        self.previous = Some(delimiter);
+        Ok(())
+    }
+
+    fn reset_inline(&mut self) {
+        self.block = Block::Paragraph;
+        self.bold = false;
+        self.italic = false;
+        self.code = false;
+        self.delimiter = None;
+        self.previous = None;
+    }
+
+    /// Print `c` in the style of the current block and emphasis.
+    fn write_text(&mut self, c: char) -> io::Result<()> {
+        let mut style = match self.block {
+            Block::Paragraph => Style::default(),
+            Block::Heading(level) => Style {
+                bold: true,
+                ..Style::color(if level <= 2 {
+                    Theme::RUST_ORANGE
+                } else {
+                    Theme::FERRIS_ORANGE
+                })
+            },
+            Block::Quote => Style {
+                italic: true,
+                ..Style::color(Theme::TEXT_SECONDARY)
+            },
+        };
+        style.bold |= self.bold;
+        style.italic |= self.italic;
+        if self.code {
+            style.color = Some(Theme::ACCENT_CYAN);
+        }
+        let mut buf = [0u8; 4];
+        self.write_styled(c.encode_utf8(&mut buf), style)
+    }
+
+    /// Print one line of a code block, highlighted if its language is known.
+    fn write_code(&mut self, line: &str, lang: &str) -> io::Result<()> {
+        let Some(syntax) = syntax_for(lang) else {
+            return self.write_styled(line, Style::color(Theme::TEXT_SECONDARY));
+        };
+        for (text, style) in highlight(line, syntax) {
+            self.write_styled(text, style)?;
+        }
+        Ok(())
+    }
+
+    fn write_styled(&mut self, text: &str, style: Style) -> io::Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.set_style(style)?;
+        queue!(self.out, Print(text))
+    }
+
+    fn set_style(&mut self, style: Style) -> io::Result<()> {
+        if style == self.applied {
+            return Ok(());
+        }
+        queue!(self.out, SetAttribute(Attribute::Reset))?;
+        if let Some(color) = style.color {
+            queue!(self.out, SetForegroundColor(color))?;
+        }
+        if style.bold {
+            queue!(self.out, SetAttribute(Attribute::Bold))?;
+        }
+        if style.italic {
+            queue!(self.out, SetAttribute(Attribute::Italic))?;
+        }
+        if style.dim {
+            queue!(self.out, SetAttribute(Attribute::Dim))?;
+        }
+        self.applied = style;
+        Ok(())
+    }
+}
+
+/// Split a line of code into styled runs: comments, strings, numbers,
+/// keywords and capitalised names (usually types).
+fn highlight<'a>(line: &'a str, syntax: &Syntax) -> Vec<(&'a str, Style)> {
+    let plain = Style::color(Theme::TEXT_PRIMARY);
+    let mut runs = Vec::new();
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        let (len, style) = if !syntax.comment.is_empty() && rest.starts_with(syntax.comment) {
+            let style = Style {
+                italic: true,
+                ..Style::color(Theme::IRON_GRAY)
+            };
+            (rest.len(), style)
+        } else if syntax.quotes.contains(&c) {
+            (string_len(rest, c), Style::color(Theme::SUCCESS_GREEN))
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '.' || c == '_'))
+                .unwrap_or(rest.len());
+            (len, Style::color(Theme::FERRIS_ORANGE))
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            let keyword = syntax.keywords.iter().any(|k| match syntax.any_case {
+                true => k.eq_ignore_ascii_case(word),
+                false => *k == word,
+            });
+            let style = if keyword {
+                Style {
+                    bold: true,
+                    ..Style::color(Theme::RUST_ORANGE)
+                }
+            } else if c.is_uppercase() {
+                Style::color(Theme::ACCENT_CYAN)
+            } else {
+                plain
+            };
+            (len, style)
+        } else {
+            (c.len_utf8(), plain)
+        };
+        runs.push((&rest[..len], style));
+        rest = &rest[len..];
+    }
+    runs
+}
+
+/// Length of the string literal at the start of `text`, quotes included;
+/// the rest of the line if it is not closed.
+fn string_len(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            c if c == quote && !escaped => return i + c.len_utf8(),
+            _ => escaped = false,
+        }
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render `chunks` and return the output without escape sequences.
+    fn render(chunks: &[&str]) -> (String, String) {
+        let mut renderer = MarkdownRenderer::new(Vec::new());
+        for chunk in chunks {
+            renderer.push(chunk).unwrap();
+        }
+        renderer.finish().unwrap();
+        let raw = String::from_utf8(renderer.out).unwrap();
+        let mut visible = String::new();
+        let mut chars = raw.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            } else {
+                visible.push(c);
+            }
+        }
+        (raw, visible)
+    }
+
+    #[test]
+    fn test_inline_markup_and_blocks() {
+        let text = "# Title\nSome **bold**, *italic* and `code`.\n- item\n2. two\n> quoted\n---\n";
+        let (raw, visible) = render(&[text]);
+        assert_eq!(
+            visible,
+            format!(
+                "Title\nSome bold, italic and code.\n• item\n2. two\n│ quoted\n{}\n",
+                "─".repeat(RULE_WIDTH)
+            )
+        );
+        assert!(raw.contains("\x1b[1m"));
+        assert!(raw.contains("\x1b[3m"));
+
+        // Streaming one character at a time gives the same output.
+        let chars: Vec<String> = text.chars().map(String::from).collect();
+        let chunks: Vec<&str> = chars.iter().map(String::as_str).collect();
+        assert_eq!(render(&chunks).0, raw);
+    }
+
+    #[test]
+    fn test_literal_delimiters() {
+        let (_, visible) = render(&["2 * 3 = 6, snake_case_name and **unclosed\n**a **"]);
+        assert_eq!(visible, "2 * 3 = 6, snake_case_name and unclosed\na **");
+    }
+
+    #[test]
+    fn test_code_blocks_are_highlighted_verbatim() {
+        let text = "```rust\nfn main() { let s = \"**x**\"; } // done\n```\nafter\n";
+        let (raw, visible) = render(&[text]);
+        assert_eq!(visible, text);
+        assert!(raw.contains("\x1b[1m"));
+
+        let unknown = "```\n# not a heading *x*\n```\n";
+        assert_eq!(render(&[unknown]).1, unknown);
+    }
+
+    #[test]
+    fn test_classify_waits_for_ambiguous_starts() {
+        assert_eq!(classify("#"), LineStart::Undecided);
+        assert_eq!(classify("--"), LineStart::Undecided);
+        assert_eq!(classify("**b"), LineStart::Plain);
+        assert_eq!(classify("12"), LineStart::Undecided);
+        assert_eq!(classify("``"), LineStart::Undecided);
+        assert_eq!(classify("```py\n"), LineStart::Fence("```", "py"));
+        assert_eq!(classify("#hashtag"), LineStart::Plain);
+        assert_eq!(
+            classify("  - x"),
+            LineStart::Block(Block::Paragraph, "  • ".to_string(), "x")
+        );
+    }
+}
@@ -1,8 +1,14 @@
 pub mod banner;
+pub mod editor;
 pub mod loader;
+pub mod markdown;
+pub mod picker;
 pub mod stream;
 pub mod theme;
 
 pub use banner::{print_banner, print_divider};
+pub use editor::{LineEditor, ReadLine};
 pub use loader::{print_model_info, ModelLoader};
+pub use markdown::MarkdownRenderer;
+pub use picker::pick;
 pub use stream::{print_welcome, PromptDisplay, StreamOutput, ThinkingSpinner};
@@ -0,0 +1,65 @@
+use std::io::{self, BufRead, Write};
+
+use crossterm::{
+    execute,
+    style::{Print, ResetColor, SetForegroundColor},
+};
+
+use super::theme::Theme;
+
+/// Print `items` as a numbered menu and read a choice from stdin.
+///
+/// Returns the index of the chosen item, or `None` when the user enters
+/// nothing or stdin is closed. Invalid choices are asked for again.
+pub fn pick(title: &str, items: &[String]) -> io::Result<Option<usize>> {
+    let mut stdout = io::stdout();
+    let width = items.len().to_string().len();
+
+    execute!(
+        stdout,
+        SetForegroundColor(Theme::RUST_ORANGE),
+        Print(format!("  {}\n", title)),
+        ResetColor,
+    )?;
+    for (i, item) in items.iter().enumerate() {
+        execute!(
+            stdout,
+            SetForegroundColor(Theme::IRON_GRAY),
+            Print(format!("  {:>width$}. ", i + 1)),
+            SetForegroundColor(Theme::TEXT_PRIMARY),
+            Print(item),
+            ResetColor,
+            Print("\n"),
+        )?;
+    }
+
+    let stdin = io::stdin();
+    loop {
+        execute!(
+            stdout,
+            SetForegroundColor(Theme::ACCENT_CYAN),
+            Print(format!("  Choose 1-{} (Enter to cancel): ", items.len())),
+            ResetColor,
+        )?;
+        stdout.flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        match line.parse::<usize>() {
+            Ok(n) if (1..=items.len()).contains(&n) => return Ok(Some(n - 1)),
+            _ => execute!(
+                stdout,
+                SetForegroundColor(Theme::ERROR_RED),
+                Print(format!("  Not a choice: {}\n", line)),
+                ResetColor,
+            )?,
+        }
+    }
+}
@@ -11,6 +11,7 @@ use crossterm::{
     terminal::{Clear, ClearType},
 };
 
+use super::markdown::MarkdownRenderer;
 use super::theme::Theme;
 
 const THINKING_FRAMES: &[&str] = &[
@@ -22,9 +23,9 @@ const THINKING_FRAMES: &[&str] = &[
 
 pub fn format_token_count(n: usize) -> String {
     if n >= 1_000_000 {
-        format!("{:.1}M", n as f64 / 1_000_000)
+        format!("{:.1}M", n as f64 / 1_000_000.0)
     } else if n >= 1_000 {
-        format!("{:.1}k", n as f64 / 1_000)
+        format!("{:.1}k", n as f64 / 1_000.0)
     } else {
         n.to_string()
     }
@@ -94,26 +95,34 @@ impl Drop for ThinkingSpinner {
     }
 }
 
-pub fn strip_special_tokens(input: &str) -> String {
-    let mut result = input.to_string();
+const SPECIAL_TOKEN_MARKERS: &[&str] = &[
+    "<|im_start|>user",
+    "<|im_start|>assistant",
+    "<|im_start|>system",
+    "<|im_start|>",
+    "<|im_end|>",
+    "<|end|>",
+    "<|start|>",
+    "<|sep|>",
+    "<s>",
+    "</s>",
+    "<pad>",
+];
 
-    let patterns = [
-        "<|im_start|>user",
-        "<|im_start|>assistant",
-        "<|im_start|>system",
-        "<|im_start|>",
-        "<|im_end|>",
-        "<|end|>",
-        "<|start|>",
-        "<|sep|>",
-        "<s>",
-        "</s>",
-        "<pad>",
-    ];
-
-    for pattern in &patterns {
-        result = result.replace(pattern, "");
+/// Remove chat-template markers from a streamed chunk without touching
+/// whitespace, so concatenated chunks still match the decoded text.
+pub fn strip_special_markers(input: &str) -> String {
+    let mut result = input.to_string();
+    for pattern in SPECIAL_TOKEN_MARKERS {
+        if result.contains(pattern) {
+            result = result.replace(pattern, "");
+        }
     }
+    result
+}
+
+pub fn strip_special_tokens(input: &str) -> String {
+    let mut result = strip_special_markers(input);
 
     result = result.replace("<br>", "\n");
     result = result.replace("\\n", "\n");
@@ -134,6 +143,7 @@ pub struct StreamOutput {
     context_limit: usize,
     prompt_tokens: usize,
     finished: bool,
+    markdown: Option<MarkdownRenderer<io::Stdout>>,
 }
 
 impl StreamOutput {
@@ -147,9 +157,16 @@ impl StreamOutput {
             context_limit: 4096,
             prompt_tokens: 0,
             finished: false,
+            markdown: None,
         }
     }
 
+    /// Render tokens as markdown instead of printing them verbatim.
+    pub fn with_markdown(mut self, enabled: bool) -> Self {
+        self.markdown = enabled.then(MarkdownRenderer::stdout);
+        self
+    }
+
     pub fn set_context(&mut self, used: usize, limit: usize) {
         self.context_used = used;
         self.context_limit = limit;
@@ -166,20 +183,19 @@ impl StreamOutput {
 
         self.token_count += 1;
 
-        let cleaned = strip_special_tokens(token);
-
-        if !cleaned.is_empty() {
-            // Add space if needed for proper word spacing
-            let output = if !cleaned.starts_with(' ')
-                && !cleaned.starts_with('\n')
-                && self.token_count > 1
-            {
-                format!(" {}", cleaned)
-            } else {
-                cleaned
-            };
-            execute!(self.stdout, Print(&output)).ok();
-            self.stdout.flush().ok();
+        let cleaned = strip_special_markers(token);
+
+        if cleaned.is_empty() {
+            return;
+        }
+        match &mut self.markdown {
+            Some(renderer) => {
+                renderer.push(&cleaned).ok();
+            }
+            None => {
+                execute!(self.stdout, Print(&cleaned)).ok();
+                self.stdout.flush().ok();
+            }
         }
     }
 
@@ -188,6 +204,9 @@ impl StreamOutput {
             return;
         }
         self.finished = true;
+        if let Some(renderer) = &mut self.markdown {
+            renderer.finish().ok();
+        }
 
         let elapsed = self.start_time.elapsed();
         let tokens_per_sec = if elapsed.as_secs_f64() > 0.0 {
@@ -0,0 +1,416 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use oxide_rs::{BatchConfig, Completion, FinishReason, GenerateOptions, Message, Model, Profile};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{lib_err, model_path, tokenizer_path};
+
+#[derive(Args, Debug)]
+pub struct BatchArgs {
+    /// Path to GGUF model file (default: from the config file)
+    #[arg(short, long, env = "OXIDE_MODEL")]
+    pub model: Option<PathBuf>,
+
+    /// Path to a Hugging Face tokenizer.json or GGUF
+    #[arg(short, long)]
+    pub tokenizer: Option<PathBuf>,
+
+    /// JSONL file of requests, one object per line
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// JSONL file the results are appended to; ids already in it are skipped
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// System prompt for requests without their own
+    #[arg(short, long)]
+    pub system: Option<String>,
+
+    /// Maximum tokens to generate per request
+    #[arg(long, default_value = "512")]
+    pub max_tokens: usize,
+
+    /// Temperature for sampling (0.0 = greedy)
+    #[arg(long, default_value = "0.3")]
+    pub temperature: f64,
+
+    /// Top-p sampling threshold
+    #[arg(long)]
+    pub top_p: Option<f64>,
+
+    /// Top-k sampling
+    #[arg(long)]
+    pub top_k: Option<usize>,
+
+    /// Repeat penalty
+    #[arg(long, default_value = "1.1")]
+    pub repeat_penalty: f32,
+
+    /// Context size for repeat penalty
+    #[arg(long, default_value = "64")]
+    pub repeat_last_n: usize,
+
+    /// Random seed, restarted for every request
+    #[arg(long, default_value = "299792458")]
+    pub seed: u64,
+
+    /// Context size (default: the model's trained context)
+    #[arg(long, short = 'c')]
+    pub ctx_size: Option<usize>,
+}
+
+/// One line of the input. Either `prompt` or `messages` is required; the
+/// other fields override the command-line options for this request.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchRequest {
+    /// Read by [`request_id`], which also handles lines that fail to parse.
+    #[allow(dead_code)]
+    id: Option<Value>,
+    prompt: Option<String>,
+    messages: Option<Vec<Message>>,
+    system: Option<String>,
+    max_tokens: Option<usize>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    top_k: Option<usize>,
+    seed: Option<u64>,
+    repeat_penalty: Option<f32>,
+    repeat_last_n: Option<usize>,
+}
+
+impl BatchRequest {
+    /// The command-line options with this request's overrides.
+    fn options(&self, base: &GenerateOptions) -> GenerateOptions {
+        GenerateOptions {
+            max_tokens: self.max_tokens.unwrap_or(base.max_tokens),
+            temperature: self.temperature.unwrap_or(base.temperature),
+            top_p: self.top_p.or(base.top_p),
+            top_k: self.top_k.or(base.top_k),
+            seed: self.seed.unwrap_or(base.seed),
+            repeat_penalty: self.repeat_penalty.unwrap_or(base.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.unwrap_or(base.repeat_last_n),
+            system_prompt: self.system.clone().or_else(|| base.system_prompt.clone()),
+            ..base.clone()
+        }
+    }
+
+    /// The messages and options of an input line.
+    fn parse(line: &str, base: &GenerateOptions) -> Result<(Vec<Message>, GenerateOptions)> {
+        let request: Self = serde_json::from_str(line).context("Invalid request")?;
+        let options = request.options(base);
+        Ok((request.messages()?, options))
+    }
+
+    fn messages(self) -> Result<Vec<Message>> {
+        match (self.prompt, self.messages) {
+            (Some(prompt), None) => Ok(vec![Message {
+                role: "user".into(),
+                content: prompt,
+            }]),
+            (None, Some(messages)) if !messages.is_empty() => Ok(messages),
+            (None, Some(_)) => anyhow::bail!("\"messages\" is empty"),
+            (None, None) => anyhow::bail!("Missing \"prompt\" or \"messages\""),
+            (Some(_), Some(_)) => anyhow::bail!("Give either \"prompt\" or \"messages\", not both"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BatchUsage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+/// One line of the output.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchRecord<'a> {
+    Reply {
+        id: &'a Value,
+        text: String,
+        finish_reason: FinishReason,
+        usage: BatchUsage,
+    },
+    Error {
+        id: &'a Value,
+        error: String,
+    },
+}
+
+/// Run the requests of `args.input` through a [`oxide_rs::DynamicBatcher`] on
+/// a single loaded model, writing the results in input order.
+pub fn run_batch(args: BatchArgs, profile: &Profile) -> Result<()> {
+    let input = std::fs::read_to_string(&args.input)
+        .with_context(|| format!("Failed to read {:?}", args.input))?;
+    let lines: Vec<(usize, &str)> = input
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect();
+
+    let mut done = read_done_ids(&args.output)?;
+    let mut seen = done.clone();
+    let (mut finished, mut repeated) = (0, 0);
+    for &(number, line) in &lines {
+        let key = id_key(&request_id(line, number));
+        if done.contains(&key) {
+            finished += 1;
+        } else if !seen.insert(key) {
+            repeated += 1;
+        }
+    }
+    let pending = lines.len() - finished - repeated;
+    if finished > 0 {
+        println!(
+            "Skipping {} of {} requests already in {:?}",
+            finished,
+            lines.len(),
+            args.output
+        );
+    }
+    if repeated > 0 {
+        println!("Skipping {} requests that repeat an earlier id", repeated);
+    }
+    if pending == 0 {
+        println!("Nothing to do");
+        return Ok(());
+    }
+
+    let base = GenerateOptions {
+        max_tokens: args.max_tokens,
+        temperature: args.temperature,
+        top_p: args.top_p,
+        top_k: args.top_k,
+        seed: args.seed,
+        repeat_penalty: args.repeat_penalty,
+        repeat_last_n: args.repeat_last_n,
+        system_prompt: args.system.clone(),
+        ctx_size: args.ctx_size,
+        ..Default::default()
+    };
+    let mut model = Model::new(model_path(args.model.as_deref(), profile)?)
+        .map_err(lib_err)?
+        .with_options(base.clone());
+    if let Some(path) = tokenizer_path(args.tokenizer.as_deref(), profile) {
+        model = model.with_tokenizer(path);
+    }
+    model.load().map_err(lib_err)?;
+
+    let runtime = tokio::runtime::Runtime::new().context("Failed to start the batch runtime")?;
+    let _runtime = runtime.enter();
+    let batcher = model
+        .into_batcher(BatchConfig::default())
+        .map_err(lib_err)?;
+
+    // Queue every pending request up front, then collect the replies in input order.
+    let mut queued = Vec::with_capacity(pending);
+    for &(number, line) in &lines {
+        let id = request_id(line, number);
+        if !done.insert(id_key(&id)) {
+            continue;
+        }
+        let reply = BatchRequest::parse(line, &base).map(|(messages, options)| {
+            let batcher = batcher.clone();
+            runtime.spawn(async move { batcher.generate(messages, options).await })
+        });
+        queued.push((id, reply));
+    }
+    drop(batcher);
+
+    let mut output = open_output(&args.output)?;
+    let started = Instant::now();
+    let mut last_reply = Instant::now();
+    let (total, mut errors) = (queued.len(), 0);
+    for (index, (id, reply)) in queued.into_iter().enumerate() {
+        let index = index + 1;
+        let result = reply.and_then(|task| match runtime.block_on(task) {
+            Ok(result) => result.map_err(anyhow::Error::msg),
+            Err(e) => Err(e).context("The batch task failed"),
+        });
+        let record = match result {
+            Ok(Completion { text, usage }) => {
+                println!(
+                    "[{}/{}] {}: {} tokens, {} ({:.1}s)",
+                    index,
+                    pending,
+                    display_id(&id),
+                    usage.completion_tokens,
+                    usage.finish_reason.as_str(),
+                    last_reply.elapsed().as_secs_f64()
+                );
+                BatchRecord::Reply {
+                    id: &id,
+                    text,
+                    finish_reason: usage.finish_reason,
+                    usage: BatchUsage {
+                        prompt_tokens: usage.prompt_tokens,
+                        completion_tokens: usage.completion_tokens,
+                        total_tokens: usage.prompt_tokens + usage.completion_tokens,
+                    },
+                }
+            }
+            Err(e) => {
+                errors += 1;
+                println!(
+                    "[{}/{}] {}: error: {:#}",
+                    index,
+                    pending,
+                    display_id(&id),
+                    e
+                );
+                BatchRecord::Error {
+                    id: &id,
+                    error: format!("{:#}", e),
+                }
+            }
+        };
+        last_reply = Instant::now();
+        serde_json::to_writer(&mut output, &record)?;
+        writeln!(output)?;
+        output
+            .flush()
+            .with_context(|| format!("Failed to write {:?}", args.output))?;
+    }
+
+    println!();
+    println!(
+        "Wrote {} results to {:?} in {:.1}s: {} ok, {} failed",
+        total,
+        args.output,
+        started.elapsed().as_secs_f64(),
+        total - errors,
+        errors
+    );
+    Ok(())
+}
+
+/// The `id` of a request line, or its line number. Lines that are not
+/// JSON objects also get their line number, so their error record can be
+/// matched up.
+fn request_id(line: &str, number: usize) -> Value {
+    serde_json::from_str::<Value>(line)
+        .ok()
+        .and_then(|value| value.get("id").cloned())
+        .filter(|id| id.is_string() || id.is_number())
+        .unwrap_or_else(|| Value::from(number))
+}
+
+/// Ids compare by their JSON text, so `1` and `"1"` are different ids.
+fn id_key(id: &Value) -> String {
+    id.to_string()
+}
+
+fn display_id(id: &Value) -> String {
+    match id {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Ids of the records already in the output file. Lines cut short by an
+/// interrupted run are ignored, so their requests run again.
+fn read_done_ids(path: &Path) -> Result<HashSet<String>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+    };
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter_map(|record| record.get("id").map(id_key))
+        .collect())
+}
+
+/// Open the output for appending, ending a line cut short by an
+/// interrupted run so the next record starts on its own line.
+fn open_output(path: &Path) -> Result<BufWriter<File>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    if file.metadata()?.len() > 0 {
+        let mut last = [0u8; 1];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+    }
+    Ok(BufWriter::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_ids_default_to_line_numbers() {
+        assert_eq!(request_id(r#"{"id": "q1", "prompt": "Hi"}"#, 3), "q1");
+        assert_eq!(request_id(r#"{"id": 7, "prompt": "Hi"}"#, 3), 7);
+        assert_eq!(request_id(r#"{"prompt": "Hi"}"#, 3), 3);
+        assert_eq!(request_id(r#"{"id": [1], "prompt": "Hi"}"#, 3), 3);
+        assert_eq!(request_id("not json", 4), 4);
+        assert_ne!(id_key(&Value::from(1)), id_key(&Value::from("1")));
+    }
+
+    #[test]
+    fn test_request_options_and_messages() {
+        let base = GenerateOptions {
+            system_prompt: Some("Be brief.".into()),
+            ..Default::default()
+        };
+        let request: BatchRequest =
+            serde_json::from_str(r#"{"prompt": "Hi", "temperature": 0.0, "max_tokens": 8}"#)
+                .unwrap();
+        let options = request.options(&base);
+        assert_eq!(options.temperature, 0.0);
+        assert_eq!(options.max_tokens, 8);
+        assert_eq!(options.seed, base.seed);
+        assert_eq!(options.system_prompt.as_deref(), Some("Be brief."));
+        assert_eq!(request.messages().unwrap()[0].content, "Hi");
+
+        let both: BatchRequest = serde_json::from_str(
+            r#"{"prompt": "Hi", "messages": [{"role": "user", "content": "Hi"}]}"#,
+        )
+        .unwrap();
+        assert!(both.messages().is_err());
+        assert!(
+            serde_json::from_str::<BatchRequest>(r#"{"prompt": "Hi", "temprature": 1}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_resumes_after_interrupted_runs() {
+        let path = std::env::temp_dir().join(format!("oxide-batch-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            "{\"id\":\"a\",\"text\":\"x\"}\n{\"id\":2,\"error\":\"e\"}\n{\"id\":\"c\",\"te",
+        )
+        .unwrap();
+
+        let done = read_done_ids(&path).unwrap();
+        assert_eq!(done.len(), 2);
+        assert!(done.contains(&id_key(&Value::from("a"))));
+        assert!(done.contains(&id_key(&Value::from(2))));
+
+        let mut output = open_output(&path).unwrap();
+        writeln!(output, "{{\"id\":\"c\"}}").unwrap();
+        drop(output);
+        assert_eq!(read_done_ids(&path).unwrap().len(), 3);
+        std::fs::remove_file(&path).ok();
+    }
+}
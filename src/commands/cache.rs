@@ -0,0 +1,100 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use oxide_rs::model::cache::{PruneStats, TokenizerCache};
+
+use super::format_size;
+
+#[derive(Args, Debug)]
+pub struct CacheArgs {
+    #[command(subcommand)]
+    pub action: CacheAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheAction {
+    /// Show cached tokenizers and the models that use them
+    List,
+
+    /// Remove entries for moved, modified or deleted models
+    Prune,
+
+    /// Remove every cached tokenizer
+    Clear,
+}
+
+pub fn run_cache(args: CacheArgs) -> Result<()> {
+    let cache = TokenizerCache::open();
+
+    match args.action {
+        CacheAction::List => list(&cache),
+        CacheAction::Prune => {
+            report("Pruned", cache.prune()?);
+            Ok(())
+        }
+        CacheAction::Clear => {
+            report("Cleared", cache.clear()?);
+            Ok(())
+        }
+    }
+}
+
+fn list(cache: &TokenizerCache) -> Result<()> {
+    let entries = cache.entries()?;
+    println!("Cache directory: {}", cache.root().display());
+
+    if entries.is_empty() {
+        println!("No cached tokenizers.");
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    for entry in &entries {
+        let status = if entry.valid { "" } else { "  (invalid)" };
+        println!(
+            "\n{}  {}  {} tokens  {}  {}{}",
+            &entry.hash[..entry.hash.len().min(12)],
+            entry.model_type.as_deref().unwrap_or("unknown"),
+            entry.vocab_size,
+            format_size(entry.size_bytes),
+            entry
+                .created
+                .map(|t| format_age(now.saturating_sub(t)))
+                .unwrap_or_default(),
+            status
+        );
+        if entry.sources.is_empty() {
+            println!("  (no models)");
+        }
+        for source in &entry.sources {
+            println!("  {}", source.display());
+        }
+    }
+
+    let total: u64 = entries.iter().map(|e| e.size_bytes).sum();
+    println!("\n{} tokenizer(s), {}", entries.len(), format_size(total));
+    Ok(())
+}
+
+fn report(verb: &str, stats: PruneStats) {
+    println!(
+        "{} {} file(s), {} freed",
+        verb,
+        stats.files,
+        format_size(stats.bytes)
+    );
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
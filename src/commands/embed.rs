@@ -0,0 +1,112 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Args;
+use oxide_rs::{EmbedOptions, Model, Pooling, Profile};
+use serde::Serialize;
+
+use super::{lib_err, model_path, text_or_stdin, tokenizer_path};
+
+#[derive(Args, Debug)]
+pub struct EmbedArgs {
+    /// Path to GGUF model file (default: from the config file)
+    #[arg(short, long, env = "OXIDE_MODEL")]
+    pub model: Option<PathBuf>,
+
+    /// Path to a Hugging Face tokenizer.json or GGUF
+    #[arg(short, long)]
+    pub tokenizer: Option<PathBuf>,
+
+    /// Texts to embed (read from stdin, one per line, if omitted)
+    pub texts: Vec<String>,
+
+    /// Pooling: mean, cls or last (default: from the model)
+    #[arg(long)]
+    pub pooling: Option<Pooling>,
+
+    /// Do not L2-normalise the embeddings
+    #[arg(long)]
+    pub no_normalize: bool,
+
+    /// Inputs per forward pass of an encoder model (decoders run one at a time)
+    #[arg(long, default_value = "8")]
+    pub batch_size: usize,
+}
+
+/// Printed in the shape of OpenAI's `/v1/embeddings` response body, so the
+/// output can be fed to tools that expect it; no server is involved.
+#[derive(Serialize)]
+struct EmbeddingList {
+    object: &'static str,
+    data: Vec<EmbeddingData>,
+    model: String,
+    usage: Usage,
+}
+
+#[derive(Serialize)]
+struct EmbeddingData {
+    object: &'static str,
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Serialize)]
+struct Usage {
+    prompt_tokens: usize,
+    total_tokens: usize,
+}
+
+pub fn run_embed(args: EmbedArgs, profile: &Profile) -> Result<()> {
+    let texts = if args.texts.is_empty() {
+        text_or_stdin(None)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect()
+    } else {
+        args.texts
+    };
+
+    let path = model_path(args.model.as_deref(), profile)?;
+    let mut model = Model::new(&path)
+        .map_err(lib_err)?
+        .with_embed_options(EmbedOptions {
+            pooling: args.pooling,
+            normalize: !args.no_normalize,
+            batch_size: args.batch_size,
+        });
+    if let Some(path) = tokenizer_path(args.tokenizer.as_deref(), profile) {
+        model = model.with_tokenizer(path);
+    }
+
+    let inputs: Vec<&str> = texts.iter().map(String::as_str).collect();
+    let embeddings = model.embed(&inputs).map_err(lib_err)?;
+
+    let mut prompt_tokens = 0;
+    for text in &inputs {
+        prompt_tokens += model.tokenize(text, true).map_err(lib_err)?.len();
+    }
+
+    let response = EmbeddingList {
+        object: "list",
+        data: embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| EmbeddingData {
+                object: "embedding",
+                index,
+                embedding,
+            })
+            .collect(),
+        model: path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        usage: Usage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    };
+    println!("{}", serde_json::to_string(&response)?);
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use oxide_rs::{ImatrixOptions, Model, Profile};
+
+use super::{lib_err, model_path, text_or_stdin, tokenizer_path};
+
+#[derive(Args, Debug)]
+pub struct ImatrixArgs {
+    /// Path to GGUF model file (default: from the config file)
+    #[arg(short, long, env = "OXIDE_MODEL")]
+    pub model: Option<PathBuf>,
+
+    /// Path to a Hugging Face tokenizer.json or GGUF
+    #[arg(short, long)]
+    pub tokenizer: Option<PathBuf>,
+
+    /// Calibration text file (read from stdin if omitted)
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+
+    /// File to write, in llama.cpp's imatrix.dat format
+    #[arg(short, long, default_value = "imatrix.dat")]
+    pub output: PathBuf,
+
+    /// Window length in tokens
+    #[arg(long, default_value = "512")]
+    pub ctx: usize,
+
+    /// Stop after this many windows
+    #[arg(long)]
+    pub chunks: Option<usize>,
+}
+
+pub fn run_imatrix(args: ImatrixArgs, profile: &Profile) -> Result<()> {
+    let (text, dataset) = match &args.file {
+        Some(path) => (
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?,
+            path.display().to_string(),
+        ),
+        None => (text_or_stdin(None)?, "stdin".to_string()),
+    };
+
+    let mut model = Model::new(model_path(args.model.as_deref(), profile)?).map_err(lib_err)?;
+    if let Some(path) = tokenizer_path(args.tokenizer.as_deref(), profile) {
+        model = model.with_tokenizer(path);
+    }
+
+    let options = ImatrixOptions {
+        ctx: args.ctx,
+        max_chunks: args.chunks,
+    };
+    let imatrix = model
+        .imatrix(&text, &options, &dataset, |chunk| {
+            println!(
+                "[{}/{}] {} tokens",
+                chunk.index + 1,
+                chunk.total,
+                chunk.tokens
+            );
+        })
+        .map_err(lib_err)?;
+    imatrix.save(&args.output)?;
+
+    println!();
+    println!(
+        "Wrote {:?}: {} weights over {} chunks of {} tokens",
+        args.output,
+        imatrix.len(),
+        imatrix.chunks(),
+        args.ctx
+    );
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Args;
+use oxide_rs::{GgufInspection, Model, Profile};
+
+use super::{format_size, lib_err, model_path};
+
+/// Array items and string characters shown per metadata value without `--full`.
+const MAX_ARRAY_ITEMS: usize = 8;
+const MAX_STRING_CHARS: usize = 80;
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// Path to GGUF model file (any shard of a split model) (default: from the config file)
+    #[arg(short, long, env = "OXIDE_MODEL")]
+    pub model: Option<PathBuf>,
+
+    /// Print JSON instead of text
+    #[arg(long)]
+    pub json: bool,
+
+    /// Show long arrays and strings in full
+    #[arg(long)]
+    pub full: bool,
+
+    /// Skip the tensor table
+    #[arg(long)]
+    pub no_tensors: bool,
+}
+
+pub fn run_inspect(args: InspectArgs, profile: &Profile) -> Result<()> {
+    let report = Model::new(model_path(args.model.as_deref(), profile)?)
+        .map_err(lib_err)?
+        .inspect()
+        .map_err(lib_err)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report, &args);
+    }
+
+    Ok(())
+}
+
+fn print_report(report: &GgufInspection, args: &InspectArgs) {
+    for file in &report.files {
+        println!("File:     {}", file.display());
+    }
+    println!(
+        "Format:   GGUF v{}, {}, {} tensors",
+        report.version,
+        format_size(report.file_size),
+        report.tensors.len()
+    );
+
+    println!("\nMetadata ({} keys)", report.metadata.len());
+    for (key, value) in &report.metadata {
+        if key == "tokenizer.chat_template" {
+            continue;
+        }
+        println!("  {} = {}", key, format_value(value, args.full));
+    }
+
+    if !report.special_tokens.is_empty() {
+        println!("\nSpecial tokens");
+        for token in &report.special_tokens {
+            let piece = token
+                .piece
+                .as_ref()
+                .map(|p| format!("{:?}", p))
+                .unwrap_or_else(|| "(out of range)".to_string());
+            println!("  {:<5} {:>8}  {}", token.name, token.id, piece);
+        }
+    }
+
+    if !args.no_tensors {
+        println!("\nTensors");
+        println!(
+            "  {:<40} {:<20} {:<6} {:>10} {:>12}",
+            "name", "shape", "type", "size", "offset"
+        );
+        for tensor in &report.tensors {
+            let offset = if report.files.len() > 1 {
+                format!("{}:{}", tensor.file + 1, tensor.offset)
+            } else {
+                tensor.offset.to_string()
+            };
+            println!(
+                "  {:<40} {:<20} {:<6} {:>10} {:>12}",
+                tensor.name,
+                format!("{:?}", tensor.shape),
+                tensor.dtype,
+                format_size(tensor.size_bytes),
+                offset
+            );
+        }
+    }
+
+    println!("\nBy type");
+    for total in &report.dtype_totals {
+        println!(
+            "  {:<6} {:>5} tensors {:>14} params {:>10}",
+            total.dtype,
+            total.tensors,
+            total.parameters,
+            format_size(total.size_bytes)
+        );
+    }
+
+    match &report.chat_template {
+        Some(template) => println!("\nChat template\n{}", template),
+        None => println!("\nChat template: none"),
+    }
+}
+
+fn format_value(value: &serde_json::Value, full: bool) -> String {
+    match value {
+        serde_json::Value::Array(items) if !full && items.len() > MAX_ARRAY_ITEMS => {
+            let shown: Vec<String> = items[..MAX_ARRAY_ITEMS]
+                .iter()
+                .map(|v| format_value(v, full))
+                .collect();
+            format!("[{}, ...] ({} items)", shown.join(", "), items.len())
+        }
+        serde_json::Value::Array(items) => {
+            let shown: Vec<String> = items.iter().map(|v| format_value(v, full)).collect();
+            format!("[{}]", shown.join(", "))
+        }
+        serde_json::Value::String(s) if !full && s.chars().count() > MAX_STRING_CHARS => {
+            let shown: String = s.chars().take(MAX_STRING_CHARS).collect();
+            format!("{:?}... ({} chars)", shown, s.chars().count())
+        }
+        serde_json::Value::String(s) => format!("{:?}", s),
+        other => other.to_string(),
+    }
+}
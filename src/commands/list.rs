@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Args;
+use oxide_rs::model::discovery::{self, ModelEntry};
+use oxide_rs::Profile;
+
+use super::format_size;
+
+#[derive(Args, Debug)]
+pub struct ListArgs {
+    /// Also search this directory; can be repeated
+    #[arg(short, long, value_name = "DIR")]
+    pub dir: Vec<PathBuf>,
+
+    /// Print JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+pub fn run_list(args: ListArgs, profile: &Profile) -> Result<()> {
+    let mut configured = args.dir;
+    configured.extend(profile.model_dir_paths());
+
+    let dirs = discovery::model_dirs(&configured);
+    let entries = discovery::discover(&dirs);
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+
+    if dirs.is_empty() {
+        println!(
+            "No model directories. Create ~/Models, set {} or add model_dirs to the config file.",
+            discovery::MODELS_ENV
+        );
+        return Ok(());
+    }
+    for dir in &dirs {
+        println!("Searching {}", dir.display());
+    }
+    if entries.is_empty() {
+        println!("No models found.");
+        return Ok(());
+    }
+
+    print_table(&entries);
+    Ok(())
+}
+
+fn print_table(entries: &[ModelEntry]) {
+    let width = |column: fn(&ModelEntry) -> usize, title: &str| {
+        entries
+            .iter()
+            .map(column)
+            .max()
+            .unwrap_or(0)
+            .max(title.len())
+    };
+    let alias_width = width(|e| e.alias.chars().count(), "alias");
+    let name_width = width(|e| e.name.chars().count(), "name");
+    let arch_width = width(|e| e.architecture.len(), "arch");
+
+    println!(
+        "\n{:<alias_width$}  {:<name_width$}  {:<arch_width$}  {:<7} {:>8} {:>8}  path",
+        "alias", "name", "arch", "quant", "size", "context",
+    );
+    for entry in entries {
+        println!(
+            "{:<alias_width$}  {:<name_width$}  {:<arch_width$}  {:<7} {:>8} {:>8}  {}",
+            entry.alias,
+            entry.name,
+            entry.architecture,
+            entry.quantization.as_deref().unwrap_or("-"),
+            format_size(entry.file_size),
+            entry.context_length,
+            entry.path.display(),
+        );
+    }
+}
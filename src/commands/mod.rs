@@ -0,0 +1,70 @@
+//! Subcommands of the `oxide-rs` binary.
+
+pub mod batch;
+pub mod cache;
+pub mod embed;
+pub mod imatrix;
+pub mod inspect;
+pub mod list;
+pub mod perplexity;
+pub mod quantize;
+pub mod tokenize;
+
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use oxide_rs::model::discovery;
+use oxide_rs::Profile;
+
+/// Convert the library's boxed errors into `anyhow` errors.
+pub fn lib_err(e: Box<dyn std::error::Error>) -> anyhow::Error {
+    anyhow::anyhow!("{}", e)
+}
+
+/// The file `-m` names: the path itself, or the model a `name:quant`
+/// alias names in the model directories. Without `-m`, the model of the
+/// config file and `--profile`.
+pub fn model_path(spec: Option<&Path>, profile: &Profile) -> Result<PathBuf> {
+    let Some(spec) = spec.map(Path::to_path_buf).or_else(|| profile.model_path()) else {
+        anyhow::bail!("No model: pass --model, set OXIDE_MODEL or add `model` to the config file");
+    };
+    if spec.exists() {
+        return Ok(spec);
+    }
+    discovery::resolve(&spec, &discovery::model_dirs(&profile.model_dir_paths()))
+}
+
+/// The tokenizer `-t` names, else the one of the config file and `--profile`.
+pub fn tokenizer_path(spec: Option<&Path>, profile: &Profile) -> Option<PathBuf> {
+    spec.map(Path::to_path_buf)
+        .or_else(|| profile.tokenizer_path())
+}
+
+/// Use the positional text if given, otherwise read all of stdin.
+pub fn text_or_stdin(text: Option<String>) -> Result<String> {
+    match text {
+        Some(text) => Ok(text),
+        None => {
+            if io::stdin().is_terminal() {
+                anyhow::bail!("No input given. Pass it as an argument or pipe it on stdin.");
+            }
+            let mut buf = String::new();
+            io::stdin().read_to_string(&mut buf)?;
+            Ok(buf)
+        }
+    }
+}
+
+/// Human-readable byte count, e.g. `4.1GB`.
+pub fn format_size(size: u64) -> String {
+    if size < 1_000 {
+        format!("{}B", size)
+    } else if size < 1_000_000 {
+        format!("{:.1}KB", size as f64 / 1e3)
+    } else if size < 1_000_000_000 {
+        format!("{:.1}MB", size as f64 / 1e6)
+    } else {
+        format!("{:.1}GB", size as f64 / 1e9)
+    }
+}
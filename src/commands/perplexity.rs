@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use oxide_rs::{ChunkResult, GenerateOptions, Model, PerplexityOptions, Profile};
+
+use super::{lib_err, model_path, text_or_stdin, tokenizer_path};
+
+#[derive(Args, Debug)]
+pub struct PerplexityArgs {
+    /// Path to GGUF model file (default: from the config file)
+    #[arg(short, long, env = "OXIDE_MODEL")]
+    pub model: Option<PathBuf>,
+
+    /// Path to a Hugging Face tokenizer.json or GGUF
+    #[arg(short, long)]
+    pub tokenizer: Option<PathBuf>,
+
+    /// Text file to evaluate (read from stdin if omitted)
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+
+    /// Window length in tokens
+    #[arg(long, default_value = "512")]
+    pub ctx: usize,
+
+    /// Tokens the window moves each step (default: half the window)
+    #[arg(long)]
+    pub stride: Option<usize>,
+
+    /// Stop after this many windows
+    #[arg(long)]
+    pub chunks: Option<usize>,
+
+    /// Context size to load the model with (default: the model's trained context)
+    #[arg(long)]
+    pub ctx_size: Option<usize>,
+
+    /// Also store the model's next-token distributions in FILE, as the
+    /// reference for --kl-divergence
+    #[arg(long, value_name = "FILE", conflicts_with = "kl_divergence")]
+    pub kl_divergence_base: Option<PathBuf>,
+
+    /// Compare with the distributions stored by --kl-divergence-base
+    /// (uses the reference's --ctx and --stride)
+    #[arg(long, value_name = "FILE")]
+    pub kl_divergence: Option<PathBuf>,
+
+    /// Print the report as JSON instead of text
+    #[arg(long)]
+    pub json: bool,
+}
+
+pub fn run_perplexity(args: PerplexityArgs, profile: &Profile) -> Result<()> {
+    let text = match &args.file {
+        Some(path) => {
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?
+        }
+        None => text_or_stdin(None)?,
+    };
+
+    let options = PerplexityOptions {
+        ctx: args.ctx,
+        stride: args.stride.unwrap_or((args.ctx / 2).max(1)),
+        max_chunks: args.chunks,
+    };
+
+    let mut model = Model::new(model_path(args.model.as_deref(), profile)?)
+        .map_err(lib_err)?
+        .with_options(GenerateOptions {
+            ctx_size: args.ctx_size,
+            ..Default::default()
+        });
+    if let Some(path) = tokenizer_path(args.tokenizer.as_deref(), profile) {
+        model = model.with_tokenizer(path);
+    }
+    model.load().map_err(lib_err)?;
+
+    let json = args.json;
+    if let Some(path) = &args.kl_divergence {
+        return run_kl_divergence(&mut model, &text, path, json);
+    }
+
+    let print_chunk = |chunk: &ChunkResult| {
+        if !json {
+            println!(
+                "[{}] {:.4} (running {:.4}, {} tokens)",
+                chunk.index + 1,
+                chunk.perplexity,
+                chunk.running_perplexity,
+                chunk.tokens
+            );
+        }
+    };
+    let report = match &args.kl_divergence_base {
+        Some(path) => model.record_logits(&text, &options, path, print_chunk),
+        None => model.perplexity(&text, &options, print_chunk),
+    }
+    .map_err(lib_err)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!();
+    println!(
+        "PPL = {:.4} +/- {:.4} (95% CI {:.4}-{:.4})",
+        report.perplexity,
+        report.perplexity_error(),
+        report.ci_low,
+        report.ci_high
+    );
+    println!(
+        "{} of {} tokens scored in {} chunks (ctx {}, stride {})",
+        report.scored_tokens,
+        report.total_tokens,
+        report.chunks.len(),
+        report.ctx,
+        report.stride
+    );
+    Ok(())
+}
+
+fn run_kl_divergence(model: &mut Model, text: &str, path: &Path, json: bool) -> Result<()> {
+    let report = model
+        .kl_divergence(text, path, |chunk| {
+            if !json {
+                println!(
+                    "[{}] KLD {:.6}, top-1 {:.2}%, PPL {:.4} (base {:.4})",
+                    chunk.index + 1,
+                    chunk.mean_kl,
+                    chunk.top1_agreement * 100.0,
+                    chunk.perplexity,
+                    chunk.base_perplexity
+                );
+            }
+        })
+        .map_err(lib_err)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!();
+    println!(
+        "PPL = {:.4}, base PPL = {:.4}, delta = {:+.4} (ratio {:.4})",
+        report.perplexity, report.base_perplexity, report.delta_perplexity, report.perplexity_ratio
+    );
+    println!(
+        "Mean KLD = {:.6} +/- {:.6}",
+        report.mean_kl, report.kl_std_error
+    );
+    println!(
+        "KLD median {:.6}, 90% {:.6}, 95% {:.6}, 99% {:.6}, max {:.6}",
+        report.kl_median, report.kl_p90, report.kl_p95, report.kl_p99, report.kl_max
+    );
+    println!(
+        "Top-1 agreement = {:.2}% over {} tokens (ctx {}, stride {})",
+        report.top1_agreement * 100.0,
+        report.scored_tokens,
+        report.ctx,
+        report.stride
+    );
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Args;
+use oxide_rs::{QuantType, QuantizeOptions};
+
+use super::{format_size, lib_err};
+
+#[derive(Args, Debug)]
+pub struct QuantizeArgs {
+    /// GGUF model to read (any shard of a split model)
+    pub input: PathBuf,
+
+    /// GGUF file to write
+    pub output: PathBuf,
+
+    /// Target type: q4_0, q4_1, q5_0, q5_1, q8_0, q2_k, q3_k_s, q3_k_m,
+    /// q3_k_l, q4_k_s, q4_k_m, q5_k_s, q5_k_m, q6_k, f16, bf16 or f32
+    #[arg(long = "type", short = 'q', default_value = "q4_k_m")]
+    pub file_type: QuantType,
+
+    /// Requantize tensors that are already quantized
+    #[arg(long)]
+    pub allow_requantize: bool,
+
+    /// Importance matrix from `oxide-rs imatrix` (or llama.cpp's
+    /// llama-imatrix) to weight k-quant rounding
+    #[arg(long, value_name = "FILE")]
+    pub imatrix: Option<PathBuf>,
+
+    /// Print the report as JSON instead of text
+    #[arg(long)]
+    pub json: bool,
+}
+
+pub fn run_quantize(args: QuantizeArgs) -> Result<()> {
+    if args.input == args.output {
+        anyhow::bail!("Input and output must be different files");
+    }
+
+    let options = QuantizeOptions {
+        allow_requantize: args.allow_requantize,
+        imatrix: args.imatrix.clone(),
+    };
+    let json = args.json;
+    let report = oxide_rs::quantize(
+        &args.input,
+        &args.output,
+        args.file_type,
+        &options,
+        |tensor| {
+            if !json {
+                println!(
+                    "[{:>4}/{}] {:<40} {:<16} {} -> {}",
+                    tensor.index + 1,
+                    tensor.total,
+                    tensor.name,
+                    format!("{:?}", tensor.shape),
+                    tensor.from,
+                    tensor.to
+                );
+            }
+        },
+    )
+    .map_err(lib_err)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!();
+    println!(
+        "Wrote {:?} as {}: {} -> {} ({} of {} tensors converted)",
+        args.output,
+        report.file_type,
+        format_size(report.input_size),
+        format_size(report.output_size),
+        report.quantized,
+        report.tensors
+    );
+    let counts: Vec<String> = report
+        .dtype_counts
+        .iter()
+        .map(|(dtype, count)| format!("{} {}", dtype, count))
+        .collect();
+    println!("Tensor types: {}", counts.join(", "));
+    if args.imatrix.is_some() {
+        println!(
+            "{} tensors used the importance matrix",
+            report.imatrix_tensors
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use oxide_rs::{Model, Profile, TokenInfo};
+
+use super::{lib_err, model_path, text_or_stdin, tokenizer_path};
+
+#[derive(Args, Debug)]
+pub struct TokenizeArgs {
+    /// Path to GGUF model file (default: from the config file)
+    #[arg(short, long, env = "OXIDE_MODEL")]
+    pub model: Option<PathBuf>,
+
+    /// Path to a Hugging Face tokenizer.json or GGUF
+    #[arg(short, long)]
+    pub tokenizer: Option<PathBuf>,
+
+    /// Text to tokenize (read from stdin if omitted)
+    pub text: Option<String>,
+
+    /// Do not add the tokenizer's BOS/EOS tokens
+    #[arg(long)]
+    pub no_special: bool,
+
+    /// Print only the number of tokens
+    #[arg(long)]
+    pub count: bool,
+
+    /// Print JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct DetokenizeArgs {
+    /// Path to GGUF model file (default: from the config file)
+    #[arg(short, long, env = "OXIDE_MODEL")]
+    pub model: Option<PathBuf>,
+
+    /// Path to a Hugging Face tokenizer.json or GGUF
+    #[arg(short, long)]
+    pub tokenizer: Option<PathBuf>,
+
+    /// Token ids, space or comma separated (read from stdin if omitted)
+    pub ids: Vec<String>,
+
+    /// Print JSON with the text and per-token pieces
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(serde::Serialize)]
+struct TokenizeOutput<'a> {
+    count: usize,
+    tokens: &'a [TokenInfo],
+}
+
+#[derive(serde::Serialize)]
+struct DetokenizeOutput {
+    text: String,
+    tokens: Vec<TokenText>,
+}
+
+#[derive(serde::Serialize)]
+struct TokenText {
+    id: u32,
+    text: String,
+}
+
+fn open_model(model: Option<&Path>, tokenizer: Option<&Path>, profile: &Profile) -> Result<Model> {
+    let model = Model::new(model_path(model, profile)?).map_err(lib_err)?;
+    Ok(match tokenizer_path(tokenizer, profile) {
+        Some(path) => model.with_tokenizer(path),
+        None => model,
+    })
+}
+
+pub fn run_tokenize(args: TokenizeArgs, profile: &Profile) -> Result<()> {
+    let text = text_or_stdin(args.text)?;
+    let mut model = open_model(args.model.as_deref(), args.tokenizer.as_deref(), profile)?;
+
+    let tokens = model
+        .tokenize_with_offsets(&text, !args.no_special)
+        .map_err(lib_err)?;
+
+    if args.count {
+        println!("{}", tokens.len());
+    } else if args.json {
+        let output = TokenizeOutput {
+            count: tokens.len(),
+            tokens: &tokens,
+        };
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        print_token_table(&tokens);
+    }
+
+    Ok(())
+}
+
+pub fn run_detokenize(args: DetokenizeArgs, profile: &Profile) -> Result<()> {
+    let raw = if args.ids.is_empty() {
+        text_or_stdin(None)?
+    } else {
+        args.ids.join(" ")
+    };
+    let ids = parse_ids(&raw)?;
+
+    let mut model = open_model(args.model.as_deref(), args.tokenizer.as_deref(), profile)?;
+    let text = model.detokenize(&ids).map_err(lib_err)?;
+
+    if args.json {
+        let tokens = ids
+            .iter()
+            .map(|&id| {
+                Ok(TokenText {
+                    id,
+                    text: model.detokenize(&[id]).map_err(lib_err)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let output = DetokenizeOutput { text, tokens };
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        println!("{}", text);
+    }
+
+    Ok(())
+}
+
+/// Parse ids separated by whitespace, commas or JSON array brackets.
+fn parse_ids(raw: &str) -> Result<Vec<u32>> {
+    raw.split(|c: char| c.is_whitespace() || c == ',' || c == '[' || c == ']')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<u32>()
+                .with_context(|| format!("Invalid token id: {:?}", s))
+        })
+        .collect()
+}
+
+fn print_token_table(tokens: &[TokenInfo]) {
+    println!("{:>6}  {:>8}  {:<24}  bytes", "index", "id", "piece");
+    for (i, token) in tokens.iter().enumerate() {
+        let piece = format!("{:?}", token.piece);
+        let marker = if token.special { " (special)" } else { "" };
+        println!(
+            "{:>6}  {:>8}  {:<24}  {}..{}{}",
+            i, token.id, piece, token.start, token.end, marker
+        );
+    }
+    println!("{} tokens", tokens.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ids() {
+        assert_eq!(parse_ids("9707 11 1879").unwrap(), vec![9707, 11, 1879]);
+        assert_eq!(parse_ids("9707,11, 1879\n").unwrap(), vec![9707, 11, 1879]);
+        assert_eq!(parse_ids("[9707, 11, 1879]").unwrap(), vec![9707, 11, 1879]);
+        assert!(parse_ids("  ").unwrap().is_empty());
+
+        let err = parse_ids("1 two 3").unwrap_err().to_string();
+        assert!(err.contains("\"two\""), "{}", err);
+        assert!(parse_ids("-1").is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! Configuration Files
+//!
+//! Defaults for the CLI and library, read from a TOML file. Top-level keys
+//! apply everywhere; `[profile.<name>]` tables override them when that
+//! profile is selected:
+//!
+//! ```toml
+//! model = "~/models/qwen2.5-7b-instruct-q4_k_m.gguf"
+//! temperature = 0.3
+//!
+//! [profile.coder]
+//! model = "~/models/qwen2.5-coder-7b-q4_k_m.gguf"
+//! temperature = 0.2
+//! system = "You are a senior Rust engineer. Answer with code."
+//! ```
+//!
+//! The file lives at `$XDG_CONFIG_HOME/oxide/config.toml`, by default
+//! `~/.config/oxide/config.toml`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::model::RopeScaling;
+use crate::GenerateOptions;
+
+const CONFIG_FILE: &str = "config.toml";
+const PROFILE_TABLE: &str = "profile";
+
+/// Environment variable naming the config file.
+pub const CONFIG_ENV: &str = "OXIDE_CONFIG";
+
+/// Directory of the config file: `$XDG_CONFIG_HOME/oxide`, then
+/// `$HOME/.config/oxide`.
+pub fn config_dir() -> Option<PathBuf> {
+    let non_empty = |var: &str| std::env::var_os(var).filter(|v| !v.is_empty());
+
+    if let Some(dir) = non_empty("XDG_CONFIG_HOME") {
+        Some(PathBuf::from(dir).join("oxide"))
+    } else {
+        non_empty("HOME").map(|home| PathBuf::from(home).join(".config").join("oxide"))
+    }
+}
+
+/// Settings from a config file or a profile in it. Every field is
+/// optional, so a profile only overrides the settings it names.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// GGUF model file; a leading `~/` is the home directory.
+    pub model: Option<PathBuf>,
+    pub tokenizer: Option<PathBuf>,
+    /// System prompt.
+    pub system: Option<String>,
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<usize>,
+    pub batch_size: Option<usize>,
+    pub seed: Option<u64>,
+    pub threads: Option<usize>,
+    pub ctx_size: Option<usize>,
+    pub rope_freq_base: Option<f32>,
+    pub rope_freq_scale: Option<f32>,
+    pub rope_scaling: Option<RopeScaling>,
+    pub rope_scale: Option<f32>,
+    /// LoRA adapters applied at scale 1.0.
+    pub lora: Option<Vec<PathBuf>>,
+    /// Print replies without rendering markdown.
+    pub plain: Option<bool>,
+    /// Directories searched for models named by alias.
+    pub model_dirs: Option<Vec<PathBuf>>,
+}
+
+impl Profile {
+    /// These settings, with the ones `other` sets taking precedence.
+    pub fn merge(self, other: Profile) -> Profile {
+        Profile {
+            model: other.model.or(self.model),
+            tokenizer: other.tokenizer.or(self.tokenizer),
+            system: other.system.or(self.system),
+            max_tokens: other.max_tokens.or(self.max_tokens),
+            temperature: other.temperature.or(self.temperature),
+            top_p: other.top_p.or(self.top_p),
+            top_k: other.top_k.or(self.top_k),
+            repeat_penalty: other.repeat_penalty.or(self.repeat_penalty),
+            repeat_last_n: other.repeat_last_n.or(self.repeat_last_n),
+            batch_size: other.batch_size.or(self.batch_size),
+            seed: other.seed.or(self.seed),
+            threads: other.threads.or(self.threads),
+            ctx_size: other.ctx_size.or(self.ctx_size),
+            rope_freq_base: other.rope_freq_base.or(self.rope_freq_base),
+            rope_freq_scale: other.rope_freq_scale.or(self.rope_freq_scale),
+            rope_scaling: other.rope_scaling.or(self.rope_scaling),
+            rope_scale: other.rope_scale.or(self.rope_scale),
+            lora: other.lora.or(self.lora),
+            plain: other.plain.or(self.plain),
+            model_dirs: other.model_dirs.or(self.model_dirs),
+        }
+    }
+
+    /// `options` with these settings applied.
+    pub fn apply(&self, options: GenerateOptions) -> GenerateOptions {
+        GenerateOptions {
+            max_tokens: self.max_tokens.unwrap_or(options.max_tokens),
+            temperature: self.temperature.unwrap_or(options.temperature),
+            top_p: self.top_p.or(options.top_p),
+            top_k: self.top_k.or(options.top_k),
+            repeat_penalty: self.repeat_penalty.unwrap_or(options.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.unwrap_or(options.repeat_last_n),
+            batch_size: self.batch_size.unwrap_or(options.batch_size),
+            seed: self.seed.unwrap_or(options.seed),
+            system_prompt: self.system.clone().or(options.system_prompt),
+            cpu_threads: self.threads.unwrap_or(options.cpu_threads),
+            ctx_size: self.ctx_size.or(options.ctx_size),
+            rope_freq_base: self.rope_freq_base.or(options.rope_freq_base),
+            rope_freq_scale: self.rope_freq_scale.or(options.rope_freq_scale),
+            rope_scaling: self.rope_scaling.or(options.rope_scaling),
+            rope_scale: self.rope_scale.or(options.rope_scale),
+            ..options
+        }
+    }
+
+    /// The model path with a leading `~/` expanded.
+    pub fn model_path(&self) -> Option<PathBuf> {
+        self.model.as_deref().map(expand_home)
+    }
+
+    /// The tokenizer path with a leading `~/` expanded.
+    pub fn tokenizer_path(&self) -> Option<PathBuf> {
+        self.tokenizer.as_deref().map(expand_home)
+    }
+
+    /// The LoRA adapter paths with a leading `~/` expanded.
+    pub fn lora_paths(&self) -> Option<Vec<PathBuf>> {
+        self.lora
+            .as_ref()
+            .map(|paths| paths.iter().map(|path| expand_home(path)).collect())
+    }
+
+    /// `model_dirs` with a leading `~/` expanded.
+    pub fn model_dir_paths(&self) -> Vec<PathBuf> {
+        self.model_dirs
+            .iter()
+            .flatten()
+            .map(|dir| expand_home(dir))
+            .collect()
+    }
+}
+
+/// A config file: top-level defaults and named profiles.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    pub defaults: Profile,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    /// The default config file, if there is one.
+    pub fn default_path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(CONFIG_FILE))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        Self::parse(&text).with_context(|| format!("Invalid config file {:?}", path))
+    }
+
+    /// The file `OXIDE_CONFIG` names, else the default config file, or an
+    /// empty config when it does not exist.
+    pub fn load_default() -> Result<Self> {
+        if let Some(path) = std::env::var_os(CONFIG_ENV).filter(|v| !v.is_empty()) {
+            return Self::load(Path::new(&path));
+        }
+        match Self::default_path() {
+            Some(path) if path.exists() => Self::load(&path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut table: toml::Table = toml::from_str(text)?;
+        let profiles = match table.remove(PROFILE_TABLE) {
+            Some(toml::Value::Table(profiles)) => profiles
+                .into_iter()
+                .map(|(name, value)| {
+                    let profile = value
+                        .try_into()
+                        .with_context(|| format!("In [profile.{}]", name))?;
+                    Ok((name, profile))
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => anyhow::bail!("`profile` must be a table of [profile.<name>] tables"),
+            None => BTreeMap::new(),
+        };
+        Ok(Self {
+            defaults: toml::Value::Table(table).try_into()?,
+            profiles,
+        })
+    }
+
+    /// The top-level settings, overridden by the profile `name` if given.
+    pub fn resolve(&self, name: Option<&str>) -> Result<Profile> {
+        let Some(name) = name else {
+            return Ok(self.defaults.clone());
+        };
+        let Some(profile) = self.profiles.get(name) else {
+            let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            anyhow::bail!(
+                "No profile '{}' in the config (profiles: {})",
+                name,
+                if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join(", ")
+                }
+            );
+        };
+        Ok(self.defaults.clone().merge(profile.clone()))
+    }
+}
+
+/// `path` with a leading `~/` replaced by the home directory.
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+model = "base.gguf"
+temperature = 0.5
+top_k = 40
+
+[profile.coder]
+model = "coder.gguf"
+temperature = 0.2
+system = "Answer with code."
+rope_scaling = "yarn"
+"#;
+
+    #[test]
+    fn test_profiles_override_defaults() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(config.defaults.model, Some(PathBuf::from("base.gguf")));
+
+        let coder = config.resolve(Some("coder")).unwrap();
+        assert_eq!(coder.model, Some(PathBuf::from("coder.gguf")));
+        assert_eq!(coder.temperature, Some(0.2));
+        assert_eq!(coder.top_k, Some(40));
+        assert_eq!(coder.rope_scaling, Some(RopeScaling::Yarn));
+
+        let options = coder.apply(GenerateOptions::default());
+        assert_eq!(options.temperature, 0.2);
+        assert_eq!(options.system_prompt.as_deref(), Some("Answer with code."));
+        assert_eq!(options.max_tokens, GenerateOptions::default().max_tokens);
+
+        let err = config.resolve(Some("writer")).unwrap_err().to_string();
+        assert!(err.contains("coder"), "{}", err);
+    }
+
+    #[test]
+    fn test_paths_expand_home() {
+        let Some(home) = std::env::var_os("HOME") else {
+            return;
+        };
+        let profile = Config::parse(
+            "model = \"~/m.gguf\"\nlora = [\"~/a.gguf\", \"b.gguf\"]\nmodel_dirs = [\"~/Models\"]",
+        )
+        .unwrap()
+        .defaults;
+        let home = PathBuf::from(home);
+        assert_eq!(profile.model_path(), Some(home.join("m.gguf")));
+        assert_eq!(
+            profile.lora_paths(),
+            Some(vec![home.join("a.gguf"), PathBuf::from("b.gguf")])
+        );
+        assert_eq!(profile.model_dir_paths(), vec![home.join("Models")]);
+        assert_eq!(Profile::default().lora_paths(), None);
+    }
+
+    #[test]
+    fn test_rejects_unknown_keys() {
+        assert!(Config::parse("temprature = 0.2").is_err());
+        let err = format!(
+            "{:#}",
+            Config::parse("[profile.a]\nmodle = \"x\"").unwrap_err()
+        );
+        assert!(err.contains("profile.a"), "{}", err);
+        assert!(Config::parse("").unwrap().profiles.is_empty());
+    }
+
+    #[test]
+    fn test_generate_options_deserialize_partially() {
+        let options: GenerateOptions =
+            toml::from_str("temperature = 0.7\nrope_scaling = \"ntk\"").unwrap();
+        assert_eq!(options.temperature, 0.7);
+        assert_eq!(options.rope_scaling, Some(RopeScaling::Ntk));
+        assert_eq!(options.seed, GenerateOptions::default().seed);
+    }
+}
@@ -0,0 +1,287 @@
+//! Conversation Files
+//!
+//! Conversations are written in three formats and read back from any of
+//! them:
+//!
+//! - **JSON**: an OpenAI-style array of `{"role", "content"}` messages. A
+//!   request body with a `messages` array is read too.
+//! - **Markdown**: a transcript with a `## System`, `## User` or
+//!   `## Assistant` heading before each message.
+//! - **ShareGPT**: one `{"conversations": [{"from", "value"}]}` line, as
+//!   used in fine-tuning datasets, with the roles `system`, `human` and
+//!   `gpt`.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::generator::Message;
+
+const ROLES: &[(&str, &str, &str)] = &[
+    // (role, Markdown heading, ShareGPT name)
+    ("system", "System", "system"),
+    ("user", "User", "human"),
+    ("assistant", "Assistant", "gpt"),
+];
+
+/// A file format for conversations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversationFormat {
+    Json,
+    Markdown,
+    ShareGpt,
+}
+
+impl ConversationFormat {
+    /// The format a file extension suggests: `.json`, `.md` or `.jsonl`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "md" | "markdown" => Some(Self::Markdown),
+            "jsonl" => Some(Self::ShareGpt),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ConversationFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" | "openai" => Ok(Self::Json),
+            "markdown" | "md" => Ok(Self::Markdown),
+            "sharegpt" | "jsonl" => Ok(Self::ShareGpt),
+            other => Err(format!(
+                "unknown conversation format '{}' (expected json, markdown or sharegpt)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConversationFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Json => "json",
+            Self::Markdown => "markdown",
+            Self::ShareGpt => "sharegpt",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ShareGptLine {
+    conversations: Vec<ShareGptTurn>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ShareGptTurn {
+    from: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct OpenAiBody {
+    messages: Vec<Message>,
+}
+
+/// `messages` in `format`. ShareGPT output is a single line ending in a
+/// newline, so exports can be appended to a dataset.
+pub fn export_conversation(messages: &[Message], format: ConversationFormat) -> Result<String> {
+    match format {
+        ConversationFormat::Json => Ok(serde_json::to_string_pretty(messages)? + "\n"),
+        ConversationFormat::Markdown => {
+            let sections = messages
+                .iter()
+                .map(|m| {
+                    Ok(format!(
+                        "## {}\n\n{}\n",
+                        role_names(&m.role)?.1,
+                        m.content.trim()
+                    ))
+                })
+                .collect::<Result<Vec<String>>>()?;
+            Ok(sections.join("\n"))
+        }
+        ConversationFormat::ShareGpt => {
+            let conversations = messages
+                .iter()
+                .map(|m| {
+                    Ok(ShareGptTurn {
+                        from: role_names(&m.role)?.2.to_string(),
+                        value: m.content.clone(),
+                    })
+                })
+                .collect::<Result<_>>()?;
+            Ok(serde_json::to_string(&ShareGptLine { conversations })? + "\n")
+        }
+    }
+}
+
+/// The messages of a conversation in any of the supported formats, which
+/// is detected from the text.
+pub fn import_conversation(text: &str) -> Result<Vec<Message>> {
+    let trimmed = text.trim_start();
+    let messages = if trimmed.starts_with('[') {
+        serde_json::from_str(text).context("Invalid JSON messages")?
+    } else if trimmed.starts_with('{') {
+        import_json_object(text)?
+    } else {
+        import_markdown(text)?
+    };
+
+    if messages.is_empty() {
+        anyhow::bail!("The conversation has no messages");
+    }
+    for (i, message) in messages.iter().enumerate() {
+        role_names(&message.role)?;
+        if message.role == "system" && i > 0 {
+            anyhow::bail!("Only the first message can be a system message");
+        }
+    }
+    Ok(messages)
+}
+
+/// An OpenAI request body or ShareGPT line. A JSONL file must hold
+/// exactly one conversation.
+fn import_json_object(text: &str) -> Result<Vec<Message>> {
+    let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+    if lines.len() > 1 && serde_json::from_str::<serde_json::Value>(text).is_err() {
+        anyhow::bail!(
+            "The file holds {} conversations, one per line; import takes one",
+            lines.len()
+        );
+    }
+
+    let value: serde_json::Value = serde_json::from_str(text).context("Invalid JSON")?;
+    if value.get("conversations").is_some() {
+        let line: ShareGptLine =
+            serde_json::from_value(value).context("Invalid ShareGPT conversation")?;
+        return line
+            .conversations
+            .into_iter()
+            .map(|turn| {
+                let role = ROLES
+                    .iter()
+                    .find(|(_, _, name)| *name == turn.from)
+                    .map(|(role, _, _)| role.to_string())
+                    .with_context(|| format!("Unknown ShareGPT role '{}'", turn.from))?;
+                Ok(Message {
+                    role,
+                    content: turn.value,
+                })
+            })
+            .collect();
+    }
+    let body: OpenAiBody =
+        serde_json::from_value(value).context("Expected a `messages` or `conversations` array")?;
+    Ok(body.messages)
+}
+
+fn import_markdown(text: &str) -> Result<Vec<Message>> {
+    let mut messages: Vec<Message> = Vec::new();
+    for line in text.lines() {
+        let heading = line
+            .strip_prefix("## ")
+            .and_then(|h| ROLES.iter().find(|(_, name, _)| *name == h.trim()));
+        match (heading, messages.last_mut()) {
+            (Some((role, _, _)), _) => messages.push(Message {
+                role: role.to_string(),
+                content: String::new(),
+            }),
+            (None, Some(message)) => {
+                message.content.push_str(line);
+                message.content.push('\n');
+            }
+            // Text before the first heading, such as a title.
+            (None, None) => {}
+        }
+    }
+    if messages.is_empty() {
+        anyhow::bail!(
+            "Not a conversation: expected JSON messages, a ShareGPT line or a Markdown \
+             transcript with ## User and ## Assistant headings"
+        );
+    }
+    for message in &mut messages {
+        message.content = message.content.trim_matches('\n').to_string();
+    }
+    Ok(messages)
+}
+
+fn role_names(role: &str) -> Result<&'static (&'static str, &'static str, &'static str)> {
+    ROLES
+        .iter()
+        .find(|(name, _, _)| *name == role)
+        .with_context(|| {
+            format!(
+                "Unknown role '{}' (expected system, user or assistant)",
+                role
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Vec<Message> {
+        [
+            ("system", "Be brief."),
+            ("user", "What is Rust?"),
+            (
+                "assistant",
+                "A systems language.\n\n```rust\nfn main() {}\n```",
+            ),
+            ("user", "Thanks"),
+        ]
+        .iter()
+        .map(|(role, content)| Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        })
+        .collect()
+    }
+
+    fn pairs(messages: &[Message]) -> Vec<(String, String)> {
+        messages
+            .iter()
+            .map(|m| (m.role.clone(), m.content.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_formats_round_trip() {
+        let messages = conversation();
+        for format in [
+            ConversationFormat::Json,
+            ConversationFormat::Markdown,
+            ConversationFormat::ShareGpt,
+        ] {
+            let text = export_conversation(&messages, format).unwrap();
+            let imported = import_conversation(&text).unwrap();
+            assert_eq!(pairs(&imported), pairs(&messages), "{}", format);
+        }
+
+        let line = export_conversation(&messages, ConversationFormat::ShareGpt).unwrap();
+        assert!(line.contains(r#""from":"human""#) && line.contains(r#""from":"gpt""#));
+        assert_eq!(line.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_imports_request_bodies_and_rejects_bad_files() {
+        let body = r#"{"model": "x", "messages": [{"role": "user", "content": "Hi"}]}"#;
+        assert_eq!(import_conversation(body).unwrap()[0].content, "Hi");
+
+        let two_lines = "{\"conversations\": []}\n{\"conversations\": []}\n";
+        assert!(import_conversation(two_lines).is_err());
+        assert!(import_conversation(r#"[{"role": "tool", "content": "x"}]"#).is_err());
+        assert!(import_conversation("just some notes").is_err());
+        assert!(import_conversation("## User\nhi\n## System\nlate").is_err());
+    }
+}
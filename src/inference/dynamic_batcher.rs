@@ -1,15 +1,22 @@
 //! Dynamic Batching for LLM Inference
 //!
 //! Groups incoming requests into small batches (max 4) that arrive within
-//! a configurable time window (default 1ms) for improved throughput while
-//! maintaining low latency.
+//! a configurable time window (default 1ms) and runs each batch on the
+//! batcher's [`Generator`], so any number of callers can share one loaded
+//! model. The requests of a batch are completed one after another on a
+//! blocking thread, each with its own sampling options and a conversation
+//! of its own.
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use anyhow::Context;
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::timeout;
 
+use super::generator::{Generator, Message, SamplingParams, Usage};
+use crate::GenerateOptions;
+
 pub struct BatchConfig {
     pub max_batch_size: usize,
     pub batch_window_ms: u64,
@@ -28,16 +35,23 @@ impl Default for BatchConfig {
 
 pub struct BatchRequest {
     pub id: u64,
-    pub prompt: String,
-    pub max_tokens: usize,
-    pub repeat_penalty: f32,
-    pub repeat_last_n: usize,
+    pub messages: Vec<Message>,
+    /// Sampling, length and system prompt for this request; the load-time
+    /// fields are ignored.
+    pub options: GenerateOptions,
     pub sender: oneshot::Sender<BatchResult>,
 }
 
+/// A reply and its token counts.
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub text: String,
+    pub usage: Usage,
+}
+
 pub struct BatchResult {
     pub id: u64,
-    pub result: Result<String, String>,
+    pub result: Result<Completion, String>,
 }
 
 pub struct DynamicBatcher {
@@ -47,15 +61,17 @@ pub struct DynamicBatcher {
 }
 
 impl DynamicBatcher {
-    pub fn new(config: BatchConfig) -> Self {
+    /// Start batching requests for `generator`. Must be called within a
+    /// Tokio runtime.
+    pub fn new(config: BatchConfig, generator: Generator) -> Self {
         let (request_tx, request_rx) = mpsc::channel(config.max_queue_size);
         let batch_counter = Arc::new(std::sync::atomic::AtomicU64::new(0));
 
-        let counter_clone = batch_counter.clone();
         let config_clone = config.clone();
+        let generator = Arc::new(Mutex::new(generator));
 
         tokio::spawn(async move {
-            Self::batcher_loop(request_rx, config_clone, counter_clone).await;
+            Self::batcher_loop(request_rx, config_clone, generator).await;
         });
 
         Self {
@@ -69,13 +85,12 @@ impl DynamicBatcher {
         &self.config
     }
 
+    /// Reply to `messages` once their batch has run.
     pub async fn generate(
         &self,
-        prompt: String,
-        max_tokens: usize,
-        repeat_penalty: f32,
-        repeat_last_n: usize,
-    ) -> Result<String, String> {
+        messages: Vec<Message>,
+        options: GenerateOptions,
+    ) -> Result<Completion, String> {
         let id = self
             .batch_counter
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
@@ -84,10 +99,8 @@ impl DynamicBatcher {
 
         let request = BatchRequest {
             id,
-            prompt,
-            max_tokens,
-            repeat_penalty,
-            repeat_last_n,
+            messages,
+            options,
             sender,
         };
 
@@ -105,7 +118,7 @@ impl DynamicBatcher {
     async fn batcher_loop(
         mut request_rx: mpsc::Receiver<BatchRequest>,
         config: BatchConfig,
-        _counter: Arc<std::sync::atomic::AtomicU64>,
+        generator: Arc<Mutex<Generator>>,
     ) {
         let window_duration = Duration::from_millis(config.batch_window_ms);
         let max_batch_size = config.max_batch_size;
@@ -125,7 +138,7 @@ impl DynamicBatcher {
                 }
             } else if time_since_last >= window_duration || pending_requests.len() >= max_batch_size {
                 if !pending_requests.is_empty() {
-                    Self::process_batch(pending_requests.drain(..).collect()).await;
+                    Self::process_batch(std::mem::take(&mut pending_requests), &generator).await;
                     last_batch_time = Instant::now();
                 }
             } else {
@@ -135,7 +148,8 @@ impl DynamicBatcher {
                             pending_requests.push(req);
                         } else {
                             if !pending_requests.is_empty() {
-                                Self::process_batch(pending_requests.drain(..).collect()).await;
+                                let batch = std::mem::take(&mut pending_requests);
+                                Self::process_batch(batch, &generator).await;
                                 last_batch_time = Instant::now();
                             }
                             pending_requests.push(req);
@@ -143,13 +157,15 @@ impl DynamicBatcher {
                     }
                     Ok(None) => {
                         if !pending_requests.is_empty() {
-                            Self::process_batch(pending_requests.drain(..).collect()).await;
+                            Self::process_batch(std::mem::take(&mut pending_requests), &generator)
+                                .await;
                         }
                         break;
                     }
                     Err(_) => {
                         if !pending_requests.is_empty() {
-                            Self::process_batch(pending_requests.drain(..).collect()).await;
+                            Self::process_batch(std::mem::take(&mut pending_requests), &generator)
+                                .await;
                             last_batch_time = Instant::now();
                         }
                     }
@@ -158,7 +174,9 @@ impl DynamicBatcher {
         }
     }
 
-    async fn process_batch(requests: Vec<BatchRequest>) {
+    /// Run `requests` on the generator on a blocking thread and send each
+    /// its result. The next batch waits for this one.
+    async fn process_batch(requests: Vec<BatchRequest>, generator: &Arc<Mutex<Generator>>) {
         if requests.is_empty() {
             return;
         }
@@ -168,14 +186,42 @@ impl DynamicBatcher {
             requests.len()
         );
 
-        // Placeholder - actual batch processing needs integration with Generator
-        for req in requests {
-            let _ = req.sender.send(BatchResult {
-                id: req.id,
-                result: Err("Batch processing not yet integrated with Generator".to_string()),
-            });
+        let generator = generator.clone();
+        let worker = tokio::task::spawn_blocking(move || {
+            let mut generator = generator.lock().unwrap_or_else(|e| e.into_inner());
+            for req in requests {
+                let result = Self::complete(&mut generator, &req).map_err(|e| format!("{:#}", e));
+                let _ = req.sender.send(BatchResult { id: req.id, result });
+            }
+        });
+        // A panicking request drops the senders of the rest of its batch,
+        // which their callers see as cancelled.
+        if let Err(e) = worker.await {
+            tracing::error!("Batch worker failed: {}", e);
         }
     }
+
+    fn complete(generator: &mut Generator, req: &BatchRequest) -> anyhow::Result<Completion> {
+        let options = &req.options;
+        generator.set_sampling(SamplingParams {
+            temperature: options.temperature,
+            top_p: options.top_p,
+            top_k: options.top_k,
+            seed: options.seed,
+        });
+        generator.set_system_prompt(options.system_prompt.clone());
+        let text = generator.complete(
+            &req.messages,
+            options.max_tokens,
+            options.repeat_penalty,
+            options.repeat_last_n,
+            |_| {},
+        )?;
+        let usage = *generator
+            .last_usage()
+            .context("The generator did not record token usage")?;
+        Ok(Completion { text, usage })
+    }
 }
 
 impl Clone for DynamicBatcher {
@@ -203,22 +249,18 @@ pub struct DynamicBatcherHandle {
 }
 
 impl DynamicBatcherHandle {
-    pub fn new(config: BatchConfig) -> Self {
+    pub fn new(config: BatchConfig, generator: Generator) -> Self {
         Self {
-            batcher: DynamicBatcher::new(config),
+            batcher: DynamicBatcher::new(config, generator),
         }
     }
 
     pub async fn generate(
         &self,
-        prompt: String,
-        max_tokens: usize,
-        repeat_penalty: f32,
-        repeat_last_n: usize,
-    ) -> Result<String, String> {
-        self.batcher
-            .generate(prompt, max_tokens, repeat_penalty, repeat_last_n)
-            .await
+        messages: Vec<Message>,
+        options: GenerateOptions,
+    ) -> Result<Completion, String> {
+        self.batcher.generate(messages, options).await
     }
 }
 
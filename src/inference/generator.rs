@@ -1,13 +1,82 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use candle_transformers::generation::{LogitsProcessor, Sampling};
 use candle_transformers::utils::apply_repeat_penalty;
+use memmap2::Mmap;
 use minijinja::{context, Environment};
 use rayon::prelude::*;
 
+use crate::inference::kl_divergence::{self, KlChunkResult, KlReport};
 use crate::inference::paged_cache::PagedKvCache;
-use crate::model::{GgufMetadata, Model, TokenizerWrapper};
+use crate::inference::perplexity::{self, ChunkResult, PerplexityOptions, PerplexityReport};
+use crate::model::{
+    ContextOverrides, EmbedOptions, GgufMetadata, Imatrix, ImatrixChunk, ImatrixOptions, LoraSpec,
+    Model, TokenizerWrapper,
+};
+
+/// Branch a new conversation starts on.
+const DEFAULT_BRANCH: &str = "main";
+
+/// How the next token is picked from the logits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingParams {
+    /// `0.0` or below samples greedily.
+    pub temperature: f64,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub seed: u64,
+}
+
+impl SamplingParams {
+    fn sampling(&self) -> Sampling {
+        let temperature = self.temperature;
+        if temperature <= 0.0 {
+            return Sampling::ArgMax;
+        }
+        match (self.top_k, self.top_p) {
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    }
+
+    fn logits_processor(&self) -> LogitsProcessor {
+        LogitsProcessor::from_sampling(self.seed, self.sampling())
+    }
+}
+
+/// Why a reply ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    /// The model produced its end-of-sequence token.
+    Stop,
+    /// The reply reached `max_tokens` or the end of the context.
+    Length,
+}
+
+impl FinishReason {
+    /// The name used in JSON output: `stop` or `length`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stop => "stop",
+            Self::Length => "length",
+        }
+    }
+}
+
+/// Token counts of the last reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Usage {
+    /// The rendered conversation, system prompt included.
+    pub prompt_tokens: usize,
+    /// Sampled tokens, the end-of-sequence token included.
+    pub completion_tokens: usize,
+    pub finish_reason: FinishReason,
+}
 
 pub enum StreamEvent {
     Token(String),
@@ -15,7 +84,7 @@ pub enum StreamEvent {
     Done,
 }
 
-#[derive(Clone, serde::Serialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
@@ -24,15 +93,42 @@ pub struct Message {
 #[derive(Clone)]
 pub struct ChatTemplate {
     template_str: Option<String>,
+    bos_token: Option<String>,
+    eos_token: Option<String>,
 }
 
 impl ChatTemplate {
     pub fn new(template: Option<String>) -> Result<Self> {
         Ok(Self {
             template_str: template,
+            bos_token: None,
+            eos_token: None,
         })
     }
 
+    /// Expose `bos_token`/`eos_token` to the template, as Hugging Face
+    /// templates expect.
+    pub fn with_special_tokens(mut self, bos: Option<String>, eos: Option<String>) -> Self {
+        self.bos_token = bos;
+        self.eos_token = eos;
+        self
+    }
+
+    /// Pick the template for a model: a `tokenizer_config.json` shipped
+    /// with an external tokenizer wins over the template embedded in the GGUF.
+    pub fn resolve(metadata: &GgufMetadata, tokenizer: &TokenizerWrapper) -> Result<Self> {
+        match tokenizer.config() {
+            Some(config) => Ok(Self::new(
+                config
+                    .chat_template
+                    .clone()
+                    .or_else(|| metadata.chat_template.clone()),
+            )?
+            .with_special_tokens(config.bos_token.clone(), config.eos_token.clone())),
+            None => Self::new(metadata.chat_template.clone()),
+        }
+    }
+
     pub fn apply(&self, messages: &[Message]) -> Result<String> {
         let template = match &self.template_str {
             Some(t) => t,
@@ -44,25 +140,38 @@ impl ChatTemplate {
         let mut env = Environment::new();
         env.add_template("chat", template)?;
         let tmpl = env.get_template("chat")?;
-        let rendered = tmpl.render(context! { messages => messages })?;
+        let rendered = tmpl.render(context! {
+            messages => messages,
+            bos_token => self.bos_token,
+            eos_token => self.eos_token,
+        })?;
         Ok(rendered)
     }
 }
 
 pub struct Generator {
     model: Model,
+    /// Kept so the weights can be rebuilt when LoRA adapters change.
+    mmaps: Vec<Mmap>,
     tokenizer: TokenizerWrapper,
     logits_processor: LogitsProcessor,
+    sampling: SamplingParams,
     template: ChatTemplate,
     metadata: GgufMetadata,
     messages: Vec<Message>,
+    /// Name of the branch `messages` belongs to.
+    branch: String,
+    /// The other branches' conversations.
+    branches: BTreeMap<String, Vec<Message>>,
     system_prompt: Option<String>,
     token_history: Vec<u32>,
     kv_cache: Option<PagedKvCache>,
     batch_size: usize,
+    last_usage: Option<Usage>,
 }
 
 impl Generator {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         model_path: &PathBuf,
         tokenizer_path: Option<&PathBuf>,
@@ -72,14 +181,17 @@ impl Generator {
         seed: u64,
         system_prompt: Option<String>,
         batch_size: usize,
+        context: &ContextOverrides,
+        lora: &[LoraSpec],
     ) -> Result<Self> {
         tracing::info!("Loading model from: {:?}", model_path);
 
-        let (mmap, model) = Model::load_with_mmap(model_path)?;
-        Model::prefetch_mmap(&mmap);
+        let (mmaps, model) = Model::load_with_mmap(model_path, context, lora)?;
+        for mmap in &mmaps {
+            Model::prefetch_mmap(mmap);
+        }
 
         let metadata = model.metadata().clone();
-        let template = ChatTemplate::new(metadata.chat_template.clone())?;
 
         let tokenizer = if let Some(path) = tokenizer_path {
             TokenizerWrapper::from_file(path)?
@@ -88,38 +200,40 @@ impl Generator {
             TokenizerWrapper::from_gguf(model_path)?
         };
 
-        let sampling = if temperature <= 0.0 {
-            Sampling::ArgMax
-        } else {
-            match (top_k, top_p) {
-                (None, None) => Sampling::All { temperature },
-                (Some(k), None) => Sampling::TopK { k, temperature },
-                (None, Some(p)) => Sampling::TopP { p, temperature },
-                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-            }
-        };
+        let template = ChatTemplate::resolve(&metadata, &tokenizer)?;
 
-        let logits_processor = LogitsProcessor::from_sampling(seed, sampling);
+        let sampling = SamplingParams {
+            temperature,
+            top_p,
+            top_k,
+            seed,
+        };
+        let logits_processor = sampling.logits_processor();
 
         let token_history = Vec::with_capacity(metadata.context_length);
 
         let kv_cache = Some(PagedKvCache::new(
-            metadata.n_embd / metadata.n_layer,
-            metadata.n_embd / metadata.n_layer,
+            metadata.head_count_kv,
+            metadata.head_dim,
             metadata.context_length,
         ));
 
         Ok(Self {
             model,
+            mmaps,
             tokenizer,
             logits_processor,
+            sampling,
             template,
             metadata,
             messages: Vec::new(),
+            branch: DEFAULT_BRANCH.to_string(),
+            branches: BTreeMap::new(),
             system_prompt,
             token_history,
             kv_cache,
             batch_size,
+            last_usage: None,
         })
     }
 
@@ -139,6 +253,48 @@ impl Generator {
         &self.metadata
     }
 
+    /// Swap, rescale or remove LoRA adapters without reloading the model
+    /// file. The conversation is kept; the next turn is computed with the
+    /// new weights.
+    pub fn set_lora(&mut self, lora: &[LoraSpec]) -> Result<()> {
+        self.model.set_lora(&self.mmaps, lora)
+    }
+
+    /// The active LoRA adapters and their scales.
+    pub fn lora(&self) -> Vec<LoraSpec> {
+        self.model.lora()
+    }
+
+    /// The sampling settings of the next turn.
+    pub fn sampling(&self) -> &SamplingParams {
+        &self.sampling
+    }
+
+    /// Replace the sampler. The random generator restarts from
+    /// `params.seed`, so the next turn is reproducible.
+    pub fn set_sampling(&mut self, params: SamplingParams) {
+        self.logits_processor = params.logits_processor();
+        self.sampling = params;
+    }
+
+    pub fn system_prompt(&self) -> Option<&str> {
+        self.system_prompt.as_deref()
+    }
+
+    /// Replace the system prompt. The conversation is kept and the next
+    /// turn is rendered with the new prompt.
+    pub fn set_system_prompt(&mut self, system_prompt: Option<String>) {
+        self.system_prompt = system_prompt;
+    }
+
+    pub fn tokenizer(&self) -> &TokenizerWrapper {
+        &self.tokenizer
+    }
+
+    pub fn template(&self) -> &ChatTemplate {
+        &self.template
+    }
+
     pub fn context_used(&self) -> usize {
         self.token_history.len()
     }
@@ -147,6 +303,18 @@ impl Generator {
         self.metadata.context_length
     }
 
+    /// Tokens the prompt would take with `message` sent next: the system
+    /// prompt, the conversation and `message`, rendered with the chat
+    /// template.
+    pub fn prompt_tokens(&self, message: &str) -> Result<usize> {
+        let mut messages = self.messages.clone();
+        messages.push(Message {
+            role: "user".into(),
+            content: message.to_string(),
+        });
+        Ok(self.render_tokens(&messages)?.len())
+    }
+
     pub fn context_percentage(&self) -> f32 {
         let limit = self.context_limit();
         if limit == 0 {
@@ -181,10 +349,88 @@ impl Generator {
         Ok(())
     }
 
+    /// Perplexity of the model on `text`, tokenized with the tokenizer's
+    /// special tokens. The weights are rebuilt for the built-in decoder,
+    /// which returns the logits of every position, on first use.
+    /// Conversation history is left untouched.
+    pub fn perplexity<F>(
+        &mut self,
+        text: &str,
+        options: &PerplexityOptions,
+        on_chunk: F,
+    ) -> Result<PerplexityReport>
+    where
+        F: FnMut(&ChunkResult),
+    {
+        let tokens = self.tokenizer.tokenize(text, true)?;
+        self.model.use_decoder(&self.mmaps)?;
+        perplexity::evaluate(&self.model, &tokens, options, on_chunk)
+    }
+
+    /// Like [`Generator::perplexity`], also storing the model's next-token
+    /// distributions in `path` as a reference for [`Generator::kl_divergence`].
+    pub fn record_logits<F>(
+        &mut self,
+        text: &str,
+        options: &PerplexityOptions,
+        path: &Path,
+        on_chunk: F,
+    ) -> Result<PerplexityReport>
+    where
+        F: FnMut(&ChunkResult),
+    {
+        let tokens = self.tokenizer.tokenize(text, true)?;
+        self.model.use_decoder(&self.mmaps)?;
+        kl_divergence::record(&self.model, &tokens, options, path, on_chunk)
+    }
+
+    /// Compare the model on `text` with the reference distributions in
+    /// `path`, using the windows they were recorded with and the same
+    /// all-positions forward as [`Generator::perplexity`].
+    pub fn kl_divergence<F>(&mut self, text: &str, path: &Path, on_chunk: F) -> Result<KlReport>
+    where
+        F: FnMut(&KlChunkResult),
+    {
+        let tokens = self.tokenizer.tokenize(text, true)?;
+        self.model.use_decoder(&self.mmaps)?;
+        kl_divergence::compare(&self.model, &tokens, path, on_chunk)
+    }
+
+    /// One embedding per tokenized input from the final hidden states of
+    /// the generation weights, LoRA adapters and context overrides
+    /// included. Conversation history is left untouched.
+    pub fn embed(&mut self, inputs: &[Vec<u32>], options: &EmbedOptions) -> Result<Vec<Vec<f32>>> {
+        self.model.embed(inputs, options)
+    }
+
+    /// Importance matrix of the model over `text`, tokenized with the
+    /// tokenizer's special tokens. The weights are rebuilt for the built-in
+    /// decoder, which sees every matmul input, on first use. Conversation
+    /// history is left untouched.
+    pub fn imatrix<F>(
+        &mut self,
+        text: &str,
+        options: &ImatrixOptions,
+        dataset: &str,
+        on_chunk: F,
+    ) -> Result<Imatrix>
+    where
+        F: FnMut(&ImatrixChunk),
+    {
+        let tokens = self.tokenizer.tokenize(text, true)?;
+        self.model.use_decoder(&self.mmaps)?;
+        Imatrix::collect(&self.model, &tokens, options, dataset, on_chunk)
+    }
+
     pub fn batch_size(&self) -> usize {
         self.batch_size
     }
 
+    /// Token counts and finish reason of the last reply.
+    pub fn last_usage(&self) -> Option<&Usage> {
+        self.last_usage.as_ref()
+    }
+
     pub fn generate<F>(
         &mut self,
         prompt: &str,
@@ -196,114 +442,322 @@ impl Generator {
     where
         F: FnMut(StreamEvent),
     {
-        self.messages.push(Message {
+        let mut messages = self.messages.clone();
+        messages.push(Message {
             role: "user".into(),
             content: prompt.into(),
         });
+        self.reply(
+            messages,
+            max_tokens,
+            repeat_penalty,
+            repeat_last_n,
+            callback,
+        )
+    }
 
-        let mut all_messages = Vec::new();
-        if let Some(ref sys) = self.system_prompt {
-            all_messages.push(Message {
-                role: "system".into(),
-                content: sys.clone(),
-            });
-        }
-        all_messages.extend(self.messages.clone());
+    pub fn generate_streaming<F>(
+        &mut self,
+        prompt: &str,
+        max_tokens: usize,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        callback: F,
+    ) -> Result<()>
+    where
+        F: FnMut(StreamEvent),
+    {
+        self.generate(prompt, max_tokens, repeat_penalty, repeat_last_n, callback)?;
+        Ok(())
+    }
 
-        let prompt_text = self.template.apply(&all_messages)?;
-        let prompt_tokens = self.tokenizer.encode(&prompt_text)?;
-
-        let total_len = self.token_history.len() + prompt_tokens.len() + max_tokens;
-        if total_len > self.metadata.context_length {
-            let excess = total_len - self.metadata.context_length;
-            if excess < self.token_history.len() {
-                let old_len = self.token_history.len();
-                self.token_history.drain(0..excess);
-                tracing::debug!(
-                    "Context truncated: {} tokens -> {} tokens (kept most recent)",
-                    old_len,
-                    self.token_history.len()
-                );
-            } else {
-                self.token_history.clear();
-            }
+    /// Reply to `messages` as a conversation of its own, leaving the
+    /// current one untouched. The system prompt comes first unless
+    /// `messages` start with a system message.
+    pub fn complete<F>(
+        &mut self,
+        messages: &[Message],
+        max_tokens: usize,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        callback: F,
+    ) -> Result<String>
+    where
+        F: FnMut(StreamEvent),
+    {
+        if messages.is_empty() {
+            anyhow::bail!("There is no message to reply to");
         }
-
-        let result = self.generate_internal_with_tokens(
+        let prompt_tokens = self.render_tokens(messages)?;
+        self.generate_internal_with_tokens(
             &prompt_tokens,
             max_tokens,
             repeat_penalty,
             repeat_last_n,
-            true,
-            callback,
             false,
-        )?;
+            callback,
+        )
+    }
 
-        self.messages.push(Message {
-            role: "assistant".into(),
-            content: result.clone(),
+    /// The conversation of the current branch, without the system prompt.
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// The system prompt, if any, followed by the conversation, for
+    /// exporting.
+    pub fn conversation(&self) -> Vec<Message> {
+        let system = self.system_prompt.as_ref().map(|prompt| Message {
+            role: "system".into(),
+            content: prompt.clone(),
         });
+        system
+            .into_iter()
+            .chain(self.messages.iter().cloned())
+            .collect()
+    }
 
-        Ok(result)
+    /// Replace the conversation of the current branch with `messages`. A
+    /// leading system message becomes the system prompt. Nothing is
+    /// prefilled until the next turn, which renders the whole conversation.
+    /// If the conversation cannot be rendered, the current one is kept.
+    pub fn set_conversation(&mut self, mut messages: Vec<Message>) -> Result<()> {
+        let token_history = self.history_tokens(&messages)?;
+        if messages.first().is_some_and(|m| m.role == "system") {
+            self.system_prompt = Some(messages.remove(0).content);
+        }
+        self.messages = messages;
+        self.token_history = token_history;
+        Ok(())
     }
 
-    pub fn generate_streaming<F>(
+    /// Generate the last assistant reply again. Call
+    /// [`set_sampling`](Self::set_sampling) first with a new seed, or a
+    /// greedy sampler gives the same reply. The previous reply is only
+    /// replaced once the new one is complete.
+    pub fn retry<F>(
+        &mut self,
+        max_tokens: usize,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        callback: F,
+    ) -> Result<String>
+    where
+        F: FnMut(StreamEvent),
+    {
+        let mut messages = self.messages.clone();
+        if messages.last().is_some_and(|m| m.role == "assistant") {
+            messages.pop();
+        }
+        if !messages.last().is_some_and(|m| m.role == "user") {
+            anyhow::bail!("There is no message to reply to");
+        }
+        self.reply(
+            messages,
+            max_tokens,
+            repeat_penalty,
+            repeat_last_n,
+            callback,
+        )
+    }
+
+    /// Remove the last user message and the reply to it, returning the
+    /// user message, or `None` if there is no user message to remove.
+    pub fn undo(&mut self) -> Result<Option<String>> {
+        let Some(keep) = self.last_user_message() else {
+            return Ok(None);
+        };
+        self.token_history = self.history_tokens(&self.messages[..keep])?;
+        Ok(self.messages.drain(keep..).next().map(|m| m.content))
+    }
+
+    /// Replace the last user message with `prompt` and generate a new reply.
+    /// The original message and reply are kept if generation fails.
+    pub fn edit<F>(
         &mut self,
         prompt: &str,
         max_tokens: usize,
         repeat_penalty: f32,
         repeat_last_n: usize,
         callback: F,
-    ) -> Result<()>
+    ) -> Result<String>
     where
         F: FnMut(StreamEvent),
     {
-        self.messages.push(Message {
+        let Some(keep) = self.last_user_message() else {
+            anyhow::bail!("There is no message to edit");
+        };
+        let mut messages = self.messages[..keep].to_vec();
+        messages.push(Message {
             role: "user".into(),
             content: prompt.into(),
         });
+        self.reply(
+            messages,
+            max_tokens,
+            repeat_penalty,
+            repeat_last_n,
+            callback,
+        )
+    }
+
+    /// Index of the last user message, if only its reply follows it.
+    fn last_user_message(&self) -> Option<usize> {
+        let mut index = self.messages.len().checked_sub(1)?;
+        if self.messages[index].role == "assistant" {
+            index = index.checked_sub(1)?;
+        }
+        (self.messages[index].role == "user").then_some(index)
+    }
 
+    /// Name of the branch the conversation continues on.
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// Branch names and their message counts, in name order.
+    pub fn branches(&self) -> Vec<(String, usize)> {
+        let mut branches: Vec<(String, usize)> = self
+            .branches
+            .iter()
+            .map(|(name, messages)| (name.clone(), messages.len()))
+            .collect();
+        branches.push((self.branch.clone(), self.messages.len()));
+        branches.sort();
+        branches
+    }
+
+    /// Fork the conversation: keep it under the current branch and continue
+    /// on a copy named `name`.
+    pub fn new_branch(&mut self, name: &str) -> Result<()> {
+        if name == self.branch || self.branches.contains_key(name) {
+            anyhow::bail!("Branch '{}' already exists", name);
+        }
+        let branch = std::mem::replace(&mut self.branch, name.to_string());
+        self.branches.insert(branch, self.messages.clone());
+        Ok(())
+    }
+
+    /// Continue the conversation saved on branch `name`.
+    pub fn switch_branch(&mut self, name: &str) -> Result<()> {
+        if name == self.branch {
+            return Ok(());
+        }
+        let Some(messages) = self.branches.get(name) else {
+            anyhow::bail!("No branch named '{}'", name);
+        };
+        self.token_history = self.history_tokens(messages)?;
+        let messages = self.branches.remove(name).expect("branch checked above");
+        let previous = std::mem::replace(&mut self.messages, messages);
+        let branch = std::mem::replace(&mut self.branch, name.to_string());
+        self.branches.insert(branch, previous);
+        Ok(())
+    }
+
+    /// Delete a branch other than the current one.
+    pub fn delete_branch(&mut self, name: &str) -> Result<()> {
+        if name == self.branch {
+            anyhow::bail!("Cannot delete the current branch '{}'", name);
+        }
+        if self.branches.remove(name).is_none() {
+            anyhow::bail!("No branch named '{}'", name);
+        }
+        Ok(())
+    }
+
+    /// `messages` after the system prompt, unless they start with their
+    /// own, rendered with the chat template and tokenized.
+    fn render_tokens(&self, messages: &[Message]) -> Result<Vec<u32>> {
         let mut all_messages = Vec::new();
         if let Some(ref sys) = self.system_prompt {
-            all_messages.push(Message {
-                role: "system".into(),
-                content: sys.clone(),
-            });
+            if !messages.first().is_some_and(|m| m.role == "system") {
+                all_messages.push(Message {
+                    role: "system".into(),
+                    content: sys.clone(),
+                });
+            }
         }
-        all_messages.extend(self.messages.clone());
-
+        all_messages.extend(messages.iter().cloned());
         let prompt_text = self.template.apply(&all_messages)?;
-        let prompt_tokens = self.tokenizer.encode(&prompt_text)?;
-
-        let total_len = self.token_history.len() + prompt_tokens.len() + max_tokens;
-        if total_len > self.metadata.context_length {
-            let excess = total_len - self.metadata.context_length;
-            if excess < self.token_history.len() {
-                let old_len = self.token_history.len();
-                self.token_history.drain(0..excess);
-                tracing::debug!(
-                    "Context truncated: {} tokens -> {} tokens (kept most recent)",
-                    old_len,
-                    self.token_history.len()
-                );
-            } else {
-                self.token_history.clear();
-            }
+        self.tokenizer.encode(&prompt_text)
+    }
+
+    /// The context `messages` take up once rendered, to count against the
+    /// context after messages were removed or swapped.
+    fn history_tokens(&self, messages: &[Message]) -> Result<Vec<u32>> {
+        if messages.is_empty() {
+            Ok(Vec::new())
+        } else {
+            self.render_tokens(messages)
         }
+    }
 
-        let _result = self.generate_internal_with_tokens(
+    /// Generate the assistant reply to `messages`, which end with a user
+    /// message, and make them and the reply the conversation. The
+    /// conversation is left unchanged if generation fails.
+    fn reply<F>(
+        &mut self,
+        mut messages: Vec<Message>,
+        max_tokens: usize,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        callback: F,
+    ) -> Result<String>
+    where
+        F: FnMut(StreamEvent),
+    {
+        let (dropped, prompt_tokens) = self.fit_context(&messages, max_tokens)?;
+        let result = self.generate_internal_with_tokens(
             &prompt_tokens,
             max_tokens,
             repeat_penalty,
             repeat_last_n,
             true,
             callback,
-            true,
         )?;
 
-        Ok(())
+        if dropped > 0 {
+            tracing::warn!(
+                "Dropped the {} oldest messages to fit the context of {} tokens",
+                dropped,
+                self.metadata.context_length
+            );
+            messages.drain(..dropped);
+        }
+        messages.push(Message {
+            role: "assistant".into(),
+            content: result.clone(),
+        });
+        self.messages = messages;
+
+        Ok(result)
+    }
+
+    /// The number of oldest `messages` to leave out, and the prompt
+    /// rendered from the rest. Whole user/assistant exchanges are dropped
+    /// until the prompt leaves room for `max_tokens` of reply. The system
+    /// prompt and the last user message are always kept, so a prompt that
+    /// is still too long is left for generation to report.
+    fn fit_context(&self, messages: &[Message], max_tokens: usize) -> Result<(usize, Vec<u32>)> {
+        let context_length = self.metadata.context_length;
+        let last = messages.len().saturating_sub(1);
+        let mut start = 0;
+        loop {
+            let tokens = self.render_tokens(&messages[start..])?;
+            if tokens.len() + max_tokens <= context_length {
+                return Ok((start, tokens));
+            }
+            // The next exchange starts at the next user message.
+            match (start + 1..=last).find(|&i| messages[i].role == "user") {
+                Some(next) => start = next,
+                None => return Ok((start, tokens)),
+            }
+        }
     }
 
+    /// Prefill `prompt_tokens` from position 0 and sample a reply. The
+    /// prompt is the whole rendered conversation, so with `store_history`
+    /// it and the reply become the new token history.
     fn generate_internal_with_tokens<F>(
         &mut self,
         prompt_tokens: &[u32],
@@ -312,42 +766,21 @@ impl Generator {
         repeat_last_n: usize,
         store_history: bool,
         mut callback: F,
-        streaming: bool,
     ) -> Result<String>
     where
         F: FnMut(StreamEvent),
     {
-        let history_len = if store_history {
-            self.token_history.len()
-        } else {
-            0
-        };
-
-        let total_len = if store_history {
-            self.token_history.len() + prompt_tokens.len() + max_tokens
-        } else {
-            prompt_tokens.len() + max_tokens
-        };
-
-        let needs_truncation = total_len > self.metadata.context_length;
-        let excess = if needs_truncation {
-            Some(total_len - self.metadata.context_length)
-        } else {
-            None
-        };
-
-        let mut all_tokens = Vec::with_capacity(self.metadata.context_length);
-
-        if store_history {
-            if let Some(excess) = excess {
-                if excess < self.token_history.len() {
-                    all_tokens.extend_from_slice(&self.token_history[excess..]);
-                }
-            } else {
-                all_tokens.extend_from_slice(&self.token_history);
-            }
+        let context_length = self.metadata.context_length;
+        if prompt_tokens.len() >= context_length {
+            anyhow::bail!(
+                "The conversation is {} tokens, more than the context of {}; clear or shorten it",
+                prompt_tokens.len(),
+                context_length
+            );
         }
+        let max_tokens = max_tokens.min(context_length - prompt_tokens.len());
 
+        let mut all_tokens = Vec::with_capacity(context_length);
         all_tokens.extend_from_slice(prompt_tokens);
 
         let eos_token = self.tokenizer.eos_token_id();
@@ -369,13 +802,10 @@ impl Generator {
 
         all_tokens.push(next_token);
 
-        // Handle first generated token
-        let mut token_buffer: Vec<u32> = Vec::new();
-        if !self.tokenizer.is_special_token(next_token) {
-            token_buffer.push(next_token);
-        }
+        self.tokenizer.clear_cache();
+        let mut response = String::new();
+        self.stream_token(next_token, &mut response, &mut callback);
 
-        let decode_batch_size = 1;
         let mut generated = 0usize;
 
         let gen_start = std::time::Instant::now();
@@ -399,38 +829,16 @@ impl Generator {
             all_tokens.push(next_token);
             generated += 1;
 
-            // Skip special tokens - use tokenizer's built-in detection
-            if !self.tokenizer.is_special_token(next_token) {
-                token_buffer.push(next_token);
-
-                if token_buffer.len() >= decode_batch_size {
-                    if let Ok(text) = self.tokenizer.decode(&token_buffer) {
-                        if !text.is_empty() {
-                            callback(StreamEvent::Token(text));
-                        }
-                    }
-                    token_buffer.clear();
-                }
-            }
+            self.stream_token(next_token, &mut response, &mut callback);
 
             if next_token == eos_token {
                 break;
             }
         }
 
-        if !token_buffer.is_empty() {
-            if let Ok(text) = self.tokenizer.decode(&token_buffer) {
-                if !text.is_empty() {
-                    callback(StreamEvent::Token(text));
-                }
-            }
-            token_buffer.clear();
-        }
-
         if let Some(rest) = self.tokenizer.decode_rest()? {
-            if !rest.is_empty() {
-                callback(StreamEvent::Token(rest));
-            }
+            response.push_str(&rest);
+            callback(StreamEvent::Token(rest));
         }
 
         self.tokenizer.clear_cache();
@@ -448,16 +856,17 @@ impl Generator {
             tokens_per_sec
         );
 
+        self.last_usage = Some(Usage {
+            prompt_tokens: prompt_tokens.len(),
+            completion_tokens: all_tokens.len() - prompt_tokens.len(),
+            finish_reason: if next_token == eos_token {
+                FinishReason::Stop
+            } else {
+                FinishReason::Length
+            },
+        });
         callback(StreamEvent::Done);
 
-        let response = if streaming {
-            String::new()
-        } else {
-            let response_start = history_len + prompt_tokens.len();
-            let response_tokens: Vec<u32> = all_tokens[response_start..].to_vec();
-            self.tokenizer.decode(&response_tokens)?
-        };
-
         if store_history {
             self.token_history = all_tokens;
         }
@@ -465,6 +874,26 @@ impl Generator {
         Ok(response)
     }
 
+    /// Run a sampled token through the streaming detokenizer, emitting any
+    /// text it completes. Special tokens are never shown.
+    fn stream_token<F>(&mut self, token: u32, response: &mut String, callback: &mut F)
+    where
+        F: FnMut(StreamEvent),
+    {
+        if self.tokenizer.is_special_token(token) {
+            return;
+        }
+
+        match self.tokenizer.decode_next(token) {
+            Ok(Some(text)) => {
+                response.push_str(&text);
+                callback(StreamEvent::Token(text));
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to decode token {}: {}", token, e),
+        }
+    }
+
     pub fn generate_batch(
         &mut self,
         prompts: Vec<&str>,
@@ -505,7 +934,6 @@ impl Generator {
                 repeat_last_n,
                 false,
                 |_| {},
-                false,
             )?;
             results.push(result);
         }
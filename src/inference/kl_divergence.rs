@@ -0,0 +1,530 @@
+//! KL Divergence Against a Reference Model
+//!
+//! [`record`] runs the perplexity windows over a reference model and stores
+//! the log-probabilities of every scored token in a file; [`compare`] runs a
+//! second model over the same text and windows and measures how far its
+//! next-token distributions drift from the stored ones. As in llama.cpp's
+//! `--kl-divergence-base` files, each distribution is stored as 16-bit steps
+//! between its smallest log-probability (clamped to -16) and 0, which keeps
+//! the file at half the size of raw f32 logits.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use super::perplexity::{
+    self, check_window, log_sum_exp, window_logits, ChunkResult, NllScorer, PerplexityOptions,
+    PerplexityReport, Scorer, Stats,
+};
+use crate::model::Model;
+
+const MAGIC: &[u8; 4] = b"OXKL";
+const VERSION: u32 = 1;
+/// Byte offsets of the header fields only known once recording finishes.
+const VOCAB_OFFSET: u64 = 8;
+const WINDOWS_OFFSET: u64 = 20;
+/// Log-probabilities below this are stored as this.
+const MIN_LOG_PROB: f64 = -16.0;
+
+/// Result for one window of [`compare`].
+#[derive(Debug, Clone, Serialize)]
+pub struct KlChunkResult {
+    pub index: usize,
+    /// Offset of the window's first token in the text.
+    pub start: usize,
+    /// Tokens scored in this window.
+    pub tokens: usize,
+    /// Mean KL divergence in this window, in nats.
+    pub mean_kl: f64,
+    /// Fraction of tokens where both models rank the same token first.
+    pub top1_agreement: f64,
+    pub perplexity: f64,
+    pub base_perplexity: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KlReport {
+    pub scored_tokens: usize,
+    pub ctx: usize,
+    pub stride: usize,
+    /// Perplexity of the reference model.
+    pub base_perplexity: f64,
+    pub perplexity: f64,
+    /// `perplexity - base_perplexity`.
+    pub delta_perplexity: f64,
+    /// `perplexity / base_perplexity`.
+    pub perplexity_ratio: f64,
+    /// KL divergence from the reference to this model, in nats per token.
+    pub mean_kl: f64,
+    /// Standard error of `mean_kl`.
+    pub kl_std_error: f64,
+    pub kl_median: f64,
+    pub kl_p90: f64,
+    pub kl_p95: f64,
+    pub kl_p99: f64,
+    pub kl_max: f64,
+    /// Fraction of tokens where both models rank the same token first.
+    pub top1_agreement: f64,
+    pub chunks: Vec<KlChunkResult>,
+}
+
+/// Score `model` on `tokens` like [`perplexity::evaluate`] and store its
+/// next-token distributions in `path` for a later [`compare`]. Needs the
+/// weights built by [`Model::use_decoder`].
+pub fn record<F>(
+    model: &Model,
+    tokens: &[u32],
+    options: &PerplexityOptions,
+    path: &Path,
+    on_chunk: F,
+) -> Result<PerplexityReport>
+where
+    F: FnMut(&ChunkResult),
+{
+    check_window(model, options.ctx)?;
+    record_with(
+        |window, first| window_logits(model, window, first),
+        tokens,
+        options,
+        path,
+        on_chunk,
+    )
+}
+
+/// Compare `model` on `tokens` with the distributions recorded in `path`,
+/// using the windows they were recorded with. `on_chunk` is called after
+/// every window. Needs the weights built by [`Model::use_decoder`].
+pub fn compare<F>(model: &Model, tokens: &[u32], path: &Path, on_chunk: F) -> Result<KlReport>
+where
+    F: FnMut(&KlChunkResult),
+{
+    let reader = BaseReader::open(path)?;
+    check_window(model, reader.options.ctx)?;
+    reader.check_tokens(tokens)?;
+    compare_with(
+        |window, first| window_logits(model, window, first),
+        tokens,
+        reader,
+        on_chunk,
+    )
+}
+
+fn record_with<M, F>(
+    forward: M,
+    tokens: &[u32],
+    options: &PerplexityOptions,
+    path: &Path,
+    on_chunk: F,
+) -> Result<PerplexityReport>
+where
+    M: FnMut(&[u32], usize) -> Result<Vec<Vec<f32>>>,
+    F: FnMut(&ChunkResult),
+{
+    let file =
+        File::create(path).with_context(|| format!("Failed to create logits file {:?}", path))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC)?;
+    for value in [VERSION, 0, options.ctx as u32, options.stride as u32, 0] {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.write_all(&(tokens.len() as u32).to_le_bytes())?;
+    for token in tokens {
+        writer.write_all(&token.to_le_bytes())?;
+    }
+
+    let mut recorder = Recorder {
+        writer,
+        tokens,
+        vocab: None,
+        nll: NllScorer::new(tokens, on_chunk),
+        steps: Vec::new(),
+    };
+    let windows = perplexity::slide(forward, tokens, options, &mut recorder)?;
+
+    let mut writer = recorder.writer;
+    writer.seek(SeekFrom::Start(VOCAB_OFFSET))?;
+    writer.write_all(&(recorder.vocab.unwrap_or(0) as u32).to_le_bytes())?;
+    writer.seek(SeekFrom::Start(WINDOWS_OFFSET))?;
+    writer.write_all(&(windows as u32).to_le_bytes())?;
+    writer.flush()?;
+
+    Ok(recorder.nll.into_report(options))
+}
+
+/// Writes `[nll: f32][min log-prob: f32][steps: u16; vocab]` per scored
+/// token. The exact NLL of the target keeps the reference perplexity exact
+/// when the target's log-probability is below the clamp.
+struct Recorder<'a, W, F> {
+    writer: W,
+    tokens: &'a [u32],
+    vocab: Option<usize>,
+    nll: NllScorer<'a, F>,
+    steps: Vec<u8>,
+}
+
+impl<W: Write, F: FnMut(&ChunkResult)> Scorer for Recorder<'_, W, F> {
+    fn score(&mut self, index: usize, logits: &[f32]) -> Result<()> {
+        self.nll.score(index, logits)?;
+        let vocab = *self.vocab.get_or_insert(logits.len());
+        if vocab != logits.len() {
+            anyhow::bail!("Model returned {} logits, expected {}", logits.len(), vocab);
+        }
+
+        let lse = log_sum_exp(logits);
+        let min = logits
+            .iter()
+            .map(|&l| l as f64 - lse)
+            .fold(0.0, f64::min)
+            .clamp(MIN_LOG_PROB, -1e-6);
+        let step = -min / u16::MAX as f64;
+
+        self.steps.clear();
+        for &l in logits {
+            let q = ((l as f64 - lse).max(min) - min) / step;
+            self.steps
+                .extend_from_slice(&(q.round() as u16).to_le_bytes());
+        }
+        let nll = lse - logits[self.tokens[index] as usize] as f64;
+        self.writer.write_all(&(nll as f32).to_le_bytes())?;
+        self.writer.write_all(&(min as f32).to_le_bytes())?;
+        self.writer.write_all(&self.steps)?;
+        Ok(())
+    }
+
+    fn end_window(&mut self, start: usize) -> Result<()> {
+        self.nll.end_window(start)
+    }
+}
+
+/// A logits file opened for [`compare`], positioned at the first record.
+struct BaseReader<R> {
+    reader: R,
+    vocab: usize,
+    options: PerplexityOptions,
+    tokens: Vec<u32>,
+}
+
+impl BaseReader<BufReader<File>> {
+    fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open logits file {:?}", path))?;
+        Self::new(BufReader::new(file))
+            .with_context(|| format!("{:?} is not a logits file recorded by oxide-rs", path))
+    }
+}
+
+impl<R: Read> BaseReader<R> {
+    fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            anyhow::bail!("Bad magic");
+        }
+        let version = read_u32(&mut reader)?;
+        if version != VERSION {
+            anyhow::bail!("Unsupported version {}", version);
+        }
+        let vocab = read_u32(&mut reader)? as usize;
+        let ctx = read_u32(&mut reader)? as usize;
+        let stride = read_u32(&mut reader)? as usize;
+        let windows = read_u32(&mut reader)? as usize;
+        let len = read_u32(&mut reader)? as usize;
+        let tokens = (0..len)
+            .map(|_| read_u32(&mut reader))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            reader,
+            vocab,
+            options: PerplexityOptions {
+                ctx,
+                stride,
+                max_chunks: Some(windows),
+            },
+            tokens,
+        })
+    }
+
+    fn check_tokens(&self, tokens: &[u32]) -> Result<()> {
+        if self.tokens != tokens {
+            anyhow::bail!(
+                "The text tokenizes differently than when the reference was recorded \
+                 ({} vs {} tokens); both models must share a tokenizer and the same text",
+                tokens.len(),
+                self.tokens.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn compare_with<M, R, F>(
+    forward: M,
+    tokens: &[u32],
+    base: BaseReader<R>,
+    on_chunk: F,
+) -> Result<KlReport>
+where
+    M: FnMut(&[u32], usize) -> Result<Vec<Vec<f32>>>,
+    R: Read,
+    F: FnMut(&KlChunkResult),
+{
+    let options = base.options.clone();
+    let mut comparer = Comparer {
+        tokens,
+        steps: vec![0; base.vocab * 2],
+        base,
+        kl: Stats::default(),
+        kls: Vec::new(),
+        agree: 0,
+        nll: Stats::default(),
+        base_nll: Stats::default(),
+        chunk: ChunkStats::default(),
+        chunks: Vec::new(),
+        on_chunk,
+    };
+    perplexity::slide(forward, tokens, &options, &mut comparer)?;
+
+    let Comparer {
+        kl,
+        mut kls,
+        agree,
+        nll,
+        base_nll,
+        chunks,
+        ..
+    } = comparer;
+    kls.sort_by(f64::total_cmp);
+    let percentile = |p: f64| {
+        let i = (p * (kls.len().max(1) - 1) as f64).round() as usize;
+        kls.get(i).copied().unwrap_or(0.0)
+    };
+
+    let perplexity = nll.mean().exp();
+    let base_perplexity = base_nll.mean().exp();
+    Ok(KlReport {
+        scored_tokens: kl.count,
+        ctx: options.ctx,
+        stride: options.stride,
+        base_perplexity,
+        perplexity,
+        delta_perplexity: perplexity - base_perplexity,
+        perplexity_ratio: perplexity / base_perplexity,
+        mean_kl: kl.mean(),
+        kl_std_error: kl.std_error(),
+        kl_median: percentile(0.5),
+        kl_p90: percentile(0.9),
+        kl_p95: percentile(0.95),
+        kl_p99: percentile(0.99),
+        kl_max: kls.last().copied().unwrap_or(0.0),
+        top1_agreement: agree as f64 / kl.count.max(1) as f64,
+        chunks,
+    })
+}
+
+#[derive(Default)]
+struct ChunkStats {
+    kl: Stats,
+    agree: usize,
+    nll: Stats,
+    base_nll: Stats,
+}
+
+/// Reads one record per scored token and compares it with the model's
+/// logits.
+struct Comparer<'a, R, F> {
+    tokens: &'a [u32],
+    base: BaseReader<R>,
+    steps: Vec<u8>,
+    kl: Stats,
+    kls: Vec<f64>,
+    agree: usize,
+    nll: Stats,
+    base_nll: Stats,
+    chunk: ChunkStats,
+    chunks: Vec<KlChunkResult>,
+    on_chunk: F,
+}
+
+impl<R: Read, F: FnMut(&KlChunkResult)> Scorer for Comparer<'_, R, F> {
+    fn score(&mut self, index: usize, logits: &[f32]) -> Result<()> {
+        if logits.len() != self.base.vocab {
+            anyhow::bail!(
+                "Model has {} logits but the reference has {}",
+                logits.len(),
+                self.base.vocab
+            );
+        }
+        let mut header = [0u8; 8];
+        self.base
+            .reader
+            .read_exact(&mut header)
+            .and_then(|_| self.base.reader.read_exact(&mut self.steps))
+            .context("Logits file ended early")?;
+        let base_nll = f32::from_le_bytes(header[..4].try_into().unwrap()) as f64;
+        let min = f32::from_le_bytes(header[4..].try_into().unwrap()) as f64;
+        let step = -min / u16::MAX as f64;
+
+        let lse = log_sum_exp(logits);
+        let target = self.tokens[index] as usize;
+        let mut kl = 0.0;
+        let (mut base_best, mut base_best_lp) = (0, f64::NEG_INFINITY);
+        for (i, (&logit, q)) in logits.iter().zip(self.steps.chunks_exact(2)).enumerate() {
+            let base_lp = min + u16::from_le_bytes([q[0], q[1]]) as f64 * step;
+            kl += base_lp.exp() * (base_lp - (logit as f64 - lse));
+            if base_lp > base_best_lp {
+                (base_best, base_best_lp) = (i, base_lp);
+            }
+        }
+        let kl = kl.max(0.0);
+        let best = logits
+            .iter()
+            .enumerate()
+            .fold((0, f32::NEG_INFINITY), |best, (i, &l)| {
+                if l > best.1 {
+                    (i, l)
+                } else {
+                    best
+                }
+            })
+            .0;
+        let nll = lse - logits[target] as f64;
+
+        for stats in [&mut self.kl, &mut self.chunk.kl] {
+            stats.add(kl);
+        }
+        for stats in [&mut self.nll, &mut self.chunk.nll] {
+            stats.add(nll);
+        }
+        for stats in [&mut self.base_nll, &mut self.chunk.base_nll] {
+            stats.add(base_nll);
+        }
+        if best == base_best {
+            self.agree += 1;
+            self.chunk.agree += 1;
+        }
+        self.kls.push(kl);
+        Ok(())
+    }
+
+    fn end_window(&mut self, start: usize) -> Result<()> {
+        let chunk = std::mem::take(&mut self.chunk);
+        let result = KlChunkResult {
+            index: self.chunks.len(),
+            start,
+            tokens: chunk.kl.count,
+            mean_kl: chunk.kl.mean(),
+            top1_agreement: chunk.agree as f64 / chunk.kl.count.max(1) as f64,
+            perplexity: chunk.nll.mean().exp(),
+            base_perplexity: chunk.base_nll.mean().exp(),
+        };
+        (self.on_chunk)(&result);
+        self.chunks.push(result);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOCAB: usize = 8;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("oxide-kl-{}-{}.bin", name, std::process::id()))
+    }
+
+    /// Logits that favour the token after the previous one by
+    /// `confidence`.
+    fn peaked(confidence: f32) -> impl FnMut(&[u32], usize) -> Result<Vec<Vec<f32>>> {
+        move |window, first| {
+            let rows = window[first - 1..window.len() - 1].iter().map(|&token| {
+                let mut logits = vec![0.0; VOCAB];
+                logits[(token as usize + 1) % VOCAB] = confidence;
+                logits
+            });
+            Ok(rows.collect())
+        }
+    }
+
+    fn record_and_open(name: &str, tokens: &[u32]) -> (std::path::PathBuf, PerplexityReport) {
+        let path = temp_path(name);
+        let options = PerplexityOptions {
+            ctx: 8,
+            stride: 4,
+            max_chunks: None,
+        };
+        let report = record_with(peaked(4.0), tokens, &options, &path, |_| {}).unwrap();
+        (path, report)
+    }
+
+    #[test]
+    fn test_same_model_has_no_divergence() {
+        let tokens: Vec<u32> = (0..30).map(|i| i % VOCAB as u32).collect();
+        let (path, base) = record_and_open("same", &tokens);
+
+        let reader = BaseReader::open(&path).unwrap();
+        assert_eq!(reader.options.ctx, 8);
+        assert_eq!(reader.options.max_chunks, Some(base.chunks.len()));
+        let report = compare_with(peaked(4.0), &tokens, reader, |_| {}).unwrap();
+
+        assert_eq!(report.scored_tokens, base.scored_tokens);
+        assert!(report.mean_kl < 1e-6);
+        assert_eq!(report.top1_agreement, 1.0);
+        assert!((report.base_perplexity - base.perplexity).abs() < 1e-3);
+        assert!(report.delta_perplexity.abs() < 1e-3);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_kl_matches_closed_form() {
+        let tokens: Vec<u32> = (0..30).map(|i| i % VOCAB as u32).collect();
+        let (path, _) = record_and_open("closed-form", &tokens);
+
+        let reader = BaseReader::open(&path).unwrap();
+        let mut chunks = 0;
+        let report = compare_with(
+            |window: &[u32], first| Ok(vec![vec![0.0; VOCAB]; window.len() - first]),
+            &tokens,
+            reader,
+            |_| chunks += 1,
+        )
+        .unwrap();
+
+        // KL(p || uniform) = log(V) - H(p)
+        let z = 4f64.exp() + (VOCAB - 1) as f64;
+        let p = [4f64.exp() / z, 1.0 / z];
+        let entropy = -(p[0] * p[0].ln() + (VOCAB - 1) as f64 * p[1] * p[1].ln());
+        let expected = (VOCAB as f64).ln() - entropy;
+        assert!((report.mean_kl - expected).abs() < 1e-3);
+        assert!((report.kl_max - report.kl_median).abs() < 1e-3);
+        assert!((report.perplexity - VOCAB as f64).abs() < 1e-9);
+        assert!(report.delta_perplexity > 0.0);
+        assert_eq!(chunks, report.chunks.len());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_rejects_different_text() {
+        let tokens: Vec<u32> = (0..20).map(|i| i % VOCAB as u32).collect();
+        let (path, _) = record_and_open("tokens", &tokens);
+
+        let reader = BaseReader::open(&path).unwrap();
+        assert!(reader.check_tokens(&tokens[1..]).is_err());
+        assert!(reader.check_tokens(&tokens).is_ok());
+        assert!(BaseReader::new(&b"GGUF\x01\0\0\0"[..]).is_err());
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,14 +1,24 @@
+pub mod conversation;
 pub mod dynamic_batcher;
 pub mod generator;
+pub mod kl_divergence;
 pub mod paged_cache;
+pub mod perplexity;
 pub mod prefix_cache;
 pub mod simd_dispatch;
 pub mod thread_pinner;
 pub mod tiled_attention;
 
-pub use dynamic_batcher::{BatchConfig, BatchResult, BatchRequest, DynamicBatcher, DynamicBatcherHandle};
-pub use generator::{ChatTemplate, Generator, Message, StreamEvent};
+pub use conversation::{export_conversation, import_conversation, ConversationFormat};
+pub use dynamic_batcher::{
+    BatchConfig, BatchResult, BatchRequest, Completion, DynamicBatcher, DynamicBatcherHandle,
+};
+pub use generator::{
+    ChatTemplate, FinishReason, Generator, Message, SamplingParams, StreamEvent, Usage,
+};
+pub use kl_divergence::{KlChunkResult, KlReport};
 pub use paged_cache::{PagedAttentionConfig, PagedKvCache};
+pub use perplexity::{ChunkResult, PerplexityOptions, PerplexityReport};
 pub use prefix_cache::{PrefixCache, PrefixCacheConfig};
 pub use simd_dispatch::{CpuFeature, CpuFeatures, SimdLevel, SimdDispatch};
 pub use thread_pinner::{ThreadPinnerConfig, ThreadPinner};
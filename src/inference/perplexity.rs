@@ -0,0 +1,391 @@
+//! Perplexity Evaluation
+//!
+//! Slides a window of `ctx` tokens through a tokenized text, `stride`
+//! tokens at a time, and scores every token once: the first window scores
+//! all of its tokens, later windows only the tokens past the end of the
+//! previous one, so each scored token sees at least `ctx - stride` tokens of
+//! context. Each window is one forward pass of the built-in decoder, which
+//! returns the logits of every position.
+
+use anyhow::Result;
+use candle_core::DType;
+use serde::Serialize;
+
+use crate::model::Model;
+
+/// z-score of a two-sided 95% confidence interval.
+const Z_95: f64 = 1.96;
+
+/// Window settings for [`evaluate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PerplexityOptions {
+    /// Window length in tokens.
+    pub ctx: usize,
+    /// Tokens the window moves each step; at most `ctx`.
+    pub stride: usize,
+    /// Stop after this many windows.
+    pub max_chunks: Option<usize>,
+}
+
+impl Default for PerplexityOptions {
+    fn default() -> Self {
+        Self {
+            ctx: 512,
+            stride: 256,
+            max_chunks: None,
+        }
+    }
+}
+
+/// Result for one window.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkResult {
+    pub index: usize,
+    /// Offset of the window's first token in the text.
+    pub start: usize,
+    /// Tokens scored in this window.
+    pub tokens: usize,
+    pub perplexity: f64,
+    /// Perplexity over all windows so far.
+    pub running_perplexity: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PerplexityReport {
+    /// Tokens in the text.
+    pub total_tokens: usize,
+    /// Tokens that were scored.
+    pub scored_tokens: usize,
+    pub ctx: usize,
+    pub stride: usize,
+    pub perplexity: f64,
+    /// Mean negative log-likelihood per token, in nats.
+    pub mean_nll: f64,
+    /// Standard error of `mean_nll`.
+    pub nll_std_error: f64,
+    /// Bounds of the 95% confidence interval of `perplexity`.
+    pub ci_low: f64,
+    pub ci_high: f64,
+    pub chunks: Vec<ChunkResult>,
+}
+
+impl PerplexityReport {
+    /// llama.cpp's `PPL = x +/- e` uncertainty: `perplexity * nll_std_error`.
+    pub fn perplexity_error(&self) -> f64 {
+        self.perplexity * self.nll_std_error
+    }
+}
+
+/// Running sums of per-token values, for a mean and its standard error.
+#[derive(Default)]
+pub(super) struct Stats {
+    pub(super) count: usize,
+    sum: f64,
+    sum_sq: f64,
+}
+
+impl Stats {
+    pub(super) fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.sum_sq += value * value;
+    }
+
+    pub(super) fn mean(&self) -> f64 {
+        self.sum / self.count.max(1) as f64
+    }
+
+    pub(super) fn std_error(&self) -> f64 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        let n = self.count as f64;
+        let variance = ((self.sum_sq / n - self.mean().powi(2)) * n / (n - 1.0)).max(0.0);
+        (variance / n).sqrt()
+    }
+}
+
+/// Receives the logits of every scored token during a [`slide`].
+pub(super) trait Scorer {
+    /// `logits` predict `tokens[index]`.
+    fn score(&mut self, index: usize, logits: &[f32]) -> Result<()>;
+
+    /// Called after the last scored token of the window starting at `start`.
+    fn end_window(&mut self, start: usize) -> Result<()>;
+}
+
+/// Perplexity of `model` on `tokens`. `on_chunk` is called after every
+/// window. Needs the weights built by [`Model::use_decoder`].
+pub fn evaluate<F>(
+    model: &Model,
+    tokens: &[u32],
+    options: &PerplexityOptions,
+    on_chunk: F,
+) -> Result<PerplexityReport>
+where
+    F: FnMut(&ChunkResult),
+{
+    check_window(model, options.ctx)?;
+    evaluate_with(
+        |window, first| window_logits(model, window, first),
+        tokens,
+        options,
+        on_chunk,
+    )
+}
+
+/// Fail if a window of `ctx` tokens does not fit the loaded context.
+pub(super) fn check_window(model: &Model, ctx: usize) -> Result<()> {
+    let context_length = model.metadata().context_length;
+    if ctx > context_length {
+        anyhow::bail!(
+            "Window of {} tokens exceeds the model's context of {} (use --ctx-size to raise it)",
+            ctx,
+            context_length
+        );
+    }
+    Ok(())
+}
+
+/// Logits predicting each of `window[first..]`, from one forward pass of
+/// `model` over the window as a new sequence.
+pub(super) fn window_logits(model: &Model, window: &[u32], first: usize) -> Result<Vec<Vec<f32>>> {
+    let logits = model.forward_observed(&window[..window.len() - 1], &mut |_, _| Ok(()))?;
+    let logits = logits.get(0)?.narrow(0, first - 1, window.len() - first)?;
+    Ok(logits.to_dtype(DType::F32)?.to_vec2()?)
+}
+
+/// Per-window and overall negative log-likelihoods.
+pub(super) struct NllScorer<'a, F> {
+    tokens: &'a [u32],
+    total: Stats,
+    chunk: Stats,
+    chunks: Vec<ChunkResult>,
+    on_chunk: F,
+}
+
+impl<'a, F: FnMut(&ChunkResult)> NllScorer<'a, F> {
+    pub(super) fn new(tokens: &'a [u32], on_chunk: F) -> Self {
+        Self {
+            tokens,
+            total: Stats::default(),
+            chunk: Stats::default(),
+            chunks: Vec::new(),
+            on_chunk,
+        }
+    }
+
+    pub(super) fn into_report(self, options: &PerplexityOptions) -> PerplexityReport {
+        let mean_nll = self.total.mean();
+        let std_error = self.total.std_error();
+        PerplexityReport {
+            total_tokens: self.tokens.len(),
+            scored_tokens: self.total.count,
+            ctx: options.ctx,
+            stride: options.stride,
+            perplexity: mean_nll.exp(),
+            mean_nll,
+            nll_std_error: std_error,
+            ci_low: (mean_nll - Z_95 * std_error).exp(),
+            ci_high: (mean_nll + Z_95 * std_error).exp(),
+            chunks: self.chunks,
+        }
+    }
+}
+
+impl<F: FnMut(&ChunkResult)> Scorer for NllScorer<'_, F> {
+    fn score(&mut self, index: usize, logits: &[f32]) -> Result<()> {
+        let nll = negative_log_likelihood(logits, self.tokens[index])?;
+        self.chunk.add(nll);
+        self.total.add(nll);
+        Ok(())
+    }
+
+    fn end_window(&mut self, start: usize) -> Result<()> {
+        let chunk = std::mem::take(&mut self.chunk);
+        let result = ChunkResult {
+            index: self.chunks.len(),
+            start,
+            tokens: chunk.count,
+            perplexity: chunk.mean().exp(),
+            running_perplexity: self.total.mean().exp(),
+        };
+        (self.on_chunk)(&result);
+        self.chunks.push(result);
+        Ok(())
+    }
+}
+
+/// [`evaluate`] over any function returning the logits predicting each of
+/// `window[first..]` for `(window, first)`.
+fn evaluate_with<M, F>(
+    forward: M,
+    tokens: &[u32],
+    options: &PerplexityOptions,
+    on_chunk: F,
+) -> Result<PerplexityReport>
+where
+    M: FnMut(&[u32], usize) -> Result<Vec<Vec<f32>>>,
+    F: FnMut(&ChunkResult),
+{
+    let mut scorer = NllScorer::new(tokens, on_chunk);
+    slide(forward, tokens, options, &mut scorer)?;
+    Ok(scorer.into_report(options))
+}
+
+/// Walk the windows over `tokens`, passing the logits of every scored token
+/// to `scorer`. Returns the number of windows.
+pub(super) fn slide<M, S>(
+    mut forward: M,
+    tokens: &[u32],
+    options: &PerplexityOptions,
+    scorer: &mut S,
+) -> Result<usize>
+where
+    M: FnMut(&[u32], usize) -> Result<Vec<Vec<f32>>>,
+    S: Scorer,
+{
+    let PerplexityOptions { ctx, stride, .. } = *options;
+    if ctx < 2 {
+        anyhow::bail!("Window must be at least 2 tokens");
+    }
+    if stride == 0 || stride > ctx {
+        anyhow::bail!("Stride must be between 1 and the window size ({})", ctx);
+    }
+    if tokens.len() < 2 {
+        anyhow::bail!(
+            "Need at least 2 tokens to measure perplexity, got {}",
+            tokens.len()
+        );
+    }
+
+    let mut windows = 0;
+    let mut scored_until: usize = 0;
+
+    for start in (0..tokens.len()).step_by(stride) {
+        if options.max_chunks.is_some_and(|max| windows >= max) {
+            break;
+        }
+
+        let end = (start + ctx).min(tokens.len());
+        let window = &tokens[start..end];
+        // The first token of a window has no context to be predicted from.
+        let first = scored_until.saturating_sub(start).max(1);
+        if first >= window.len() {
+            break;
+        }
+
+        let logits = forward(window, first)?;
+        if logits.len() != window.len() - first {
+            anyhow::bail!(
+                "Expected logits for {} positions, got {}",
+                window.len() - first,
+                logits.len()
+            );
+        }
+        for (pos, logits) in (first..).zip(&logits) {
+            scorer.score(start + pos, logits)?;
+        }
+        scorer.end_window(start)?;
+        windows += 1;
+
+        scored_until = end;
+        if end == tokens.len() {
+            break;
+        }
+    }
+
+    Ok(windows)
+}
+
+/// `-log softmax(logits)[target]`, computed in f64.
+pub(super) fn negative_log_likelihood(logits: &[f32], target: u32) -> Result<f64> {
+    let target = logits.get(target as usize).copied().ok_or_else(|| {
+        anyhow::anyhow!(
+            "Token {} is outside the model's {} logits",
+            target,
+            logits.len()
+        )
+    })?;
+    Ok(log_sum_exp(logits) - target as f64)
+}
+
+/// `log(sum(exp(logits)))`, the log-softmax normaliser.
+pub(super) fn log_sum_exp(logits: &[f32]) -> f64 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+    logits
+        .iter()
+        .map(|&l| (l as f64 - max).exp())
+        .sum::<f64>()
+        .ln()
+        + max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uniform_logits_give_vocab_size() {
+        let tokens: Vec<u32> = (0..100).map(|i| i % 10).collect();
+        let options = PerplexityOptions {
+            ctx: 16,
+            stride: 8,
+            max_chunks: None,
+        };
+        let uniform = |window: &[u32], first| Ok(vec![vec![0.0; 10]; window.len() - first]);
+        let report = evaluate_with(uniform, &tokens, &options, |_| {}).unwrap();
+
+        assert!((report.perplexity - 10.0).abs() < 1e-9);
+        assert!(report.nll_std_error < 1e-6);
+        assert_eq!(report.scored_tokens, 99);
+    }
+
+    #[test]
+    fn test_every_token_scored_once_with_enough_context() {
+        let tokens: Vec<u32> = (0..50).collect();
+        let options = PerplexityOptions {
+            ctx: 10,
+            stride: 4,
+            max_chunks: None,
+        };
+
+        // Logits that put all mass on the token after the previous one, so
+        // every prediction is correct and records which positions ran.
+        let mut predicted = Vec::new();
+        let report = evaluate_with(
+            |window: &[u32], first| {
+                let rows = window[first - 1..window.len() - 1].iter().map(|&token| {
+                    predicted.push(token + 1);
+                    let mut logits = vec![-100.0; 64];
+                    logits[token as usize + 1] = 100.0;
+                    logits
+                });
+                Ok(rows.collect())
+            },
+            &tokens,
+            &options,
+            |chunk| assert!(chunk.tokens <= 10),
+        )
+        .unwrap();
+
+        assert_eq!(predicted, (1..50).collect::<Vec<u32>>());
+        assert!(report.perplexity < 1.0001);
+        assert_eq!(report.chunks[1].start, 4);
+        assert_eq!(report.chunks[1].tokens, 4);
+    }
+
+    #[test]
+    fn test_rejects_bad_windows() {
+        let tokens = vec![1, 2, 3];
+        let forward = |_: &[u32], _| Ok(vec![vec![0.0; 4]]);
+        let bad = |ctx, stride| PerplexityOptions {
+            ctx,
+            stride,
+            max_chunks: None,
+        };
+        assert!(evaluate_with(forward, &tokens, &bad(1, 1), |_| {}).is_err());
+        assert!(evaluate_with(forward, &tokens, &bad(4, 0), |_| {}).is_err());
+        assert!(evaluate_with(forward, &tokens, &bad(4, 5), |_| {}).is_err());
+    }
+}
@@ -23,6 +23,7 @@ pub enum SimdLevel {
 }
 
 impl SimdLevel {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Self {
         match s.to_lowercase().as_str() {
             "avx512" => SimdLevel::Avx512,
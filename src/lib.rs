@@ -4,13 +4,22 @@
 //!
 //! # Features
 //!
-//! - GGUF model support (LLaMA, LFM2 architectures)
+//! - GGUF model support (LLaMA/Mistral/Mixtral, LFM2, Qwen2/3, Phi-3, Gemma 3 architectures)
 //! - Full tokenizer compatibility (SPM, BPE, WPM, UGM, RWKV)
 //! - Automatic chat templates from GGUF files
 //! - Streaming token generation
 //! - Multiple sampling strategies (temperature, top-k, top-p)
 //! - Interactive REPL and one-shot modes
 //! - Memory-mapped loading for instant startup
+//! - LoRA adapters, swappable on a loaded model
+//! - Sentence embeddings from decoder models, sharing the generation weights, and BERT-style
+//!   GGUF models
+//! - Perplexity and KL-divergence evaluation for comparing quantizations
+//! - GGUF requantization to llama.cpp's Q4_K_M, Q5_K_M, Q8_0 and other types
+//! - Importance-matrix collection (llama.cpp `imatrix.dat`) for better k-quants
+//! - TOML config files with named profiles, shared by the CLI and library
+//! - Model discovery in model directories, with `name:quant` aliases
+//! - Conversation export and import as JSON, Markdown or ShareGPT
 //!
 //! # Quick Start
 //!
@@ -77,6 +86,7 @@
 //! - [Documentation](https://docs.rs/oxide-rs)
 
 pub mod cli;
+pub mod config;
 pub mod inference;
 pub mod model;
 
@@ -84,11 +94,19 @@ use std::path::Path;
 use std::path::PathBuf;
 
 pub use inference::{
-    BatchConfig, DynamicBatcher, Generator, PagedAttentionConfig, PagedKvCache, 
-    PrefixCache, PrefixCacheConfig, SimdLevel, StreamEvent,
-    ThreadPinnerConfig, ThreadPinner,
+    export_conversation, import_conversation, BatchConfig, ChatTemplate, ChunkResult, Completion,
+    ConversationFormat, DynamicBatcher, FinishReason, Generator, KlChunkResult, KlReport, Message,
+    PagedAttentionConfig, PagedKvCache, PerplexityOptions, PerplexityReport, PrefixCache,
+    PrefixCacheConfig, SamplingParams, SimdLevel, StreamEvent, ThreadPinnerConfig, ThreadPinner,
+    Usage,
+};
+pub use config::{Config, Profile};
+pub use model::{
+    ContextOverrides, EmbedOptions, Embedder, GgufInspection, GgufMetadata, Imatrix, ImatrixChunk,
+    ImatrixOptions, LoraSpec, Model as ModelWrapper, ModelEntry, Pooling, QuantType,
+    QuantizeOptions, QuantizeReport, RopeParams, RopeScaling, TensorProgress, TokenInfo,
+    TokenizerWrapper,
 };
-pub use model::{GgufMetadata, Model as ModelWrapper, TokenizerWrapper};
 
 /// Configuration options for text generation.
 ///
@@ -108,7 +126,10 @@ pub use model::{GgufMetadata, Model as ModelWrapper, TokenizerWrapper};
 ///     system_prompt: None,
 /// };
 /// ```
-#[derive(Clone, Debug)]
+///
+/// Deserializable from TOML or JSON; missing fields take their defaults.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct GenerateOptions {
     /// Maximum number of tokens to generate.
     ///
@@ -193,6 +214,33 @@ pub struct GenerateOptions {
     ///
     /// Default: `auto`
     pub simd_level: String,
+
+    /// Context length to use instead of the one stored in the model.
+    ///
+    /// Default: `None` (the model's trained context)
+    pub ctx_size: Option<usize>,
+
+    /// RoPE frequency base override.
+    ///
+    /// Default: `None`
+    pub rope_freq_base: Option<f32>,
+
+    /// RoPE frequency scale, as in llama.cpp. Values other than `1.0` are
+    /// linear scaling by the inverse factor.
+    ///
+    /// Default: `None`
+    pub rope_freq_scale: Option<f32>,
+
+    /// RoPE scaling type for running past the trained context, replacing
+    /// the one in the GGUF.
+    ///
+    /// Default: `None`
+    pub rope_scaling: Option<RopeScaling>,
+
+    /// RoPE scaling factor; derived from `ctx_size` when unset.
+    ///
+    /// Default: `None`
+    pub rope_scale: Option<f32>,
 }
 
 impl Default for GenerateOptions {
@@ -214,6 +262,23 @@ impl Default for GenerateOptions {
             cpu_threads: 0,
             reserve_cores: 0,
             simd_level: "auto".to_string(),
+            ctx_size: None,
+            rope_freq_base: None,
+            rope_freq_scale: None,
+            rope_scaling: None,
+            rope_scale: None,
+        }
+    }
+}
+
+impl GenerateOptions {
+    fn context_overrides(&self) -> ContextOverrides {
+        ContextOverrides {
+            ctx_size: self.ctx_size,
+            rope_freq_base: self.rope_freq_base,
+            rope_freq_scale: self.rope_freq_scale,
+            rope_scaling: self.rope_scaling,
+            rope_scale: self.rope_scale,
         }
     }
 }
@@ -244,9 +309,13 @@ impl Default for GenerateOptions {
 /// ```
 pub struct Model {
     generator: Option<Generator>,
+    tokenizer: Option<(TokenizerWrapper, ChatTemplate)>,
     model_path: PathBuf,
     tokenizer_path: Option<PathBuf>,
     options: GenerateOptions,
+    lora: Vec<LoraSpec>,
+    embedder: Option<Embedder>,
+    embed_options: EmbedOptions,
 }
 
 impl Model {
@@ -266,9 +335,13 @@ impl Model {
     pub fn new<P: AsRef<Path>>(model_path: P) -> Result<Self, Box<dyn std::error::Error>> {
         Ok(Self {
             generator: None,
+            tokenizer: None,
             model_path: model_path.as_ref().to_path_buf(),
             tokenizer_path: None,
             options: GenerateOptions::default(),
+            lora: Vec::new(),
+            embedder: None,
+            embed_options: EmbedOptions::default(),
         })
     }
 
@@ -289,9 +362,47 @@ impl Model {
         self
     }
 
+    /// Replace the generation options.
+    ///
+    /// On a loaded model the sampler and system prompt are rebuilt for the
+    /// next call, keeping the weights and the conversation history. The
+    /// sampler's random generator restarts from `seed`. Context and RoPE
+    /// settings, `batch_size` and the thread settings only apply to the
+    /// next `load()`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// model.set_options(GenerateOptions {
+    ///     temperature: 0.8,
+    ///     top_k: Some(40),
+    ///     ..model.options().clone()
+    /// });
+    /// ```
+    pub fn set_options(&mut self, options: GenerateOptions) {
+        if let Some(generator) = self.generator.as_mut() {
+            generator.set_sampling(SamplingParams {
+                temperature: options.temperature,
+                top_p: options.top_p,
+                top_k: options.top_k,
+                seed: options.seed,
+            });
+            generator.set_system_prompt(options.system_prompt.clone());
+        }
+        self.options = options;
+    }
+
+    /// The current generation options.
+    pub fn options(&self) -> &GenerateOptions {
+        &self.options
+    }
+
     /// Set a custom tokenizer path.
     ///
-    /// If not provided, the tokenizer will be extracted from the GGUF file.
+    /// Accepts a Hugging Face `tokenizer.json` or a GGUF file. A
+    /// `tokenizer_config.json` next to `tokenizer.json` supplies the chat
+    /// template and special tokens. If not provided, the tokenizer will be
+    /// extracted from the GGUF file.
     ///
     /// # Example
     ///
@@ -304,6 +415,55 @@ impl Model {
         self
     }
 
+    /// Apply a LoRA adapter when the model is loaded.
+    ///
+    /// `path` is a GGUF adapter as produced by llama.cpp's
+    /// `convert_lora_to_gguf.py`; `scale` multiplies its effect (`1.0` is
+    /// the adapter as trained). Call repeatedly to stack adapters. The
+    /// adapters are merged into the weights they target, which are then
+    /// kept as F16 (F32 if they were F32) so that the delta is not rounded
+    /// away.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let mut model = Model::new("model.gguf")?
+    ///     .with_lora("customer-a.gguf", 1.0);
+    /// model.load()?;
+    /// ```
+    pub fn with_lora<P: AsRef<Path>>(mut self, path: P, scale: f32) -> Self {
+        self.lora.push(LoraSpec::new(path, scale));
+        self
+    }
+
+    /// Replace the LoRA adapters, swapping, rescaling or removing them.
+    ///
+    /// On a loaded model the weights are rebuilt from the already mapped
+    /// model file, and adapters that stay active are not read again. The
+    /// conversation history is kept. Pass an empty slice for the base model.
+    /// If the new adapters fail to load, the previous ones stay active.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use oxide_rs::LoraSpec;
+    ///
+    /// model.set_lora(&[LoraSpec::new("customer-b.gguf", 0.8)])?;
+    /// model.set_lora(&[])?;
+    /// ```
+    pub fn set_lora(&mut self, adapters: &[LoraSpec]) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(generator) = self.generator.as_mut() {
+            generator.set_lora(adapters)?;
+        }
+        self.lora = adapters.to_vec();
+        Ok(())
+    }
+
+    /// The LoRA adapters applied to the model.
+    pub fn lora(&self) -> &[LoraSpec] {
+        &self.lora
+    }
+
     /// Load the model into memory.
     ///
     /// This must be called before `generate()`.
@@ -323,14 +483,121 @@ impl Model {
             self.options.seed,
             self.options.system_prompt.clone(),
             self.options.batch_size,
+            &self.options.context_overrides(),
+            &self.lora,
         )?;
         self.generator = Some(generator);
+        self.tokenizer = None;
         Ok(())
     }
 
+    /// Tokenizer and chat template, from the loaded generator or, before
+    /// `load()`, read from the model file without loading weights.
+    fn text_tools(
+        &mut self,
+    ) -> Result<(&TokenizerWrapper, &ChatTemplate), Box<dyn std::error::Error>> {
+        if self.generator.is_none() && self.tokenizer.is_none() {
+            let metadata = model::Model::read_metadata(&self.model_path)?;
+            let tokenizer = match &self.tokenizer_path {
+                Some(path) => TokenizerWrapper::from_file(path)?,
+                None => TokenizerWrapper::from_gguf(&self.model_path)?,
+            };
+            let template = ChatTemplate::resolve(&metadata, &tokenizer)?;
+            self.tokenizer = Some((tokenizer, template));
+        }
+
+        match (&self.generator, &self.tokenizer) {
+            (Some(generator), _) => Ok((generator.tokenizer(), generator.template())),
+            (None, Some((tokenizer, template))) => Ok((tokenizer, template)),
+            (None, None) => unreachable!("tokenizer loaded above"),
+        }
+    }
+
+    /// Convert text to token ids.
+    ///
+    /// Works before `load()`: only the tokenizer is read from the model file.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to tokenize
+    /// * `add_special` - Add the tokenizer's BOS/EOS tokens
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let ids = model.tokenize("Hello, world!", false)?;
+    /// println!("{} tokens", ids.len());
+    /// ```
+    pub fn tokenize(
+        &mut self,
+        text: &str,
+        add_special: bool,
+    ) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+        let (tokenizer, _) = self.text_tools()?;
+        Ok(tokenizer.tokenize(text, add_special)?)
+    }
+
+    /// Convert text to tokens with their pieces and byte offsets into `text`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// for token in model.tokenize_with_offsets("Hello", false)? {
+    ///     println!("{} {:?} {}..{}", token.id, token.piece, token.start, token.end);
+    /// }
+    /// ```
+    pub fn tokenize_with_offsets(
+        &mut self,
+        text: &str,
+        add_special: bool,
+    ) -> Result<Vec<TokenInfo>, Box<dyn std::error::Error>> {
+        let (tokenizer, _) = self.text_tools()?;
+        Ok(tokenizer.tokenize_with_offsets(text, add_special)?)
+    }
+
+    /// Convert token ids back to text.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let text = model.detokenize(&[9707, 11, 1879])?;
+    /// ```
+    pub fn detokenize(&mut self, ids: &[u32]) -> Result<String, Box<dyn std::error::Error>> {
+        let (tokenizer, _) = self.text_tools()?;
+        Ok(tokenizer.decode(ids)?)
+    }
+
+    /// Count the prompt tokens a conversation renders to.
+    ///
+    /// Messages are passed through the chat template exactly as given; the
+    /// configured system prompt is not added.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use oxide_rs::Message;
+    ///
+    /// let messages = vec![Message {
+    ///     role: "user".into(),
+    ///     content: "What is Rust?".into(),
+    /// }];
+    /// println!("{} prompt tokens", model.count_chat_tokens(&messages)?);
+    /// ```
+    pub fn count_chat_tokens(
+        &mut self,
+        messages: &[Message],
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let (tokenizer, template) = self.text_tools()?;
+        let prompt = template.apply(messages)?;
+        Ok(tokenizer.encode(&prompt)?.len())
+    }
+
     /// Generate text from a prompt.
     ///
-    /// Requires `load()` to be called first.
+    /// Requires `load()` to be called first. The prompt and reply are added
+    /// to the conversation; when it leaves no room for `max_tokens` of
+    /// reply, its oldest user/assistant exchanges are dropped first. The
+    /// system prompt and `prompt` are always kept.
     ///
     /// # Arguments
     ///
@@ -410,6 +677,62 @@ impl Model {
         Ok(output)
     }
 
+    /// Reply to a conversation given as messages, independent of the
+    /// model's own history.
+    ///
+    /// The system prompt from the options comes first unless `messages`
+    /// start with a system message. Requires `load()` to be called first.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let messages = vec![
+    ///     Message { role: "user".into(), content: "Name a prime.".into() },
+    ///     Message { role: "assistant".into(), content: "7".into() },
+    ///     Message { role: "user".into(), content: "Another one?".into() },
+    /// ];
+    /// println!("{}", model.complete(&messages)?);
+    /// ```
+    pub fn complete(&mut self, messages: &[Message]) -> Result<String, Box<dyn std::error::Error>> {
+        let generator = self
+            .generator
+            .as_mut()
+            .ok_or("Model not loaded. Call load() first.")?;
+
+        let result = generator.complete(
+            messages,
+            self.options.max_tokens,
+            self.options.repeat_penalty,
+            self.options.repeat_last_n,
+            |_event| {},
+        )?;
+
+        Ok(result)
+    }
+
+    /// Hand the loaded model to a [`DynamicBatcher`], which replies to the
+    /// requests sent to it in small batches, each with its own options.
+    ///
+    /// Requires `load()` to be called first, and a Tokio runtime to run in.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let batcher = model.into_batcher(BatchConfig::default())?;
+    /// let reply = batcher.generate(messages, GenerateOptions::default()).await?;
+    /// println!("{}", reply.text);
+    /// ```
+    pub fn into_batcher(
+        mut self,
+        config: BatchConfig,
+    ) -> Result<DynamicBatcher, Box<dyn std::error::Error>> {
+        let generator = self
+            .generator
+            .take()
+            .ok_or("Model not loaded. Call load() first.")?;
+        Ok(DynamicBatcher::new(config, generator))
+    }
+
     /// Generate text from multiple prompts in batch.
     ///
     /// Processes multiple prompts sequentially, sharing the loaded model for efficiency.
@@ -473,6 +796,105 @@ impl Model {
         Ok(())
     }
 
+    /// Measure the model's perplexity on `text`.
+    ///
+    /// A window of `options.ctx` tokens slides over the text `options.stride`
+    /// tokens at a time and every token is scored once, with at least
+    /// `ctx - stride` tokens of context after the first window. `on_chunk`
+    /// receives each window's result as it completes.
+    ///
+    /// Requires `load()` to be called first.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use oxide_rs::PerplexityOptions;
+    ///
+    /// let text = std::fs::read_to_string("wiki.test.raw")?;
+    /// let report = model.perplexity(&text, &PerplexityOptions::default(), |chunk| {
+    ///     println!("[{}] {:.4}", chunk.index + 1, chunk.running_perplexity);
+    /// })?;
+    /// println!("PPL = {:.4} +/- {:.4}", report.perplexity, report.perplexity_error());
+    /// ```
+    pub fn perplexity<F>(
+        &mut self,
+        text: &str,
+        options: &PerplexityOptions,
+        on_chunk: F,
+    ) -> Result<PerplexityReport, Box<dyn std::error::Error>>
+    where
+        F: FnMut(&ChunkResult),
+    {
+        let generator = self
+            .generator
+            .as_mut()
+            .ok_or("Model not loaded. Call load() first.")?;
+        Ok(generator.perplexity(text, options, on_chunk)?)
+    }
+
+    /// Measure perplexity like [`Model::perplexity`] and store the model's
+    /// next-token distributions in `path`, as the reference for a later
+    /// [`Model::kl_divergence`] run with another quantization of the model.
+    ///
+    /// Requires `load()` to be called first.
+    pub fn record_logits<P, F>(
+        &mut self,
+        text: &str,
+        options: &PerplexityOptions,
+        path: P,
+        on_chunk: F,
+    ) -> Result<PerplexityReport, Box<dyn std::error::Error>>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&ChunkResult),
+    {
+        let generator = self
+            .generator
+            .as_mut()
+            .ok_or("Model not loaded. Call load() first.")?;
+        Ok(generator.record_logits(text, options, path.as_ref(), on_chunk)?)
+    }
+
+    /// Compare this model with reference distributions recorded by
+    /// [`Model::record_logits`] over the same text.
+    ///
+    /// Uses the windows the reference was recorded with and reports the
+    /// KL divergence from the reference (mean and percentiles), how often
+    /// both models rank the same token first, and the perplexity change.
+    ///
+    /// Requires `load()` to be called first.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let text = std::fs::read_to_string("wiki.test.raw")?;
+    ///
+    /// let mut q8 = Model::new("model-Q8_0.gguf")?;
+    /// q8.load()?;
+    /// q8.record_logits(&text, &PerplexityOptions::default(), "q8.kld", |_| {})?;
+    ///
+    /// let mut q4 = Model::new("model-Q4_K_M.gguf")?;
+    /// q4.load()?;
+    /// let report = q4.kl_divergence(&text, "q8.kld", |_| {})?;
+    /// println!("KLD {:.4}, top-1 {:.1}%", report.mean_kl, report.top1_agreement * 100.0);
+    /// ```
+    pub fn kl_divergence<P, F>(
+        &mut self,
+        text: &str,
+        path: P,
+        on_chunk: F,
+    ) -> Result<KlReport, Box<dyn std::error::Error>>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&KlChunkResult),
+    {
+        let generator = self
+            .generator
+            .as_mut()
+            .ok_or("Model not loaded. Call load() first.")?;
+        Ok(generator.kl_divergence(text, path.as_ref(), on_chunk)?)
+    }
+
     /// Clear conversation history.
     ///
     /// Removes all previous messages from the conversation context.
@@ -489,6 +911,173 @@ impl Model {
         }
     }
 
+    /// The conversation so far, starting with the system prompt if there
+    /// is one. Empty until `load()` is called.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use oxide_rs::{export_conversation, ConversationFormat};
+    ///
+    /// model.generate("What is Rust?")?;
+    /// let transcript = export_conversation(&model.conversation(), ConversationFormat::Markdown)?;
+    /// std::fs::write("chat.md", transcript)?;
+    /// ```
+    pub fn conversation(&self) -> Vec<Message> {
+        self.generator
+            .as_ref()
+            .map(|g| g.conversation())
+            .unwrap_or_default()
+    }
+
+    /// Continue from `messages` instead of the current conversation. A
+    /// leading system message replaces the system prompt; the next turn
+    /// prefills the whole conversation.
+    ///
+    /// Requires `load()` to be called first.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use oxide_rs::import_conversation;
+    ///
+    /// let messages = import_conversation(&std::fs::read_to_string("chat.json")?)?;
+    /// model.set_conversation(messages)?;
+    /// let reply = model.generate("Where were we?")?;
+    /// ```
+    pub fn set_conversation(
+        &mut self,
+        messages: Vec<Message>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let generator = self
+            .generator
+            .as_mut()
+            .ok_or("Model not loaded. Call load() first.")?;
+        generator.set_conversation(messages)?;
+        Ok(())
+    }
+
+    /// Set the pooling, normalisation and batch size used by `embed()`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use oxide_rs::{EmbedOptions, Pooling};
+    ///
+    /// let model = Model::new("bge-small.gguf")?.with_embed_options(EmbedOptions {
+    ///     pooling: Some(Pooling::Cls),
+    ///     ..Default::default()
+    /// });
+    /// ```
+    pub fn with_embed_options(mut self, options: EmbedOptions) -> Self {
+        self.embed_options = options;
+        self
+    }
+
+    /// Compute one embedding per text from the model's final hidden states.
+    ///
+    /// Decoder models run the generation weights, LoRA adapters and context
+    /// overrides included, and are loaded with `load()` on first use if
+    /// needed. BERT and Nomic BERT embedding models cannot generate; their
+    /// weights are read on first use and kept. Inputs are tokenized with
+    /// the tokenizer's special tokens and truncated to the model's context
+    /// length.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let mut model = Model::new("nomic-embed-text-v1.5.Q8_0.gguf")?;
+    /// let vectors = model.embed(&["search_query: what is rust?"])?;
+    /// println!("{} dimensions", vectors[0].len());
+    /// ```
+    pub fn embed(&mut self, texts: &[&str]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let (tokenizer, _) = self.text_tools()?;
+        let inputs = texts
+            .iter()
+            .map(|text| tokenizer.tokenize(text, true))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        self.load_for("Embeddings")?;
+        match (&self.embedder, &mut self.generator) {
+            (Some(embedder), _) => Ok(embedder.embed(&inputs, &self.embed_options)?),
+            (None, Some(generator)) => Ok(generator.embed(&inputs, &self.embed_options)?),
+            (None, None) => unreachable!("model loaded above"),
+        }
+    }
+
+    /// Collect an importance matrix from calibration text.
+    ///
+    /// The text is split into windows of `options.ctx` tokens and run
+    /// through the model, summing the squared inputs of every matmul, the
+    /// output projection included. Decoder models are loaded with `load()`
+    /// on first use if needed, and their generation weights are rebuilt for
+    /// the built-in decoder pass, which generation then keeps using;
+    /// encoder models use the same weights as [`embed`](Self::embed).
+    /// `dataset` is stored in the file as the calibration source. Pass the
+    /// saved matrix to [`quantize`] through [`QuantizeOptions::imatrix`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let text = std::fs::read_to_string("calibration.txt")?;
+    /// let imatrix = model.imatrix(&text, &ImatrixOptions::default(), "calibration.txt", |c| {
+    ///     println!("[{}/{}]", c.index + 1, c.total);
+    /// })?;
+    /// imatrix.save("imatrix.dat".as_ref())?;
+    /// ```
+    pub fn imatrix<F>(
+        &mut self,
+        text: &str,
+        options: &ImatrixOptions,
+        dataset: &str,
+        on_chunk: F,
+    ) -> Result<Imatrix, Box<dyn std::error::Error>>
+    where
+        F: FnMut(&ImatrixChunk),
+    {
+        self.load_for("Importance matrices")?;
+        if let Some(generator) = &mut self.generator {
+            return Ok(generator.imatrix(text, options, dataset, on_chunk)?);
+        }
+
+        let (tokenizer, _) = self.text_tools()?;
+        let tokens = tokenizer.tokenize(text, true)?;
+        let embedder = self.embedder.as_ref().expect("encoder loaded above");
+        Ok(Imatrix::collect_encoder(
+            embedder, &tokens, options, dataset, on_chunk,
+        )?)
+    }
+
+    /// Load what `embed()` and `imatrix()` run on: the generation model for
+    /// decoder architectures, the encoder weights otherwise. `purpose`
+    /// names the feature in the error for other architectures.
+    fn load_for(&mut self, purpose: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.embedder.is_some() || self.generator.is_some() {
+            return Ok(());
+        }
+        let metadata = model::Model::read_metadata(&self.model_path)?;
+        let arch = metadata.architecture.as_str();
+        if model::embedding::ENCODER_ARCHITECTURES.contains(&arch) {
+            self.embedder = Some(Embedder::load(&self.model_path)?);
+        } else if model::loader::SUPPORTED_ARCHITECTURES.contains(&arch) {
+            self.load()?;
+        } else {
+            let supported: Vec<&str> = model::loader::SUPPORTED_ARCHITECTURES
+                .iter()
+                .chain(model::embedding::ENCODER_ARCHITECTURES)
+                .copied()
+                .collect();
+            return Err(format!(
+                "{} are not supported for '{}' models (supported: {})",
+                purpose,
+                arch,
+                supported.join(", ")
+            )
+            .into());
+        }
+        Ok(())
+    }
+
     /// Get model metadata.
     ///
     /// Returns information about the loaded model including name,
@@ -506,6 +1095,38 @@ impl Model {
         self.generator.as_ref().map(|g| g.metadata())
     }
 
+    /// Read every metadata key, the tensor table and the special tokens
+    /// from the model file.
+    ///
+    /// Only the GGUF header is parsed, so this is cheap and works before
+    /// `load()`. Split models are read from all of their shards.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let report = model.inspect()?;
+    /// for tensor in &report.tensors {
+    ///     println!("{} {:?} {}", tensor.name, tensor.shape, tensor.dtype);
+    /// }
+    /// ```
+    pub fn inspect(&self) -> Result<GgufInspection, Box<dyn std::error::Error>> {
+        Ok(model::inspect::inspect(&self.model_path)?)
+    }
+
+    /// Token counts and finish reason of the last reply.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let reply = model.generate("Hello")?;
+    /// if let Some(usage) = model.last_usage() {
+    ///     println!("{} tokens, {:?}", usage.completion_tokens, usage.finish_reason);
+    /// }
+    /// ```
+    pub fn last_usage(&self) -> Option<&Usage> {
+        self.generator.as_ref().and_then(|g| g.last_usage())
+    }
+
     /// Get current context usage.
     ///
     /// Returns the number of tokens currently in the context.
@@ -584,3 +1205,45 @@ pub fn generate<P: AsRef<Path>>(
     model.load()?;
     model.generate(prompt)
 }
+
+/// Requantize a GGUF model.
+///
+/// Reads `input` (any shard of a split model), converts its weights to
+/// `file_type` using llama.cpp's per-tensor mixes, and writes a single GGUF
+/// file to `output` with the metadata copied and `general.file_type`
+/// updated. `on_tensor` is called before each tensor is converted.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// use oxide_rs::{quantize, QuantType, QuantizeOptions};
+///
+/// let report = quantize(
+///     "model-f16.gguf",
+///     "model-Q4_K_M.gguf",
+///     QuantType::Q4_K_M,
+///     &QuantizeOptions::default(),
+///     |t| println!("[{}/{}] {}", t.index + 1, t.total, t.name),
+/// )?;
+/// println!("{} -> {} bytes", report.input_size, report.output_size);
+/// ```
+pub fn quantize<P, Q, F>(
+    input: P,
+    output: Q,
+    file_type: QuantType,
+    options: &QuantizeOptions,
+    on_tensor: F,
+) -> Result<QuantizeReport, Box<dyn std::error::Error>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    F: FnMut(&TensorProgress),
+{
+    Ok(model::quantize(
+        input.as_ref(),
+        output.as_ref(),
+        file_type,
+        options,
+        on_tensor,
+    )?)
+}
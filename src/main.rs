@@ -1,31 +1,49 @@
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::fmt;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use oxide_rs::cli::stream::strip_special_markers;
 use oxide_rs::cli::{
-    print_banner, print_divider, print_model_info, print_welcome, ModelLoader, PromptDisplay,
-    StreamOutput, ThinkingSpinner,
+    pick, print_banner, print_divider, print_model_info, print_welcome, LineEditor,
+    MarkdownRenderer, ModelLoader, PromptDisplay, ReadLine, StreamOutput, ThinkingSpinner,
+};
+use oxide_rs::inference::{FinishReason, Generator, SamplingParams, StreamEvent};
+use oxide_rs::model::discovery;
+use oxide_rs::{
+    export_conversation, import_conversation, Config, ContextOverrides, ConversationFormat,
+    LoraSpec, Message, Profile, RopeScaling,
 };
-use oxide_rs::inference::{Generator, StreamEvent};
 use rayon::ThreadPoolBuilder;
+use serde::Serialize;
+use slash::{parse_optional, parse_setting, split_words};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod commands;
+mod slash;
+
 const DEFAULT_SYSTEM_PROMPT: &str = "You are a helpful, honest, and accurate AI assistant. If you don't know something, say so clearly. Do not make up information or hallucinate facts.";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to GGUF model file
-    #[arg(short, long)]
-    model: PathBuf,
+    #[arg(short, long, env = "OXIDE_MODEL")]
+    model: Option<PathBuf>,
 
-    /// Path to tokenizer.json (optional, will extract from GGUF if not provided)
+    /// Path to a Hugging Face tokenizer.json or GGUF (optional, uses the model's embedded tokenizer if not provided)
     #[arg(short, long)]
     tokenizer: Option<PathBuf>,
 
     /// Maximum tokens to generate
-    #[arg(short, long, default_value = "512")]
+    #[arg(long, default_value = "512")]
     max_tokens: usize,
 
     /// Temperature for sampling (0.0 = greedy)
@@ -71,6 +89,237 @@ struct Args {
     /// Run in non-interactive mode (generate and exit)
     #[arg(short, long)]
     once: bool,
+
+    /// Context size (default: the model's trained context)
+    #[arg(long, short = 'c')]
+    ctx_size: Option<usize>,
+
+    /// RoPE frequency base (default: from the model)
+    #[arg(long)]
+    rope_freq_base: Option<f32>,
+
+    /// RoPE frequency scale; other than 1 it means linear scaling by 1/scale
+    #[arg(long)]
+    rope_freq_scale: Option<f32>,
+
+    /// RoPE scaling for running past the trained context: none, linear, ntk or yarn
+    #[arg(long)]
+    rope_scaling: Option<RopeScaling>,
+
+    /// RoPE scaling factor, implying ntk (default: ctx-size / trained context)
+    #[arg(long)]
+    rope_scale: Option<f32>,
+
+    /// LoRA adapter (GGUF) to apply; can be repeated
+    #[arg(long, value_name = "PATH")]
+    lora: Vec<PathBuf>,
+
+    /// LoRA adapter with a user-defined scale; can be repeated
+    #[arg(long, num_args = 2, value_names = ["PATH", "SCALE"])]
+    lora_scaled: Vec<String>,
+
+    /// Print replies as plain text instead of rendering markdown
+    #[arg(long)]
+    plain: bool,
+
+    /// Reply format for --once: text, json (one object with the text, usage
+    /// and finish reason) or jsonl (a line per chunk, then that object);
+    /// json and jsonl imply --once and --quiet
+    #[arg(long, default_value = "text")]
+    output: OutputFormat,
+
+    /// Only print the reply: no banner, model info, spinner or stats
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Config file with defaults and profiles (default: ~/.config/oxide/config.toml)
+    #[arg(long, env = "OXIDE_CONFIG", value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+
+    /// Profile from the config file to use, e.g. coder
+    #[arg(long, env = "OXIDE_PROFILE", global = true)]
+    profile: Option<String>,
+
+    /// Continue a conversation saved with /export (JSON, Markdown or ShareGPT)
+    #[arg(long, value_name = "FILE")]
+    resume: Option<PathBuf>,
+}
+
+/// Let the user choose one of the discovered models.
+fn pick_model(model_dirs: &[PathBuf]) -> Result<PathBuf> {
+    let entries = discovery::discover(model_dirs);
+    if entries.is_empty() {
+        anyhow::bail!(
+            "No model: pass --model, or put GGUF files in ~/Models or a directory in {}",
+            discovery::MODELS_ENV
+        );
+    }
+
+    let items: Vec<String> = entries
+        .iter()
+        .map(|e| {
+            format!(
+                "{}  ({}, {})",
+                e.alias,
+                e.architecture,
+                commands::format_size(e.file_size)
+            )
+        })
+        .collect();
+    match pick("Select a model", &items)? {
+        Some(i) => Ok(entries[i].path.clone()),
+        None => anyhow::bail!("No model selected"),
+    }
+}
+
+/// How `--once` prints the reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+    Jsonl,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::Jsonl),
+            other => Err(format!(
+                "unknown output format '{}' (expected text, json or jsonl)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Text => "text",
+            Self::Json => "json",
+            Self::Jsonl => "jsonl",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Args {
+    /// The config file's top-level settings merged with `--profile`.
+    fn load_profile(&self) -> Result<Profile> {
+        let config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::load_default()?,
+        };
+        config.resolve(self.profile.as_deref())
+    }
+
+    /// Fill in settings from the config file and `--profile`. Flags and
+    /// environment variables win over the profile, which wins over the
+    /// file's top-level keys, which win over the built-in defaults.
+    /// Returns the configured model directories.
+    fn apply_config(&mut self, matches: &ArgMatches) -> Result<Vec<PathBuf>> {
+        let profile = self.load_profile()?;
+        let model_dirs = profile.model_dir_paths();
+
+        let explicit = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+        macro_rules! pick {
+            ($field:ident, $value:expr) => {
+                if let (false, Some(value)) = (explicit(stringify!($field)), $value) {
+                    self.$field = value;
+                }
+            };
+        }
+        pick!(model, profile.model_path().map(Some));
+        pick!(tokenizer, profile.tokenizer_path().map(Some));
+        pick!(system, profile.system.clone().map(Some));
+        pick!(max_tokens, profile.max_tokens);
+        pick!(temperature, profile.temperature);
+        pick!(top_p, profile.top_p.map(Some));
+        pick!(top_k, profile.top_k.map(Some));
+        pick!(repeat_penalty, profile.repeat_penalty);
+        pick!(repeat_last_n, profile.repeat_last_n);
+        pick!(batch_size, profile.batch_size);
+        pick!(seed, profile.seed);
+        pick!(threads, profile.threads.map(Some));
+        pick!(ctx_size, profile.ctx_size.map(Some));
+        pick!(rope_freq_base, profile.rope_freq_base.map(Some));
+        pick!(rope_freq_scale, profile.rope_freq_scale.map(Some));
+        pick!(rope_scaling, profile.rope_scaling.map(Some));
+        pick!(rope_scale, profile.rope_scale.map(Some));
+        pick!(lora, profile.lora_paths());
+        pick!(plain, profile.plain);
+        Ok(model_dirs)
+    }
+
+    /// Adapters from `--lora` and `--lora-scaled`.
+    fn lora_specs(&self) -> Result<Vec<LoraSpec>> {
+        let mut specs: Vec<LoraSpec> = self.lora.iter().map(|p| LoraSpec::new(p, 1.0)).collect();
+        for pair in self.lora_scaled.chunks(2) {
+            let scale = pair[1]
+                .parse()
+                .with_context(|| format!("Invalid LoRA scale '{}'", pair[1]))?;
+            specs.push(LoraSpec::new(&pair[0], scale));
+        }
+        Ok(specs)
+    }
+
+    /// Whether replies are rendered as markdown: only on a terminal, so
+    /// piped output stays plain.
+    fn markdown(&self) -> bool {
+        !self.plain && io::stdout().is_terminal()
+    }
+
+    /// Generate one reply and exit instead of starting the REPL.
+    fn once(&self) -> bool {
+        self.once || self.output != OutputFormat::Text
+    }
+
+    /// Print nothing but the reply, so stdout can be piped.
+    fn quiet(&self) -> bool {
+        self.quiet || self.output != OutputFormat::Text
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Tokenize text and print token ids, pieces and byte offsets
+    Tokenize(commands::tokenize::TokenizeArgs),
+
+    /// Convert token ids back to text
+    Detokenize(commands::tokenize::DetokenizeArgs),
+
+    /// Manage the tokenizer cache
+    Cache(commands::cache::CacheArgs),
+
+    /// Show GGUF metadata, tensors and special tokens without loading weights
+    Inspect(commands::inspect::InspectArgs),
+
+    /// List models in the model directories with their aliases
+    List(commands::list::ListArgs),
+
+    /// Compute sentence embeddings, printed as an OpenAI embeddings response
+    Embed(commands::embed::EmbedArgs),
+
+    /// Measure perplexity on a text file with a sliding window
+    Perplexity(commands::perplexity::PerplexityArgs),
+
+    /// Requantize a GGUF model, e.g. to Q4_K_M
+    Quantize(commands::quantize::QuantizeArgs),
+
+    /// Collect an importance matrix from calibration text for quantize --imatrix
+    Imatrix(commands::imatrix::ImatrixArgs),
+
+    /// Generate replies for a JSONL file of requests, resuming where a previous run stopped
+    Batch(commands::batch::BatchArgs),
 }
 
 fn main() -> Result<()> {
@@ -79,10 +328,40 @@ fn main() -> Result<()> {
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "oxide_rs=error".into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(io::stderr))
         .init();
 
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    if let Some(command) = args.command.take() {
+        // Subcommands take their model, tokenizer and model directories
+        // from the config; the other settings are for chat only.
+        let profile = args.load_profile()?;
+        return match command {
+            Command::Tokenize(args) => commands::tokenize::run_tokenize(args, &profile),
+            Command::Detokenize(args) => commands::tokenize::run_detokenize(args, &profile),
+            Command::Cache(args) => commands::cache::run_cache(args),
+            Command::Inspect(args) => commands::inspect::run_inspect(args, &profile),
+            Command::List(args) => commands::list::run_list(args, &profile),
+            Command::Embed(args) => commands::embed::run_embed(args, &profile),
+            Command::Perplexity(args) => commands::perplexity::run_perplexity(args, &profile),
+            Command::Quantize(args) => commands::quantize::run_quantize(args),
+            Command::Imatrix(args) => commands::imatrix::run_imatrix(args, &profile),
+            Command::Batch(args) => commands::batch::run_batch(args, &profile),
+        };
+    }
+
+    let model_dirs = discovery::model_dirs(&args.apply_config(&matches)?);
+    let model_path = match &args.model {
+        Some(spec) => discovery::resolve(spec, &model_dirs)?,
+        None if !args.once() && io::stdin().is_terminal() && io::stdout().is_terminal() => {
+            pick_model(&model_dirs)?
+        }
+        None => anyhow::bail!(
+            "No model: pass --model, set OXIDE_MODEL or add `model` to the config file"
+        ),
+    };
 
     let num_threads = args
         .threads
@@ -95,12 +374,27 @@ fn main() -> Result<()> {
 
     tracing::info!("Using {} threads for inference", num_threads);
 
-    print_banner();
+    // Read a piped prompt before spending time on loading the model.
+    let once_prompt = match args.once() {
+        true => Some(read_once_prompt(args.prompt.take())?),
+        false => None,
+    };
+
+    let resumed = match &args.resume {
+        Some(path) => Some(read_conversation(path)?),
+        None => None,
+    };
+
+    let quiet = args.quiet();
+    if !quiet {
+        print_banner();
+    }
 
-    let loader = ModelLoader::new();
+    let lora = args.lora_specs()?;
+    let loader = (!quiet).then(ModelLoader::new);
 
-    let generator = match Generator::new(
-        &args.model,
+    let mut generator = match Generator::new(
+        &model_path,
         args.tokenizer.as_ref(),
         args.temperature,
         args.top_p,
@@ -110,6 +404,14 @@ fn main() -> Result<()> {
             .clone()
             .or_else(|| Some(DEFAULT_SYSTEM_PROMPT.to_string())),
         args.batch_size,
+        &ContextOverrides {
+            ctx_size: args.ctx_size,
+            rope_freq_base: args.rope_freq_base,
+            rope_freq_scale: args.rope_freq_scale,
+            rope_scaling: args.rope_scaling,
+            rope_scale: args.rope_scale,
+        },
+        &lora,
     ) {
         Ok(mut g) => {
             if let Err(e) = g.warmup(128) {
@@ -118,65 +420,39 @@ fn main() -> Result<()> {
             g
         }
         Err(e) => {
-            loader.finish_with_error(&format!("Failed: {}", e));
+            if let Some(loader) = loader {
+                loader.finish_with_error(&format!("Failed: {}", e));
+            }
             return Err(e);
         }
     };
 
-    let metadata = generator.metadata().clone();
-    loader.finish(&metadata.name);
-
-    print_model_info(
-        &metadata.name,
-        &format_size(metadata.file_size),
-        metadata.quantization.as_deref().unwrap_or("Unknown"),
-        metadata.n_layer,
-        metadata.n_embd,
-        metadata.context_length,
-    );
+    if let Some(loader) = loader {
+        let metadata = generator.metadata();
+        loader.finish(&metadata.name);
+        print_model_info(
+            &metadata.name,
+            &commands::format_size(metadata.file_size),
+            metadata.quantization.as_deref().unwrap_or("Unknown"),
+            metadata.n_layer,
+            metadata.n_embd,
+            metadata.context_length,
+        );
+    }
 
-    if args.once {
-        let prompt = args
-            .prompt
-            .unwrap_or_else(|| "Write a hello world program in Rust".to_string());
-
-        let mut prompt_display = PromptDisplay::new();
-        prompt_display.show_user_input(&prompt);
-
-        let mut gen_output = generator;
-        let mut stream = StreamOutput::new();
-        let mut thinking_spinner: Option<ThinkingSpinner> = None;
-        let context_limit = gen_output.context_limit();
-        let context_used = gen_output.context_used();
-        let mut prompt_token_count = 0usize;
-
-        gen_output.generate_streaming(
-            &prompt,
-            args.max_tokens,
-            args.repeat_penalty,
-            args.repeat_last_n,
-            |event| match event {
-                StreamEvent::PrefillStatus(count) => {
-                    prompt_token_count = count;
-                    stream.set_prompt_tokens(count);
-                    if thinking_spinner.is_none() {
-                        thinking_spinner = Some(ThinkingSpinner::new());
-                    }
-                }
-                StreamEvent::Token(t) => {
-                    if let Some(spinner) = thinking_spinner.take() {
-                        spinner.stop();
-                    }
-                    stream.set_context(context_used, context_limit);
-                    stream.print_token(&t);
-                }
-                StreamEvent::Done => {
-                    stream.finish();
-                }
-            },
-        )?;
+    if let Some(messages) = resumed {
+        let count = messages.len();
+        generator.set_conversation(messages)?;
+        if !quiet {
+            println!("  Resumed {} messages.", count);
+        }
+    }
 
-        return Ok(());
+    if let Some(prompt) = once_prompt {
+        return match quiet {
+            true => run_once_quiet(generator, &args, &prompt),
+            false => run_once(generator, &args, &prompt),
+        };
     }
 
     print_divider();
@@ -186,17 +462,199 @@ fn main() -> Result<()> {
     interactive_mode(generator, args)
 }
 
+/// The prompt for `--once`: piped stdin, `--prompt`, or both with stdin
+/// first (e.g. a file followed by a question about it).
+fn read_once_prompt(prompt: Option<String>) -> Result<String> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        return prompt.context("No prompt: pass --prompt or pipe stdin");
+    }
+
+    let mut input = String::new();
+    stdin
+        .lock()
+        .read_to_string(&mut input)
+        .context("Failed to read the prompt from stdin")?;
+    let input = input.trim_end();
+    match (input.is_empty(), prompt) {
+        (true, None) => anyhow::bail!("No prompt: stdin is empty and --prompt was not given"),
+        (true, Some(prompt)) => Ok(prompt),
+        (false, None) => Ok(input.to_string()),
+        (false, Some(prompt)) => Ok(format!("{}\n\n{}", input, prompt)),
+    }
+}
+
+/// Generate one reply with the prompt echoed, the thinking spinner and
+/// the stats line.
+fn run_once(generator: Generator, args: &Args, prompt: &str) -> Result<()> {
+    let mut prompt_display = PromptDisplay::new();
+    prompt_display.show_user_input(prompt);
+
+    let mut gen_output = generator;
+    let mut stream = StreamOutput::new().with_markdown(args.markdown());
+    let mut thinking_spinner: Option<ThinkingSpinner> = None;
+    let context_limit = gen_output.context_limit();
+    let context_used = gen_output.context_used();
+
+    let result = gen_output.generate_streaming(
+        prompt,
+        args.max_tokens,
+        args.repeat_penalty,
+        args.repeat_last_n,
+        |event| match event {
+            StreamEvent::PrefillStatus(count) => {
+                stream.set_prompt_tokens(count);
+                if thinking_spinner.is_none() {
+                    thinking_spinner = Some(ThinkingSpinner::new());
+                }
+            }
+            StreamEvent::Token(t) => {
+                if let Some(spinner) = thinking_spinner.take() {
+                    spinner.stop();
+                }
+                stream.set_context(context_used, context_limit);
+                stream.print_token(&t);
+            }
+            StreamEvent::Done => {
+                stream.finish();
+            }
+        },
+    );
+    if let Some(spinner) = thinking_spinner.take() {
+        spinner.stop();
+    }
+    result
+}
+
+/// Token counts of a `--output json` reply.
+#[derive(Serialize)]
+struct OnceUsage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+/// The `--output json` object, also the last line of `--output jsonl`.
+#[derive(Serialize)]
+struct OnceReply<'a> {
+    model: &'a str,
+    text: &'a str,
+    finish_reason: FinishReason,
+    usage: OnceUsage,
+}
+
+/// A `--output jsonl` line for each chunk of the reply.
+#[derive(Serialize)]
+struct OnceChunk<'a> {
+    delta: &'a str,
+}
+
+/// Generate one reply and print only the reply, as text or JSON. Output
+/// errors (e.g. a closed pipe) fail the command.
+fn run_once_quiet(mut generator: Generator, args: &Args, prompt: &str) -> Result<()> {
+    let mut stdout = io::stdout();
+    let mut renderer =
+        (args.output == OutputFormat::Text && args.markdown()).then(MarkdownRenderer::stdout);
+    let mut write_error: Option<io::Error> = None;
+
+    let text = generator.generate(
+        prompt,
+        args.max_tokens,
+        args.repeat_penalty,
+        args.repeat_last_n,
+        |event| {
+            let StreamEvent::Token(t) = event else {
+                return;
+            };
+            if write_error.is_some() {
+                return;
+            }
+            let written = match (args.output, &mut renderer) {
+                (OutputFormat::Text, Some(renderer)) => renderer.push(&strip_special_markers(&t)),
+                (OutputFormat::Text, None) => {
+                    write!(stdout, "{}", strip_special_markers(&t)).and_then(|()| stdout.flush())
+                }
+                (OutputFormat::Jsonl, _) => {
+                    serde_json::to_writer(&mut stdout, &OnceChunk { delta: &t })
+                        .map_err(io::Error::from)
+                        .and_then(|()| writeln!(stdout))
+                }
+                (OutputFormat::Json, _) => Ok(()),
+            };
+            write_error = written.err();
+        },
+    )?;
+    if let Some(e) = write_error {
+        return Err(e).context("Failed to write the reply");
+    }
+
+    match args.output {
+        OutputFormat::Text => {
+            if let Some(renderer) = &mut renderer {
+                renderer.finish()?;
+            }
+            if !text.ends_with('\n') {
+                writeln!(stdout)?;
+            }
+        }
+        OutputFormat::Json | OutputFormat::Jsonl => {
+            let usage = generator
+                .last_usage()
+                .context("The generator did not record token usage")?;
+            let reply = OnceReply {
+                model: &generator.metadata().name,
+                text: &text,
+                finish_reason: usage.finish_reason,
+                usage: OnceUsage {
+                    prompt_tokens: usage.prompt_tokens,
+                    completion_tokens: usage.completion_tokens,
+                    total_tokens: usage.prompt_tokens + usage.completion_tokens,
+                },
+            };
+            serde_json::to_writer(&mut stdout, &reply)?;
+            writeln!(stdout)?;
+        }
+    }
+    stdout.flush().context("Failed to write the reply")
+}
+
+/// Slash commands offered by Tab completion.
+const REPL_COMMANDS: &[&str] = &[
+    "/branch", "/clear", "/context", "/edit", "/exit", "/export", "/file", "/help", "/import",
+    "/lora", "/paste", "/quit", "/retry", "/set", "/stats", "/system", "/undo",
+];
+
+/// Text attached with `/file` or `/paste`, sent before the next message.
+struct Attachment {
+    label: String,
+    text: String,
+    tokens: usize,
+}
+
+/// Settings read on every REPL turn, changed with `/set`. Sampling
+/// settings live in the generator.
+struct TurnSettings {
+    max_tokens: usize,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+}
+
 fn interactive_mode(generator: Generator, args: Args) -> Result<()> {
     let mut generator = generator;
-    let mut prompt_display = PromptDisplay::new();
+    let mut editor = LineEditor::new(REPL_COMMANDS);
+    let markdown = args.markdown();
+    let mut settings = TurnSettings {
+        max_tokens: args.max_tokens,
+        repeat_penalty: args.repeat_penalty,
+        repeat_last_n: args.repeat_last_n,
+    };
+    let mut attachments: Vec<Attachment> = Vec::new();
 
     loop {
-        prompt_display.show_input_prompt();
-        io::stdout().flush()?;
-
-        let mut prompt = String::new();
-        io::stdin().read_line(&mut prompt)?;
-        let prompt = prompt.trim().to_string();
+        let prompt = match editor.read_line()? {
+            ReadLine::Line(line) => line.trim().to_string(),
+            ReadLine::Interrupted | ReadLine::Eof => break,
+        };
 
         if prompt.is_empty() {
             continue;
@@ -217,6 +675,23 @@ fn interactive_mode(generator: Generator, args: Args) -> Result<()> {
             println!("    /clear   - Clear conversation history");
             println!("    /context - Show context usage");
             println!("    /stats   - Show model info and settings");
+            println!("    /lora    - Show, swap (/lora <path> [scale]) or remove (/lora off) LoRA adapters");
+            println!("    /set     - Show settings, or change one for the next turn (/set temperature 0.8)");
+            println!("    /system  - Show, replace (/system <text>) or remove (/system off) the system prompt");
+            println!("    /retry   - Regenerate the last reply with a new seed");
+            println!("    /undo    - Remove the last message and its reply");
+            println!(
+                "    /edit    - Edit the last message (or replace it: /edit <text>) and regenerate"
+            );
+            println!("    /branch  - List branches, or fork (/branch new <name>), switch (/branch switch <name>) or delete one");
+            println!("    /file    - Attach files to the next message (/file <path>...), list them, or drop them (/file off)");
+            println!(
+                "    /paste   - Attach a multi-line block to the next message; Ctrl+D ends it"
+            );
+            println!(
+                "    /export  - Save the conversation (/export <file> [json|markdown|sharegpt])"
+            );
+            println!("    /import  - Continue a saved conversation (/import <file>)");
             println!("    /exit    - Exit the program");
             println!("    /help    - Show this help\n");
             continue;
@@ -227,11 +702,136 @@ fn interactive_mode(generator: Generator, args: Args) -> Result<()> {
             let limit = generator.context_limit();
             let percentage = generator.context_percentage();
             println!(
-                "  Context: {} / {} tokens ({:.1}%)\n",
+                "  Context: {} / {} tokens ({:.1}%)",
                 format_token_count(used),
                 format_token_count(limit),
                 percentage
             );
+            let attached: usize = attachments.iter().map(|a| a.tokens).sum();
+            if attached > 0 {
+                println!(
+                    "  Attached: {} tokens, sent with the next message",
+                    format_token_count(attached)
+                );
+            }
+            println!();
+            continue;
+        }
+
+        if prompt == "/lora" || prompt.starts_with("/lora ") {
+            run_lora_command(&mut generator, prompt["/lora".len()..].trim());
+            continue;
+        }
+
+        if prompt == "/set" || prompt.starts_with("/set ") {
+            run_set_command(&mut generator, &mut settings, prompt["/set".len()..].trim());
+            continue;
+        }
+
+        if prompt == "/system" || prompt.starts_with("/system ") {
+            run_system_command(&mut generator, prompt["/system".len()..].trim());
+            continue;
+        }
+
+        if prompt == "/retry" {
+            let seed = fresh_seed();
+            generator.set_sampling(SamplingParams {
+                seed,
+                ..*generator.sampling()
+            });
+            if generator.sampling().temperature <= 0.0 {
+                println!("  Temperature is 0, so the reply will not change.");
+            }
+            println!("  Retrying with seed {}.\n", seed);
+            let turn = stream_reply(&mut generator, markdown, |generator, on_event| {
+                generator.retry(
+                    settings.max_tokens,
+                    settings.repeat_penalty,
+                    settings.repeat_last_n,
+                    on_event,
+                )
+            });
+            if let Err(e) = turn {
+                println!("  Error: {}\n", e);
+            }
+            continue;
+        }
+
+        if prompt == "/undo" {
+            match generator.undo() {
+                Ok(Some(removed)) => println!("  Removed: {}\n", preview(&removed)),
+                Ok(None) => println!("  Nothing to undo.\n"),
+                Err(e) => println!("  Error: {}\n", e),
+            }
+            continue;
+        }
+
+        if prompt == "/edit" || prompt.starts_with("/edit ") {
+            let mut text = prompt["/edit".len()..].trim().to_string();
+            if text.is_empty() {
+                // Open the last message in the editor.
+                let Some(last) = generator.messages().iter().rev().find(|m| m.role == "user")
+                else {
+                    println!("  No message to edit.\n");
+                    continue;
+                };
+                text = match editor.read_line_with(&last.content)? {
+                    ReadLine::Line(line) => line.trim().to_string(),
+                    ReadLine::Interrupted | ReadLine::Eof => continue,
+                };
+                if text.is_empty() {
+                    continue;
+                }
+            }
+            let turn = stream_reply(&mut generator, markdown, |generator, on_event| {
+                generator.edit(
+                    &text,
+                    settings.max_tokens,
+                    settings.repeat_penalty,
+                    settings.repeat_last_n,
+                    on_event,
+                )
+            });
+            if let Err(e) = turn {
+                println!("  Error: {}\n", e);
+            }
+            continue;
+        }
+
+        if prompt == "/branch" || prompt.starts_with("/branch ") {
+            run_branch_command(&mut generator, prompt["/branch".len()..].trim());
+            continue;
+        }
+
+        if prompt == "/file" || prompt.starts_with("/file ") {
+            run_file_command(
+                &generator,
+                &settings,
+                &mut attachments,
+                prompt["/file".len()..].trim(),
+            );
+            continue;
+        }
+
+        if prompt == "/paste" {
+            println!("  Paste or type the text, then press Ctrl+D.");
+            match editor.read_block()? {
+                ReadLine::Line(text) if !text.trim().is_empty() => {
+                    let text = format!("<paste>\n{}\n</paste>", text.trim_end());
+                    attach(&generator, &settings, &mut attachments, "pasted text", text);
+                }
+                _ => println!("  Nothing pasted.\n"),
+            }
+            continue;
+        }
+
+        if prompt == "/export" || prompt.starts_with("/export ") {
+            run_export_command(&generator, prompt["/export".len()..].trim());
+            continue;
+        }
+
+        if prompt == "/import" || prompt.starts_with("/import ") {
+            run_import_command(&mut generator, prompt["/import".len()..].trim());
             continue;
         }
 
@@ -242,67 +842,511 @@ fn interactive_mode(generator: Generator, args: Args) -> Result<()> {
                 "  Quant:     {}",
                 meta.quantization.as_deref().unwrap_or("Unknown")
             );
-            println!(
-                "  Context:   {} tokens",
-                format_token_count(meta.context_length)
-            );
+            if meta.context_length == meta.trained_context_length {
+                println!(
+                    "  Context:   {} tokens",
+                    format_token_count(meta.context_length)
+                );
+            } else {
+                println!(
+                    "  Context:   {} tokens (trained {})",
+                    format_token_count(meta.context_length),
+                    format_token_count(meta.trained_context_length)
+                );
+            }
+            println!("  Arch:      {}", meta.architecture);
             println!("  Layers:    {}", meta.n_layer);
             println!("  Embedding: {}", meta.n_embd);
-            println!("  Vocab:     {}", meta.vocab_size);
-            println!("  Temp:      {}", args.temperature);
-            println!("  Max Tok:   {}", args.max_tokens);
-            println!("  Seed:      {}", args.seed);
+            println!(
+                "  Heads:     {} query, {} KV, {} dim",
+                meta.head_count, meta.head_count_kv, meta.head_dim
+            );
+            if let Some(ffn) = meta.feed_forward_length {
+                println!("  FFN:       {}", ffn);
+            }
+            if let Some(experts) = meta.expert_count {
+                println!(
+                    "  Experts:   {} ({} per token)",
+                    experts,
+                    meta.expert_used_count.unwrap_or(0)
+                );
+            }
+            if let Some(base) = meta.rope.freq_base {
+                let scaling = match (&meta.rope.scaling_type, meta.rope.scaling_factor) {
+                    (Some(kind), Some(factor)) => format!(", {} x{}", kind, factor),
+                    _ => String::new(),
+                };
+                println!("  RoPE:      base {}{}", base, scaling);
+            }
+            println!(
+                "  Vocab:     {} ({})",
+                meta.vocab_size,
+                meta.tokenizer_model.as_deref().unwrap_or("unknown")
+            );
+            let token_id = |id: Option<u32>| id.map_or("-".to_string(), |id| id.to_string());
+            println!(
+                "  Tokens:    BOS {}, EOS {}, PAD {}",
+                token_id(meta.bos_token_id),
+                token_id(meta.eos_token_id),
+                token_id(meta.pad_token_id)
+            );
+            for spec in generator.lora() {
+                println!(
+                    "  LoRA:      {} (scale {})",
+                    spec.path.display(),
+                    spec.scale
+                );
+            }
+            let sampling = generator.sampling();
+            println!("  Temp:      {}", sampling.temperature);
+            println!("  Max Tok:   {}", settings.max_tokens);
+            println!("  Seed:      {}", sampling.seed);
             println!();
             continue;
         }
 
-        let mut stream = StreamOutput::new();
-        let mut thinking_spinner: Option<ThinkingSpinner> = None;
-        let context_limit = generator.context_limit();
-        let context_used = generator.context_used();
-        let mut prompt_token_count = 0usize;
-
-        generator.generate_streaming(
-            &prompt,
-            args.max_tokens,
-            args.repeat_penalty,
-            args.repeat_last_n,
-            |event| match event {
-                StreamEvent::PrefillStatus(count) => {
-                    prompt_token_count = count;
-                    stream.set_prompt_tokens(count);
-                    if thinking_spinner.is_none() {
-                        thinking_spinner = Some(ThinkingSpinner::new());
-                    }
+        let message = match attachments.is_empty() {
+            true => prompt,
+            false => {
+                let mut blocks: Vec<&str> = attachments.iter().map(|a| a.text.as_str()).collect();
+                blocks.push(&prompt);
+                blocks.join("\n\n")
+            }
+        };
+        let turn = stream_reply(&mut generator, markdown, |generator, on_event| {
+            generator.generate(
+                &message,
+                settings.max_tokens,
+                settings.repeat_penalty,
+                settings.repeat_last_n,
+                on_event,
+            )
+        });
+        match turn {
+            Ok(()) => attachments.clear(),
+            Err(e) => println!("  Error: {}\n", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Run one turn, streaming the reply to the terminal with the thinking
+/// spinner and stats line.
+fn stream_reply<T>(generator: &mut Generator, markdown: bool, turn: T) -> Result<()>
+where
+    T: FnOnce(&mut Generator, &mut dyn FnMut(StreamEvent)) -> Result<String>,
+{
+    let mut stream = StreamOutput::new().with_markdown(markdown);
+    let mut thinking_spinner: Option<ThinkingSpinner> = None;
+    let context_limit = generator.context_limit();
+
+    let result = turn(generator, &mut |event| match event {
+        StreamEvent::PrefillStatus(count) => {
+            stream.set_prompt_tokens(count);
+            if thinking_spinner.is_none() {
+                thinking_spinner = Some(ThinkingSpinner::new());
+            }
+        }
+        StreamEvent::Token(t) => {
+            if let Some(spinner) = thinking_spinner.take() {
+                spinner.stop();
+            }
+            // The prompt is the whole conversation, so nothing precedes it.
+            stream.set_context(0, context_limit);
+            stream.print_token(&t);
+        }
+        StreamEvent::Done => {
+            stream.finish();
+        }
+    });
+    if let Some(spinner) = thinking_spinner.take() {
+        spinner.stop();
+    }
+    result?;
+
+    print_divider();
+    Ok(())
+}
+
+/// `/branch` lists the conversation branches; `/branch new <name>` forks
+/// the conversation, `/branch switch <name>` continues another branch and
+/// `/branch delete <name>` removes one.
+fn run_branch_command(generator: &mut Generator, args: &str) {
+    let (action, name) = match args.split_once(char::is_whitespace) {
+        Some((action, name)) => (action, name.trim()),
+        None => (args, ""),
+    };
+    let result = match (action, name) {
+        ("", _) => {
+            for (name, messages) in generator.branches() {
+                let marker = if name == generator.branch() { "*" } else { " " };
+                println!("  {} {} ({} messages)", marker, name, messages);
+            }
+            println!();
+            return;
+        }
+        ("new", name) if !name.is_empty() => generator
+            .new_branch(name)
+            .map(|()| format!("Forked the conversation onto '{}'.", name)),
+        ("switch", name) if !name.is_empty() => generator.switch_branch(name).map(|()| {
+            format!(
+                "Switched to '{}' ({} messages).",
+                name,
+                generator.messages().len()
+            )
+        }),
+        ("delete", name) if !name.is_empty() => generator
+            .delete_branch(name)
+            .map(|()| format!("Deleted branch '{}'.", name)),
+        _ => {
+            println!("  Usage: /branch [new|switch|delete <name>]\n");
+            return;
+        }
+    };
+    match result {
+        Ok(message) => println!("  {}\n", message),
+        Err(e) => println!("  {}\n", e),
+    }
+}
+
+/// A seed for `/retry`, different on every call.
+fn fresh_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// The first line of `text`, shortened for a status message.
+fn preview(text: &str) -> String {
+    let line = text.lines().next().unwrap_or("");
+    if line.chars().count() > 60 || text.lines().count() > 1 {
+        format!("{}...", line.chars().take(60).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
+
+/// `/lora` lists the active adapters, `/lora <path> [scale]` swaps to one
+/// adapter and `/lora off` goes back to the base weights.
+fn run_lora_command(generator: &mut Generator, args: &str) {
+    if args.is_empty() {
+        let active = generator.lora();
+        if active.is_empty() {
+            println!("  No LoRA adapters active.");
+        }
+        for spec in active {
+            println!("  {} (scale {})", spec.path.display(), spec.scale);
+        }
+        println!();
+        return;
+    }
+
+    let lora = if args == "off" {
+        Vec::new()
+    } else {
+        let (path, scale) = match args.rsplit_once(' ') {
+            Some((path, scale)) => match scale.parse::<f32>() {
+                Ok(scale) => (path.trim(), scale),
+                Err(_) => (args, 1.0),
+            },
+            None => (args, 1.0),
+        };
+        vec![LoraSpec::new(path, scale)]
+    };
+
+    match generator.set_lora(&lora) {
+        Ok(()) if lora.is_empty() => println!("  LoRA adapters removed.\n"),
+        Ok(()) => println!(
+            "  LoRA adapter {} active (scale {}).\n",
+            lora[0].path.display(),
+            lora[0].scale
+        ),
+        Err(e) => println!("  Failed to apply LoRA adapter: {}\n", e),
+    }
+}
+
+/// `/set` lists the settings; `/set <name> <value>` changes one for the
+/// next turn. `top_p` and `top_k` take `off` to disable them.
+fn run_set_command(generator: &mut Generator, settings: &mut TurnSettings, args: &str) {
+    if args.is_empty() {
+        let sampling = generator.sampling();
+        let or_off = |v: Option<String>| v.unwrap_or_else(|| "off".to_string());
+        println!("  temperature    {}", sampling.temperature);
+        println!(
+            "  top_p          {}",
+            or_off(sampling.top_p.map(|p| p.to_string()))
+        );
+        println!(
+            "  top_k          {}",
+            or_off(sampling.top_k.map(|k| k.to_string()))
+        );
+        println!("  seed           {}", sampling.seed);
+        println!("  max_tokens     {}", settings.max_tokens);
+        println!("  repeat_penalty {}", settings.repeat_penalty);
+        println!("  repeat_last_n  {}", settings.repeat_last_n);
+        println!();
+        return;
+    }
+
+    let Some((name, value)) = args.split_once(char::is_whitespace) else {
+        println!("  Usage: /set <name> <value>\n");
+        return;
+    };
+    let name = name.replace('-', "_");
+    let value = value.trim();
+    let mut sampling = *generator.sampling();
+
+    let result = match name.as_str() {
+        "temperature" | "temp" => {
+            parse_setting(value, |t: f64| t >= 0.0).map(|t| sampling.temperature = t)
+        }
+        "top_p" => parse_optional(value, |p: f64| p > 0.0 && p <= 1.0).map(|p| sampling.top_p = p),
+        "top_k" => parse_optional(value, |k: usize| k > 0).map(|k| sampling.top_k = k),
+        "seed" => parse_setting(value, |_: u64| true).map(|s| sampling.seed = s),
+        "max_tokens" => parse_setting(value, |n: usize| n > 0).map(|n| settings.max_tokens = n),
+        "repeat_penalty" => {
+            parse_setting(value, |p: f32| p > 0.0).map(|p| settings.repeat_penalty = p)
+        }
+        "repeat_last_n" => {
+            parse_setting(value, |_: usize| true).map(|n| settings.repeat_last_n = n)
+        }
+        _ => {
+            println!(
+                "  Unknown setting '{}'. Settings: temperature, top_p, top_k, seed, \
+                 max_tokens, repeat_penalty, repeat_last_n\n",
+                name
+            );
+            return;
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            // Setting the seed restarts the random generator even when the
+            // value is unchanged, so a turn can be reproduced.
+            if sampling != *generator.sampling() || name == "seed" {
+                generator.set_sampling(sampling);
+            }
+            println!("  {} = {}\n", name, value);
+        }
+        Err(()) => println!("  Invalid value '{}' for {}.\n", value, name),
+    }
+}
+
+/// `/export <file> [format]` writes the conversation, in the format the
+/// extension suggests unless one is given. ShareGPT lines are appended so
+/// a dataset can grow over several sessions.
+fn run_export_command(generator: &Generator, args: &str) {
+    let (path, format) = match args.rsplit_once(char::is_whitespace) {
+        Some((path, format)) if format.parse::<ConversationFormat>().is_ok() => {
+            (path.trim(), format.parse().ok())
+        }
+        _ => (args, None),
+    };
+    if path.is_empty() {
+        println!("  Usage: /export <file> [json|markdown|sharegpt]\n");
+        return;
+    }
+    let path = oxide_rs::config::expand_home(path.as_ref());
+    let format = format
+        .or_else(|| ConversationFormat::from_path(&path))
+        .unwrap_or(ConversationFormat::Json);
+
+    let messages = generator.conversation();
+    if generator.messages().is_empty() {
+        println!("  Nothing to export yet.\n");
+        return;
+    }
+    let result = export_conversation(&messages, format).and_then(|text| {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(format == ConversationFormat::ShareGpt)
+            .truncate(format != ConversationFormat::ShareGpt)
+            .open(&path)?;
+        file.write_all(text.as_bytes())?;
+        Ok(())
+    });
+    match result {
+        Ok(()) => println!(
+            "  {} {} messages to {} ({}).\n",
+            match format {
+                ConversationFormat::ShareGpt => "Appended",
+                _ => "Exported",
+            },
+            messages.len(),
+            path.display(),
+            format
+        ),
+        Err(e) => println!("  Error: {:#}\n", e),
+    }
+}
+
+/// `/import <file>` replaces the conversation with one from a file.
+fn run_import_command(generator: &mut Generator, args: &str) {
+    if args.is_empty() {
+        println!("  Usage: /import <file>\n");
+        return;
+    }
+    match read_conversation(&oxide_rs::config::expand_home(args.as_ref())) {
+        Ok(messages) => {
+            let count = messages.len();
+            if let Err(e) = generator.set_conversation(messages) {
+                println!("  Error: {:#}\n", e);
+                return;
+            }
+            println!("  Imported {} messages.", count);
+            if generator
+                .messages()
+                .last()
+                .is_some_and(|m| m.role == "user")
+            {
+                println!("  The last message has no reply yet; /retry generates one.");
+            }
+            println!();
+        }
+        Err(e) => println!("  Error: {:#}\n", e),
+    }
+}
+
+fn read_conversation(path: &Path) -> Result<Vec<Message>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("Cannot read {:?}", path))?;
+    import_conversation(&text).with_context(|| format!("Cannot import {:?}", path))
+}
+
+/// `/file <path>...` attaches files to the next message, `/file` lists the
+/// attachments and `/file off` drops them. Paths with spaces are quoted.
+fn run_file_command(
+    generator: &Generator,
+    settings: &TurnSettings,
+    attachments: &mut Vec<Attachment>,
+    args: &str,
+) {
+    match args {
+        "" if attachments.is_empty() => println!("  Nothing attached.\n"),
+        "" => {
+            for attachment in attachments.iter() {
+                println!(
+                    "  {} ({} tokens)",
+                    attachment.label,
+                    format_token_count(attachment.tokens)
+                );
+            }
+            println!();
+        }
+        "off" => {
+            attachments.clear();
+            println!("  Attachments dropped.\n");
+        }
+        _ => {
+            let paths = match split_words(args) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    println!("  {} in {}\n", e, args);
+                    return;
                 }
-                StreamEvent::Token(t) => {
-                    if let Some(spinner) = thinking_spinner.take() {
-                        spinner.stop();
+            };
+            for path in &paths {
+                match read_text_file(path) {
+                    Ok(text) => {
+                        let text =
+                            format!("<file name=\"{}\">\n{}\n</file>", path, text.trim_end());
+                        attach(generator, settings, attachments, path, text);
                     }
-                    stream.set_context(context_used, context_limit);
-                    stream.print_token(&t);
-                }
-                StreamEvent::Done => {
-                    stream.finish();
+                    Err(e) => println!("  {:#}\n", e),
                 }
-            },
-        )?;
+            }
+        }
+    }
+}
 
-        print_divider();
+/// Read a file to attach, refusing binary files.
+fn read_text_file(path: &str) -> Result<String> {
+    let bytes = std::fs::read(oxide_rs::config::expand_home(path.as_ref()))
+        .with_context(|| format!("Cannot read {}", path))?;
+    match String::from_utf8(bytes) {
+        Ok(text) if !text.contains('\0') => Ok(text),
+        _ => anyhow::bail!("{} is not a UTF-8 text file", path),
     }
+}
 
-    Ok(())
+/// Attach `text` if the prompt still fits in the context with the
+/// conversation and the other attachments and leaves room for a reply,
+/// and report what is left for the reply.
+fn attach(
+    generator: &Generator,
+    settings: &TurnSettings,
+    attachments: &mut Vec<Attachment>,
+    label: &str,
+    text: String,
+) {
+    let mut blocks: Vec<&str> = attachments.iter().map(|a| a.text.as_str()).collect();
+    blocks.push(&text);
+    let counts = generator
+        .tokenizer()
+        .encode(&text)
+        .and_then(|tokens| Ok((tokens.len(), generator.prompt_tokens(&blocks.join("\n\n"))?)));
+    let (tokens, prompt_tokens) = match counts {
+        Ok(counts) => counts,
+        Err(e) => {
+            println!("  Cannot count the tokens of {}: {}\n", label, e);
+            return;
+        }
+    };
+
+    // The reply needs at least one token of the context.
+    let limit = generator.context_limit();
+    if prompt_tokens >= limit {
+        println!(
+            "  {} is {} tokens, which makes the prompt {} tokens and leaves no room for a reply in the context of {}. Not attached.\n",
+            label,
+            format_token_count(tokens),
+            format_token_count(prompt_tokens),
+            format_token_count(limit)
+        );
+        return;
+    }
+
+    println!(
+        "  Attached {} ({} tokens). Context after sending: {} / {} tokens.",
+        label,
+        format_token_count(tokens),
+        format_token_count(prompt_tokens),
+        format_token_count(limit)
+    );
+    let free = limit - prompt_tokens;
+    if free < settings.max_tokens {
+        println!(
+            "  Only {} tokens are left for the reply (max-tokens is {}).",
+            format_token_count(free),
+            settings.max_tokens
+        );
+    }
+    println!();
+    attachments.push(Attachment {
+        label: label.to_string(),
+        text,
+        tokens,
+    });
 }
 
-fn format_size(size: u64) -> String {
-    if size < 1_000 {
-        format!("{}B", size)
-    } else if size < 1_000_000 {
-        format!("{:.1}KB", size as f64 / 1e3)
-    } else if size < 1_000_000_000 {
-        format!("{:.1}MB", size as f64 / 1e6)
-    } else {
-        format!("{:.1}GB", size as f64 / 1e9)
+/// `/system` shows the system prompt, `/system <text>` replaces it and
+/// `/system off` removes it. The conversation is kept.
+fn run_system_command(generator: &mut Generator, args: &str) {
+    match args {
+        "" => match generator.system_prompt() {
+            Some(prompt) => println!("  {}\n", prompt),
+            None => println!("  No system prompt.\n"),
+        },
+        "off" => {
+            generator.set_system_prompt(None);
+            println!("  System prompt removed.\n");
+        }
+        _ => {
+            generator.set_system_prompt(Some(args.to_string()));
+            println!("  System prompt set for the next turn.\n");
+        }
     }
 }
 
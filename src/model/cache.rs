@@ -0,0 +1,578 @@
+//! Tokenizer Cache
+//!
+//! Stores each GGUF tokenizer as a compact, tokenizer-only GGUF (vocab,
+//! merges, scores, token types and the other `tokenizer.ggml.*` keys, no
+//! tensors) named by the SHA-256 of that payload. Models that share a
+//! tokenizer share one entry. A small ref file per model path records the
+//! file's size and modification time, so a cache hit never has to read the
+//! model itself. Every entry carries a format version and its payload hash,
+//! both checked on load.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use candle_core::quantized::gguf_file::{self, Value};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Bump when the entry layout changes; older entries are then rebuilt.
+pub const CACHE_FORMAT_VERSION: u32 = 1;
+
+const TOKENIZER_DIR: &str = "tokenizers";
+const REFS_DIR: &str = "refs";
+const ENTRY_EXTENSION: &str = "gguf";
+const TOKENIZER_KEY_PREFIX: &str = "tokenizer.ggml.";
+const VERSION_KEY: &str = "oxide.cache.version";
+const HASH_KEY: &str = "oxide.cache.content_hash";
+const LEGACY_EXTENSIONS: &[&str] = &["tokenizer_cache", "tokenizer_json"];
+
+/// Root directory for everything oxide caches.
+///
+/// `OXIDE_CACHE_DIR` wins, then `$XDG_CACHE_HOME/oxide`, then
+/// `$HOME/.cache/oxide`.
+pub fn cache_dir() -> PathBuf {
+    let non_empty = |var: &str| std::env::var_os(var).filter(|v| !v.is_empty());
+
+    if let Some(dir) = non_empty("OXIDE_CACHE_DIR") {
+        PathBuf::from(dir)
+    } else if let Some(dir) = non_empty("XDG_CACHE_HOME") {
+        PathBuf::from(dir).join("oxide")
+    } else if let Some(home) = non_empty("HOME") {
+        PathBuf::from(home).join(".cache").join("oxide")
+    } else {
+        std::env::temp_dir().join("oxide")
+    }
+}
+
+/// Which model file a cache entry was built from.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct SourceRef {
+    source: PathBuf,
+    size: u64,
+    modified_ns: u128,
+    hash: String,
+}
+
+impl SourceRef {
+    fn for_file(path: &Path, hash: String) -> Result<Self> {
+        let (size, modified_ns) = file_stamp(path)?;
+        Ok(Self {
+            source: path.to_path_buf(),
+            size,
+            modified_ns,
+            hash,
+        })
+    }
+
+    fn is_current(&self) -> bool {
+        file_stamp(&self.source)
+            .map(|stamp| stamp == (self.size, self.modified_ns))
+            .unwrap_or(false)
+    }
+}
+
+/// A cached tokenizer, as reported by [`TokenizerCache::entries`].
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub hash: String,
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub model_type: Option<String>,
+    pub vocab_size: usize,
+    pub sources: Vec<PathBuf>,
+    /// Seconds since the epoch at which the entry was written.
+    pub created: Option<u64>,
+    pub valid: bool,
+}
+
+/// Files and bytes removed by [`TokenizerCache::prune`] or
+/// [`TokenizerCache::clear`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneStats {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+pub struct TokenizerCache {
+    root: PathBuf,
+}
+
+impl TokenizerCache {
+    /// The cache under [`cache_dir`].
+    pub fn open() -> Self {
+        Self::at(cache_dir())
+    }
+
+    pub fn at<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn tokenizer_dir(&self) -> PathBuf {
+        self.root.join(TOKENIZER_DIR)
+    }
+
+    fn refs_dir(&self) -> PathBuf {
+        self.tokenizer_dir().join(REFS_DIR)
+    }
+
+    fn entry_path(&self, hash: &str) -> PathBuf {
+        self.tokenizer_dir()
+            .join(format!("{}.{}", hash, ENTRY_EXTENSION))
+    }
+
+    fn ref_path(&self, model_path: &Path) -> PathBuf {
+        let key = hex(&Sha256::digest(model_path.to_string_lossy().as_bytes()));
+        self.refs_dir().join(format!("{}.json", key))
+    }
+
+    /// Return a validated cache entry for `model_path`, if one exists.
+    ///
+    /// Stale refs and entries that fail validation are removed.
+    pub fn lookup(&self, model_path: &Path) -> Option<PathBuf> {
+        let model_path = canonical(model_path);
+        let ref_path = self.ref_path(&model_path);
+        let source_ref = read_ref(&ref_path).ok()?;
+
+        if source_ref.source != model_path || !source_ref.is_current() {
+            tracing::debug!("Tokenizer cache ref for {:?} is stale", model_path);
+            fs::remove_file(&ref_path).ok();
+            return None;
+        }
+
+        let entry = self.entry_path(&source_ref.hash);
+        match validate_entry(&entry, &source_ref.hash) {
+            Ok(()) => Some(entry),
+            Err(e) => {
+                tracing::warn!("Discarding tokenizer cache entry {:?}: {}", entry, e);
+                fs::remove_file(&entry).ok();
+                fs::remove_file(&ref_path).ok();
+                None
+            }
+        }
+    }
+
+    /// Extract the tokenizer from a GGUF model into the cache.
+    pub fn store(&self, model_path: &Path) -> Result<PathBuf> {
+        let model_path = canonical(model_path);
+
+        let file = File::open(&model_path)
+            .with_context(|| format!("Failed to open model file: {:?}", model_path))?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let content = gguf_file::Content::read(&mut Cursor::new(&mmap))
+            .with_context(|| format!("Failed to read GGUF file: {:?}", model_path))?;
+
+        let payload = tokenizer_payload(&content.metadata);
+        if payload.is_empty() {
+            anyhow::bail!("{:?} has no tokenizer.ggml metadata", model_path);
+        }
+        let hash = payload_hash(&payload);
+
+        fs::create_dir_all(self.refs_dir())?;
+
+        let entry = self.entry_path(&hash);
+        if validate_entry(&entry, &hash).is_err() {
+            write_entry(&entry, &payload, &hash)?;
+        }
+
+        let source_ref = SourceRef::for_file(&model_path, hash)?;
+        write_atomic(
+            &self.ref_path(&model_path),
+            &serde_json::to_vec_pretty(&source_ref)?,
+        )?;
+
+        Ok(entry)
+    }
+
+    /// List cached tokenizers with the model files that refer to them.
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let refs = self.refs();
+        let mut entries = Vec::new();
+
+        for path in list_files(&self.tokenizer_dir(), ENTRY_EXTENSION) {
+            let hash = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(stem) => stem.to_string(),
+                None => continue,
+            };
+            let size_bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            let created = modified_secs(&path);
+
+            let (model_type, vocab_size, valid) = match read_entry(&path) {
+                Ok(md) => {
+                    let valid = check_entry(&md, &hash).is_ok();
+                    let model_type = md
+                        .get("tokenizer.ggml.model")
+                        .and_then(|v| v.to_string().ok().cloned());
+                    let vocab_size = match md.get("tokenizer.ggml.tokens") {
+                        Some(Value::Array(tokens)) => tokens.len(),
+                        _ => 0,
+                    };
+                    (model_type, vocab_size, valid)
+                }
+                Err(_) => (None, 0, false),
+            };
+
+            let sources = refs
+                .iter()
+                .filter(|(_, r)| r.hash == hash)
+                .map(|(_, r)| r.source.clone())
+                .collect();
+
+            entries.push(CacheEntry {
+                hash,
+                path,
+                size_bytes,
+                model_type,
+                vocab_size,
+                sources,
+                created,
+                valid,
+            });
+        }
+
+        entries.sort_by(|a, b| a.hash.cmp(&b.hash));
+        Ok(entries)
+    }
+
+    /// Remove refs to moved or modified models, entries nothing refers to,
+    /// entries that fail validation, and caches left by older versions.
+    pub fn prune(&self) -> Result<PruneStats> {
+        let mut stats = PruneStats::default();
+
+        let mut live = HashMap::new();
+        for (ref_path, source_ref) in self.refs() {
+            if source_ref.is_current() {
+                live.insert(source_ref.hash.clone(), ());
+            } else {
+                remove_counted(&ref_path, &mut stats);
+            }
+        }
+
+        for entry in self.entries()? {
+            if !entry.valid || !live.contains_key(&entry.hash) {
+                remove_counted(&entry.path, &mut stats);
+            }
+        }
+
+        for path in self.legacy_files() {
+            remove_counted(&path, &mut stats);
+        }
+
+        Ok(stats)
+    }
+
+    /// Remove every cached tokenizer.
+    pub fn clear(&self) -> Result<PruneStats> {
+        let mut stats = PruneStats::default();
+
+        for path in list_files(&self.refs_dir(), "json") {
+            remove_counted(&path, &mut stats);
+        }
+        for path in list_files(&self.tokenizer_dir(), ENTRY_EXTENSION) {
+            remove_counted(&path, &mut stats);
+        }
+        for path in self.legacy_files() {
+            remove_counted(&path, &mut stats);
+        }
+
+        Ok(stats)
+    }
+
+    /// Cache files written by versions that copied whole models.
+    fn legacy_files(&self) -> Vec<PathBuf> {
+        LEGACY_EXTENSIONS
+            .iter()
+            .flat_map(|ext| list_files(&self.root, ext))
+            .collect()
+    }
+
+    fn refs(&self) -> Vec<(PathBuf, SourceRef)> {
+        list_files(&self.refs_dir(), "json")
+            .into_iter()
+            .filter_map(|path| read_ref(&path).ok().map(|r| (path, r)))
+            .collect()
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn file_stamp(path: &Path) -> Result<(u64, u128)> {
+    let md = fs::metadata(path)?;
+    let modified_ns = md
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    Ok((md.len(), modified_ns))
+}
+
+fn read_ref(path: &Path) -> Result<SourceRef> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// The `tokenizer.ggml.*` keys, sorted so the hash is stable.
+fn tokenizer_payload(metadata: &HashMap<String, Value>) -> Vec<(&str, &Value)> {
+    let mut payload: Vec<(&str, &Value)> = metadata
+        .iter()
+        .filter(|(k, _)| k.starts_with(TOKENIZER_KEY_PREFIX))
+        .map(|(k, v)| (k.as_str(), v))
+        .collect();
+    payload.sort_by(|a, b| a.0.cmp(b.0));
+    payload
+}
+
+fn payload_hash(payload: &[(&str, &Value)]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(CACHE_FORMAT_VERSION.to_le_bytes());
+    for (key, value) in payload {
+        hash_bytes(&mut hasher, key.as_bytes());
+        hash_value(&mut hasher, value);
+    }
+    hex(&hasher.finalize())
+}
+
+fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+fn hash_value(hasher: &mut Sha256, value: &Value) {
+    let (tag, bytes): (u8, Vec<u8>) = match value {
+        Value::U8(v) => (0, v.to_le_bytes().to_vec()),
+        Value::I8(v) => (1, v.to_le_bytes().to_vec()),
+        Value::U16(v) => (2, v.to_le_bytes().to_vec()),
+        Value::I16(v) => (3, v.to_le_bytes().to_vec()),
+        Value::U32(v) => (4, v.to_le_bytes().to_vec()),
+        Value::I32(v) => (5, v.to_le_bytes().to_vec()),
+        Value::U64(v) => (6, v.to_le_bytes().to_vec()),
+        Value::I64(v) => (7, v.to_le_bytes().to_vec()),
+        Value::F32(v) => (8, v.to_le_bytes().to_vec()),
+        Value::F64(v) => (9, v.to_le_bytes().to_vec()),
+        Value::Bool(v) => (10, vec![*v as u8]),
+        Value::String(s) => {
+            hasher.update([11u8]);
+            hash_bytes(hasher, s.as_bytes());
+            return;
+        }
+        Value::Array(values) => {
+            hasher.update([12u8]);
+            hasher.update((values.len() as u64).to_le_bytes());
+            for v in values {
+                hash_value(hasher, v);
+            }
+            return;
+        }
+    };
+    hasher.update([tag]);
+    hasher.update(bytes);
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read_entry(path: &Path) -> Result<HashMap<String, Value>> {
+    let mut file = File::open(path)?;
+    Ok(gguf_file::Content::read(&mut file)?.metadata)
+}
+
+fn check_entry(metadata: &HashMap<String, Value>, expected_hash: &str) -> Result<()> {
+    let version = metadata
+        .get(VERSION_KEY)
+        .and_then(|v| v.to_u32().ok())
+        .context("missing format version")?;
+    if version != CACHE_FORMAT_VERSION {
+        anyhow::bail!(
+            "format version {} (expected {})",
+            version,
+            CACHE_FORMAT_VERSION
+        );
+    }
+
+    let stored = metadata
+        .get(HASH_KEY)
+        .and_then(|v| v.to_string().ok())
+        .context("missing content hash")?;
+    let actual = payload_hash(&tokenizer_payload(metadata));
+    if stored != expected_hash || actual != expected_hash {
+        anyhow::bail!("content hash mismatch");
+    }
+
+    Ok(())
+}
+
+fn validate_entry(path: &Path, expected_hash: &str) -> Result<()> {
+    check_entry(&read_entry(path)?, expected_hash)
+}
+
+fn write_entry(path: &Path, payload: &[(&str, &Value)], hash: &str) -> Result<()> {
+    let version = Value::U32(CACHE_FORMAT_VERSION);
+    let hash_value = Value::String(hash.to_string());
+
+    let mut metadata = vec![(VERSION_KEY, &version), (HASH_KEY, &hash_value)];
+    metadata.extend_from_slice(payload);
+
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    {
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        gguf_file::write(&mut writer, &metadata, &[])?;
+    }
+    fs::rename(&tmp, path)?;
+
+    tracing::info!("Tokenizer cached to {:?}", path);
+    Ok(())
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn list_files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    read_dir
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && p.extension().and_then(|e| e.to_str()) == Some(extension))
+        .collect()
+}
+
+fn remove_counted(path: &Path, stats: &mut PruneStats) {
+    let bytes = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    if fs::remove_file(path).is_ok() {
+        stats.files += 1;
+        stats.bytes += bytes;
+    }
+}
+
+fn modified_secs(path: &Path) -> Option<u64> {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("oxide-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_model(path: &Path, tokens: &[&str]) {
+        let model = Value::String("llama".to_string());
+        let arch = Value::String("llama".to_string());
+        let tokens = Value::Array(
+            tokens
+                .iter()
+                .map(|t| Value::String(t.to_string()))
+                .collect(),
+        );
+        let eos = Value::U32(2);
+        let metadata = [
+            ("general.architecture", &arch),
+            ("tokenizer.ggml.model", &model),
+            ("tokenizer.ggml.tokens", &tokens),
+            ("tokenizer.ggml.eos_token_id", &eos),
+        ];
+        let mut file = File::create(path).unwrap();
+        gguf_file::write(&mut file, &metadata, &[]).unwrap();
+    }
+
+    #[test]
+    fn test_store_and_lookup_share_entries() {
+        let dir = temp_dir("store");
+        let cache = TokenizerCache::at(dir.join("cache"));
+        let a = dir.join("a.gguf");
+        let b = dir.join("b.gguf");
+        write_model(&a, &["<unk>", "<s>", "</s>", "hello"]);
+        write_model(&b, &["<unk>", "<s>", "</s>", "hello"]);
+
+        assert!(cache.lookup(&a).is_none());
+        let entry_a = cache.store(&a).unwrap();
+        let entry_b = cache.store(&b).unwrap();
+        assert_eq!(entry_a, entry_b);
+        assert_eq!(cache.lookup(&a), Some(entry_a.clone()));
+
+        let md = read_entry(&entry_a).unwrap();
+        assert!(md.contains_key("tokenizer.ggml.tokens"));
+        assert!(!md.contains_key("general.architecture"));
+
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].vocab_size, 4);
+        assert_eq!(entries[0].sources.len(), 2);
+        assert!(entries[0].valid);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_lookup_rejects_corrupt_and_stale_entries() {
+        let dir = temp_dir("corrupt");
+        let cache = TokenizerCache::at(dir.join("cache"));
+        let model = dir.join("model.gguf");
+        write_model(&model, &["<unk>", "<s>", "</s>"]);
+
+        let entry = cache.store(&model).unwrap();
+        let mut bytes = fs::read(&entry).unwrap();
+        let pos = bytes.windows(5).position(|w| w == b"<unk>").unwrap();
+        bytes[pos + 1] = b'x';
+        fs::write(&entry, bytes).unwrap();
+
+        assert!(cache.lookup(&model).is_none());
+        assert!(!entry.exists());
+
+        cache.store(&model).unwrap();
+        write_model(&model, &["<unk>", "<s>", "</s>", "changed"]);
+        assert!(cache.lookup(&model).is_none());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_prune_and_clear() {
+        let dir = temp_dir("prune");
+        let root = dir.join("cache");
+        let cache = TokenizerCache::at(&root);
+        let kept = dir.join("kept.gguf");
+        let gone = dir.join("gone.gguf");
+        write_model(&kept, &["<unk>", "<s>", "</s>"]);
+        write_model(&gone, &["<unk>", "<s>", "</s>", "other"]);
+        cache.store(&kept).unwrap();
+        cache.store(&gone).unwrap();
+        fs::write(root.join("deadbeef.tokenizer_cache"), b"legacy").unwrap();
+
+        fs::remove_file(&gone).unwrap();
+        let stats = cache.prune().unwrap();
+        assert_eq!(stats.files, 3);
+        assert_eq!(cache.entries().unwrap().len(), 1);
+        assert!(cache.lookup(&kept).is_some());
+
+        let stats = cache.clear().unwrap();
+        assert_eq!(stats.files, 2);
+        assert!(cache.entries().unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
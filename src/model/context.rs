@@ -0,0 +1,369 @@
+//! Context Length and RoPE Overrides
+//!
+//! The model builders read `<arch>.context_length`, `<arch>.rope.freq_base`
+//! and `<arch>.rope.scaling.*` from the GGUF metadata, so overrides are
+//! applied by rewriting those keys before the weights are loaded. NTK-aware
+//! scaling is a larger frequency base. Linear and YaRN scaling change the
+//! positions or per-dimension frequencies in the RoPE tables
+//! ([`rope_tables`](super::layers::rope_tables)), which only the built-in
+//! decoder can do, so the loader picks it for those models.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Result;
+use candle_core::quantized::gguf_file::{Content, Value};
+
+use super::loader::GgufMetadata;
+
+const DEFAULT_ROPE_FREQ_BASE: f32 = 10_000.0;
+
+/// How RoPE positions are stretched to reach beyond the trained context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RopeScaling {
+    #[default]
+    None,
+    Linear,
+    Ntk,
+    Yarn,
+}
+
+impl FromStr for RopeScaling {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "linear" => Ok(Self::Linear),
+            "ntk" => Ok(Self::Ntk),
+            "yarn" => Ok(Self::Yarn),
+            other => Err(format!(
+                "unknown RoPE scaling '{}' (expected none, linear, ntk or yarn)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RopeScaling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::None => "none",
+            Self::Linear => "linear",
+            Self::Ntk => "ntk",
+            Self::Yarn => "yarn",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Overrides for the context window and RoPE settings stored in the GGUF.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContextOverrides {
+    /// Context length to use instead of the trained one.
+    pub ctx_size: Option<usize>,
+    /// RoPE frequency base to use instead of the model's.
+    pub rope_freq_base: Option<f32>,
+    /// llama.cpp-style frequency scale, the inverse of `rope_scale`; on its
+    /// own anything but `1.0` selects linear scaling.
+    pub rope_freq_scale: Option<f32>,
+    /// Scaling type, replacing the one in the GGUF.
+    pub rope_scaling: Option<RopeScaling>,
+    /// Scaling factor; on its own it selects NTK scaling. Defaults to
+    /// `ctx_size / trained context` when a scaling type is given.
+    pub rope_scale: Option<f32>,
+}
+
+impl ContextOverrides {
+    /// Validate the overrides and write them into the GGUF metadata that
+    /// the model is built from, updating `metadata` to match.
+    pub(crate) fn apply(&self, content: &mut Content, metadata: &mut GgufMetadata) -> Result<()> {
+        let arch = metadata.architecture.clone();
+        let trained = metadata.trained_context_length;
+
+        let ctx = self.ctx_size.unwrap_or(metadata.context_length);
+        if ctx == 0 {
+            anyhow::bail!("Context size must be greater than zero");
+        }
+
+        let (scaling, factor) = self.scaling(ctx, trained)?;
+        let original_base = metadata.rope.freq_base.unwrap_or(DEFAULT_ROPE_FREQ_BASE);
+        let mut freq_base = self.rope_freq_base.unwrap_or(original_base);
+        if freq_base <= 0.0 {
+            anyhow::bail!("RoPE frequency base must be positive");
+        }
+
+        if scaling == RopeScaling::Ntk && factor > 1.0 {
+            let dims = metadata
+                .rope
+                .dimension_count
+                .unwrap_or(metadata.head_dim)
+                .max(4) as f32;
+            freq_base *= factor.powf(dims / (dims - 2.0));
+        }
+
+        let supported = trained as f32 * factor.max(1.0);
+        if ctx as f32 > supported {
+            tracing::warn!(
+                "Context size {} exceeds the trained context of {} tokens{}; output may degrade",
+                ctx,
+                trained,
+                if factor > 1.0 {
+                    format!(" scaled by {}", factor)
+                } else {
+                    " (consider --rope-scaling)".to_string()
+                }
+            );
+        }
+
+        if freq_base != original_base {
+            content
+                .metadata
+                .insert(format!("{}.rope.freq_base", arch), Value::F32(freq_base));
+            metadata.rope.freq_base = Some(freq_base);
+        }
+
+        if ctx != metadata.context_length {
+            content
+                .metadata
+                .insert(format!("{}.context_length", arch), Value::U32(ctx as u32));
+            metadata.context_length = ctx;
+        }
+
+        if self.rope_scaling.is_some() || scaling != RopeScaling::None {
+            // NTK scaling is already in the frequency base; the builders
+            // must not scale positions on top of it.
+            let stored = match scaling {
+                RopeScaling::Linear | RopeScaling::Yarn => scaling,
+                RopeScaling::None | RopeScaling::Ntk => RopeScaling::None,
+            };
+            let key = |name: &str| format!("{}.rope.scaling.{}", arch, name);
+            content
+                .metadata
+                .insert(key("type"), Value::String(stored.to_string()));
+            content.metadata.insert(key("factor"), Value::F32(factor));
+            content
+                .metadata
+                .insert(key("original_context_length"), Value::U32(trained as u32));
+
+            metadata.rope.scaling_type = Some(scaling.to_string()).filter(|s| s != "none");
+            metadata.rope.scaling_factor = Some(factor);
+            metadata.rope.original_context_length = Some(trained);
+        }
+
+        Ok(())
+    }
+
+    /// The requested scaling type and factor.
+    fn scaling(&self, ctx: usize, trained: usize) -> Result<(RopeScaling, f32)> {
+        let freq_scale = match self.rope_freq_scale {
+            Some(scale) if scale <= 0.0 => anyhow::bail!("RoPE frequency scale must be positive"),
+            Some(scale) if scale != 1.0 => Some(scale),
+            _ => None,
+        };
+        if let (Some(scale), Some(factor)) = (freq_scale, self.rope_scale) {
+            if (1.0 / scale - factor).abs() > 1e-6 {
+                anyhow::bail!(
+                    "RoPE frequency scale {} and RoPE scale {} disagree; pass one of them",
+                    scale,
+                    factor
+                );
+            }
+        }
+
+        let scaling = match self.rope_scaling {
+            Some(scaling) => scaling,
+            None if self.rope_scale.is_some() => RopeScaling::Ntk,
+            None if freq_scale.is_some() => RopeScaling::Linear,
+            None => RopeScaling::None,
+        };
+        if scaling == RopeScaling::None {
+            return Ok((scaling, 1.0));
+        }
+
+        let factor = match (self.rope_scale, freq_scale) {
+            (Some(factor), _) if factor <= 0.0 => anyhow::bail!("RoPE scale must be positive"),
+            (Some(factor), _) => factor,
+            (None, Some(scale)) => 1.0 / scale,
+            (None, None) => (ctx as f32 / trained.max(1) as f32).max(1.0),
+        };
+        Ok((scaling, factor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+    use candle_core::quantized::gguf_file;
+    use std::io::Cursor;
+
+    fn qwen2_content(context_length: u32) -> (Content, GgufMetadata) {
+        let metadata = [
+            ("general.architecture", Value::String("qwen2".into())),
+            ("qwen2.block_count", Value::U32(2)),
+            ("qwen2.embedding_length", Value::U32(128)),
+            ("qwen2.vocab_size", Value::U32(100)),
+            ("qwen2.context_length", Value::U32(context_length)),
+            ("qwen2.attention.head_count", Value::U32(2)),
+            ("qwen2.rope.freq_base", Value::F32(10_000.0)),
+        ];
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let mut buf = Vec::new();
+        gguf_file::write(&mut Cursor::new(&mut buf), &metadata, &[]).unwrap();
+        let content = Content::read(&mut Cursor::new(&buf)).unwrap();
+        let md = Model::extract_metadata(&content, "model.gguf", 0).unwrap();
+        (content, md)
+    }
+
+    #[test]
+    fn test_no_overrides_changes_nothing() {
+        let (mut content, mut md) = qwen2_content(4096);
+        ContextOverrides::default()
+            .apply(&mut content, &mut md)
+            .unwrap();
+        assert_eq!(md.context_length, 4096);
+        assert_eq!(md.rope.freq_base, Some(10_000.0));
+        assert_eq!(md.rope.scaling_type, None);
+    }
+
+    #[test]
+    fn test_ctx_size_with_ntk_scaling() {
+        let (mut content, mut md) = qwen2_content(4096);
+        let overrides = ContextOverrides {
+            ctx_size: Some(16384),
+            rope_scaling: Some(RopeScaling::Ntk),
+            ..Default::default()
+        };
+        overrides.apply(&mut content, &mut md).unwrap();
+
+        assert_eq!(md.context_length, 16384);
+        assert_eq!(md.trained_context_length, 4096);
+        assert_eq!(md.rope.scaling_factor, Some(4.0));
+        // head_dim 64: base * 4^(64/62)
+        let expected = 10_000.0 * 4f32.powf(64.0 / 62.0);
+        assert!((md.rope.freq_base.unwrap() - expected).abs() < 1.0);
+        assert_eq!(
+            content.metadata["qwen2.context_length"].to_u32().unwrap(),
+            16384
+        );
+        assert_eq!(
+            content.metadata["qwen2.rope.freq_base"].to_f32().unwrap(),
+            md.rope.freq_base.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_scaling_type_and_factor() {
+        let scaling = |overrides: ContextOverrides| overrides.scaling(8192, 4096);
+        for mode in [RopeScaling::Linear, RopeScaling::Yarn] {
+            assert_eq!(
+                scaling(ContextOverrides {
+                    rope_scaling: Some(mode),
+                    ..Default::default()
+                })
+                .unwrap(),
+                (mode, 2.0)
+            );
+        }
+        assert_eq!(
+            scaling(ContextOverrides {
+                rope_freq_scale: Some(0.25),
+                ..Default::default()
+            })
+            .unwrap(),
+            (RopeScaling::Linear, 4.0)
+        );
+        assert!(scaling(ContextOverrides {
+            rope_freq_scale: Some(0.0),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(scaling(ContextOverrides {
+            rope_freq_scale: Some(0.5),
+            rope_scale: Some(4.0),
+            ..Default::default()
+        })
+        .is_err());
+
+        assert_eq!(
+            scaling(ContextOverrides {
+                rope_freq_scale: Some(1.0),
+                ..Default::default()
+            })
+            .unwrap(),
+            (RopeScaling::None, 1.0)
+        );
+        assert_eq!(
+            scaling(ContextOverrides {
+                rope_scale: Some(2.0),
+                ..Default::default()
+            })
+            .unwrap(),
+            (RopeScaling::Ntk, 2.0)
+        );
+    }
+
+    #[test]
+    fn test_yarn_scaling_is_written_for_the_builders() {
+        let (mut content, mut md) = qwen2_content(4096);
+        let overrides = ContextOverrides {
+            ctx_size: Some(16384),
+            rope_scaling: Some(RopeScaling::Yarn),
+            ..Default::default()
+        };
+        overrides.apply(&mut content, &mut md).unwrap();
+
+        // Positions are scaled in the tables, not through the base.
+        assert_eq!(md.rope.freq_base, Some(10_000.0));
+        let value = |key: &str| content.metadata[key].clone();
+        assert_eq!(
+            value("qwen2.rope.scaling.type").to_string().unwrap(),
+            "yarn"
+        );
+        assert_eq!(value("qwen2.rope.scaling.factor").to_f32().unwrap(), 4.0);
+        assert_eq!(
+            value("qwen2.rope.scaling.original_context_length")
+                .to_u32()
+                .unwrap(),
+            4096
+        );
+
+        let reread = Model::extract_metadata(&content, "model.gguf", 0).unwrap();
+        assert_eq!(reread.rope.scaling_type.as_deref(), Some("yarn"));
+        assert_eq!(reread.rope.scaling_factor, Some(4.0));
+        assert_eq!(reread.rope.original_context_length, Some(4096));
+    }
+
+    #[test]
+    fn test_freq_base_override_is_written() {
+        let (mut content, mut md) = qwen2_content(4096);
+        let overrides = ContextOverrides {
+            ctx_size: Some(65536),
+            rope_freq_base: Some(500_000.0),
+            ..Default::default()
+        };
+        overrides.apply(&mut content, &mut md).unwrap();
+
+        assert_eq!(md.context_length, 65536);
+        assert_eq!(md.rope.freq_base, Some(500_000.0));
+        assert_eq!(
+            content.metadata["qwen2.rope.freq_base"].to_f32().unwrap(),
+            500_000.0
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_context() {
+        let (mut content, mut md) = qwen2_content(4096);
+        let zero = ContextOverrides {
+            ctx_size: Some(0),
+            ..Default::default()
+        };
+        assert!(zero.apply(&mut content, &mut md).is_err());
+
+        assert_eq!("YaRN".parse::<RopeScaling>().unwrap(), RopeScaling::Yarn);
+        assert!("dynamic".parse::<RopeScaling>().is_err());
+    }
+}
@@ -0,0 +1,1350 @@
+//! Decoder Forward Pass
+//!
+//! The forward pass of every architecture in
+//! [`SUPPORTED_ARCHITECTURES`](super::loader::SUPPORTED_ARCHITECTURES).
+//! All of them are stacks of pre-norm blocks with rotary attention and a
+//! gated feed-forward; the differences are read from the tensors present
+//! in the GGUF:
+//!
+//! - `attn_qkv` (Phi-3) fuses the query, key and value projections, and an
+//!   `ffn_up` without `ffn_gate` stacks the gate and up projections.
+//! - `attn_q_norm` and `attn_k_norm` (Qwen3, Gemma 3, LFM2) normalise each
+//!   head of the queries and keys.
+//! - `post_attention_norm` and `post_ffw_norm` (Gemma 2 and 3) normalise
+//!   the outputs of the attention and feed-forward before the residual.
+//! - `ffn_gate_inp` (Mixtral) routes each token to its best experts.
+//! - `shortconv.*` (LFM2) replaces attention with a gated causal
+//!   convolution in some layers.
+//!
+//! Gemma 2's attention and logit soft-capping come from its
+//! `attn_logit_softcapping` and `final_logit_softcapping` metadata.
+//!
+//! candle's quantized models cover generation and embeddings, but return
+//! only the logits of the last position and keep their layers private.
+//! This pass also returns the logits of every position and shows every
+//! matmul input to an observer, which importance matrices need, and runs
+//! models whose metadata candle's implementations cannot honour. It reads
+//! the weights held by [`Model`](super::loader::Model), so it sees the same
+//! merged LoRA adapters and context overrides.
+
+use anyhow::{Context, Result};
+use candle_core::{Device, Module, Tensor, D};
+use candle_nn::Activation;
+
+use super::layers::{rope_tables, Linear, Norm, Observer, RopeScale, Weights};
+use super::loader::GgufMetadata;
+
+/// Gemma 3 makes every sixth layer global unless the GGUF says otherwise.
+const DEFAULT_SLIDING_WINDOW_PATTERN: usize = 6;
+/// Gemma 2 alternates local and global layers.
+const GEMMA2_SLIDING_WINDOW_PATTERN: usize = 2;
+/// Layers of Gemma 2 27B, which scales its queries by `dim / heads`
+/// instead of the head size.
+const GEMMA2_27B_LAYERS: usize = 46;
+
+pub(crate) struct Decoder {
+    token_embd: Tensor,
+    /// Gemma multiplies the token embeddings by `sqrt(dim)`.
+    embd_scale: Option<f64>,
+    blocks: Vec<Block>,
+    output_norm: Norm,
+    output: Linear,
+    /// Gemma 2 squashes the logits into `(-cap, cap)` with `tanh`.
+    logit_softcap: Option<f64>,
+}
+
+struct Block {
+    attn_norm: Norm,
+    mixer: Mixer,
+    post_attn_norm: Option<Norm>,
+    ffn_norm: Norm,
+    ffn: Ffn,
+    post_ffn_norm: Option<Norm>,
+}
+
+enum Mixer {
+    Attention(Box<Attention>),
+    Conv(ShortConv),
+}
+
+enum Qkv {
+    Separate(Linear, Linear, Linear),
+    Fused(Linear),
+}
+
+struct Attention {
+    qkv: Qkv,
+    q_norm: Option<Norm>,
+    k_norm: Option<Norm>,
+    output: Linear,
+    head_count: usize,
+    head_count_kv: usize,
+    head_dim: usize,
+    /// Multiplies the attention scores; `1 / sqrt(head_dim)` except for
+    /// Gemma 2 27B.
+    scale: f64,
+    /// Gemma 2 squashes the attention scores into `(-cap, cap)`.
+    softcap: Option<f64>,
+    rope_base: f32,
+    /// Position scaling of the RoPE tables; Gemma 3's local layers have
+    /// none.
+    rope_scale: Option<RopeScale>,
+    /// GPT-J style RoPE as used by LLaMA GGUFs, over the NeoX style of
+    /// the others.
+    interleaved: bool,
+    /// How many earlier positions each token attends to besides itself
+    /// (the local layers of Gemma 2 and 3); older ones are hidden, as in
+    /// candle.
+    window: Option<usize>,
+}
+
+/// LFM2's convolution: `out(c * conv(b * x))` with `b`, `c` and `x` from
+/// one input projection and a depthwise causal kernel.
+struct ShortConv {
+    in_proj: Linear,
+    out_proj: Linear,
+    /// `(dim, kernel size)`.
+    kernel: Tensor,
+}
+
+enum Ffn {
+    Gated {
+        gate: Linear,
+        up: Linear,
+        down: Linear,
+        activation: Activation,
+    },
+    /// Gate and up projections stacked in one matrix.
+    Fused {
+        gate_up: Linear,
+        down: Linear,
+        hidden: usize,
+    },
+    /// Each token goes through its `used` most likely experts, weighted by
+    /// their router probabilities renormalised to sum to one.
+    Experts {
+        router: Linear,
+        experts: Vec<Ffn>,
+        used: usize,
+    },
+}
+
+enum LayerCache {
+    Kv(Tensor, Tensor),
+    /// The last `kernel size - 1` convolution inputs.
+    Conv(Tensor),
+}
+
+/// What the layers carry from one call to the next.
+#[derive(Default)]
+pub(crate) struct Cache {
+    layers: Vec<Option<LayerCache>>,
+    /// Tokens processed so far.
+    len: usize,
+}
+
+impl Cache {
+    pub(crate) fn clear(&mut self) {
+        self.layers.clear();
+        self.len = 0;
+    }
+}
+
+impl Decoder {
+    /// Build the model described by `metadata` from `weights`.
+    pub(crate) fn load(weights: &mut Weights, metadata: &GgufMetadata) -> Result<Self> {
+        let arch = metadata.architecture.as_str();
+        let key = |suffix: &str| format!("{}.{}", arch, suffix);
+        let eps = metadata
+            .get_f32(&key("attention.layer_norm_rms_epsilon"))
+            .unwrap_or(1e-6);
+
+        let head_count = metadata.head_count;
+        let head_dim = metadata.head_dim;
+        if let Some(dims) = metadata.rope.dimension_count.filter(|&d| d != head_dim) {
+            anyhow::bail!(
+                "Partial RoPE ({} of {} head dimensions) is not supported",
+                dims,
+                head_dim
+            );
+        }
+        let rope_base = metadata.rope.freq_base.unwrap_or(match arch {
+            "gemma3" | "lfm2" => 1_000_000.0,
+            _ => 10_000.0,
+        });
+        let rope_scale = RopeScale::from_params(&metadata.rope, metadata.trained_context_length)?;
+
+        // Gemma interleaves local sliding-window layers between global
+        // ones; Gemma 3's have their own RoPE base and no scaling.
+        let local = match (arch, metadata.get_u32(&key("attention.sliding_window"))) {
+            ("gemma3", Some(window)) => {
+                let pattern = metadata
+                    .get_u32(&key("attention.sliding_window_type"))
+                    .map_or(DEFAULT_SLIDING_WINDOW_PATTERN, |p| p as usize)
+                    .max(1);
+                let base = metadata
+                    .get_f32(&key("rope.local_freq_base"))
+                    .unwrap_or(10_000.0);
+                Some((window as usize, pattern, base, None))
+            }
+            ("gemma2", Some(window)) => Some((
+                window as usize,
+                GEMMA2_SLIDING_WINDOW_PATTERN,
+                rope_base,
+                rope_scale,
+            )),
+            _ => None,
+        };
+
+        let query_scalar = if arch == "gemma2" && metadata.n_layer == GEMMA2_27B_LAYERS {
+            metadata.n_embd / head_count
+        } else {
+            head_dim
+        };
+        let softcap = metadata
+            .get_f32(&key("attn_logit_softcapping"))
+            .map(f64::from);
+
+        let activation = if matches!(arch, "gemma2" | "gemma3") {
+            Activation::GeluPytorchTanh
+        } else {
+            Activation::Silu
+        };
+        let experts = metadata.expert_count.filter(|&n| n > 1);
+        let gated = |weights: &mut Weights, suffix: &str, i: usize| -> Result<Ffn> {
+            let p = |name: &str| format!("blk.{}.{}{}", i, name, suffix);
+            Ok(Ffn::Gated {
+                gate: weights.linear(&p("ffn_gate"))?,
+                up: weights.linear(&p("ffn_up"))?,
+                down: weights.linear(&p("ffn_down"))?,
+                activation,
+            })
+        };
+
+        let token_embd = weights.dense("token_embd.weight")?;
+
+        let mut blocks = Vec::with_capacity(metadata.n_layer);
+        for i in 0..metadata.n_layer {
+            let p = |name: &str| format!("blk.{}.{}", i, name);
+            let optional_norm = |weights: &mut Weights, name: &str| -> Result<Option<Norm>> {
+                if weights.has(&p(&format!("{}.weight", name))) {
+                    Ok(Some(weights.norm(&p(name), false, eps)?))
+                } else {
+                    Ok(None)
+                }
+            };
+
+            let mixer = if weights.has(&p("shortconv.in_proj.weight")) {
+                let mut kernel = weights.dense(&p("shortconv.conv.weight"))?;
+                if kernel.rank() == 3 {
+                    kernel = kernel.squeeze(1)?;
+                }
+                if kernel.dim(0)? != metadata.n_embd {
+                    kernel = kernel.t()?.contiguous()?;
+                }
+                Mixer::Conv(ShortConv {
+                    in_proj: weights.linear(&p("shortconv.in_proj"))?,
+                    out_proj: weights.linear(&p("shortconv.out_proj"))?,
+                    kernel,
+                })
+            } else {
+                let (qkv, kv_rows) = if weights.has(&p("attn_qkv.weight")) {
+                    let rows = weights.dims(&p("attn_qkv.weight"))?[0];
+                    let kv_rows = rows.saturating_sub(head_count * head_dim) / 2;
+                    (Qkv::Fused(weights.linear(&p("attn_qkv"))?), kv_rows)
+                } else {
+                    let kv_rows = weights.dims(&p("attn_k.weight"))?[0];
+                    let qkv = Qkv::Separate(
+                        weights.linear(&p("attn_q"))?,
+                        weights.linear(&p("attn_k"))?,
+                        weights.linear(&p("attn_v"))?,
+                    );
+                    (qkv, kv_rows)
+                };
+                let head_count_kv = kv_rows / head_dim;
+                if head_count_kv == 0 || head_count % head_count_kv != 0 {
+                    anyhow::bail!(
+                        "Layer {} has {} key/value rows, which do not divide {} heads of {}",
+                        i,
+                        kv_rows,
+                        head_count,
+                        head_dim
+                    );
+                }
+
+                let (window, rope_base, rope_scale) = match local {
+                    Some((window, pattern, base, scale)) if (i + 1) % pattern > 0 => {
+                        (Some(window), base, scale)
+                    }
+                    _ => (None, rope_base, rope_scale),
+                };
+                Mixer::Attention(Box::new(Attention {
+                    qkv,
+                    q_norm: optional_norm(weights, "attn_q_norm")?,
+                    k_norm: optional_norm(weights, "attn_k_norm")?,
+                    output: weights.linear(&p("attn_output"))?,
+                    head_count,
+                    head_count_kv,
+                    head_dim,
+                    scale: 1.0 / (query_scalar as f64).sqrt(),
+                    softcap,
+                    rope_base,
+                    rope_scale,
+                    interleaved: arch == "llama",
+                    window,
+                }))
+            };
+
+            let ffn = if let Some(count) = experts {
+                let used = metadata
+                    .expert_used_count
+                    .context("Missing metadata key: expert_used_count")?;
+                Ffn::Experts {
+                    router: weights.linear(&p("ffn_gate_inp"))?,
+                    experts: (0..count)
+                        .map(|e| gated(weights, &format!(".{}", e), i))
+                        .collect::<Result<_>>()?,
+                    used,
+                }
+            } else if weights.has(&p("ffn_gate.weight")) {
+                gated(weights, "", i)?
+            } else {
+                Ffn::Fused {
+                    hidden: weights.dims(&p("ffn_up.weight"))?[0] / 2,
+                    gate_up: weights.linear(&p("ffn_up"))?,
+                    down: weights.linear(&p("ffn_down"))?,
+                }
+            };
+
+            blocks.push(Block {
+                attn_norm: weights.norm(&p("attn_norm"), false, eps)?,
+                mixer,
+                post_attn_norm: optional_norm(weights, "post_attention_norm")?,
+                ffn_norm: weights.norm(&p("ffn_norm"), false, eps)?,
+                ffn,
+                post_ffn_norm: optional_norm(weights, "post_ffw_norm")?,
+            });
+        }
+
+        // LFM2 stores its final norm as `token_embd_norm`, and models with
+        // tied embeddings have no `output`.
+        let output_norm = if weights.has("output_norm.weight") {
+            weights.norm("output_norm", false, eps)?
+        } else {
+            weights.norm("token_embd_norm", false, eps)?
+        };
+        let output = if weights.has("output.weight") {
+            weights.linear("output")?
+        } else {
+            weights.linear("token_embd")?
+        };
+
+        Ok(Self {
+            token_embd,
+            embd_scale: matches!(arch, "gemma2" | "gemma3")
+                .then(|| (metadata.n_embd as f64).sqrt()),
+            blocks,
+            output_norm,
+            output,
+            logit_softcap: metadata
+                .get_f32(&key("final_logit_softcapping"))
+                .map(f64::from),
+        })
+    }
+
+    /// Logits of the last of `tokens`, which follow the tokens already in
+    /// `cache` at positions `pos..`. Position 0 starts a new sequence.
+    pub(crate) fn forward(&self, tokens: &[u32], pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let hidden = self.last_hidden(tokens, pos, cache)?;
+        self.logits(&hidden, &mut |_, _| Ok(()))
+    }
+
+    /// Normalised final hidden state of the last of `tokens`, `(1, dim)`,
+    /// placed like [`Decoder::forward`] places them.
+    pub(crate) fn last_hidden(
+        &self,
+        tokens: &[u32],
+        pos: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        if tokens.is_empty() {
+            anyhow::bail!("No tokens to process");
+        }
+        if pos == 0 {
+            cache.clear();
+        } else if pos != cache.len {
+            anyhow::bail!(
+                "Position {} does not follow the {} cached tokens",
+                pos,
+                cache.len
+            );
+        }
+
+        let ids = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let hidden = self.run(&ids, cache, &mut |_, _| Ok(()))?;
+        Ok(hidden.get(0)?.narrow(0, tokens.len() - 1, 1)?)
+    }
+
+    /// Logits of every position of `tokens` as a new sequence, passing the
+    /// input of every matmul, the output projection included, to
+    /// `observe`. Uses its own cache.
+    pub(crate) fn forward_observed(&self, tokens: &[u32], observe: Observer) -> Result<Tensor> {
+        let ids = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let hidden = self.run(&ids, &mut Cache::default(), observe)?;
+        self.logits(&hidden, observe)
+    }
+
+    fn logits(&self, hidden: &Tensor, observe: Observer) -> Result<Tensor> {
+        let logits = self.output.forward(hidden, observe)?;
+        match self.logit_softcap {
+            Some(cap) => softcap(&logits, cap),
+            None => Ok(logits),
+        }
+    }
+
+    /// Normalised final hidden states of `ids`, `(batch, len)`, continuing
+    /// from `cache`.
+    fn run(&self, ids: &Tensor, cache: &mut Cache, observe: Observer) -> Result<Tensor> {
+        let (b, t) = ids.dims2()?;
+        let mut xs = self
+            .token_embd
+            .index_select(&ids.flatten_all()?, 0)?
+            .reshape((b, t, ()))?;
+        if let Some(scale) = self.embd_scale {
+            xs = (xs * scale)?;
+        }
+
+        let mut positions = Positions::new(cache.len, t);
+        cache.layers.resize_with(self.blocks.len(), || None);
+        for (block, state) in self.blocks.iter().zip(cache.layers.iter_mut()) {
+            let normed = block.attn_norm.forward(&xs)?;
+            let mut ys = match &block.mixer {
+                Mixer::Attention(attention) => {
+                    attention.forward(&normed, &mut positions, state, observe)?
+                }
+                Mixer::Conv(conv) => conv.forward(&normed, state, observe)?,
+            };
+            if let Some(norm) = &block.post_attn_norm {
+                ys = norm.forward(&ys)?;
+            }
+            xs = (xs + ys)?;
+
+            let normed = block.ffn_norm.forward(&xs)?;
+            let mut ys = block.ffn.forward(&normed, observe)?;
+            if let Some(norm) = &block.post_ffn_norm {
+                ys = norm.forward(&ys)?;
+            }
+            xs = (xs + ys)?;
+        }
+        cache.len += t;
+
+        self.output_norm.forward(&xs)
+    }
+}
+
+/// `cap * tanh(xs / cap)`.
+fn softcap(xs: &Tensor, cap: f64) -> Result<Tensor> {
+    Ok(((xs / cap)?.tanh()? * cap)?)
+}
+
+/// RoPE tables for a frequency base, head size and scaling.
+type RopeEntry = ((f32, usize, Option<RopeScale>), (Tensor, Tensor));
+
+/// Masks and RoPE tables for the positions of one call, built once and
+/// shared by the layers with the same settings.
+struct Positions {
+    start: usize,
+    len: usize,
+    masks: Vec<(Option<usize>, Option<Tensor>)>,
+    ropes: Vec<RopeEntry>,
+}
+
+impl Positions {
+    fn new(start: usize, len: usize) -> Self {
+        Self {
+            start,
+            len,
+            masks: Vec::new(),
+            ropes: Vec::new(),
+        }
+    }
+
+    /// Additive mask of shape `(len, start + len)` hiding later positions
+    /// and those outside `window`; `None` when nothing is hidden.
+    fn mask(&mut self, window: Option<usize>) -> Result<Option<Tensor>> {
+        if let Some((_, mask)) = self.masks.iter().find(|(w, _)| *w == window) {
+            return Ok(mask.clone());
+        }
+
+        let total = self.start + self.len;
+        let mask = if self.len == 1 && !window.is_some_and(|w| total > w + 1) {
+            None
+        } else {
+            let mut mask = vec![0f32; self.len * total];
+            for i in 0..self.len {
+                let at = self.start + i;
+                for j in 0..total {
+                    if j > at || window.is_some_and(|w| at - j > w) {
+                        mask[i * total + j] = f32::NEG_INFINITY;
+                    }
+                }
+            }
+            Some(Tensor::from_vec(mask, (self.len, total), &Device::Cpu)?)
+        };
+        self.masks.push((window, mask.clone()));
+        Ok(mask)
+    }
+
+    fn rope(
+        &mut self,
+        base: f32,
+        head_dim: usize,
+        scale: Option<RopeScale>,
+    ) -> Result<(Tensor, Tensor)> {
+        let key = (base, head_dim, scale);
+        if let Some((_, tables)) = self.ropes.iter().find(|(k, _)| *k == key) {
+            return Ok(tables.clone());
+        }
+        let tables = rope_tables(base, head_dim, scale, self.start, self.len, &Device::Cpu)?;
+        self.ropes.push((key, tables.clone()));
+        Ok(tables)
+    }
+}
+
+impl Attention {
+    fn forward(
+        &self,
+        xs: &Tensor,
+        positions: &mut Positions,
+        cache: &mut Option<LayerCache>,
+        observe: Observer,
+    ) -> Result<Tensor> {
+        let (b, t, _) = xs.dims3()?;
+        let head_dim = self.head_dim;
+        let (q, k, v) = match &self.qkv {
+            Qkv::Separate(q, k, v) => (
+                q.forward(xs, observe)?,
+                k.forward(xs, observe)?,
+                v.forward(xs, observe)?,
+            ),
+            Qkv::Fused(qkv) => {
+                let ys = qkv.forward(xs, observe)?;
+                let q_dim = self.head_count * head_dim;
+                let kv_dim = self.head_count_kv * head_dim;
+                (
+                    ys.narrow(D::Minus1, 0, q_dim)?,
+                    ys.narrow(D::Minus1, q_dim, kv_dim)?,
+                    ys.narrow(D::Minus1, q_dim + kv_dim, kv_dim)?,
+                )
+            }
+        };
+
+        let heads = |x: Tensor, n: usize| -> Result<Tensor> {
+            Ok(x.reshape((b, t, n, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?)
+        };
+        let mut q = heads(q, self.head_count)?;
+        let mut k = heads(k, self.head_count_kv)?;
+        let v = heads(v, self.head_count_kv)?;
+
+        if let Some(norm) = &self.q_norm {
+            q = norm.forward(&q)?;
+        }
+        if let Some(norm) = &self.k_norm {
+            k = norm.forward(&k)?;
+        }
+
+        let (cos, sin) = positions.rope(self.rope_base, head_dim, self.rope_scale)?;
+        let rope = if self.interleaved {
+            candle_nn::rotary_emb::rope_i
+        } else {
+            candle_nn::rotary_emb::rope
+        };
+        let q = rope(&q, &cos, &sin)?;
+        let k = rope(&k, &cos, &sin)?;
+
+        let (k, v) = match cache.take() {
+            Some(LayerCache::Kv(past_k, past_v)) => (
+                Tensor::cat(&[&past_k, &k], 2)?,
+                Tensor::cat(&[&past_v, &v], 2)?,
+            ),
+            _ => (k, v),
+        };
+        *cache = Some(LayerCache::Kv(k.clone(), v.clone()));
+
+        let n_rep = self.head_count / self.head_count_kv;
+        let k = candle_transformers::utils::repeat_kv(k, n_rep)?.contiguous()?;
+        let v = candle_transformers::utils::repeat_kv(v, n_rep)?.contiguous()?;
+
+        let mut scores = (q.matmul(&k.t()?)? * self.scale)?;
+        if let Some(cap) = self.softcap {
+            scores = softcap(&scores, cap)?;
+        }
+        if let Some(mask) = positions.mask(self.window)? {
+            scores = scores.broadcast_add(&mask)?;
+        }
+        let weights = candle_nn::ops::softmax_last_dim(&scores)?;
+        let ys =
+            weights
+                .matmul(&v)?
+                .transpose(1, 2)?
+                .reshape((b, t, self.head_count * head_dim))?;
+        self.output.forward(&ys, observe)
+    }
+}
+
+impl ShortConv {
+    fn forward(
+        &self,
+        xs: &Tensor,
+        cache: &mut Option<LayerCache>,
+        observe: Observer,
+    ) -> Result<Tensor> {
+        let (b, t, dim) = xs.dims3()?;
+        let bcx = self.in_proj.forward(xs, observe)?.transpose(1, 2)?;
+        let gate_in = bcx.narrow(1, 0, dim)?;
+        let gate_out = bcx.narrow(1, dim, dim)?;
+        let bx = (gate_in * bcx.narrow(1, 2 * dim, dim)?)?;
+
+        let size = self.kernel.dim(1)?;
+        let history = match cache.take() {
+            Some(LayerCache::Conv(history)) => history,
+            _ => Tensor::zeros((b, dim, size - 1), bx.dtype(), bx.device())?,
+        };
+        let padded = Tensor::cat(&[&history, &bx], 2)?;
+        let mut conv = padded
+            .narrow(2, 0, t)?
+            .broadcast_mul(&self.kernel.narrow(1, 0, 1)?)?;
+        for i in 1..size {
+            let tap = padded
+                .narrow(2, i, t)?
+                .broadcast_mul(&self.kernel.narrow(1, i, 1)?)?;
+            conv = (conv + tap)?;
+        }
+        *cache = Some(LayerCache::Conv(
+            padded.narrow(2, t, size - 1)?.contiguous()?,
+        ));
+
+        let ys = (gate_out * conv)?.transpose(1, 2)?.contiguous()?;
+        self.out_proj.forward(&ys, observe)
+    }
+}
+
+impl Ffn {
+    fn forward(&self, xs: &Tensor, observe: Observer) -> Result<Tensor> {
+        match self {
+            Self::Gated {
+                gate,
+                up,
+                down,
+                activation,
+            } => {
+                let gate = activation.forward(&gate.forward(xs, observe)?)?;
+                down.forward(&(gate * up.forward(xs, observe)?)?, observe)
+            }
+            Self::Fused {
+                gate_up,
+                down,
+                hidden,
+            } => {
+                let ys = gate_up.forward(xs, observe)?;
+                let gate = ys.narrow(D::Minus1, 0, *hidden)?.silu()?;
+                let up = ys.narrow(D::Minus1, *hidden, *hidden)?;
+                down.forward(&(gate * up)?, observe)
+            }
+            Self::Experts {
+                router,
+                experts,
+                used,
+            } => {
+                let (b, t, dim) = xs.dims3()?;
+                let xs = xs.reshape((b * t, dim))?;
+                let probs = candle_nn::ops::softmax_last_dim(&router.forward(&xs, observe)?)?;
+
+                let mut rows = vec![Vec::new(); experts.len()];
+                let mut scales = vec![Vec::new(); experts.len()];
+                for (row, probs) in probs.to_vec2::<f32>()?.iter().enumerate() {
+                    let mut order: Vec<usize> = (0..probs.len()).collect();
+                    order.sort_by(|&i, &j| probs[j].total_cmp(&probs[i]));
+                    let top = &order[..(*used).min(order.len())];
+                    let sum: f32 = top.iter().map(|&e| probs[e]).sum();
+                    for &e in top {
+                        rows[e].push(row as u32);
+                        scales[e].push(probs[e] / sum);
+                    }
+                }
+
+                let mut ys = xs.zeros_like()?;
+                for (e, expert) in experts.iter().enumerate() {
+                    if rows[e].is_empty() {
+                        continue;
+                    }
+                    let index = Tensor::new(rows[e].as_slice(), xs.device())?;
+                    let scale = Tensor::new(scales[e].as_slice(), xs.device())?.reshape(((), 1))?;
+                    let out = expert
+                        .forward(&xs.index_select(&index, 0)?, observe)?
+                        .broadcast_mul(&scale)?;
+                    ys = ys.index_add(&index, &out, 0)?;
+                }
+                Ok(ys.reshape((b, t, dim))?)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::context::ContextOverrides;
+    use crate::model::embedding::{EmbedOptions, Pooling};
+    use crate::model::imatrix::{Imatrix, ImatrixOptions};
+    use crate::model::loader::Model;
+    use crate::model::shards::ShardedGguf;
+    use candle_core::quantized::gguf_file::{self, Value};
+    use candle_core::quantized::{GgmlDType, QTensor};
+    use candle_core::DType;
+    use candle_nn::VarBuilder;
+    use candle_transformers::models::{
+        gemma2, quantized_gemma3, quantized_lfm2, quantized_llama, quantized_phi3, quantized_qwen2,
+        quantized_qwen3,
+    };
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+
+    const DIM: usize = 16;
+    const FFN: usize = 32;
+    const VOCAB: usize = 24;
+
+    /// A two-layer model of `arch` with random weights. `llama-moe` is a
+    /// LLaMA with three experts.
+    fn write_model(name: &str, arch: &str, extra: &[(&str, Value)]) -> PathBuf {
+        let gguf_arch = arch.trim_end_matches("-moe");
+        let head_dim = if arch.starts_with("gemma") { 12 } else { 8 };
+        let (q_dim, kv_dim) = (2 * head_dim, head_dim);
+
+        let key = |k: &str| format!("{}.{}", gguf_arch, k);
+        let mut metadata = vec![
+            (
+                "general.architecture".to_string(),
+                Value::String(gguf_arch.into()),
+            ),
+            (key("block_count"), Value::U32(2)),
+            (key("embedding_length"), Value::U32(DIM as u32)),
+            (key("feed_forward_length"), Value::U32(FFN as u32)),
+            (key("context_length"), Value::U32(64)),
+            (key("vocab_size"), Value::U32(VOCAB as u32)),
+            (key("attention.head_count"), Value::U32(2)),
+            (key("attention.layer_norm_rms_epsilon"), Value::F32(1e-5)),
+            (key("rope.freq_base"), Value::F32(10_000.0)),
+            // candle's Qwen3 keeps its RoPE tables in F16 otherwise.
+            ("general.dtype".to_string(), Value::U32(0)),
+        ];
+        match arch {
+            "lfm2" => {
+                let kv = Value::Array(vec![Value::U32(0), Value::U32(1)]);
+                metadata.push((key("attention.head_count_kv"), kv));
+                metadata.push((key("shortconv.l_cache"), Value::U32(3)));
+            }
+            "gemma3" => {
+                metadata.push((key("attention.head_count_kv"), Value::U32(1)));
+                metadata.push((key("attention.key_length"), Value::U32(12)));
+                metadata.push((key("attention.value_length"), Value::U32(12)));
+                metadata.push((key("attention.sliding_window"), Value::U32(64)));
+                metadata.push((key("attention.sliding_window_type"), Value::U32(2)));
+            }
+            "gemma2" => {
+                metadata.push((key("attention.head_count_kv"), Value::U32(1)));
+                metadata.push((key("attention.key_length"), Value::U32(12)));
+                metadata.push((key("attention.value_length"), Value::U32(12)));
+                metadata.push((key("attention.sliding_window"), Value::U32(64)));
+                // Small caps, so that the comparison with candle sees them.
+                metadata.push((key("attn_logit_softcapping"), Value::F32(2.0)));
+                metadata.push((key("final_logit_softcapping"), Value::F32(3.0)));
+            }
+            _ => {
+                metadata.push((key("attention.head_count_kv"), Value::U32(1)));
+                metadata.push((key("attention.key_length"), Value::U32(8)));
+                metadata.push((key("rope.dimension_count"), Value::U32(8)));
+            }
+        }
+        if arch == "llama-moe" {
+            metadata.push((key("expert_count"), Value::U32(3)));
+            metadata.push((key("expert_used_count"), Value::U32(2)));
+        }
+        for (k, v) in extra {
+            metadata.retain(|(existing, _)| existing != k);
+            metadata.push((k.to_string(), v.clone()));
+        }
+
+        let mut tensors: Vec<(String, Vec<usize>)> = vec![
+            ("token_embd.weight".into(), vec![VOCAB, DIM]),
+            ("output_norm.weight".into(), vec![DIM]),
+        ];
+        if matches!(arch, "llama" | "phi3") {
+            tensors.push(("output.weight".into(), vec![VOCAB, DIM]));
+        }
+        for i in 0..2 {
+            let mut add = |name: &str, shape: Vec<usize>| {
+                tensors.push((format!("blk.{}.{}", i, name), shape));
+            };
+            add("attn_norm.weight", vec![DIM]);
+            add("ffn_norm.weight", vec![DIM]);
+
+            if arch == "lfm2" && i == 0 {
+                add("shortconv.in_proj.weight", vec![3 * DIM, DIM]);
+                add("shortconv.out_proj.weight", vec![DIM, DIM]);
+                add("shortconv.conv.weight", vec![DIM, 3]);
+            } else if arch == "phi3" {
+                add("attn_qkv.weight", vec![q_dim + 2 * kv_dim, DIM]);
+                add("attn_output.weight", vec![DIM, q_dim]);
+            } else {
+                add("attn_q.weight", vec![q_dim, DIM]);
+                add("attn_k.weight", vec![kv_dim, DIM]);
+                add("attn_v.weight", vec![kv_dim, DIM]);
+                add("attn_output.weight", vec![DIM, q_dim]);
+                if arch == "qwen2" {
+                    add("attn_q.bias", vec![q_dim]);
+                    add("attn_k.bias", vec![kv_dim]);
+                    add("attn_v.bias", vec![kv_dim]);
+                }
+                if matches!(arch, "qwen3" | "gemma3" | "lfm2") {
+                    add("attn_q_norm.weight", vec![head_dim]);
+                    add("attn_k_norm.weight", vec![head_dim]);
+                }
+            }
+            if arch.starts_with("gemma") {
+                add("post_attention_norm.weight", vec![DIM]);
+                add("post_ffw_norm.weight", vec![DIM]);
+            }
+
+            if arch == "phi3" {
+                add("ffn_up.weight", vec![2 * FFN, DIM]);
+                add("ffn_down.weight", vec![DIM, FFN]);
+            } else if arch == "llama-moe" {
+                add("ffn_gate_inp.weight", vec![3, DIM]);
+                for e in 0..3 {
+                    add(&format!("ffn_gate.{}.weight", e), vec![FFN, DIM]);
+                    add(&format!("ffn_up.{}.weight", e), vec![FFN, DIM]);
+                    add(&format!("ffn_down.{}.weight", e), vec![DIM, FFN]);
+                }
+            } else {
+                add("ffn_gate.weight", vec![FFN, DIM]);
+                add("ffn_up.weight", vec![FFN, DIM]);
+                add("ffn_down.weight", vec![DIM, FFN]);
+            }
+        }
+
+        let device = Device::Cpu;
+        let tensors: Vec<(String, QTensor)> = tensors
+            .into_iter()
+            .map(|(name, shape)| {
+                let t = Tensor::randn(0f32, 0.5, shape, &device).unwrap();
+                (name, QTensor::quantize(&t, GgmlDType::F32).unwrap())
+            })
+            .collect();
+
+        let path = std::env::temp_dir().join(format!(
+            "oxide-decoder-{}-{}-{}.gguf",
+            name,
+            arch,
+            std::process::id()
+        ));
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        let mut file = File::create(&path).unwrap();
+        gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+        path
+    }
+
+    fn load_decoder(path: &Path) -> Decoder {
+        let sharded = ShardedGguf::open(path).unwrap();
+        let metadata = Model::extract_metadata(&sharded.content, "model.gguf", 0).unwrap();
+        let mut weights = Weights::new(&sharded.content, sharded.reader());
+        Decoder::load(&mut weights, &metadata).unwrap()
+    }
+
+    /// candle's implementation of the same architecture.
+    enum Reference {
+        Llama(quantized_llama::ModelWeights),
+        Lfm2(quantized_lfm2::ModelWeights),
+        Qwen2(quantized_qwen2::ModelWeights),
+        Qwen3(quantized_qwen3::ModelWeights),
+        Phi3(quantized_phi3::ModelWeights),
+        Gemma3(quantized_gemma3::ModelWeights),
+        Gemma2(gemma2::Model),
+    }
+
+    impl Reference {
+        fn load(path: &Path, arch: &str) -> Self {
+            let mut file = File::open(path).unwrap();
+            let content = gguf_file::Content::read(&mut file).unwrap();
+            let device = Device::Cpu;
+            let f = &mut file;
+            match arch {
+                "llama" | "llama-moe" => Self::Llama(
+                    quantized_llama::ModelWeights::from_gguf(content, f, &device).unwrap(),
+                ),
+                "lfm2" => Self::Lfm2(
+                    quantized_lfm2::ModelWeights::from_gguf(content, f, &device).unwrap(),
+                ),
+                "qwen2" => Self::Qwen2(
+                    quantized_qwen2::ModelWeights::from_gguf(content, f, &device).unwrap(),
+                ),
+                "qwen3" => Self::Qwen3(
+                    quantized_qwen3::ModelWeights::from_gguf(content, f, &device).unwrap(),
+                ),
+                "phi3" => Self::Phi3(
+                    quantized_phi3::ModelWeights::from_gguf(false, content, f, &device).unwrap(),
+                ),
+                "gemma3" => Self::Gemma3(
+                    quantized_gemma3::ModelWeights::from_gguf(content, f, &device).unwrap(),
+                ),
+                "gemma2" => Self::Gemma2(Self::gemma2(&content, f)),
+                other => panic!("no reference for {}", other),
+            }
+        }
+
+        /// candle has no quantized Gemma 2, so its full-precision model is
+        /// built from the same tensors under their Hugging Face names.
+        fn gemma2(content: &gguf_file::Content, file: &mut File) -> gemma2::Model {
+            let device = Device::Cpu;
+            let mut tensors = HashMap::new();
+            for name in content.tensor_infos.keys() {
+                let mut tensor = content
+                    .tensor(file, name, &device)
+                    .unwrap()
+                    .dequantize(&device)
+                    .unwrap();
+                // llama.cpp stores Gemma's norm weights plus one.
+                if name.ends_with("norm.weight") {
+                    tensor = (tensor - 1.0).unwrap();
+                }
+                let hf = match name.split('.').collect::<Vec<_>>()[..] {
+                    ["token_embd", _] => "model.embed_tokens.weight".to_string(),
+                    ["output_norm", _] => "model.norm.weight".to_string(),
+                    ["blk", i, layer, _] => {
+                        let layer = match layer {
+                            "attn_norm" => "input_layernorm",
+                            "attn_q" => "self_attn.q_proj",
+                            "attn_k" => "self_attn.k_proj",
+                            "attn_v" => "self_attn.v_proj",
+                            "attn_output" => "self_attn.o_proj",
+                            "post_attention_norm" => "post_attention_layernorm",
+                            "ffn_norm" => "pre_feedforward_layernorm",
+                            "ffn_gate" => "mlp.gate_proj",
+                            "ffn_up" => "mlp.up_proj",
+                            "ffn_down" => "mlp.down_proj",
+                            "post_ffw_norm" => "post_feedforward_layernorm",
+                            other => panic!("unexpected tensor {}", other),
+                        };
+                        format!("model.layers.{}.{}.weight", i, layer)
+                    }
+                    _ => panic!("unexpected tensor {}", name),
+                };
+                tensors.insert(hf, tensor);
+            }
+
+            let config = gemma2::Config {
+                attention_bias: false,
+                head_dim: 12,
+                hidden_activation: Activation::GeluPytorchTanh,
+                hidden_size: DIM,
+                intermediate_size: FFN,
+                num_attention_heads: 2,
+                num_hidden_layers: 2,
+                num_key_value_heads: 1,
+                rms_norm_eps: 1e-5,
+                rope_theta: 10_000.0,
+                vocab_size: VOCAB,
+                final_logit_softcapping: Some(3.0),
+                attn_logit_softcapping: Some(2.0),
+                query_pre_attn_scalar: 12,
+                // The test prompts are shorter than the window.
+                sliding_window: None,
+                max_position_embeddings: 64,
+            };
+            let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+            gemma2::Model::new(false, &config, vb).unwrap()
+        }
+
+        fn forward(&mut self, tokens: &[u32], pos: usize) -> Vec<f32> {
+            let input = Tensor::new(tokens, &Device::Cpu)
+                .unwrap()
+                .unsqueeze(0)
+                .unwrap();
+            let logits = match self {
+                Self::Llama(m) => m.forward(&input, pos),
+                Self::Lfm2(m) => m.forward(&input, pos),
+                Self::Qwen2(m) => m.forward(&input, pos),
+                Self::Qwen3(m) => m.forward(&input, pos),
+                Self::Phi3(m) => m.forward(&input, pos),
+                Self::Gemma3(m) => m.forward(&input, pos),
+                Self::Gemma2(m) => m.forward(&input, pos),
+            };
+            logits.unwrap().flatten_all().unwrap().to_vec1().unwrap()
+        }
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32], what: &str) {
+        assert_eq!(actual.len(), expected.len(), "{}", what);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(
+                (a - e).abs() <= 1e-4 * (1.0 + e.abs()),
+                "{}: {} != {}",
+                what,
+                a,
+                e
+            );
+        }
+    }
+
+    /// candle's Gemma 3 uses SiLU where the model was trained with GELU;
+    /// switch to it to compare everything else.
+    fn use_silu(decoder: &mut Decoder) {
+        for block in &mut decoder.blocks {
+            if let Ffn::Gated { activation, .. } = &mut block.ffn {
+                *activation = Activation::Silu;
+            }
+        }
+    }
+
+    #[test]
+    fn test_logits_match_candle_for_every_architecture() {
+        for arch in [
+            "llama",
+            "llama-moe",
+            "lfm2",
+            "qwen2",
+            "qwen3",
+            "phi3",
+            "gemma2",
+            "gemma3",
+        ] {
+            let path = write_model("candle", arch, &[]);
+            let mut decoder = load_decoder(&path);
+            if arch == "gemma3" {
+                use_silu(&mut decoder);
+            }
+            let mut reference = Reference::load(&path, arch);
+            let mut cache = Cache::default();
+
+            let steps: [(&[u32], usize); 3] = [(&[1, 5, 3, 7], 0), (&[2], 4), (&[9], 5)];
+            for (tokens, pos) in steps {
+                let ours: Vec<f32> = decoder
+                    .forward(tokens, pos, &mut cache)
+                    .unwrap()
+                    .flatten_all()
+                    .unwrap()
+                    .to_vec1()
+                    .unwrap();
+                let theirs = reference.forward(tokens, pos);
+                assert_close(&ours, &theirs, &format!("{} at position {}", arch, pos));
+            }
+            std::fs::remove_file(&path).ok();
+        }
+    }
+
+    #[test]
+    fn test_mask_hides_positions_outside_the_window() {
+        let hidden = |start, len, window| -> Option<Vec<Vec<usize>>> {
+            let mask = Positions::new(start, len).mask(window).unwrap()?;
+            let rows: Vec<Vec<f32>> = mask.to_vec2().unwrap();
+            Some(
+                rows.iter()
+                    .map(|row| (0..row.len()).filter(|&j| row[j].is_infinite()).collect())
+                    .collect(),
+            )
+        };
+
+        // Each position sees itself and the two before it.
+        let prefill = hidden(0, 5, Some(2)).unwrap();
+        assert_eq!(
+            prefill,
+            [
+                vec![1, 2, 3, 4],
+                vec![2, 3, 4],
+                vec![3, 4],
+                vec![0, 4],
+                vec![0, 1]
+            ]
+        );
+        assert_eq!(hidden(3, 2, Some(2)).unwrap(), [vec![0, 4], vec![0, 1]]);
+        assert_eq!(hidden(0, 3, None).unwrap(), [vec![1, 2], vec![2], vec![]]);
+
+        // A single new token only needs a mask once the window is full.
+        assert_eq!(hidden(2, 1, Some(2)), None);
+        assert_eq!(hidden(3, 1, Some(2)).unwrap(), [vec![0]]);
+        assert_eq!(hidden(3, 1, None), None);
+    }
+
+    #[test]
+    fn test_sliding_window_matches_candle_in_prefill() {
+        // candle only applies the window to prompts, so compare a prompt
+        // longer than the window.
+        let window = [
+            ("gemma3.attention.sliding_window", Value::U32(2)),
+            ("gemma3.attention.sliding_window_type", Value::U32(2)),
+        ];
+        let path = write_model("window-candle", "gemma3", &window);
+        let mut decoder = load_decoder(&path);
+        use_silu(&mut decoder);
+        let mut reference = Reference::load(&path, "gemma3");
+
+        let tokens = [1, 5, 3, 7, 2, 9];
+        let ours: Vec<f32> = decoder
+            .forward(&tokens, 0, &mut Cache::default())
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        assert_close(&ours, &reference.forward(&tokens, 0), "gemma3 window");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_sliding_window_hides_older_tokens() {
+        // Both layers local with a window of one: the last position sees
+        // positions 2 and 3 in the second layer, which saw 1 to 3 in the
+        // first, so 0 cannot reach it.
+        let window = [
+            ("gemma3.attention.sliding_window", Value::U32(1)),
+            ("gemma3.attention.sliding_window_type", Value::U32(6)),
+        ];
+        let path = write_model("window", "gemma3", &window);
+        let decoder = load_decoder(&path);
+        let mut cache = Cache::default();
+        let logits = |tokens: &[u32], pos, cache: &mut Cache| -> Vec<f32> {
+            let logits = decoder.forward(tokens, pos, cache).unwrap();
+            logits.flatten_all().unwrap().to_vec1().unwrap()
+        };
+
+        let full = logits(&[1, 2, 3, 4], 0, &mut cache);
+        assert_close(&logits(&[9, 2, 3, 4], 0, &mut cache), &full, "prefill");
+        logits(&[1, 2, 3], 0, &mut cache);
+        assert_close(&logits(&[4], 3, &mut cache), &full, "decode");
+        assert_ne!(logits(&[1, 7, 3, 4], 0, &mut cache), full);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_rope_tables_scale_positions() {
+        let device = Device::Cpu;
+        let angles = |scale, head_dim| -> (Vec<Vec<f32>>, Vec<Vec<f32>>) {
+            let (cos, sin) = rope_tables(10_000.0, head_dim, scale, 0, 4, &device).unwrap();
+            let (cos, sin): (Vec<Vec<f32>>, Vec<Vec<f32>>) =
+                (cos.to_vec2().unwrap(), sin.to_vec2().unwrap());
+            let angle = cos
+                .iter()
+                .zip(&sin)
+                .map(|(c, s)| c.iter().zip(s).map(|(c, s)| s.atan2(*c)).collect())
+                .collect();
+            let norm = cos
+                .iter()
+                .zip(&sin)
+                .map(|(c, s)| c.iter().zip(s).map(|(c, s)| c.hypot(*s)).collect())
+                .collect();
+            (angle, norm)
+        };
+
+        // Linear scaling puts position 2 where position 1 was.
+        let (plain, _) = angles(None, 16);
+        let (linear, _) = angles(Some(RopeScale::Linear { factor: 2.0 }), 16);
+        assert_close(&linear[2], &plain[1], "linear");
+
+        // With a 4096-token original context the first three frequencies
+        // turn often enough to keep, the last two are interpolated fully
+        // and the ones between are blended.
+        let yarn = RopeScale::Yarn {
+            factor: 4.0,
+            original_context: 4096,
+        };
+        let (scaled, norm) = angles(Some(yarn), 16);
+        assert_close(&scaled[1][..3], &plain[1][..3], "yarn kept");
+        let quarter: Vec<f32> = plain[1][6..].iter().map(|a| a / 4.0).collect();
+        assert_close(&scaled[1][6..], &quarter, "yarn interpolated");
+        for i in 3..6 {
+            assert!(scaled[1][i] < plain[1][i] && scaled[1][i] > plain[1][i] / 4.0);
+        }
+        let mscale = 0.1 * 4f32.ln() + 1.0;
+        assert_close(&norm[3], &[mscale; 8], "yarn mscale");
+    }
+
+    #[test]
+    fn test_rope_scaling_from_metadata() {
+        let logits = |extra: &[(&str, Value)]| -> Result<Vec<f32>> {
+            let path = write_model("scaled", "llama", extra);
+            let overrides = ContextOverrides::default();
+            let loaded = Model::load_with_mmap(&path, &overrides, &[]);
+            std::fs::remove_file(&path).ok();
+            let (_, mut model) = loaded?;
+            Ok(model.forward(&[1, 5, 3, 7], 0)?.flatten_all()?.to_vec1()?)
+        };
+        let scaling = |kind: &str| {
+            [
+                ("llama.rope.scaling.type", Value::String(kind.into())),
+                ("llama.rope.scaling.factor", Value::F32(4.0)),
+                ("llama.rope.scaling.original_context_length", Value::U32(16)),
+            ]
+        };
+
+        let plain = logits(&[]).unwrap();
+        for kind in ["linear", "yarn"] {
+            let scaled = logits(&scaling(kind)).unwrap();
+            assert_eq!(scaled.len(), VOCAB);
+            assert_ne!(scaled, plain, "{}", kind);
+        }
+        let err = logits(&scaling("longrope")).unwrap_err();
+        assert!(err.to_string().contains("not supported"), "{}", err);
+    }
+
+    #[test]
+    fn test_gemma2_alternates_local_layers() {
+        let path = write_model("gemma2-layers", "gemma2", &[]);
+        let decoder = load_decoder(&path);
+        let windows: Vec<Option<usize>> = decoder
+            .blocks
+            .iter()
+            .map(|block| match &block.mixer {
+                Mixer::Attention(attention) => attention.window,
+                Mixer::Conv(_) => None,
+            })
+            .collect();
+        assert_eq!(windows, [Some(64), None]);
+        assert_eq!(decoder.logit_softcap, Some(3.0));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_positions_must_follow_the_cache() {
+        let path = write_model("positions", "qwen2", &[]);
+        let decoder = load_decoder(&path);
+        let mut cache = Cache::default();
+
+        decoder.forward(&[1, 2], 0, &mut cache).unwrap();
+        let err = decoder.forward(&[3], 5, &mut cache).unwrap_err();
+        assert!(err.to_string().contains("2 cached tokens"), "{}", err);
+        assert!(decoder.forward(&[], 0, &mut cache).is_err());
+        decoder.forward(&[3], 0, &mut cache).unwrap();
+        decoder.forward(&[4], 1, &mut cache).unwrap();
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_observer_sees_every_matmul_input() {
+        let path = write_model("observe", "llama", &[]);
+        let decoder = load_decoder(&path);
+
+        let mut seen = Vec::new();
+        let logits = decoder
+            .forward_observed(&[1, 2, 3], &mut |name, xs| {
+                seen.push((name.to_string(), xs.dims().to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        let names: Vec<&str> = seen.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names.len(), 2 * 7 + 1);
+        assert_eq!(
+            names[..7],
+            [
+                "blk.0.attn_q.weight",
+                "blk.0.attn_k.weight",
+                "blk.0.attn_v.weight",
+                "blk.0.attn_output.weight",
+                "blk.0.ffn_gate.weight",
+                "blk.0.ffn_up.weight",
+                "blk.0.ffn_down.weight",
+            ]
+        );
+        assert_eq!(seen[6].1, [1, 3, FFN]);
+        assert_eq!(seen[14], ("output.weight".to_string(), vec![1, 3, DIM]));
+
+        let last = logits
+            .get(0)
+            .unwrap()
+            .get(2)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        let mut cache = Cache::default();
+        let expected = decoder.forward(&[1, 2, 3], 0, &mut cache).unwrap();
+        assert_close(
+            &last,
+            &expected.flatten_all().unwrap().to_vec1().unwrap(),
+            "logits",
+        );
+
+        let overrides = ContextOverrides::default();
+        let (mmaps, mut model) = Model::load_with_mmap(&path, &overrides, &[]).unwrap();
+        model.use_decoder(&mmaps).unwrap();
+        let options = ImatrixOptions {
+            ctx: 4,
+            max_chunks: None,
+        };
+        let imatrix = Imatrix::collect(
+            &model,
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9],
+            &options,
+            "test",
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(imatrix.chunks(), 2);
+        assert_eq!(imatrix.len(), 15);
+        assert_eq!(imatrix.weights("output.weight").unwrap().len(), DIM);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_candle_weights_agree_with_the_decoder() {
+        for arch in ["llama", "qwen3", "phi3"] {
+            let path = write_model("backends", arch, &[]);
+            let overrides = ContextOverrides::default();
+            let (mmaps, mut model) = Model::load_with_mmap(&path, &overrides, &[]).unwrap();
+
+            let inputs = vec![vec![1, 2, 3], vec![4, 5, 6, 7, 8]];
+            let run = |model: &mut Model| {
+                let logits: Vec<f32> = model
+                    .forward(&[1, 5, 3, 7], 0)
+                    .unwrap()
+                    .flatten_all()
+                    .unwrap()
+                    .to_vec1()
+                    .unwrap();
+                let mut embeddings = Vec::new();
+                for pooling in [Pooling::Mean, Pooling::Cls, Pooling::Last] {
+                    let options = EmbedOptions {
+                        pooling: Some(pooling),
+                        ..Default::default()
+                    };
+                    embeddings.push(model.embed(&inputs, &options).unwrap());
+                }
+                (logits, embeddings)
+            };
+
+            let (logits, embeddings) = run(&mut model);
+            model.use_decoder(&mmaps).unwrap();
+            let (expected_logits, expected_embeddings) = run(&mut model);
+
+            assert_eq!(logits.len(), VOCAB);
+            assert_close(&logits, &expected_logits, &format!("{} logits", arch));
+            for (ours, theirs) in embeddings
+                .iter()
+                .flatten()
+                .zip(expected_embeddings.iter().flatten())
+            {
+                assert_eq!(ours.len(), DIM);
+                assert_close(ours, theirs, arch);
+            }
+
+            std::fs::remove_file(&path).ok();
+        }
+    }
+}
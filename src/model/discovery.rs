@@ -0,0 +1,307 @@
+//! Model Discovery
+//!
+//! Finds GGUF models in the model directories — `OXIDE_MODELS`, the
+//! config file's `model_dirs` and `~/Models` — and gives each an alias of
+//! the form `name:quant`, e.g. `meta-llama-3-8b-instruct:q4_k_m` for
+//! `Meta-Llama-3-8B-Instruct-Q4_K_M.gguf`.
+//!
+//! Aliases can be abbreviated: `llama-3-8b:q4` matches any model whose name
+//! contains `llama-3-8b` and whose quantization starts with `q4`, ignoring
+//! case and punctuation.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use super::loader::Model;
+use super::quantize::QuantType;
+use super::shards;
+
+/// Environment variable with extra model directories, separated like `PATH`.
+pub const MODELS_ENV: &str = "OXIDE_MODELS";
+
+/// Directory levels searched below each model directory.
+const MAX_DEPTH: usize = 3;
+
+/// A GGUF model found in a model directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelEntry {
+    /// `name:quant`, or just `name` when the quantization is unknown.
+    pub alias: String,
+    /// The file, or the first shard of a split model.
+    pub path: PathBuf,
+    /// `general.name` from the metadata.
+    pub name: String,
+    pub architecture: String,
+    pub quantization: Option<String>,
+    /// Bytes, over all shards.
+    pub file_size: u64,
+    pub context_length: usize,
+}
+
+/// Directories to search, in order: `OXIDE_MODELS`, `configured` and
+/// `~/Models`. Missing directories and duplicates are left out.
+pub fn model_dirs(configured: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = std::env::var_os(MODELS_ENV)
+        .map(|paths| std::env::split_paths(&paths).collect())
+        .unwrap_or_default();
+    dirs.extend(configured.iter().cloned());
+    if let Some(home) = std::env::var_os("HOME").filter(|h| !h.is_empty()) {
+        dirs.push(PathBuf::from(home).join("Models"));
+    }
+
+    let mut seen = Vec::new();
+    dirs.retain(|dir| {
+        let key = fs::canonicalize(dir).ok();
+        let keep = key.is_some() && !seen.contains(&key);
+        seen.push(key);
+        keep
+    });
+    dirs
+}
+
+/// Every model in `dirs`, sorted by alias. Files that cannot be read as
+/// GGUF models, and LoRA adapters, are skipped.
+pub fn discover(dirs: &[PathBuf]) -> Vec<ModelEntry> {
+    let mut files = Vec::new();
+    for dir in dirs {
+        collect_gguf_files(dir, 0, &mut files);
+    }
+
+    let mut entries: Vec<ModelEntry> = files
+        .iter()
+        .filter(|path| shards::first_shard(path) == **path)
+        .filter_map(|path| match read_entry(path) {
+            Ok(entry) => entry,
+            Err(e) => {
+                tracing::debug!("Skipping {:?}: {:#}", path, e);
+                None
+            }
+        })
+        .collect();
+    entries.sort_by(|a, b| a.alias.cmp(&b.alias));
+    entries
+}
+
+/// The model `spec` names: a path if one exists, otherwise an alias of a
+/// model in `dirs`. Specs that look like paths are returned unchanged, so
+/// loading reports the missing file.
+pub fn resolve(spec: &Path, dirs: &[PathBuf]) -> Result<PathBuf> {
+    let text = spec.to_string_lossy();
+    let is_path = spec.components().count() > 1 || text.ends_with(".gguf");
+    if spec.exists() || is_path {
+        return Ok(spec.to_path_buf());
+    }
+
+    let entries = discover(dirs);
+    Ok(find(&entries, &text, dirs)?.path.clone())
+}
+
+/// The entry `alias` names. An exact alias wins, taking the first of
+/// duplicates; otherwise the abbreviation must match one model.
+pub fn find<'a>(
+    entries: &'a [ModelEntry],
+    alias: &str,
+    dirs: &[PathBuf],
+) -> Result<&'a ModelEntry> {
+    if let Some(entry) = entries.iter().find(|e| e.alias.eq_ignore_ascii_case(alias)) {
+        return Ok(entry);
+    }
+
+    let matches: Vec<&ModelEntry> = entries.iter().filter(|e| matches_alias(e, alias)).collect();
+    match matches.as_slice() {
+        [entry] => Ok(entry),
+        [] => {
+            let searched: Vec<String> = dirs.iter().map(|d| d.display().to_string()).collect();
+            anyhow::bail!(
+                "No model file or alias '{}' (searched {}; see `oxide-rs list`)",
+                alias,
+                if searched.is_empty() {
+                    "no model directories".to_string()
+                } else {
+                    searched.join(", ")
+                }
+            )
+        }
+        _ => {
+            let aliases: Vec<&str> = matches.iter().map(|e| e.alias.as_str()).collect();
+            anyhow::bail!("'{}' matches several models: {}", alias, aliases.join(", "))
+        }
+    }
+}
+
+fn collect_gguf_files(dir: &Path, depth: usize, files: &mut Vec<PathBuf>) {
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut paths: Vec<PathBuf> = read_dir.flatten().map(|e| e.path()).collect();
+    paths.sort();
+
+    for path in paths {
+        let hidden = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map_or(true, |n| n.starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            if depth < MAX_DEPTH {
+                collect_gguf_files(&path, depth + 1, files);
+            }
+        } else if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gguf"))
+        {
+            files.push(path);
+        }
+    }
+}
+
+fn read_entry(path: &Path) -> Result<Option<ModelEntry>> {
+    let metadata = Model::read_metadata(path)?;
+    if metadata.get_str("general.type") == Some("adapter") {
+        return Ok(None);
+    }
+
+    let (stem, stem_quant) = split_quant(&shards::model_stem(path));
+    let quantization = metadata
+        .get_u32("general.file_type")
+        .and_then(QuantType::from_file_type)
+        .map(|t| t.to_string())
+        .or(stem_quant)
+        .or(metadata.quantization.clone());
+
+    Ok(Some(ModelEntry {
+        alias: make_alias(&stem, quantization.as_deref()),
+        path: path.to_path_buf(),
+        name: metadata.name.clone(),
+        architecture: metadata.architecture.clone(),
+        quantization,
+        file_size: metadata.file_size,
+        context_length: metadata.trained_context_length,
+    }))
+}
+
+/// Split a trailing quantization such as `-Q4_K_M` or `.f16` off a file
+/// stem.
+fn split_quant(stem: &str) -> (String, Option<String>) {
+    // `Q4_K_M` contains underscores itself, so take the longest suffix
+    // after a separator that looks like a quantization.
+    for (i, c) in stem.char_indices().skip(1) {
+        if matches!(c, '-' | '.' | '_') && is_quant(&stem[i + 1..]) {
+            return (stem[..i].to_string(), Some(stem[i + 1..].to_uppercase()));
+        }
+    }
+    (stem.to_string(), None)
+}
+
+fn is_quant(s: &str) -> bool {
+    let s = s.to_ascii_lowercase();
+    if matches!(s.as_str(), "f16" | "f32" | "bf16") {
+        return true;
+    }
+    let body = s
+        .strip_prefix("iq")
+        .or_else(|| s.strip_prefix('q'))
+        .unwrap_or("");
+    body.starts_with(|c: char| c.is_ascii_digit())
+        && body.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn make_alias(stem: &str, quantization: Option<&str>) -> String {
+    let name = stem.to_lowercase();
+    match quantization {
+        Some(quant) => format!("{}:{}", name, quant.to_lowercase()),
+        None => name,
+    }
+}
+
+fn matches_alias(entry: &ModelEntry, alias: &str) -> bool {
+    let (name, quant) = match alias.split_once(':') {
+        Some((name, quant)) => (name, Some(quant)),
+        None => (alias, None),
+    };
+    let (entry_name, entry_quant) = match entry.alias.split_once(':') {
+        Some((name, quant)) => (name, quant),
+        None => (entry.alias.as_str(), ""),
+    };
+
+    normalize(entry_name).contains(&normalize(name))
+        && quant.map_or(true, |q| normalize(entry_quant).starts_with(&normalize(q)))
+}
+
+/// Lowercase letters and digits only, so `Llama-3` and `llama3` compare equal.
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(file: &str, quant: &str) -> ModelEntry {
+        let (stem, _) = split_quant(file);
+        ModelEntry {
+            alias: make_alias(&stem, Some(quant)),
+            path: PathBuf::from(format!("{}.gguf", file)),
+            name: stem,
+            architecture: "llama".to_string(),
+            quantization: Some(quant.to_string()),
+            file_size: 0,
+            context_length: 4096,
+        }
+    }
+
+    #[test]
+    fn test_split_quant() {
+        assert_eq!(
+            split_quant("Meta-Llama-3-8B-Instruct-Q4_K_M"),
+            (
+                "Meta-Llama-3-8B-Instruct".to_string(),
+                Some("Q4_K_M".to_string())
+            )
+        );
+        assert_eq!(
+            split_quant("mistral-7b-v0.1.Q8_0"),
+            ("mistral-7b-v0.1".to_string(), Some("Q8_0".to_string()))
+        );
+        assert_eq!(
+            split_quant("phi-3-mini-iq4_xs"),
+            ("phi-3-mini".to_string(), Some("IQ4_XS".to_string()))
+        );
+        assert_eq!(
+            split_quant("qwen2.5-0.5b"),
+            ("qwen2.5-0.5b".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn test_find_by_alias() {
+        let entries = vec![
+            entry("Meta-Llama-3-8B-Instruct-Q4_K_M", "Q4_K_M"),
+            entry("Meta-Llama-3-8B-Instruct-Q8_0", "Q8_0"),
+            entry("qwen2.5-7b-instruct-q4_k_m", "Q4_K_M"),
+        ];
+        let find = |alias: &str| find(&entries, alias, &[]).map(|e| e.alias.clone());
+
+        assert_eq!(
+            find("meta-llama-3-8b-instruct:q8_0").unwrap(),
+            "meta-llama-3-8b-instruct:q8_0"
+        );
+        assert_eq!(
+            find("llama3-8b:q4").unwrap(),
+            "meta-llama-3-8b-instruct:q4_k_m"
+        );
+        assert_eq!(find("Qwen2.5").unwrap(), "qwen2.5-7b-instruct:q4_k_m");
+
+        let err = find("llama3-8b").unwrap_err().to_string();
+        assert!(err.contains("several"), "{}", err);
+        assert!(find("gemma").is_err());
+    }
+}
@@ -0,0 +1,636 @@
+//! Sentence Embeddings
+//!
+//! Decoder models embed through the generation weights ([`Model::embed`]):
+//! candle's models are built with an identity output projection, so their
+//! forward pass returns the final hidden state of the last token. BERT
+//! (`bert`, e.g. bge) and Nomic BERT (`nomic-bert`) encoders cannot
+//! generate and run here instead, with bidirectional attention and
+//! post-norm blocks. Truncation and normalisation are shared.
+//!
+//! The encoder pass, with an observer on the input of every matmul, also
+//! collects importance matrices for encoder models.
+//!
+//! Encoder inputs are padded to a common length per batch. Padding only
+//! ever sits after the real tokens and is masked out of attention and
+//! pooling, so an input embeds the same alone or in a batch.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Result;
+use candle_core::{Device, Tensor, D};
+
+use super::layers::{rope_tables, Linear, Norm, Observer, Weights};
+use super::loader::{GgufMetadata, Model};
+use super::shards::ShardedGguf;
+
+/// Encoder architectures, which only run through [`Embedder`]. Decoder
+/// models embed with the generation weights ([`Model::embed`]).
+pub const ENCODER_ARCHITECTURES: &[&str] = &["bert", "nomic-bert"];
+
+/// How the states of an input's tokens are reduced to one vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pooling {
+    /// Average over all tokens.
+    Mean,
+    /// The first token (`[CLS]` for BERT models).
+    Cls,
+    /// The last token, for decoder models.
+    Last,
+}
+
+impl Pooling {
+    /// llama.cpp's `<arch>.pooling_type`.
+    fn from_gguf(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Mean),
+            2 => Some(Self::Cls),
+            3 => Some(Self::Last),
+            _ => None,
+        }
+    }
+
+    /// The pooling the model declares, if any.
+    pub(crate) fn from_metadata(metadata: &GgufMetadata) -> Option<Self> {
+        metadata
+            .arch_value("pooling_type")
+            .and_then(|v| v.to_u32().ok())
+            .and_then(Self::from_gguf)
+    }
+}
+
+impl FromStr for Pooling {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mean" => Ok(Self::Mean),
+            "cls" => Ok(Self::Cls),
+            "last" => Ok(Self::Last),
+            other => Err(format!(
+                "unknown pooling '{}' (expected mean, cls or last)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Pooling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Mean => "mean",
+            Self::Cls => "cls",
+            Self::Last => "last",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Options for computing embeddings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbedOptions {
+    /// Pooling to use. Defaults to the model's `pooling_type`, otherwise
+    /// mean pooling for encoders and last-token pooling for decoders.
+    pub pooling: Option<Pooling>,
+    /// Scale every embedding to unit L2 norm.
+    pub normalize: bool,
+    /// Inputs per forward pass of an encoder model; decoder models run one
+    /// input at a time.
+    pub batch_size: usize,
+}
+
+impl Default for EmbedOptions {
+    fn default() -> Self {
+        Self {
+            pooling: None,
+            normalize: true,
+            batch_size: 8,
+        }
+    }
+}
+
+enum Qkv {
+    Separate(Linear, Linear, Linear),
+    Fused(Linear),
+}
+
+struct Layer {
+    qkv: Qkv,
+    output: Linear,
+    /// After the attention residual.
+    attn_norm: Norm,
+    up: Linear,
+    /// SwiGLU gate; without it the FFN is `down(gelu(up(x)))`.
+    gate: Option<Linear>,
+    down: Linear,
+    ffn_norm: Norm,
+}
+
+/// Attention geometry shared by all layers.
+struct Attention {
+    head_count: usize,
+    head_count_kv: usize,
+    head_dim: usize,
+    /// NeoX-style RoPE frequency base; `None` for learned positions.
+    rope: Option<f32>,
+}
+
+/// Forward pass of a GGUF encoder model up to its final hidden states.
+pub struct Embedder {
+    token_embd: Tensor,
+    position_embd: Option<Tensor>,
+    token_types: Option<Tensor>,
+    embd_norm: Option<Norm>,
+    layers: Vec<Layer>,
+    attention: Attention,
+    metadata: GgufMetadata,
+    pooling: Option<Pooling>,
+}
+
+impl Embedder {
+    /// Read the weights of the GGUF encoder model at `path` (any shard of a
+    /// split model).
+    pub fn load(path: &Path) -> Result<Self> {
+        let sharded = ShardedGguf::open(path)?;
+        let filename = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown");
+        let file_size = sharded.mmaps.iter().map(|m| m.len() as u64).sum();
+        let metadata = Model::extract_metadata(&sharded.content, filename, file_size)?;
+
+        let rope = match metadata.architecture.as_str() {
+            "bert" => None,
+            "nomic-bert" => Some(metadata.rope.freq_base.unwrap_or(1_000.0)),
+            other => anyhow::bail!(
+                "'{}' is not an encoder model (encoders: {})",
+                other,
+                ENCODER_ARCHITECTURES.join(", ")
+            ),
+        };
+        let eps = metadata
+            .arch_value("attention.layer_norm_epsilon")
+            .and_then(|v| v.to_f32().ok())
+            .unwrap_or(1e-12);
+
+        if rope.is_some()
+            && metadata
+                .rope
+                .dimension_count
+                .is_some_and(|d| d != metadata.head_dim)
+        {
+            anyhow::bail!("Partial RoPE is not supported");
+        }
+
+        let mut weights = Weights::new(&sharded.content, sharded.reader());
+
+        let token_embd = weights.dense("token_embd.weight")?;
+        let position_embd = weights.optional("position_embd.weight")?;
+        let token_types = weights.optional("token_types.weight")?;
+        let embd_norm = if weights.has("token_embd_norm.weight") {
+            Some(weights.norm("token_embd_norm", true, eps)?)
+        } else {
+            None
+        };
+
+        let mut layers = Vec::with_capacity(metadata.n_layer);
+        for i in 0..metadata.n_layer {
+            let p = |name: &str| format!("blk.{}.{}", i, name);
+            let qkv = if weights.has(&p("attn_qkv.weight")) {
+                Qkv::Fused(weights.linear(&p("attn_qkv"))?)
+            } else {
+                Qkv::Separate(
+                    weights.linear(&p("attn_q"))?,
+                    weights.linear(&p("attn_k"))?,
+                    weights.linear(&p("attn_v"))?,
+                )
+            };
+            let gate = if weights.has(&p("ffn_gate.weight")) {
+                Some(weights.linear(&p("ffn_gate"))?)
+            } else {
+                None
+            };
+
+            layers.push(Layer {
+                qkv,
+                output: weights.linear(&p("attn_output"))?,
+                attn_norm: weights.norm(&p("attn_output_norm"), true, eps)?,
+                up: weights.linear(&p("ffn_up"))?,
+                gate,
+                down: weights.linear(&p("ffn_down"))?,
+                ffn_norm: weights.norm(&p("layer_output_norm"), true, eps)?,
+            });
+        }
+
+        let pooling = Pooling::from_metadata(&metadata);
+
+        tracing::info!(
+            "Loaded {} embedding model ({} layers, {} dim)",
+            metadata.architecture,
+            metadata.n_layer,
+            metadata.n_embd
+        );
+
+        Ok(Self {
+            token_embd,
+            position_embd,
+            token_types,
+            embd_norm,
+            layers,
+            attention: Attention {
+                head_count: metadata.head_count,
+                head_count_kv: metadata.head_count_kv,
+                head_dim: metadata.head_dim,
+                rope,
+            },
+            metadata,
+            pooling,
+        })
+    }
+
+    pub fn metadata(&self) -> &GgufMetadata {
+        &self.metadata
+    }
+
+    /// Length of the embedding vectors.
+    pub fn dim(&self) -> usize {
+        self.metadata.n_embd
+    }
+
+    /// Longest input, in tokens; longer inputs are truncated.
+    pub fn max_tokens(&self) -> usize {
+        self.metadata.context_length
+    }
+
+    /// The pooling used when [`EmbedOptions::pooling`] is not set.
+    pub fn default_pooling(&self) -> Pooling {
+        self.pooling.unwrap_or(Pooling::Mean)
+    }
+
+    /// Embed tokenized inputs, one vector per input, in input order.
+    pub fn embed(&self, inputs: &[Vec<u32>], options: &EmbedOptions) -> Result<Vec<Vec<f32>>> {
+        let pooling = options.pooling.unwrap_or_else(|| self.default_pooling());
+        embed_batches(inputs, options, pooling, self.max_tokens(), |batch| {
+            self.forward(batch)
+        })
+    }
+
+    /// Hidden states of a batch, `(batch, longest input, dim)`.
+    fn forward(&self, batch: &[&[u32]]) -> Result<Tensor> {
+        self.forward_observed(batch, &mut |_, _| Ok(()))
+    }
+
+    /// [`Embedder::forward`], passing the input of every matmul to `observe`.
+    pub(crate) fn forward_observed(&self, batch: &[&[u32]], observe: Observer) -> Result<Tensor> {
+        let device = Device::Cpu;
+        let b = batch.len();
+        let t = batch.iter().map(|s| s.len()).max().unwrap_or(0);
+
+        let mut ids = vec![0u32; b * t];
+        for (row, tokens) in batch.iter().enumerate() {
+            ids[row * t..row * t + tokens.len()].copy_from_slice(tokens);
+        }
+        let ids = Tensor::from_vec(ids, b * t, &device)?;
+
+        let mut xs = self
+            .token_embd
+            .index_select(&ids, 0)?
+            .reshape((b, t, self.dim()))?;
+        if let Some(positions) = &self.position_embd {
+            xs = xs.broadcast_add(&positions.narrow(0, 0, t)?)?;
+        }
+        if let Some(types) = &self.token_types {
+            xs = xs.broadcast_add(&types.get(0)?)?;
+        }
+        if let Some(norm) = &self.embd_norm {
+            xs = norm.forward(&xs)?;
+        }
+
+        let lengths: Vec<usize> = batch.iter().map(|s| s.len()).collect();
+        let mask = attention_mask(&lengths, t, &device)?;
+        let rope = match self.attention.rope {
+            Some(base) => Some(rope_tables(
+                base,
+                self.attention.head_dim,
+                None,
+                0,
+                t,
+                &device,
+            )?),
+            None => None,
+        };
+
+        for layer in &self.layers {
+            let attn = layer.attention(&xs, &mask, rope.as_ref(), &self.attention, observe)?;
+            let attended = layer.attn_norm.forward(&(&xs + attn)?)?;
+            xs = layer
+                .ffn_norm
+                .forward(&(&attended + layer.ffn(&attended, observe)?)?)?;
+        }
+        Ok(xs)
+    }
+}
+
+impl Layer {
+    fn attention(
+        &self,
+        xs: &Tensor,
+        mask: &Tensor,
+        rope: Option<&(Tensor, Tensor)>,
+        geometry: &Attention,
+        observe: Observer,
+    ) -> Result<Tensor> {
+        let (b, t, _) = xs.dims3()?;
+        let Attention {
+            head_count,
+            head_count_kv,
+            head_dim,
+            ..
+        } = *geometry;
+
+        let (q, k, v) = match &self.qkv {
+            Qkv::Separate(q, k, v) => (
+                q.forward(xs, observe)?,
+                k.forward(xs, observe)?,
+                v.forward(xs, observe)?,
+            ),
+            Qkv::Fused(qkv) => {
+                let ys = qkv.forward(xs, observe)?;
+                let q_dim = head_count * head_dim;
+                let kv_dim = head_count_kv * head_dim;
+                (
+                    ys.narrow(D::Minus1, 0, q_dim)?,
+                    ys.narrow(D::Minus1, q_dim, kv_dim)?,
+                    ys.narrow(D::Minus1, q_dim + kv_dim, kv_dim)?,
+                )
+            }
+        };
+
+        let heads = |x: Tensor, n: usize| -> Result<Tensor> {
+            Ok(x.reshape((b, t, n, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?)
+        };
+        let mut q = heads(q, head_count)?;
+        let mut k = heads(k, head_count_kv)?;
+        let v = heads(v, head_count_kv)?;
+
+        if let Some((cos, sin)) = rope {
+            q = candle_nn::rotary_emb::rope(&q, cos, sin)?;
+            k = candle_nn::rotary_emb::rope(&k, cos, sin)?;
+        }
+
+        let n_rep = head_count / head_count_kv.max(1);
+        let k = candle_transformers::utils::repeat_kv(k, n_rep)?.contiguous()?;
+        let v = candle_transformers::utils::repeat_kv(v, n_rep)?.contiguous()?;
+
+        let scores = (q.matmul(&k.t()?)? / (head_dim as f64).sqrt())?.broadcast_add(mask)?;
+        let weights = candle_nn::ops::softmax_last_dim(&scores)?;
+        let ys = weights
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b, t, head_count * head_dim))?;
+        self.output.forward(&ys, observe)
+    }
+
+    fn ffn(&self, xs: &Tensor, observe: Observer) -> Result<Tensor> {
+        let hidden = match &self.gate {
+            Some(gate) => (gate.forward(xs, observe)?.silu()? * self.up.forward(xs, observe)?)?,
+            None => self.up.forward(xs, observe)?.gelu_erf()?,
+        };
+        self.down.forward(&hidden, observe)
+    }
+}
+
+/// Additive mask of shape `(batch, 1, len, len)` hiding padding.
+fn attention_mask(lengths: &[usize], len: usize, device: &Device) -> Result<Tensor> {
+    let mut mask = vec![0f32; lengths.len() * len * len];
+    for (row, &n) in lengths.iter().enumerate() {
+        for i in 0..len {
+            for j in 0..len {
+                if j >= n {
+                    mask[(row * len + i) * len + j] = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+    Ok(Tensor::from_vec(
+        mask,
+        (lengths.len(), 1, len, len),
+        device,
+    )?)
+}
+
+/// Embed `inputs` in batches of similar length, truncated to
+/// `max_tokens`. `forward` returns the hidden states of a batch padded on
+/// the right, `(batch, longest input, dim)`.
+pub(crate) fn embed_batches<F>(
+    inputs: &[Vec<u32>],
+    options: &EmbedOptions,
+    pooling: Pooling,
+    max_tokens: usize,
+    mut forward: F,
+) -> Result<Vec<Vec<f32>>>
+where
+    F: FnMut(&[&[u32]]) -> Result<Tensor>,
+{
+    let inputs = truncate(inputs, max_tokens)?;
+
+    // Batch inputs of similar length together to keep padding low.
+    let mut order: Vec<usize> = (0..inputs.len()).collect();
+    order.sort_by_key(|&i| inputs[i].len());
+
+    let mut embeddings = vec![Vec::new(); inputs.len()];
+    for chunk in order.chunks(options.batch_size.max(1)) {
+        let batch: Vec<&[u32]> = chunk.iter().map(|&i| inputs[i]).collect();
+        let hidden = forward(&batch)?;
+        for (row, &index) in chunk.iter().enumerate() {
+            let states = hidden.get(row)?;
+            let len = batch[row].len();
+            let pooled = match pooling {
+                Pooling::Mean => states.narrow(0, 0, len)?.mean(0)?,
+                Pooling::Cls => states.get(0)?,
+                Pooling::Last => states.get(len - 1)?,
+            };
+            let mut vector: Vec<f32> = pooled.to_vec1()?;
+            if options.normalize {
+                normalize(&mut vector);
+            }
+            embeddings[index] = vector;
+        }
+    }
+
+    Ok(embeddings)
+}
+
+/// `inputs` cut to `max_tokens`, warning once if any were longer. Empty
+/// inputs are an error.
+pub(crate) fn truncate(inputs: &[Vec<u32>], max_tokens: usize) -> Result<Vec<&[u32]>> {
+    let mut truncated = 0;
+    let inputs: Vec<&[u32]> = inputs
+        .iter()
+        .enumerate()
+        .map(|(i, tokens)| {
+            if tokens.is_empty() {
+                anyhow::bail!("Input {} is empty", i);
+            }
+            if tokens.len() > max_tokens {
+                truncated += 1;
+            }
+            Ok(&tokens[..tokens.len().min(max_tokens)])
+        })
+        .collect::<Result<_>>()?;
+    if truncated > 0 {
+        tracing::warn!(
+            "Truncated {} input(s) to the model's {} tokens",
+            truncated,
+            max_tokens
+        );
+    }
+    Ok(inputs)
+}
+
+/// Scale `vector` to unit L2 norm, leaving all-zero vectors alone.
+pub fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::gguf_file::{self, Value};
+    use candle_core::quantized::{GgmlDType, QTensor};
+    use std::fs::File;
+
+    /// A one-layer encoder with random weights.
+    fn write_model(path: &Path, arch: &str) {
+        let device = Device::Cpu;
+        let (dim, ffn, vocab) = (8usize, 16usize, 12usize);
+
+        let key = |k: &str| format!("{}.{}", arch, k);
+        let metadata = [
+            (
+                "general.architecture".to_string(),
+                Value::String(arch.into()),
+            ),
+            (key("block_count"), Value::U32(1)),
+            (key("embedding_length"), Value::U32(dim as u32)),
+            (key("feed_forward_length"), Value::U32(ffn as u32)),
+            (key("attention.head_count"), Value::U32(2)),
+            (key("context_length"), Value::U32(16)),
+            (key("vocab_size"), Value::U32(vocab as u32)),
+        ];
+
+        let mut tensors: Vec<(String, Vec<usize>)> = vec![
+            ("token_embd.weight".into(), vec![vocab, dim]),
+            ("blk.0.attn_q.weight".into(), vec![dim, dim]),
+            ("blk.0.attn_k.weight".into(), vec![dim, dim]),
+            ("blk.0.attn_v.weight".into(), vec![dim, dim]),
+            ("blk.0.attn_output.weight".into(), vec![dim, dim]),
+            ("blk.0.ffn_up.weight".into(), vec![ffn, dim]),
+            ("blk.0.ffn_down.weight".into(), vec![dim, ffn]),
+        ];
+        tensors.push(("position_embd.weight".into(), vec![16, dim]));
+        for norm in [
+            "token_embd_norm",
+            "blk.0.attn_output_norm",
+            "blk.0.layer_output_norm",
+        ] {
+            tensors.push((format!("{}.weight", norm), vec![dim]));
+            tensors.push((format!("{}.bias", norm), vec![dim]));
+        }
+
+        let tensors: Vec<(String, QTensor)> = tensors
+            .into_iter()
+            .map(|(name, shape)| {
+                let t = Tensor::randn(0f32, 0.5, shape, &device).unwrap();
+                (name, QTensor::quantize(&t, GgmlDType::F32).unwrap())
+            })
+            .collect();
+
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        let mut file = File::create(path).unwrap();
+        gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+    }
+
+    #[test]
+    fn test_padding_does_not_change_embeddings() {
+        let path = std::env::temp_dir().join(format!("oxide-embed-{}.gguf", std::process::id()));
+        write_model(&path, "bert");
+        let embedder = Embedder::load(&path).unwrap();
+
+        let inputs = vec![vec![1, 2, 3], vec![4, 5, 6, 7, 8, 9, 10]];
+        let options = EmbedOptions::default();
+        let batched = embedder.embed(&inputs, &options).unwrap();
+        let alone = embedder.embed(&inputs[..1], &options).unwrap();
+
+        assert_eq!(batched.len(), 2);
+        assert_eq!(batched[0].len(), 8);
+        let norm: f32 = batched[1].iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-5);
+        for (a, b) in batched[0].iter().zip(&alone[0]) {
+            assert!((a - b).abs() < 1e-5, "embeds differently when padded");
+        }
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_rejects_decoder_models() {
+        let path =
+            std::env::temp_dir().join(format!("oxide-embed-decoder-{}.gguf", std::process::id()));
+        write_model(&path, "llama");
+        let err = Embedder::load(&path).err().unwrap();
+        assert!(err.to_string().contains("not an encoder"), "{}", err);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_observer_sees_every_matmul_input() {
+        let path =
+            std::env::temp_dir().join(format!("oxide-embed-observe-{}.gguf", std::process::id()));
+        write_model(&path, "bert");
+        let embedder = Embedder::load(&path).unwrap();
+
+        let mut seen = Vec::new();
+        embedder
+            .forward_observed(&[&[1, 2, 3]], &mut |name, xs| {
+                seen.push((name.to_string(), xs.dims().to_vec()));
+                Ok(())
+            })
+            .unwrap();
+
+        let names: Vec<&str> = seen.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "blk.0.attn_q.weight",
+                "blk.0.attn_k.weight",
+                "blk.0.attn_v.weight",
+                "blk.0.attn_output.weight",
+                "blk.0.ffn_up.weight",
+                "blk.0.ffn_down.weight",
+            ]
+        );
+        assert_eq!(seen[5].1, [1, 3, 16]);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_pooling_names_and_normalize() {
+        assert_eq!("CLS".parse::<Pooling>().unwrap(), Pooling::Cls);
+        assert!("max".parse::<Pooling>().is_err());
+        assert_eq!(Pooling::from_gguf(3), Some(Pooling::Last));
+
+        let mut v = vec![3.0, 4.0];
+        normalize(&mut v);
+        assert_eq!(v, vec![0.6, 0.8]);
+    }
+}
@@ -0,0 +1,289 @@
+//! Streaming GGUF Writer
+//!
+//! candle's `gguf_file::write` needs every tensor in memory at once, which
+//! for a requantised 7B model means gigabytes of buffers. Tensor sizes
+//! follow from their type and shape, so the header can be written up front
+//! and the data streamed one tensor at a time.
+
+use std::io::Write;
+
+use anyhow::Result;
+use candle_core::quantized::gguf_file::Value;
+use candle_core::quantized::GgmlDType;
+
+const GGUF_MAGIC: u32 = 0x4655_4747;
+const GGUF_VERSION: u32 = 3;
+pub(crate) const DEFAULT_ALIGNMENT: u64 = 32;
+
+/// Name, shape (candle order, outermost first) and type of a tensor to write.
+#[derive(Debug, Clone)]
+pub(crate) struct TensorSpec {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub dtype: GgmlDType,
+}
+
+impl TensorSpec {
+    /// Size of the tensor data in bytes.
+    pub fn size(&self) -> usize {
+        let elems: usize = self.shape.iter().product();
+        elems / self.dtype.block_size() * self.dtype.type_size()
+    }
+}
+
+/// Writes a GGUF header, then accepts tensor data in header order.
+pub(crate) struct GgufWriter<W> {
+    writer: W,
+    tensors: Vec<TensorSpec>,
+    next: usize,
+    alignment: u64,
+    written: u64,
+}
+
+impl<W: Write> GgufWriter<W> {
+    pub fn new(
+        writer: W,
+        metadata: &[(String, Value)],
+        tensors: Vec<TensorSpec>,
+        alignment: u64,
+    ) -> Result<Self> {
+        let mut this = Self {
+            writer,
+            tensors,
+            next: 0,
+            alignment,
+            written: 0,
+        };
+
+        this.put(&GGUF_MAGIC.to_le_bytes())?;
+        this.put(&GGUF_VERSION.to_le_bytes())?;
+        this.put(&(this.tensors.len() as u64).to_le_bytes())?;
+        this.put(&(metadata.len() as u64).to_le_bytes())?;
+        for (key, value) in metadata {
+            this.put_string(key)?;
+            this.put(&value_type(value).to_le_bytes())?;
+            this.put_value(value)?;
+        }
+
+        let mut offset = 0u64;
+        let mut infos = Vec::new();
+        for tensor in &this.tensors {
+            infos.push((tensor.clone(), offset));
+            offset += padded(tensor.size() as u64, alignment);
+        }
+        for (tensor, offset) in infos {
+            this.put_string(&tensor.name)?;
+            this.put(&(tensor.shape.len() as u32).to_le_bytes())?;
+            for dim in tensor.shape.iter().rev() {
+                this.put(&(*dim as u64).to_le_bytes())?;
+            }
+            this.put(&ggml_type_id(tensor.dtype).to_le_bytes())?;
+            this.put(&offset.to_le_bytes())?;
+        }
+        this.pad()?;
+        Ok(this)
+    }
+
+    /// Write the data of the next tensor in the header.
+    pub fn write_tensor(&mut self, data: &[u8]) -> Result<()> {
+        let Some(tensor) = self.tensors.get(self.next) else {
+            anyhow::bail!("All {} tensors were already written", self.tensors.len());
+        };
+        if data.len() != tensor.size() {
+            anyhow::bail!(
+                "{} has {} bytes of data, expected {} for {:?} {:?}",
+                tensor.name,
+                data.len(),
+                tensor.size(),
+                tensor.dtype,
+                tensor.shape
+            );
+        }
+        self.put(data)?;
+        self.pad()?;
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Flush and return the number of bytes written.
+    pub fn finish(mut self) -> Result<u64> {
+        if self.next != self.tensors.len() {
+            anyhow::bail!(
+                "Only {} of {} tensors were written",
+                self.next,
+                self.tensors.len()
+            );
+        }
+        self.writer.flush()?;
+        Ok(self.written)
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<()> {
+        self.writer.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn put_string(&mut self, s: &str) -> Result<()> {
+        self.put(&(s.len() as u64).to_le_bytes())?;
+        self.put(s.as_bytes())
+    }
+
+    fn pad(&mut self) -> Result<()> {
+        let padding = padded(self.written, self.alignment) - self.written;
+        self.put(&vec![0u8; padding as usize])
+    }
+
+    fn put_value(&mut self, value: &Value) -> Result<()> {
+        match value {
+            Value::U8(v) => self.put(&v.to_le_bytes()),
+            Value::I8(v) => self.put(&v.to_le_bytes()),
+            Value::U16(v) => self.put(&v.to_le_bytes()),
+            Value::I16(v) => self.put(&v.to_le_bytes()),
+            Value::U32(v) => self.put(&v.to_le_bytes()),
+            Value::I32(v) => self.put(&v.to_le_bytes()),
+            Value::U64(v) => self.put(&v.to_le_bytes()),
+            Value::I64(v) => self.put(&v.to_le_bytes()),
+            Value::F32(v) => self.put(&v.to_le_bytes()),
+            Value::F64(v) => self.put(&v.to_le_bytes()),
+            Value::Bool(v) => self.put(&[*v as u8]),
+            Value::String(v) => self.put_string(v),
+            Value::Array(values) => {
+                let element_type = values.first().map(value_type).unwrap_or(0);
+                if values.iter().any(|v| value_type(v) != element_type) {
+                    anyhow::bail!("GGUF arrays must hold a single type");
+                }
+                self.put(&element_type.to_le_bytes())?;
+                self.put(&(values.len() as u64).to_le_bytes())?;
+                for value in values {
+                    self.put_value(value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn padded(offset: u64, alignment: u64) -> u64 {
+    (offset + alignment - 1) / alignment * alignment
+}
+
+/// GGUF value type ids.
+fn value_type(value: &Value) -> u32 {
+    match value {
+        Value::U8(_) => 0,
+        Value::I8(_) => 1,
+        Value::U16(_) => 2,
+        Value::I16(_) => 3,
+        Value::U32(_) => 4,
+        Value::I32(_) => 5,
+        Value::F32(_) => 6,
+        Value::Bool(_) => 7,
+        Value::String(_) => 8,
+        Value::Array(_) => 9,
+        Value::U64(_) => 10,
+        Value::I64(_) => 11,
+        Value::F64(_) => 12,
+    }
+}
+
+/// ggml type ids, as stored in GGUF tensor infos.
+fn ggml_type_id(dtype: GgmlDType) -> u32 {
+    match dtype {
+        GgmlDType::F32 => 0,
+        GgmlDType::F16 => 1,
+        GgmlDType::Q4_0 => 2,
+        GgmlDType::Q4_1 => 3,
+        GgmlDType::Q5_0 => 6,
+        GgmlDType::Q5_1 => 7,
+        GgmlDType::Q8_0 => 8,
+        GgmlDType::Q8_1 => 9,
+        GgmlDType::Q2K => 10,
+        GgmlDType::Q3K => 11,
+        GgmlDType::Q4K => 12,
+        GgmlDType::Q5K => 13,
+        GgmlDType::Q6K => 14,
+        GgmlDType::Q8K => 15,
+        GgmlDType::BF16 => 30,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::{gguf_file, QTensor};
+    use candle_core::{Device, Tensor};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_round_trips_through_candle() {
+        let device = Device::Cpu;
+        let weight = Tensor::arange(0f32, 512.0, &device)
+            .unwrap()
+            .reshape((2, 256))
+            .unwrap();
+        let norm = Tensor::ones(3, candle_core::DType::F32, &device).unwrap();
+        let tensors = [
+            ("w", QTensor::quantize(&weight, GgmlDType::Q8_0).unwrap()),
+            ("n", QTensor::quantize(&norm, GgmlDType::F32).unwrap()),
+        ];
+        let metadata = vec![
+            ("general.name".to_string(), Value::String("tiny".into())),
+            ("a.count".to_string(), Value::U32(7)),
+            ("a.flag".to_string(), Value::Bool(true)),
+            (
+                "a.list".to_string(),
+                Value::Array(vec![Value::I32(-1), Value::I32(2)]),
+            ),
+        ];
+        let specs = tensors
+            .iter()
+            .map(|(name, t)| TensorSpec {
+                name: name.to_string(),
+                shape: t.shape().dims().to_vec(),
+                dtype: t.dtype(),
+            })
+            .collect();
+
+        let mut buf = Vec::new();
+        let mut writer = GgufWriter::new(&mut buf, &metadata, specs, DEFAULT_ALIGNMENT).unwrap();
+        for (_, t) in &tensors {
+            writer.write_tensor(&t.data().unwrap()).unwrap();
+        }
+        let size = writer.finish().unwrap();
+        assert_eq!(size as usize, buf.len());
+
+        let mut reader = Cursor::new(&buf);
+        let content = gguf_file::Content::read(&mut reader).unwrap();
+        assert_eq!(content.metadata["a.count"].to_u32().unwrap(), 7);
+        assert!(content.metadata["a.flag"].to_bool().unwrap());
+        assert_eq!(content.metadata["a.list"].to_vec().unwrap().len(), 2);
+
+        let w = content.tensor(&mut reader, "w", &device).unwrap();
+        assert_eq!(w.dtype(), GgmlDType::Q8_0);
+        assert_eq!(w.shape().dims(), &[2, 256]);
+        let values: Vec<f32> = w
+            .dequantize(&device)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        assert!((values[300] - 300.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn test_rejects_wrong_tensor_data() {
+        let spec = TensorSpec {
+            name: "w".into(),
+            shape: vec![2, 32],
+            dtype: GgmlDType::Q8_0,
+        };
+        assert_eq!(spec.size(), 2 * 34);
+        let mut buf = Vec::new();
+        let mut writer = GgufWriter::new(&mut buf, &[], vec![spec], DEFAULT_ALIGNMENT).unwrap();
+        assert!(writer.write_tensor(&[0u8; 10]).is_err());
+        writer.write_tensor(&[0u8; 68]).unwrap();
+        assert!(writer.write_tensor(&[0u8; 68]).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Hugging Face tokenizer files
+//!
+//! Loads `tokenizer.json` through the `tokenizers` crate and picks up the
+//! companion `tokenizer_config.json` (and `chat_template.jinja`, if present)
+//! for the chat template and special tokens, so GGUF weights can be paired
+//! with an upstream tokenizer when the embedded one is wrong.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+const TOKENIZER_CONFIG_FILE: &str = "tokenizer_config.json";
+const CHAT_TEMPLATE_FILE: &str = "chat_template.jinja";
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+/// Well-known end-of-sequence tokens, tried when the config names none.
+const FALLBACK_EOS_TOKENS: &[&str] = &[
+    "<|im_end|>",
+    "<|eot_id|>",
+    "<|endoftext|>",
+    "<end_of_turn>",
+    "<|end|>",
+    "</s>",
+];
+
+/// Settings from `tokenizer_config.json` that affect prompting.
+#[derive(Debug, Clone, Default)]
+pub struct TokenizerConfig {
+    pub chat_template: Option<String>,
+    pub bos_token: Option<String>,
+    pub eos_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawTokenizerConfig {
+    #[serde(default)]
+    chat_template: Option<RawChatTemplate>,
+    #[serde(default)]
+    bos_token: Option<RawToken>,
+    #[serde(default)]
+    eos_token: Option<RawToken>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawChatTemplate {
+    Single(String),
+    Named(Vec<NamedTemplate>),
+}
+
+#[derive(Deserialize)]
+struct NamedTemplate {
+    name: String,
+    template: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawToken {
+    Plain(String),
+    Added { content: String },
+}
+
+impl RawToken {
+    fn into_content(self) -> String {
+        match self {
+            RawToken::Plain(s) => s,
+            RawToken::Added { content } => content,
+        }
+    }
+}
+
+impl TokenizerConfig {
+    pub fn from_json(json: &str) -> Result<Self> {
+        let raw: RawTokenizerConfig =
+            serde_json::from_str(json).context("Invalid tokenizer_config.json")?;
+
+        let chat_template = raw.chat_template.and_then(|t| match t {
+            RawChatTemplate::Single(s) => Some(s),
+            RawChatTemplate::Named(templates) => {
+                let default = templates.iter().position(|t| t.name == "default");
+                templates
+                    .into_iter()
+                    .nth(default.unwrap_or(0))
+                    .map(|t| t.template)
+            }
+        });
+
+        Ok(Self {
+            chat_template,
+            bos_token: raw.bos_token.map(RawToken::into_content),
+            eos_token: raw.eos_token.map(RawToken::into_content),
+        })
+    }
+
+    /// Load the config files that sit next to a `tokenizer.json`.
+    ///
+    /// Missing files are not an error; a standalone `chat_template.jinja`
+    /// takes precedence over the template in `tokenizer_config.json`.
+    pub fn load_beside(tokenizer_path: &Path) -> Result<Self> {
+        let dir = match tokenizer_path.parent() {
+            Some(dir) => dir,
+            None => return Ok(Self::default()),
+        };
+
+        let config_path = dir.join(TOKENIZER_CONFIG_FILE);
+        let mut config = if config_path.exists() {
+            let json = std::fs::read_to_string(&config_path)
+                .with_context(|| format!("Failed to read {:?}", config_path))?;
+            tracing::info!("Loaded tokenizer config from {:?}", config_path);
+            Self::from_json(&json)?
+        } else {
+            Self::default()
+        };
+
+        let template_path = dir.join(CHAT_TEMPLATE_FILE);
+        if template_path.exists() {
+            config.chat_template = Some(
+                std::fs::read_to_string(&template_path)
+                    .with_context(|| format!("Failed to read {:?}", template_path))?,
+            );
+        }
+
+        Ok(config)
+    }
+}
+
+/// Returns true if the file starts with the GGUF magic.
+pub fn is_gguf_file(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == GGUF_MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Resolve the EOS token id for a Hugging Face tokenizer.
+pub fn resolve_eos_token(
+    tokenizer: &tokenizers::Tokenizer,
+    config: &TokenizerConfig,
+) -> Option<u32> {
+    config
+        .eos_token
+        .as_deref()
+        .and_then(|t| tokenizer.token_to_id(t))
+        .or_else(|| {
+            FALLBACK_EOS_TOKENS
+                .iter()
+                .find_map(|t| tokenizer.token_to_id(t))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_plain_tokens() {
+        let config = TokenizerConfig::from_json(
+            r#"{"bos_token": "<s>", "eos_token": "</s>", "chat_template": "{{ messages }}"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.bos_token.as_deref(), Some("<s>"));
+        assert_eq!(config.eos_token.as_deref(), Some("</s>"));
+        assert_eq!(config.chat_template.as_deref(), Some("{{ messages }}"));
+    }
+
+    #[test]
+    fn test_config_added_token_objects_and_named_templates() {
+        let config = TokenizerConfig::from_json(
+            r#"{
+                "bos_token": {"content": "<|begin|>", "lstrip": false, "special": true},
+                "eos_token": null,
+                "chat_template": [
+                    {"name": "tool_use", "template": "tools"},
+                    {"name": "default", "template": "chat"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.bos_token.as_deref(), Some("<|begin|>"));
+        assert_eq!(config.eos_token, None);
+        assert_eq!(config.chat_template.as_deref(), Some("chat"));
+    }
+}
@@ -0,0 +1,350 @@
+//! Importance Matrices
+//!
+//! Calibration text is run through the model in windows of `ctx` tokens
+//! and, for every matmul, the squares of each input column are summed over
+//! all tokens. Their means tell the quantizer which columns of a weight
+//! matter most. Decoder models run the same forward pass as generation,
+//! output projection included; encoder models run the
+//! [`Embedder`](super::embedding::Embedder) pass.
+//!
+//! Files use llama.cpp's original `imatrix.dat` layout, which
+//! `llama-quantize --imatrix` also reads:
+//!
+//! ```text
+//! i32 entry count
+//! per entry: i32 name length, name, i32 calls, i32 values, f32 values
+//! i32 chunks processed, i32 dataset name length, dataset name
+//! ```
+//!
+//! where each value is the mean square of its column times `calls`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use candle_core::Tensor;
+
+use super::embedding::Embedder;
+use super::layers::Observer;
+use super::loader::Model;
+
+/// Window settings for [`Imatrix::collect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImatrixOptions {
+    /// Tokens per forward pass.
+    pub ctx: usize,
+    /// Stop after this many windows.
+    pub max_chunks: Option<usize>,
+}
+
+impl Default for ImatrixOptions {
+    fn default() -> Self {
+        Self {
+            ctx: 512,
+            max_chunks: None,
+        }
+    }
+}
+
+/// Progress of [`Imatrix::collect`], after each window.
+#[derive(Debug, Clone)]
+pub struct ImatrixChunk {
+    pub index: usize,
+    pub total: usize,
+    pub tokens: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    sum_sq: Vec<f64>,
+    /// Rows summed into `sum_sq`.
+    count: u64,
+}
+
+/// Per-column activation statistics for each matmul weight of a model.
+#[derive(Debug, Clone, Default)]
+pub struct Imatrix {
+    entries: BTreeMap<String, Entry>,
+    chunks: usize,
+    dataset: String,
+}
+
+impl Imatrix {
+    /// Run `tokens` through `model` in windows of `options.ctx` tokens.
+    /// A final partial window is dropped unless it is the only one.
+    pub fn collect<F>(
+        model: &Model,
+        tokens: &[u32],
+        options: &ImatrixOptions,
+        dataset: &str,
+        on_chunk: F,
+    ) -> Result<Self>
+    where
+        F: FnMut(&ImatrixChunk),
+    {
+        let max_tokens = model.metadata().context_length;
+        Self::collect_windows(
+            max_tokens,
+            tokens,
+            options,
+            dataset,
+            on_chunk,
+            |window, observe| model.forward_observed(window, observe).map(drop),
+        )
+    }
+
+    /// [`Imatrix::collect`] for an encoder model.
+    pub fn collect_encoder<F>(
+        embedder: &Embedder,
+        tokens: &[u32],
+        options: &ImatrixOptions,
+        dataset: &str,
+        on_chunk: F,
+    ) -> Result<Self>
+    where
+        F: FnMut(&ImatrixChunk),
+    {
+        let max_tokens = embedder.max_tokens();
+        Self::collect_windows(
+            max_tokens,
+            tokens,
+            options,
+            dataset,
+            on_chunk,
+            |window, observe| embedder.forward_observed(&[window], observe).map(drop),
+        )
+    }
+
+    fn collect_windows<F, R>(
+        max_tokens: usize,
+        tokens: &[u32],
+        options: &ImatrixOptions,
+        dataset: &str,
+        mut on_chunk: F,
+        mut run: R,
+    ) -> Result<Self>
+    where
+        F: FnMut(&ImatrixChunk),
+        R: FnMut(&[u32], Observer) -> Result<()>,
+    {
+        if options.ctx == 0 {
+            anyhow::bail!("Window must be at least 1 token");
+        }
+        if options.ctx > max_tokens {
+            anyhow::bail!(
+                "Window of {} tokens exceeds the model's context of {}",
+                options.ctx,
+                max_tokens
+            );
+        }
+        if tokens.is_empty() {
+            anyhow::bail!("No calibration tokens");
+        }
+
+        let mut windows: Vec<&[u32]> = tokens.chunks(options.ctx).collect();
+        if windows.len() > 1 && windows.last().is_some_and(|w| w.len() < options.ctx) {
+            windows.pop();
+        }
+        if let Some(max) = options.max_chunks {
+            windows.truncate(max);
+        }
+
+        let mut imatrix = Self {
+            dataset: dataset.to_string(),
+            ..Default::default()
+        };
+        for (index, window) in windows.iter().enumerate() {
+            run(window, &mut |name, xs| imatrix.add(name, xs))?;
+            imatrix.chunks += 1;
+            on_chunk(&ImatrixChunk {
+                index,
+                total: windows.len(),
+                tokens: window.len(),
+            });
+        }
+        Ok(imatrix)
+    }
+
+    /// Add the rows of `xs`, the input of the matmul with weight `name`.
+    fn add(&mut self, name: &str, xs: &Tensor) -> Result<()> {
+        let columns = xs.dim(candle_core::D::Minus1)?;
+        let rows = xs.elem_count() / columns.max(1);
+        let xs = xs.reshape((rows, columns))?;
+        let sums: Vec<f32> = xs.sqr()?.sum(0)?.to_vec1()?;
+
+        let entry = self.entries.entry(name.to_string()).or_default();
+        if entry.sum_sq.is_empty() {
+            entry.sum_sq = vec![0.0; columns];
+        }
+        if entry.sum_sq.len() != columns {
+            anyhow::bail!(
+                "{} had inputs of {} columns, now {}",
+                name,
+                entry.sum_sq.len(),
+                columns
+            );
+        }
+        for (total, sum) in entry.sum_sq.iter_mut().zip(sums) {
+            *total += sum as f64;
+        }
+        entry.count += rows as u64;
+        Ok(())
+    }
+
+    /// Mean squared input of each column of the weight `name`.
+    pub fn weights(&self, name: &str) -> Option<Vec<f32>> {
+        let entry = self.entries.get(name)?;
+        let count = entry.count.max(1) as f64;
+        Some(entry.sum_sq.iter().map(|s| (s / count) as f32).collect())
+    }
+
+    /// Names of the weights with statistics.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(|k| k.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Windows the statistics were collected over.
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+        let mut w = BufWriter::new(file);
+        let calls = self.chunks.max(1) as f64;
+
+        write_i32(&mut w, self.entries.len())?;
+        for (name, entry) in &self.entries {
+            write_i32(&mut w, name.len())?;
+            w.write_all(name.as_bytes())?;
+            write_i32(&mut w, self.chunks.max(1))?;
+            write_i32(&mut w, entry.sum_sq.len())?;
+            let count = entry.count.max(1) as f64;
+            for sum in &entry.sum_sq {
+                w.write_all(&((sum / count * calls) as f32).to_le_bytes())?;
+            }
+        }
+        write_i32(&mut w, self.chunks)?;
+        write_i32(&mut w, self.dataset.len())?;
+        w.write_all(self.dataset.as_bytes())?;
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        Self::read(BufReader::new(file))
+            .with_context(|| format!("{:?} is not an imatrix.dat file", path))
+    }
+
+    fn read<R: Read>(mut r: R) -> Result<Self> {
+        let mut imatrix = Self::default();
+        let n = read_len(&mut r)?;
+        for _ in 0..n {
+            let name = read_string(&mut r)?;
+            let calls = read_len(&mut r)?.max(1) as f64;
+            let len = read_len(&mut r)?;
+            let mut sum_sq = Vec::with_capacity(len);
+            for _ in 0..len {
+                let mut buf = [0u8; 4];
+                r.read_exact(&mut buf)?;
+                sum_sq.push(f32::from_le_bytes(buf) as f64 / calls);
+            }
+            imatrix.entries.insert(name, Entry { sum_sq, count: 1 });
+        }
+
+        // Files from older llama.cpp versions end after the entries.
+        if let Ok(chunks) = read_len(&mut r) {
+            imatrix.chunks = chunks;
+            imatrix.dataset = read_string(&mut r).unwrap_or_default();
+        }
+        Ok(imatrix)
+    }
+}
+
+fn write_i32<W: Write>(w: &mut W, value: usize) -> Result<()> {
+    let value = i32::try_from(value).context("Value too large for imatrix.dat")?;
+    w.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_len<R: Read>(r: &mut R) -> Result<usize> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    let value = i32::from_le_bytes(buf);
+    usize::try_from(value).map_err(|_| anyhow::anyhow!("Negative length {}", value))
+}
+
+fn read_string<R: Read>(r: &mut R) -> Result<String> {
+    let len = read_len(r)?;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn test_accumulates_mean_squares_and_round_trips() {
+        let device = Device::Cpu;
+        let mut imatrix = Imatrix::default();
+        let a = Tensor::new(&[[[1f32, 2.0], [3.0, 0.0]]], &device).unwrap();
+        let b = Tensor::new(&[[[1f32, -2.0]]], &device).unwrap();
+        imatrix.add("blk.0.attn_q.weight", &a).unwrap();
+        imatrix.add("blk.0.attn_q.weight", &b).unwrap();
+        imatrix.chunks = 2;
+        imatrix.dataset = "calib.txt".into();
+
+        // (1 + 9 + 1) / 3 and (4 + 0 + 4) / 3
+        let weights = imatrix.weights("blk.0.attn_q.weight").unwrap();
+        assert!((weights[0] - 11.0 / 3.0).abs() < 1e-6);
+        assert!((weights[1] - 8.0 / 3.0).abs() < 1e-6);
+        assert!(imatrix
+            .add(
+                "blk.0.attn_q.weight",
+                &Tensor::ones((1, 3), candle_core::DType::F32, &device).unwrap()
+            )
+            .is_err());
+
+        let path = std::env::temp_dir().join(format!("oxide-imatrix-{}.dat", std::process::id()));
+        imatrix.save(&path).unwrap();
+        let loaded = Imatrix::load(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.chunks(), 2);
+        assert_eq!(loaded.dataset, "calib.txt");
+        let reloaded = loaded.weights("blk.0.attn_q.weight").unwrap();
+        assert!((reloaded[0] - weights[0]).abs() < 1e-5);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_reads_files_without_trailer() {
+        let mut buf = Vec::new();
+        for v in [1i32, 1] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.push(b'w');
+        for v in [4i32, 1] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.extend_from_slice(&8f32.to_le_bytes());
+
+        let imatrix = Imatrix::read(&buf[..]).unwrap();
+        assert_eq!(imatrix.weights("w").unwrap(), vec![2.0]);
+        assert_eq!(imatrix.chunks(), 0);
+        assert!(Imatrix::read(&[0xff, 0xff, 0xff, 0xff][..]).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! GGUF Inspection
+//!
+//! Reads a GGUF header (every shard, for split models) and reports all of
+//! it: metadata, tensor table, size per quantization type, chat template and
+//! special tokens. Only the header is parsed; tensor data is never touched.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use candle_core::quantized::gguf_file::{self, Value};
+use candle_core::quantized::GgmlDType;
+use memmap2::Mmap;
+use serde::Serialize;
+
+use super::shards;
+
+/// Metadata keys that name special tokens, with the label shown for each.
+const SPECIAL_TOKEN_KEYS: &[(&str, &str)] = &[
+    ("bos", "tokenizer.ggml.bos_token_id"),
+    ("eos", "tokenizer.ggml.eos_token_id"),
+    ("eot", "tokenizer.ggml.eot_token_id"),
+    ("unk", "tokenizer.ggml.unknown_token_id"),
+    ("pad", "tokenizer.ggml.padding_token_id"),
+    ("sep", "tokenizer.ggml.seperator_token_id"),
+    ("mask", "tokenizer.ggml.mask_token_id"),
+];
+
+/// Everything in a GGUF header.
+#[derive(Debug, Clone, Serialize)]
+pub struct GgufInspection {
+    pub files: Vec<PathBuf>,
+    pub version: u32,
+    pub file_size: u64,
+    /// Every metadata key of the first shard, sorted by key.
+    pub metadata: BTreeMap<String, serde_json::Value>,
+    pub tensors: Vec<TensorEntry>,
+    pub dtype_totals: Vec<DtypeTotal>,
+    pub chat_template: Option<String>,
+    pub special_tokens: Vec<SpecialToken>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TensorEntry {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub dtype: String,
+    pub size_bytes: u64,
+    /// Index of the file holding the tensor, into `files`.
+    pub file: usize,
+    /// Byte offset of the tensor data from the start of its file.
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DtypeTotal {
+    pub dtype: String,
+    pub tensors: usize,
+    pub parameters: u64,
+    pub size_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SpecialToken {
+    pub name: String,
+    pub id: u32,
+    pub piece: Option<String>,
+}
+
+/// Read the header of the GGUF model at `path`.
+pub fn inspect(path: &Path) -> Result<GgufInspection> {
+    let files = shards::shard_paths(path)?;
+
+    let mut first: Option<gguf_file::Content> = None;
+    let mut tensors = Vec::new();
+    let mut file_size = 0;
+
+    for (index, file_path) in files.iter().enumerate() {
+        let file = File::open(file_path)
+            .with_context(|| format!("Failed to open model file: {:?}", file_path))?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let content = gguf_file::Content::read(&mut Cursor::new(&mmap))
+            .with_context(|| format!("Failed to read GGUF file: {:?}", file_path))?;
+        file_size += mmap.len() as u64;
+
+        for (name, info) in &content.tensor_infos {
+            let shape = info.shape.dims().to_vec();
+            tensors.push(TensorEntry {
+                name: name.clone(),
+                size_bytes: tensor_size(info.ggml_dtype, info.shape.elem_count()),
+                dtype: dtype_name(info.ggml_dtype),
+                shape,
+                file: index,
+                offset: content.tensor_data_offset + info.offset,
+            });
+        }
+
+        first.get_or_insert(content);
+    }
+
+    let content = first.context("No GGUF files to inspect")?;
+    tensors.sort_by_key(|t| (t.file, t.offset));
+
+    let version = match content.magic {
+        gguf_file::VersionedMagic::GgufV1 => 1,
+        gguf_file::VersionedMagic::GgufV2 => 2,
+        gguf_file::VersionedMagic::GgufV3 => 3,
+    };
+
+    let metadata = content
+        .metadata
+        .iter()
+        .map(|(k, v)| (k.clone(), to_json(v)))
+        .collect();
+
+    let chat_template = content
+        .metadata
+        .get("tokenizer.chat_template")
+        .and_then(|v| v.to_string().ok().cloned());
+
+    Ok(GgufInspection {
+        files,
+        version,
+        file_size,
+        metadata,
+        dtype_totals: dtype_totals(&tensors),
+        tensors,
+        chat_template,
+        special_tokens: special_tokens(&content.metadata),
+    })
+}
+
+/// llama.cpp's name for a quantization type, e.g. `Q4_K`.
+pub fn dtype_name(dtype: GgmlDType) -> String {
+    let name = format!("{:?}", dtype);
+    match name.strip_suffix('K') {
+        Some(base) if base.starts_with('Q') && !base.contains('_') => format!("{}_K", base),
+        _ => name,
+    }
+}
+
+fn tensor_size(dtype: GgmlDType, elements: usize) -> u64 {
+    (elements / dtype.block_size() * dtype.type_size()) as u64
+}
+
+fn dtype_totals(tensors: &[TensorEntry]) -> Vec<DtypeTotal> {
+    let mut totals: BTreeMap<&str, DtypeTotal> = BTreeMap::new();
+    for tensor in tensors {
+        let total = totals.entry(&tensor.dtype).or_insert_with(|| DtypeTotal {
+            dtype: tensor.dtype.clone(),
+            tensors: 0,
+            parameters: 0,
+            size_bytes: 0,
+        });
+        total.tensors += 1;
+        total.parameters += tensor.shape.iter().product::<usize>() as u64;
+        total.size_bytes += tensor.size_bytes;
+    }
+
+    let mut totals: Vec<DtypeTotal> = totals.into_values().collect();
+    totals.sort_by_key(|t| std::cmp::Reverse(t.size_bytes));
+    totals
+}
+
+fn special_tokens(metadata: &HashMap<String, Value>) -> Vec<SpecialToken> {
+    let tokens = match metadata.get("tokenizer.ggml.tokens") {
+        Some(Value::Array(tokens)) => Some(tokens),
+        _ => None,
+    };
+
+    SPECIAL_TOKEN_KEYS
+        .iter()
+        .filter_map(|(name, key)| {
+            let id = metadata.get(*key)?.to_u32().ok()?;
+            let piece = tokens
+                .and_then(|t| t.get(id as usize))
+                .and_then(|v| v.to_string().ok().cloned());
+            Some(SpecialToken {
+                name: name.to_string(),
+                id,
+                piece,
+            })
+        })
+        .collect()
+}
+
+fn to_json(value: &Value) -> serde_json::Value {
+    use serde_json::Value as Json;
+
+    match value {
+        Value::U8(v) => Json::from(*v),
+        Value::I8(v) => Json::from(*v),
+        Value::U16(v) => Json::from(*v),
+        Value::I16(v) => Json::from(*v),
+        Value::U32(v) => Json::from(*v),
+        Value::I32(v) => Json::from(*v),
+        Value::U64(v) => Json::from(*v),
+        Value::I64(v) => Json::from(*v),
+        Value::F32(v) => Json::from(*v),
+        Value::F64(v) => Json::from(*v),
+        Value::Bool(v) => Json::from(*v),
+        Value::String(v) => Json::from(v.as_str()),
+        Value::Array(values) => Json::Array(values.iter().map(to_json).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::QTensor;
+    use candle_core::{Device, Tensor};
+
+    #[test]
+    fn test_dtype_names() {
+        assert_eq!(dtype_name(GgmlDType::Q4K), "Q4_K");
+        assert_eq!(dtype_name(GgmlDType::Q8_0), "Q8_0");
+        assert_eq!(dtype_name(GgmlDType::F16), "F16");
+    }
+
+    #[test]
+    fn test_inspect_reports_tensors_and_special_tokens() {
+        let path = std::env::temp_dir().join(format!("oxide-inspect-{}.gguf", std::process::id()));
+
+        let tokens = Value::Array(
+            ["<unk>", "<s>", "</s>"]
+                .iter()
+                .map(|t| Value::String(t.to_string()))
+                .collect(),
+        );
+        let eos = Value::U32(2);
+        let template = Value::String("{{ messages }}".to_string());
+        let metadata = [
+            ("tokenizer.ggml.tokens", &tokens),
+            ("tokenizer.ggml.eos_token_id", &eos),
+            ("tokenizer.chat_template", &template),
+        ];
+
+        let weight = QTensor::quantize(
+            &Tensor::zeros((4, 8), candle_core::DType::F32, &Device::Cpu).unwrap(),
+            GgmlDType::F32,
+        )
+        .unwrap();
+        let mut file = File::create(&path).unwrap();
+        gguf_file::write(&mut file, &metadata, &[("blk.0.weight", &weight)]).unwrap();
+
+        let report = inspect(&path).unwrap();
+        assert_eq!(report.metadata.len(), 3);
+        assert_eq!(report.chat_template.as_deref(), Some("{{ messages }}"));
+
+        assert_eq!(report.tensors.len(), 1);
+        assert_eq!(report.tensors[0].dtype, "F32");
+        assert_eq!(report.tensors[0].size_bytes, 4 * 8 * 4);
+        assert_eq!(report.dtype_totals[0].parameters, 32);
+
+        assert_eq!(report.special_tokens.len(), 1);
+        assert_eq!(report.special_tokens[0].name, "eos");
+        assert_eq!(report.special_tokens[0].piece.as_deref(), Some("</s>"));
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,223 @@
+//! Layers Shared by the Forward Passes
+//!
+//! Weight reading, projections with an observer hook, norms and RoPE
+//! tables, with linear and YaRN position scaling, used by both the decoder
+//! ([`super::decoder`]) and the encoder ([`super::embedding`]) forward
+//! passes.
+
+use anyhow::{Context, Result};
+use candle_core::quantized::{gguf_file, QMatMul, QTensor};
+use candle_core::{Device, Module, Tensor};
+use candle_nn::LayerNorm;
+
+use super::loader::RopeParams;
+use super::shards::ShardReader;
+
+/// YaRN keeps the frequencies of dimensions that rotate more than this many
+/// times over the original context.
+const YARN_BETA_FAST: f64 = 32.0;
+/// YaRN fully interpolates dimensions that rotate fewer times than this.
+const YARN_BETA_SLOW: f64 = 1.0;
+
+/// Called with the name of each matmul weight and its input.
+pub(crate) type Observer<'a> = &'a mut dyn FnMut(&str, &Tensor) -> Result<()>;
+
+/// A projection, optionally with a bias.
+pub(crate) struct Linear {
+    /// Name of the weight tensor.
+    name: String,
+    weight: QMatMul,
+    bias: Option<Tensor>,
+}
+
+impl Linear {
+    pub(crate) fn forward(&self, xs: &Tensor, observe: Observer) -> Result<Tensor> {
+        observe(&self.name, xs)?;
+        let ys = self.weight.forward(xs)?;
+        Ok(match &self.bias {
+            Some(bias) => ys.broadcast_add(bias)?,
+            None => ys,
+        })
+    }
+}
+
+pub(crate) enum Norm {
+    Rms { weight: Tensor, eps: f32 },
+    Layer(LayerNorm),
+}
+
+impl Norm {
+    pub(crate) fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        Ok(match self {
+            Self::Rms { weight, eps } => candle_nn::ops::rms_norm(xs, weight, *eps)?,
+            Self::Layer(norm) => norm.forward(xs)?,
+        })
+    }
+}
+
+/// Reads named tensors out of a GGUF.
+pub(crate) struct Weights<'a> {
+    content: &'a gguf_file::Content,
+    reader: ShardReader<'a>,
+    device: Device,
+}
+
+impl<'a> Weights<'a> {
+    pub(crate) fn new(content: &'a gguf_file::Content, reader: ShardReader<'a>) -> Self {
+        Self {
+            content,
+            reader,
+            device: Device::Cpu,
+        }
+    }
+
+    pub(crate) fn has(&self, name: &str) -> bool {
+        self.content.tensor_infos.contains_key(name)
+    }
+
+    /// Dimensions of a tensor, outermost first, without reading it.
+    pub(crate) fn dims(&self, name: &str) -> Result<Vec<usize>> {
+        let info = self
+            .content
+            .tensor_infos
+            .get(name)
+            .with_context(|| format!("Missing tensor {}", name))?;
+        Ok(info.shape.dims().to_vec())
+    }
+
+    pub(crate) fn qtensor(&mut self, name: &str) -> Result<QTensor> {
+        self.content
+            .tensor(&mut self.reader, name, &self.device)
+            .with_context(|| format!("Failed to read tensor {}", name))
+    }
+
+    pub(crate) fn dense(&mut self, name: &str) -> Result<Tensor> {
+        Ok(self.qtensor(name)?.dequantize(&self.device)?)
+    }
+
+    pub(crate) fn optional(&mut self, name: &str) -> Result<Option<Tensor>> {
+        if self.has(name) {
+            Ok(Some(self.dense(name)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// `<prefix>.weight` and, if present, `<prefix>.bias`.
+    pub(crate) fn linear(&mut self, prefix: &str) -> Result<Linear> {
+        let name = format!("{}.weight", prefix);
+        let weight = QMatMul::from_qtensor(self.qtensor(&name)?)?;
+        let bias = self.optional(&format!("{}.bias", prefix))?;
+        Ok(Linear { name, weight, bias })
+    }
+
+    pub(crate) fn norm(&mut self, prefix: &str, encoder: bool, eps: f32) -> Result<Norm> {
+        let weight = self.dense(&format!("{}.weight", prefix))?;
+        Ok(if encoder {
+            let bias = match self.optional(&format!("{}.bias", prefix))? {
+                Some(bias) => bias,
+                None => weight.zeros_like()?,
+            };
+            Norm::Layer(LayerNorm::new(weight, bias, eps as f64))
+        } else {
+            Norm::Rms { weight, eps }
+        })
+    }
+}
+
+/// How RoPE positions are stretched beyond the trained context, from
+/// `<arch>.rope.scaling.*`. NTK-aware scaling is not listed: it is a larger
+/// frequency base.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RopeScale {
+    /// Positions divided by `factor`.
+    Linear { factor: f32 },
+    /// YaRN: low frequencies are interpolated by `factor`, high ones kept,
+    /// with a ramp between, and the tables are scaled by `0.1 ln(factor) + 1`.
+    Yarn {
+        factor: f32,
+        original_context: usize,
+    },
+}
+
+impl RopeScale {
+    /// The scaling `rope` asks for; `trained` stands in for a missing
+    /// original context length.
+    pub(crate) fn from_params(rope: &RopeParams, trained: usize) -> Result<Option<Self>> {
+        let factor = rope.scaling_factor.unwrap_or(1.0);
+        let scale = match rope.scaling_type.as_deref() {
+            None | Some("ntk") => None,
+            Some(_) if factor == 1.0 => None,
+            Some(_) if factor <= 0.0 => anyhow::bail!("RoPE scaling factor must be positive"),
+            Some("linear") => Some(Self::Linear { factor }),
+            Some("yarn") => Some(Self::Yarn {
+                factor,
+                original_context: rope.original_context_length.unwrap_or(trained),
+            }),
+            Some(other) => anyhow::bail!("RoPE scaling '{}' is not supported", other),
+        };
+        Ok(scale)
+    }
+}
+
+/// RoPE `cos` and `sin` tables for positions `start..start + len`.
+pub(crate) fn rope_tables(
+    base: f32,
+    head_dim: usize,
+    scale: Option<RopeScale>,
+    start: usize,
+    len: usize,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let half = head_dim / 2;
+    let mut inv_freq: Vec<f64> = (0..half)
+        .map(|i| 1.0 / (base as f64).powf(2.0 * i as f64 / head_dim as f64))
+        .collect();
+    let mut mscale = 1.0;
+    match scale {
+        None => {}
+        Some(RopeScale::Linear { factor }) => {
+            inv_freq.iter_mut().for_each(|f| *f /= factor as f64);
+        }
+        Some(RopeScale::Yarn {
+            factor,
+            original_context,
+        }) => {
+            // Dimensions that turn fewer than `rotations` times over the
+            // original context, as in Hugging Face's implementation.
+            let correction_dim = |rotations: f64| {
+                let ratio = original_context as f64 / (rotations * 2.0 * std::f64::consts::PI);
+                head_dim as f64 * ratio.ln() / (2.0 * (base as f64).ln())
+            };
+            let low = correction_dim(YARN_BETA_FAST).floor().max(0.0);
+            let high = correction_dim(YARN_BETA_SLOW)
+                .ceil()
+                .min(head_dim as f64 - 1.0);
+            let span = if high > low { high - low } else { 0.001 };
+            for (i, freq) in inv_freq.iter_mut().enumerate() {
+                let interpolated = ((i as f64 - low) / span).clamp(0.0, 1.0);
+                *freq *= 1.0 - interpolated + interpolated / factor as f64;
+            }
+            if factor > 1.0 {
+                mscale = 0.1 * (factor as f64).ln() + 1.0;
+            }
+        }
+    }
+
+    let inv_freq: Vec<f32> = inv_freq.into_iter().map(|f| f as f32).collect();
+    let mscale = mscale as f32;
+    let mut cos = Vec::with_capacity(len * half);
+    let mut sin = Vec::with_capacity(len * half);
+    for pos in start..start + len {
+        for freq in &inv_freq {
+            let angle = pos as f32 * freq;
+            cos.push(angle.cos() * mscale);
+            sin.push(angle.sin() * mscale);
+        }
+    }
+    let shape = (len, half);
+    Ok((
+        Tensor::from_vec(cos, shape, device)?,
+        Tensor::from_vec(sin, shape, device)?,
+    ))
+}
@@ -1,14 +1,27 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Cursor, Seek};
-use std::path::PathBuf;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use candle_core::quantized::gguf_file;
-use candle_core::{Device, Tensor};
-use candle_transformers::models::quantized_lfm2::ModelWeights as Lfm2Model;
-use candle_transformers::models::quantized_llama::ModelWeights as LlamaModel;
+use candle_core::quantized::gguf_file::{self, TensorInfo, Value};
+use candle_core::quantized::{GgmlDType, QMatMul, QTensor};
+use candle_core::{DType, Device, Module, Tensor};
+use candle_transformers::models::quantized_gemma3::{self, ModelWeights as GemmaModel};
+use candle_transformers::models::quantized_llama::{self, ModelWeights as LlamaModel};
+use candle_transformers::models::quantized_phi3::ModelWeights as Phi3Model;
+use candle_transformers::models::quantized_qwen2::ModelWeights as Qwen2Model;
+use candle_transformers::models::quantized_qwen3::ModelWeights as Qwen3Model;
 use memmap2::Mmap;
 
+use super::context::ContextOverrides;
+use super::decoder::{Cache, Decoder};
+use super::embedding::{self, EmbedOptions, Pooling};
+use super::layers::{Observer, RopeScale, Weights};
+use super::lora::{self, LoraAdapter, LoraSpec};
+use super::shards::{self, ShardReader, ShardedGguf};
+
 #[derive(Debug, Clone)]
 pub struct GgufMetadata {
     pub name: String,
@@ -16,20 +29,242 @@ pub struct GgufMetadata {
     pub n_layer: usize,
     pub n_embd: usize,
     pub vocab_size: usize,
+    /// Context length in use; see `trained_context_length` for the one the
+    /// model was trained with.
     pub context_length: usize,
+    pub trained_context_length: usize,
     pub file_size: u64,
     pub chat_template: Option<String>,
     pub quantization: Option<String>,
+    /// Query heads per layer.
+    pub head_count: usize,
+    /// Key/value heads per layer; smaller than `head_count` with GQA.
+    pub head_count_kv: usize,
+    /// Size of one attention head.
+    pub head_dim: usize,
+    pub feed_forward_length: Option<usize>,
+    pub rope: RopeParams,
+    /// Experts per MoE layer, and how many are used for each token.
+    pub expert_count: Option<usize>,
+    pub expert_used_count: Option<usize>,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: Option<u32>,
+    pub pad_token_id: Option<u32>,
+    /// `tokenizer.ggml.model`, e.g. `llama` (SPM) or `gpt2` (BPE).
+    pub tokenizer_model: Option<String>,
+    /// Every metadata key except the large `tokenizer.ggml.*` arrays, for
+    /// the typed accessors below.
+    values: Arc<HashMap<String, Value>>,
+}
+
+/// Rotary position embedding settings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RopeParams {
+    pub freq_base: Option<f32>,
+    pub dimension_count: Option<usize>,
+    /// `linear`, `yarn`, ... when the context was extended by scaling.
+    pub scaling_type: Option<String>,
+    pub scaling_factor: Option<f32>,
+    pub original_context_length: Option<usize>,
+}
+
+impl GgufMetadata {
+    /// The raw value of a metadata key.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.values.get(key)
+    }
+
+    /// An integer key of any width, as `u64`.
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(value_to_u64)
+    }
+
+    pub fn get_u32(&self, key: &str) -> Option<u32> {
+        self.get_u64(key).and_then(|v| u32::try_from(v).ok())
+    }
+
+    /// A float key, or an integer one converted to float.
+    pub fn get_f32(&self, key: &str) -> Option<f32> {
+        match self.get(key)? {
+            Value::F32(v) => Some(*v),
+            Value::F64(v) => Some(*v as f32),
+            other => value_to_u64(other).map(|v| v as f32),
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        match self.get(key)? {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)? {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// A key under the model's architecture prefix, e.g. `rope.freq_base`
+    /// for `llama.rope.freq_base`.
+    pub fn arch_value(&self, suffix: &str) -> Option<&Value> {
+        self.get(&format!("{}.{}", self.architecture, suffix))
+    }
+
+    /// Bytes of K and V cache needed per token of context.
+    pub fn kv_bytes_per_token(&self, bytes_per_value: usize) -> usize {
+        2 * self.n_layer * self.head_count_kv * self.head_dim * bytes_per_value
+    }
+}
+
+fn value_to_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::U8(v) => Some(*v as u64),
+        Value::U16(v) => Some(*v as u64),
+        Value::U32(v) => Some(*v as u64),
+        Value::U64(v) => Some(*v),
+        Value::I8(v) => u64::try_from(*v).ok(),
+        Value::I16(v) => u64::try_from(*v).ok(),
+        Value::I32(v) => u64::try_from(*v).ok(),
+        Value::I64(v) => u64::try_from(*v).ok(),
+        _ => None,
+    }
+}
+
+/// A per-model count, or the largest entry of a per-layer array (LFM2 lists
+/// KV heads per layer, with zero for its convolution layers).
+fn value_to_count(value: &Value) -> Option<usize> {
+    match value {
+        Value::Array(values) => values.iter().filter_map(value_to_u64).max(),
+        other => value_to_u64(other),
+    }
+    .map(|v| v as usize)
+}
+
+/// Values of `general.architecture` that can be loaded.
+///
+/// Mistral and Mixtral GGUFs use the `llama` architecture; Mixtral's experts
+/// are picked up from `llama.expert_count`.
+pub const SUPPORTED_ARCHITECTURES: &[&str] = &[
+    "llama", "lfm2", "qwen2", "qwen3", "phi3", "gemma2", "gemma3",
+];
+
+/// The weights of a model, as one of candle's quantized implementations or
+/// the built-in [`Decoder`].
+///
+/// candle's models are built with an identity `output.weight`, so their
+/// forward pass returns the final hidden state of the last token; the real
+/// output projection is kept alongside them.
+pub(crate) enum ModelInner {
+    Llama(LlamaModel, QMatMul),
+    Qwen2(Qwen2Model, QMatMul),
+    Qwen3(Qwen3Model, QMatMul),
+    Phi3(Phi3Model, QMatMul),
+    Gemma(GemmaModel, QMatMul),
+    /// The decoder and the keys, values and convolution inputs of the
+    /// tokens seen so far.
+    Decoder(Decoder, Cache),
+}
+
+/// Which forward pass the weights are built for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    /// candle's implementation of the architecture.
+    Candle,
+    /// The built-in [`Decoder`], which can also return the logits of every
+    /// position and sees every matmul input.
+    Decoder,
+}
+
+impl Backend {
+    /// candle's implementation, unless the metadata asks for something it
+    /// cannot do. Fails on RoPE scaling that neither backend implements.
+    fn for_metadata(metadata: &GgufMetadata) -> Result<Self> {
+        let scaled = RopeScale::from_params(&metadata.rope, metadata.trained_context_length)?;
+        let reason = match metadata.architecture.as_str() {
+            _ if scaled.is_some() => Some("candle's models do not scale RoPE positions"),
+            "gemma2" => Some("candle has no quantized Gemma 2 model"),
+            "lfm2" => Some(
+                "candle's LFM2 model carries its convolution state into a new sequence \
+                 that starts with a single token",
+            ),
+            "llama" if metadata.context_length > quantized_llama::MAX_SEQ_LEN => {
+                Some("candle's LLaMA model stops at 4096 positions")
+            }
+            "gemma3" if metadata.context_length > quantized_gemma3::MAX_SEQ_LEN => {
+                Some("candle's Gemma 3 model stops at 131072 positions")
+            }
+            "phi3" if metadata.rope.freq_base.is_some_and(|base| base != 10_000.0) => {
+                Some("candle's Phi-3 model ignores rope.freq_base")
+            }
+            _ => None,
+        };
+        match reason {
+            Some(reason) => {
+                tracing::info!("Using the built-in decoder: {}", reason);
+                Ok(Self::Decoder)
+            }
+            None => Ok(Self::Candle),
+        }
+    }
 }
 
-pub enum ModelInner {
-    Llama(LlamaModel),
-    Lfm2(Lfm2Model),
+impl ModelInner {
+    /// Logits of the last of `tokens`, placed at positions `pos..`.
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor> {
+        match self {
+            Self::Decoder(decoder, cache) => decoder.forward(tokens, pos, cache),
+            _ => {
+                let hidden = self.hidden(tokens, pos)?;
+                Ok(self.head().forward(&hidden)?)
+            }
+        }
+    }
+
+    /// The output projection kept alongside candle's models.
+    fn head(&self) -> &QMatMul {
+        match self {
+            Self::Llama(_, head)
+            | Self::Qwen2(_, head)
+            | Self::Qwen3(_, head)
+            | Self::Phi3(_, head)
+            | Self::Gemma(_, head) => head,
+            Self::Decoder(..) => unreachable!("the decoder applies its own output projection"),
+        }
+    }
+
+    /// Normalised final hidden state of the last of `tokens`, `(1, dim)`.
+    fn hidden(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let hidden = match self {
+            Self::Llama(m, _) => m.forward(&input, pos)?,
+            Self::Qwen2(m, _) => m.forward(&input, pos)?,
+            Self::Qwen3(m, _) => {
+                // Unlike the other models, Qwen3 keeps appending to its KV
+                // cache when a new sequence starts at position 0.
+                if pos == 0 {
+                    m.clear_kv_cache();
+                }
+                m.forward(&input, pos)?
+            }
+            Self::Phi3(m, _) => m.forward(&input, pos)?,
+            Self::Gemma(m, _) => m.forward(&input, pos)?,
+            Self::Decoder(decoder, cache) => decoder.last_hidden(tokens, pos, cache)?,
+        };
+        Ok(hidden)
+    }
 }
 
 pub struct Model {
-    inner: ModelInner,
+    /// `None` only while the weights are rebuilt for new adapters.
+    inner: Option<ModelInner>,
+    backend: Backend,
     metadata: GgufMetadata,
+    /// Header of the base model, without the tokenizer, kept to rebuild the
+    /// weights when adapters change.
+    base: gguf_file::Content,
+    lora: Vec<(LoraAdapter, f32)>,
 }
 
 pub struct ModelWithMmap {
@@ -39,32 +274,38 @@ pub struct ModelWithMmap {
 
 impl Model {
     pub fn load(path: &PathBuf) -> Result<Self> {
-        let (_, model) = Self::load_with_mmap(path)?;
+        let (_, model) = Self::load_with_mmap(path, &ContextOverrides::default(), &[])?;
         Ok(model)
     }
 
-    pub fn load_with_mmap(path: &PathBuf) -> Result<(Mmap, Self)> {
-        let file_size = std::fs::metadata(path)?.len();
+    /// Load a model, returning the memory maps of its file (one per shard
+    /// for split models) alongside it. The maps are needed again to change
+    /// LoRA adapters with [`Model::set_lora`].
+    pub fn load_with_mmap(
+        path: &PathBuf,
+        overrides: &ContextOverrides,
+        lora: &[LoraSpec],
+    ) -> Result<(Vec<Mmap>, Self)> {
         let filename = path
             .file_name()
             .and_then(|n| n.to_str())
             .unwrap_or("unknown");
 
-        let device = Device::Cpu;
-
-        let file =
-            File::open(path).with_context(|| format!("Failed to open model file: {:?}", path))?;
+        let ShardedGguf {
+            paths,
+            mmaps,
+            mut content,
+        } = ShardedGguf::open(path)?;
+        let file_size: u64 = mmaps.iter().map(|m| m.len() as u64).sum();
 
-        tracing::info!("Memory-mapping GGUF file ({} MB)...", file_size / 1_000_000);
-
-        let mmap = unsafe { Mmap::map(&file)? };
-
-        let mut cursor = Cursor::new(&mmap);
-
-        let content = gguf_file::Content::read(&mut cursor)
-            .with_context(|| format!("Failed to read GGUF file: {:?}", path))?;
+        tracing::info!(
+            "Memory-mapped GGUF file ({} MB in {} file(s))",
+            file_size / 1_000_000,
+            paths.len()
+        );
 
-        let metadata = Self::extract_metadata(&content, filename, file_size)?;
+        let mut metadata = Self::extract_metadata(&content, filename, file_size)?;
+        overrides.apply(&mut content, &mut metadata)?;
 
         let arch = metadata.architecture.as_str();
         tracing::info!(
@@ -76,22 +317,245 @@ impl Model {
             arch
         );
 
-        cursor.seek(std::io::SeekFrom::Start(0))?;
+        if !SUPPORTED_ARCHITECTURES.contains(&arch) {
+            anyhow::bail!(
+                "Unsupported architecture '{}' in {:?} (supported: {})",
+                arch,
+                path,
+                SUPPORTED_ARCHITECTURES.join(", ")
+            );
+        }
 
-        let inner = if arch == "lfm2" {
-            let weights = Lfm2Model::from_gguf(content, &mut cursor, &device)
-                .with_context(|| "Failed to load LFM2 model weights from GGUF")?;
-            ModelInner::Lfm2(weights)
+        let adapters = lora
+            .iter()
+            .map(|spec| Ok((LoraAdapter::load(&spec.path)?, spec.scale)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let backend = Backend::for_metadata(&metadata)?;
+        let base = without_tokenizer(&content);
+        let inner = if adapters.is_empty() {
+            Self::build_weights(arch, backend, content, &mmaps, &[])?
         } else {
-            let weights = LlamaModel::from_gguf(content, &mut cursor, &device)
-                .with_context(|| "Failed to load LLaMA model weights from GGUF")?;
-            ModelInner::Llama(weights)
+            let (content, overlay) = Self::merge_lora(&base, &mmaps, arch, &adapters)?;
+            Self::build_weights(arch, backend, content, &mmaps, &overlay)?
         };
 
         tracing::info!("Model loaded successfully");
 
-        let model = Self { inner, metadata };
-        Ok((mmap, model))
+        let model = Self {
+            inner: Some(inner),
+            backend,
+            metadata,
+            base,
+            lora: adapters,
+        };
+        Ok((mmaps, model))
+    }
+
+    /// Replace the active LoRA adapters, rebuilding the weights from the
+    /// model's memory maps. An empty list restores the base weights.
+    ///
+    /// Adapters already active are not read again, so changing only the
+    /// scales skips the adapter files. On error the previous adapters stay
+    /// active.
+    pub fn set_lora(&mut self, mmaps: &[Mmap], lora: &[LoraSpec]) -> Result<()> {
+        let adapters = lora
+            .iter()
+            .map(|spec| {
+                let adapter = match self.lora.iter().find(|(a, _)| a.path() == spec.path) {
+                    Some((adapter, _)) => adapter.clone(),
+                    None => LoraAdapter::load(&spec.path)?,
+                };
+                Ok((adapter, spec.scale))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.rebuild(mmaps, adapters, self.backend)?;
+
+        tracing::info!("Active LoRA adapters: {}", self.lora.len());
+        Ok(())
+    }
+
+    /// Rebuild the weights for the built-in decoder, which
+    /// [`Model::forward_observed`] needs. Generation carries on with it.
+    pub(crate) fn use_decoder(&mut self, mmaps: &[Mmap]) -> Result<()> {
+        if self.backend == Backend::Decoder {
+            return Ok(());
+        }
+        tracing::info!("Rebuilding the weights for the built-in decoder");
+        self.rebuild(mmaps, self.lora.clone(), Backend::Decoder)
+    }
+
+    /// Rebuild the weights for `backend` with `adapters` merged in. On error
+    /// the previous weights are restored.
+    fn rebuild(
+        &mut self,
+        mmaps: &[Mmap],
+        adapters: Vec<(LoraAdapter, f32)>,
+        backend: Backend,
+    ) -> Result<()> {
+        let arch = self.metadata.architecture.clone();
+        let (content, overlay) = Self::merge_lora(&self.base, mmaps, &arch, &adapters)?;
+
+        // Free the old weights before reading the new ones, and rebuild
+        // them if the new ones fail so the model stays usable.
+        self.inner = None;
+        let inner = match Self::build_weights(&arch, backend, content, mmaps, &overlay) {
+            Ok(inner) => inner,
+            Err(e) => {
+                let (content, overlay) = Self::merge_lora(&self.base, mmaps, &arch, &self.lora)?;
+                self.inner = Some(
+                    Self::build_weights(&arch, self.backend, content, mmaps, &overlay)
+                        .with_context(|| {
+                            format!("{:#}; restoring the previous weights also failed", e)
+                        })?,
+                );
+                return Err(e);
+            }
+        };
+        self.inner = Some(inner);
+        self.lora = adapters;
+        self.backend = backend;
+        Ok(())
+    }
+
+    /// The active LoRA adapters and their scales.
+    pub fn lora(&self) -> Vec<LoraSpec> {
+        self.lora
+            .iter()
+            .map(|(adapter, scale)| LoraSpec::new(adapter.path(), *scale))
+            .collect()
+    }
+
+    /// A copy of `base` with `adapters` merged into its weights, and the
+    /// merged tensor data to read after the shards.
+    fn merge_lora(
+        base: &gguf_file::Content,
+        mmaps: &[Mmap],
+        arch: &str,
+        adapters: &[(LoraAdapter, f32)],
+    ) -> Result<(gguf_file::Content, Vec<u8>)> {
+        let mut content = without_tokenizer(base);
+        if adapters.is_empty() {
+            return Ok((content, Vec::new()));
+        }
+
+        let base_len = mmaps.iter().map(|m| m.len() as u64).sum();
+        let mut reader = ShardReader::new(mmaps.iter().map(|m| &m[..]).collect());
+        let adapters: Vec<(&LoraAdapter, f32)> = adapters.iter().map(|(a, s)| (a, *s)).collect();
+        let overlay = lora::merge(&mut content, &mut reader, base_len, arch, &adapters)?;
+        Ok((content, overlay))
+    }
+
+    /// Read the weights described by `content` from the shards, followed by
+    /// `overlay`, for `backend`.
+    fn build_weights(
+        arch: &str,
+        backend: Backend,
+        content: gguf_file::Content,
+        mmaps: &[Mmap],
+        overlay: &[u8],
+    ) -> Result<ModelInner> {
+        let name = match arch {
+            "llama" => "LLaMA",
+            "lfm2" => "LFM2",
+            "qwen2" => "Qwen2",
+            "qwen3" => "Qwen3",
+            "phi3" => "Phi-3",
+            "gemma2" => "Gemma 2",
+            "gemma3" => "Gemma 3",
+            other => anyhow::bail!("Unsupported architecture '{}'", other),
+        };
+        Self::read_weights(arch, backend, content, mmaps, overlay)
+            .with_context(|| format!("Failed to load {} model weights from GGUF", name))
+    }
+
+    fn read_weights(
+        arch: &str,
+        backend: Backend,
+        mut content: gguf_file::Content,
+        mmaps: &[Mmap],
+        overlay: &[u8],
+    ) -> Result<ModelInner> {
+        let device = Device::Cpu;
+        let metadata = Self::extract_metadata(&content, arch, 0)?;
+        let mut slices: Vec<&[u8]> = mmaps.iter().map(|m| &m[..]).collect();
+        slices.push(overlay);
+
+        if backend == Backend::Decoder {
+            let mut weights = Weights::new(&content, ShardReader::new(slices));
+            let decoder = Decoder::load(&mut weights, &metadata)?;
+            return Ok(ModelInner::Decoder(decoder, Cache::default()));
+        }
+
+        // candle's models end with the output projection and keep it
+        // private, so they get an identity in its place and the real one,
+        // possibly tied to the token embeddings, is applied here.
+        let head_name = match content.tensor_infos.contains_key("output.weight") {
+            true => "output.weight",
+            false => "token_embd.weight",
+        };
+        let mut reader = ShardReader::new(slices.clone());
+        let head = QMatMul::from_qtensor(content.tensor(&mut reader, head_name, &device)?)?;
+
+        let dim = metadata.n_embd;
+        let identity = QTensor::quantize(&Tensor::eye(dim, DType::F32, &device)?, GgmlDType::F32)?;
+        let identity = identity.data()?;
+        let data_len: u64 = slices.iter().map(|s| s.len() as u64).sum();
+        content.tensor_infos.insert(
+            "output.weight".to_string(),
+            TensorInfo {
+                ggml_dtype: GgmlDType::F32,
+                shape: (dim, dim).into(),
+                offset: data_len - content.tensor_data_offset,
+            },
+        );
+        slices.push(&identity);
+        let mut reader = ShardReader::new(slices);
+
+        let inner = match arch {
+            "llama" => {
+                ModelInner::Llama(LlamaModel::from_gguf(content, &mut reader, &device)?, head)
+            }
+            "qwen2" => {
+                ModelInner::Qwen2(Qwen2Model::from_gguf(content, &mut reader, &device)?, head)
+            }
+            "qwen3" => {
+                ModelInner::Qwen3(Qwen3Model::from_gguf(content, &mut reader, &device)?, head)
+            }
+            "phi3" => ModelInner::Phi3(
+                Phi3Model::from_gguf(false, content, &mut reader, &device)?,
+                head,
+            ),
+            "gemma3" => {
+                ModelInner::Gemma(GemmaModel::from_gguf(content, &mut reader, &device)?, head)
+            }
+            other => anyhow::bail!("No candle implementation for '{}'", other),
+        };
+        Ok(inner)
+    }
+
+    /// Read only the GGUF header and metadata, without loading weights.
+    ///
+    /// For split models the metadata comes from the first shard and
+    /// `file_size` covers all shards.
+    pub fn read_metadata(path: &Path) -> Result<GgufMetadata> {
+        let file_size = shards::total_size(path)?;
+        let path = shards::first_shard(path);
+        let filename = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown");
+
+        let file =
+            File::open(&path).with_context(|| format!("Failed to open model file: {:?}", path))?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let mut cursor = Cursor::new(&mmap);
+
+        let content = gguf_file::Content::read(&mut cursor)
+            .with_context(|| format!("Failed to read GGUF file: {:?}", path))?;
+
+        Self::extract_metadata(&content, filename, file_size)
     }
 
     pub fn prefetch_mmap(mmap: &Mmap) {
@@ -114,7 +578,7 @@ impl Model {
         );
     }
 
-    fn extract_metadata(
+    pub(crate) fn extract_metadata(
         content: &gguf_file::Content,
         filename: &str,
         file_size: u64,
@@ -136,13 +600,13 @@ impl Model {
 
         let find_key = |key_suffix: &str| -> Option<usize> {
             if let Some(v) = md.get(&format!("{}.{}", arch, key_suffix)) {
-                return v.to_u32().ok().map(|v| v as usize);
+                return value_to_count(v);
             }
 
             for (k, v) in md.iter() {
                 if k.ends_with(&format!(".{}", key_suffix)) {
-                    if let Ok(val) = v.to_u32() {
-                        return Some(val as usize);
+                    if let Some(val) = value_to_count(v) {
+                        return Some(val);
                     }
                 }
             }
@@ -185,8 +649,7 @@ impl Model {
             .or_else(|| {
                 filename
                     .split('.')
-                    .filter(|s| !s.eq_ignore_ascii_case("gguf") && !s.eq_ignore_ascii_case("bin"))
-                    .last()
+                    .rfind(|s| !s.eq_ignore_ascii_case("gguf") && !s.eq_ignore_ascii_case("bin"))
                     .map(|s| s.to_string())
                     .filter(|s| {
                         s.len() >= 2
@@ -197,16 +660,82 @@ impl Model {
                     })
             });
 
+        // Most converters only record the vocabulary size in the tokenizer.
+        let vocab_size = match find_key("vocab_size") {
+            Some(n) => n,
+            None => match md.get("tokenizer.ggml.tokens") {
+                Some(gguf_file::Value::Array(tokens)) => tokens.len(),
+                _ => anyhow::bail!("Missing metadata key: vocab_size"),
+            },
+        };
+
+        let n_embd = get_required("embedding_length")?;
+        let context_length = get_optional("context_length", 4096);
+        let head_count = get_optional("attention.head_count", 1).max(1);
+        let head_count_kv = find_key("attention.head_count_kv")
+            .filter(|&n| n > 0)
+            .unwrap_or(head_count);
+        let head_dim = find_key("attention.key_length").unwrap_or(n_embd / head_count);
+
+        let arch_f32 = |key_suffix: &str| -> Option<f32> {
+            match md.get(&format!("{}.{}", arch, key_suffix))? {
+                Value::F32(v) => Some(*v),
+                Value::F64(v) => Some(*v as f32),
+                other => value_to_u64(other).map(|v| v as f32),
+            }
+        };
+        let arch_usize = |key_suffix: &str| -> Option<usize> {
+            md.get(&format!("{}.{}", arch, key_suffix))
+                .and_then(value_to_count)
+        };
+        let token_id = |key: &str| -> Option<u32> {
+            md.get(key)
+                .and_then(value_to_u64)
+                .and_then(|v| u32::try_from(v).ok())
+        };
+
+        let rope = RopeParams {
+            freq_base: arch_f32("rope.freq_base"),
+            dimension_count: arch_usize("rope.dimension_count"),
+            scaling_type: md
+                .get(&format!("{}.rope.scaling.type", arch))
+                .and_then(|v| v.to_string().ok().cloned())
+                .filter(|t| t != "none"),
+            scaling_factor: arch_f32("rope.scaling.factor"),
+            original_context_length: arch_usize("rope.scaling.original_context_length"),
+        };
+
+        let values: HashMap<String, Value> = md
+            .iter()
+            .filter(|(k, v)| !(k.starts_with("tokenizer.ggml.") && matches!(v, Value::Array(_))))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
         Ok(GgufMetadata {
             name: model_name,
             architecture: arch.clone(),
             n_layer: get_required("block_count")?,
-            n_embd: get_required("embedding_length")?,
-            vocab_size: get_required("vocab_size")?,
-            context_length: get_optional("context_length", 4096),
+            n_embd,
+            vocab_size,
+            context_length,
+            trained_context_length: context_length,
             file_size,
             chat_template,
             quantization,
+            head_count,
+            head_count_kv,
+            head_dim,
+            feed_forward_length: arch_usize("feed_forward_length"),
+            rope,
+            expert_count: arch_usize("expert_count").filter(|&n| n > 0),
+            expert_used_count: arch_usize("expert_used_count").filter(|&n| n > 0),
+            bos_token_id: token_id("tokenizer.ggml.bos_token_id"),
+            eos_token_id: token_id("tokenizer.ggml.eos_token_id"),
+            pad_token_id: token_id("tokenizer.ggml.padding_token_id"),
+            tokenizer_model: md
+                .get("tokenizer.ggml.model")
+                .and_then(|v| v.to_string().ok().cloned()),
+            values: Arc::new(values),
         })
     }
 
@@ -214,13 +743,234 @@ impl Model {
         &self.metadata
     }
 
+    /// Logits of the last of `tokens`, placed at positions `pos..` after
+    /// the tokens of earlier calls. Position 0 starts a new sequence.
     pub fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor> {
-        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let inner = self
+            .inner
+            .as_mut()
+            .context("Model weights are not loaded")?;
+        inner.forward(tokens, pos)
+    }
 
-        let logits = match &mut self.inner {
-            ModelInner::Llama(m) => m.forward(&input, pos)?,
-            ModelInner::Lfm2(m) => m.forward(&input, pos)?,
-        };
-        Ok(logits)
+    /// Embed tokenized inputs from the model's final hidden states, one
+    /// vector per input.
+    ///
+    /// Each input runs as a new sequence, so the next [`Model::forward`]
+    /// must start at position 0. Mean pooling runs the input one token at
+    /// a time to see the state of every position.
+    pub fn embed(&mut self, inputs: &[Vec<u32>], options: &EmbedOptions) -> Result<Vec<Vec<f32>>> {
+        let inner = self
+            .inner
+            .as_mut()
+            .context("Model weights are not loaded")?;
+        let pooling = options
+            .pooling
+            .or_else(|| Pooling::from_metadata(&self.metadata))
+            .unwrap_or(Pooling::Last);
+
+        embedding::truncate(inputs, self.metadata.context_length)?
+            .into_iter()
+            .map(|tokens| {
+                let pooled = match pooling {
+                    Pooling::Last => inner.hidden(tokens, 0)?,
+                    Pooling::Cls => inner.hidden(&tokens[..1], 0)?,
+                    Pooling::Mean => {
+                        let mut sum = inner.hidden(&tokens[..1], 0)?;
+                        for (pos, &token) in tokens.iter().enumerate().skip(1) {
+                            sum = (sum + inner.hidden(&[token], pos)?)?;
+                        }
+                        (sum / tokens.len() as f64)?
+                    }
+                };
+                let mut vector: Vec<f32> = pooled.flatten_all()?.to_vec1()?;
+                if options.normalize {
+                    embedding::normalize(&mut vector);
+                }
+                Ok(vector)
+            })
+            .collect()
+    }
+
+    /// Logits of every position of `tokens` as a new sequence, passing the
+    /// input of every matmul to `observe`. The generation cache is left
+    /// untouched. Needs the weights built by [`Model::use_decoder`].
+    pub(crate) fn forward_observed(&self, tokens: &[u32], observe: Observer) -> Result<Tensor> {
+        match &self.inner {
+            Some(ModelInner::Decoder(decoder, _)) => decoder.forward_observed(tokens, observe),
+            Some(_) => anyhow::bail!("Model weights are not built for the decoder"),
+            None => anyhow::bail!("Model weights are not loaded"),
+        }
+    }
+}
+
+/// A copy of `content` without the `tokenizer.ggml.*` arrays, which the
+/// model builders never read.
+fn without_tokenizer(content: &gguf_file::Content) -> gguf_file::Content {
+    gguf_file::Content {
+        magic: content.magic,
+        metadata: content
+            .metadata
+            .iter()
+            .filter(|(k, _)| !k.starts_with("tokenizer.ggml."))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        tensor_infos: content
+            .tensor_infos
+            .iter()
+            .map(|(name, info)| {
+                let info = gguf_file::TensorInfo {
+                    ggml_dtype: info.ggml_dtype,
+                    shape: info.shape.clone(),
+                    offset: info.offset,
+                };
+                (name.clone(), info)
+            })
+            .collect(),
+        tensor_data_offset: content.tensor_data_offset,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_content(metadata: &[(&str, Value)]) -> gguf_file::Content {
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let mut buf = Vec::new();
+        gguf_file::write(&mut Cursor::new(&mut buf), &metadata, &[]).unwrap();
+        gguf_file::Content::read(&mut Cursor::new(&buf)).unwrap()
+    }
+
+    #[test]
+    fn test_extract_metadata_geometry_and_tokens() {
+        let content = read_content(&[
+            ("general.architecture", Value::String("llama".into())),
+            ("llama.block_count", Value::U32(32)),
+            ("llama.embedding_length", Value::U32(4096)),
+            ("llama.context_length", Value::U32(32768)),
+            ("llama.feed_forward_length", Value::U32(14336)),
+            ("llama.attention.head_count", Value::U32(32)),
+            ("llama.attention.head_count_kv", Value::U32(8)),
+            ("llama.rope.freq_base", Value::F32(1_000_000.0)),
+            ("llama.rope.scaling.type", Value::String("yarn".into())),
+            ("llama.rope.scaling.factor", Value::F32(4.0)),
+            ("llama.expert_count", Value::U32(8)),
+            ("llama.expert_used_count", Value::U32(2)),
+            (
+                "tokenizer.ggml.tokens",
+                Value::Array(vec![Value::String("<s>".into()); 10]),
+            ),
+            ("tokenizer.ggml.model", Value::String("llama".into())),
+            ("tokenizer.ggml.bos_token_id", Value::U32(1)),
+            ("tokenizer.ggml.eos_token_id", Value::U32(2)),
+            ("custom.flag", Value::Bool(true)),
+        ]);
+
+        let md = Model::extract_metadata(&content, "model.gguf", 0).unwrap();
+        assert_eq!((md.head_count, md.head_count_kv, md.head_dim), (32, 8, 128));
+        assert_eq!(md.kv_bytes_per_token(2), 2 * 32 * 8 * 128 * 2);
+        assert_eq!(md.vocab_size, 10);
+        assert_eq!(md.feed_forward_length, Some(14336));
+        assert_eq!(md.rope.freq_base, Some(1_000_000.0));
+        assert_eq!(md.rope.scaling_type.as_deref(), Some("yarn"));
+        assert_eq!(md.rope.scaling_factor, Some(4.0));
+        assert_eq!((md.expert_count, md.expert_used_count), (Some(8), Some(2)));
+        assert_eq!((md.bos_token_id, md.eos_token_id), (Some(1), Some(2)));
+        assert_eq!(md.pad_token_id, None);
+        assert_eq!(md.tokenizer_model.as_deref(), Some("llama"));
+
+        assert_eq!(md.get_bool("custom.flag"), Some(true));
+        assert_eq!(md.get_u32("llama.block_count"), Some(32));
+        assert_eq!(md.get_f32("llama.block_count"), Some(32.0));
+        assert_eq!(md.get_str("general.architecture"), Some("llama"));
+        assert!(md.arch_value("attention.head_count").is_some());
+        assert!(md.get("tokenizer.ggml.tokens").is_none());
+    }
+
+    #[test]
+    fn test_extract_metadata_per_layer_kv_heads() {
+        let content = read_content(&[
+            ("general.architecture", Value::String("lfm2".into())),
+            ("lfm2.block_count", Value::U32(4)),
+            ("lfm2.embedding_length", Value::U32(64)),
+            ("lfm2.vocab_size", Value::U32(100)),
+            ("lfm2.attention.head_count", Value::U32(4)),
+            (
+                "lfm2.attention.head_count_kv",
+                Value::Array(vec![
+                    Value::U32(0),
+                    Value::U32(2),
+                    Value::U32(0),
+                    Value::U32(2),
+                ]),
+            ),
+        ]);
+
+        let md = Model::extract_metadata(&content, "model.gguf", 0).unwrap();
+        assert_eq!((md.head_count, md.head_count_kv, md.head_dim), (4, 2, 16));
+        assert_eq!(md.feed_forward_length, None);
+        assert_eq!(md.rope, RopeParams::default());
+    }
+
+    #[test]
+    fn test_build_weights_dispatches_on_architecture() {
+        let content = || read_content(&[("general.architecture", Value::String("x".into()))]);
+        for (arch, context) in [
+            ("llama", "LLaMA"),
+            ("lfm2", "LFM2"),
+            ("qwen2", "Qwen2"),
+            ("qwen3", "Qwen3"),
+            ("phi3", "Phi-3"),
+            ("gemma2", "Gemma 2"),
+            ("gemma3", "Gemma 3"),
+        ] {
+            assert!(SUPPORTED_ARCHITECTURES.contains(&arch));
+            for backend in [Backend::Candle, Backend::Decoder] {
+                let err = Model::build_weights(arch, backend, content(), &[], &[])
+                    .err()
+                    .unwrap()
+                    .to_string();
+                assert_eq!(
+                    err,
+                    format!("Failed to load {} model weights from GGUF", context)
+                );
+            }
+        }
+        let err = Model::build_weights("gemma", Backend::Candle, content(), &[], &[])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Unsupported"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_rejects_unsupported_architectures() {
+        let path = std::env::temp_dir().join(format!("oxide-arch-{}.gguf", std::process::id()));
+        for arch in ["gemma", "falcon"] {
+            let key = |name: &str| format!("{}.{}", arch, name);
+            let metadata = [
+                (
+                    "general.architecture".to_string(),
+                    Value::String(arch.into()),
+                ),
+                (key("block_count"), Value::U32(2)),
+                (key("embedding_length"), Value::U32(64)),
+                (key("attention.head_count"), Value::U32(4)),
+                (key("vocab_size"), Value::U32(100)),
+            ];
+            let metadata: Vec<(&str, &Value)> =
+                metadata.iter().map(|(k, v)| (k.as_str(), v)).collect();
+            let mut file = std::fs::File::create(&path).unwrap();
+            gguf_file::write(&mut file, &metadata, &[]).unwrap();
+
+            let err = Model::load(&path).err().unwrap().to_string();
+            assert!(
+                err.starts_with(&format!("Unsupported architecture '{}'", arch)),
+                "{}",
+                err
+            );
+            assert!(err.contains("gemma3"), "{}", err);
+        }
+        std::fs::remove_file(&path).ok();
     }
 }
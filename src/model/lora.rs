@@ -0,0 +1,410 @@
+//! LoRA Adapters
+//!
+//! Adapters are GGUF files as written by llama.cpp's `convert_lora_to_gguf.py`:
+//! `general.type = "adapter"`, `adapter.type = "lora"`, an optional
+//! `adapter.lora.alpha`, and for every adapted weight `<name>.lora_a`
+//! (rank x in) and `<name>.lora_b` (out x rank).
+//!
+//! candle's quantized models have no hook for a per-layer delta, so adapters
+//! are merged: each target weight is dequantized, `scale * alpha / rank * B·A`
+//! is added for every active adapter, and the sum is stored as F16, or F32
+//! for F32 weights. Requantizing to a weight's k-quant type would round a
+//! delta smaller than its quantization step away, at the cost of larger
+//! adapted tensors. The merged tensors are kept in an overlay that sits after
+//! the mapped shards, so swapping or rescaling adapters rebuilds the model
+//! from the existing maps instead of mapping the base file again.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use candle_core::quantized::gguf_file::{Content, TensorInfo};
+use candle_core::quantized::{GgmlDType, QTensor};
+use candle_core::{Device, Tensor};
+
+const LORA_A_SUFFIX: &str = ".lora_a";
+const LORA_B_SUFFIX: &str = ".lora_b";
+
+/// An adapter file and the scale to apply it with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoraSpec {
+    pub path: PathBuf,
+    pub scale: f32,
+}
+
+impl LoraSpec {
+    pub fn new<P: AsRef<Path>>(path: P, scale: f32) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            scale,
+        }
+    }
+}
+
+/// A LoRA adapter read into memory, as `(A, B)` pairs keyed by the name of
+/// the base tensor they adapt.
+#[derive(Clone)]
+pub struct LoraAdapter {
+    path: PathBuf,
+    architecture: Option<String>,
+    alpha: Option<f32>,
+    pairs: BTreeMap<String, (Tensor, Tensor)>,
+}
+
+impl LoraAdapter {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut file =
+            File::open(path).with_context(|| format!("Failed to open LoRA adapter: {:?}", path))?;
+        let content = Content::read(&mut file)
+            .with_context(|| format!("Failed to read LoRA adapter: {:?}", path))?;
+
+        let get_str = |key: &str| {
+            content
+                .metadata
+                .get(key)
+                .and_then(|v| v.to_string().ok())
+                .cloned()
+        };
+        if let Some(kind) = get_str("general.type") {
+            if kind != "adapter" {
+                anyhow::bail!("{:?} is a '{}' GGUF, not an adapter", path, kind);
+            }
+        }
+        if let Some(kind) = get_str("adapter.type") {
+            if kind != "lora" {
+                anyhow::bail!("{:?} is a '{}' adapter, only LoRA is supported", path, kind);
+            }
+        }
+
+        let alpha = content
+            .metadata
+            .get("adapter.lora.alpha")
+            .and_then(|v| v.to_f32().ok())
+            .filter(|a| *a > 0.0);
+
+        let device = Device::Cpu;
+        let mut pairs = BTreeMap::new();
+        for name in content.tensor_infos.keys() {
+            let Some(target) = name.strip_suffix(LORA_A_SUFFIX) else {
+                continue;
+            };
+            let b_name = format!("{}{}", target, LORA_B_SUFFIX);
+            if !content.tensor_infos.contains_key(&b_name) {
+                anyhow::bail!("{:?} has {} but no {}", path, name, b_name);
+            }
+
+            let a = content
+                .tensor(&mut file, name, &device)?
+                .dequantize(&device)?;
+            let b = content
+                .tensor(&mut file, &b_name, &device)?
+                .dequantize(&device)?;
+            let (rank, _) = a.dims2()?;
+            let (_, b_rank) = b.dims2()?;
+            if rank != b_rank {
+                anyhow::bail!(
+                    "{:?}: {} has rank {} but {} has rank {}",
+                    path,
+                    name,
+                    rank,
+                    b_name,
+                    b_rank
+                );
+            }
+            pairs.insert(target.to_string(), (a, b));
+        }
+
+        if pairs.is_empty() {
+            anyhow::bail!("{:?} contains no LoRA tensors", path);
+        }
+
+        tracing::info!("Loaded LoRA adapter {:?} ({} tensors)", path, pairs.len());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            architecture: get_str("general.architecture"),
+            alpha,
+            pairs,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Names of the base tensors this adapter changes.
+    pub fn targets(&self) -> impl Iterator<Item = &str> {
+        self.pairs.keys().map(|k| k.as_str())
+    }
+
+    /// `scale * B·A`, with the `alpha / rank` factor folded in.
+    fn delta(&self, target: &str, scale: f32) -> Result<Option<Tensor>> {
+        let Some((a, b)) = self.pairs.get(target) else {
+            return Ok(None);
+        };
+        let rank = a.dim(0)?;
+        let factor = match self.alpha {
+            Some(alpha) => scale * alpha / rank as f32,
+            None => scale,
+        };
+        Ok(Some((b.matmul(a)? * factor as f64)?))
+    }
+}
+
+/// Merge `adapters` into the weights described by `content`.
+///
+/// Returns the merged tensor data, to be read after `base_len` bytes of
+/// shard data; the tensor infos of adapted weights in `content` are pointed
+/// at it.
+pub(crate) fn merge<R: Read + Seek>(
+    content: &mut Content,
+    reader: &mut R,
+    base_len: u64,
+    architecture: &str,
+    adapters: &[(&LoraAdapter, f32)],
+) -> Result<Vec<u8>> {
+    let device = Device::Cpu;
+    let mut overlay = Vec::new();
+
+    let mut targets = BTreeSet::new();
+    for (adapter, _) in adapters {
+        if let Some(arch) = &adapter.architecture {
+            if arch != architecture {
+                anyhow::bail!(
+                    "LoRA adapter {:?} is for '{}' models, not '{}'",
+                    adapter.path,
+                    arch,
+                    architecture
+                );
+            }
+        }
+        for target in adapter.targets() {
+            if !content.tensor_infos.contains_key(target) {
+                anyhow::bail!(
+                    "LoRA adapter {:?} targets {}, which is not in the model",
+                    adapter.path,
+                    target
+                );
+            }
+            targets.insert(target.to_string());
+        }
+    }
+
+    for target in &targets {
+        let base = content.tensor(reader, target, &device)?;
+        let dtype = match base.dtype() {
+            GgmlDType::F32 => GgmlDType::F32,
+            _ => GgmlDType::F16,
+        };
+        let mut weight = base.dequantize(&device)?;
+        drop(base);
+
+        for (adapter, scale) in adapters {
+            if let Some(delta) = adapter.delta(target, *scale)? {
+                if delta.dims() != weight.dims() {
+                    anyhow::bail!(
+                        "LoRA adapter {:?} has shape {:?} for {}, but the model has {:?}",
+                        adapter.path,
+                        delta.dims(),
+                        target,
+                        weight.dims()
+                    );
+                }
+                weight = (weight + delta)?;
+            }
+        }
+
+        let merged = QTensor::quantize(&weight, dtype)
+            .with_context(|| format!("Failed to store {} as {:?}", target, dtype))?;
+        let offset = base_len + overlay.len() as u64 - content.tensor_data_offset;
+        overlay.extend_from_slice(&merged.data()?);
+        content.tensor_infos.insert(
+            target.clone(),
+            TensorInfo {
+                ggml_dtype: dtype,
+                shape: merged.shape().clone(),
+                offset,
+            },
+        );
+    }
+
+    tracing::info!(
+        "Merged {} LoRA adapter(s) into {} tensors",
+        adapters.len(),
+        targets.len()
+    );
+
+    Ok(overlay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::shards::ShardReader;
+    use candle_core::quantized::gguf_file;
+    use gguf_file::Value;
+    use std::io::Cursor;
+
+    fn write_gguf(path: &Path, metadata: &[(&str, Value)], tensors: &[(&str, Tensor)]) {
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let tensors: Vec<(&str, QTensor)> = tensors
+            .iter()
+            .map(|(n, t)| (*n, QTensor::quantize(t, GgmlDType::F32).unwrap()))
+            .collect();
+        let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(n, t)| (*n, t)).collect();
+        let mut file = File::create(path).unwrap();
+        gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+    }
+
+    fn adapter_file(name: &str, arch: &str, a: &Tensor, b: &Tensor) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("oxide-lora-{}-{}.gguf", name, std::process::id()));
+        write_gguf(
+            &path,
+            &[
+                ("general.type", Value::String("adapter".into())),
+                ("general.architecture", Value::String(arch.into())),
+                ("adapter.type", Value::String("lora".into())),
+                ("adapter.lora.alpha", Value::F32(4.0)),
+            ],
+            &[
+                ("blk.0.attn_q.weight.lora_a", a.clone()),
+                ("blk.0.attn_q.weight.lora_b", b.clone()),
+            ],
+        );
+        path
+    }
+
+    fn base_model(weight: &Tensor, dtype: GgmlDType) -> (Vec<u8>, Content) {
+        let qtensor = QTensor::quantize(weight, dtype).unwrap();
+        let mut buf = Vec::new();
+        gguf_file::write(
+            &mut Cursor::new(&mut buf),
+            &[],
+            &[("blk.0.attn_q.weight", &qtensor)],
+        )
+        .unwrap();
+        let content = Content::read(&mut Cursor::new(&buf)).unwrap();
+        (buf, content)
+    }
+
+    #[test]
+    fn test_merge_adds_scaled_delta() {
+        let device = Device::Cpu;
+        let weight = Tensor::ones((8, 4), candle_core::DType::F32, &device).unwrap();
+        let a = Tensor::ones((2, 4), candle_core::DType::F32, &device).unwrap();
+        let b = (Tensor::ones((8, 2), candle_core::DType::F32, &device).unwrap() * 0.5).unwrap();
+        let path = adapter_file("merge", "llama", &a, &b);
+        let adapter = LoraAdapter::load(&path).unwrap();
+        assert_eq!(
+            adapter.targets().collect::<Vec<_>>(),
+            ["blk.0.attn_q.weight"]
+        );
+
+        let (buf, mut content) = base_model(&weight, GgmlDType::F32);
+        let overlay = merge(
+            &mut content,
+            &mut Cursor::new(&buf),
+            buf.len() as u64,
+            "llama",
+            &[(&adapter, 0.5)],
+        )
+        .unwrap();
+
+        // B·A = 1.0 everywhere; scale 0.5 * alpha 4 / rank 2 = 1.0
+        let mut reader = ShardReader::new(vec![&buf[..], &overlay[..]]);
+        let merged = content
+            .tensor(&mut reader, "blk.0.attn_q.weight", &device)
+            .unwrap()
+            .dequantize(&device)
+            .unwrap();
+        let values: Vec<f32> = merged.flatten_all().unwrap().to_vec1().unwrap();
+        assert!(values.iter().all(|v| (v - 2.0).abs() < 1e-6));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_merge_keeps_small_deltas_of_quantized_weights() {
+        let device = Device::Cpu;
+        let weight = Tensor::randn(0f32, 1.0, (8, 32), &device).unwrap();
+        // A delta well below the Q4_0 step, which requantizing would lose.
+        let a = Tensor::randn(0f32, 0.1, (2, 32), &device).unwrap();
+        let b = Tensor::randn(0f32, 0.1, (8, 2), &device).unwrap();
+        let path = adapter_file("quantized", "llama", &a, &b);
+        let adapter = LoraAdapter::load(&path).unwrap();
+
+        let (buf, mut content) = base_model(&weight, GgmlDType::Q4_0);
+        let base = content
+            .tensor(&mut Cursor::new(&buf), "blk.0.attn_q.weight", &device)
+            .unwrap()
+            .dequantize(&device)
+            .unwrap();
+        let overlay = merge(
+            &mut content,
+            &mut Cursor::new(&buf),
+            buf.len() as u64,
+            "llama",
+            &[(&adapter, 1.5)],
+        )
+        .unwrap();
+
+        let mut reader = ShardReader::new(vec![&buf[..], &overlay[..]]);
+        let merged = content
+            .tensor(&mut reader, "blk.0.attn_q.weight", &device)
+            .unwrap();
+        assert_eq!(merged.dtype(), GgmlDType::F16);
+        let merged = merged.dequantize(&device).unwrap();
+
+        // W + scale * alpha / rank * B·A, with scale 1.5, alpha 4, rank 2.
+        let delta = (b.matmul(&a).unwrap() * 3.0).unwrap();
+        let expected: Vec<f32> = (&base + &delta)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        let merged: Vec<f32> = merged.flatten_all().unwrap().to_vec1().unwrap();
+        for (m, e) in merged.iter().zip(&expected) {
+            assert!((m - e).abs() <= 1e-3 * e.abs() + 1e-6, "{} != {}", m, e);
+        }
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_merge_rejects_mismatched_adapters() {
+        let device = Device::Cpu;
+        let weight = Tensor::ones((8, 4), candle_core::DType::F32, &device).unwrap();
+        let a = Tensor::ones((2, 6), candle_core::DType::F32, &device).unwrap();
+        let b = Tensor::ones((8, 2), candle_core::DType::F32, &device).unwrap();
+
+        let path = adapter_file("mismatch", "qwen2", &a, &b);
+        let adapter = LoraAdapter::load(&path).unwrap();
+        let (buf, mut content) = base_model(&weight, GgmlDType::F32);
+
+        let err = merge(
+            &mut content,
+            &mut Cursor::new(&buf),
+            0,
+            "llama",
+            &[(&adapter, 1.0)],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("'qwen2' models"));
+
+        let err = merge(
+            &mut content,
+            &mut Cursor::new(&buf),
+            0,
+            "qwen2",
+            &[(&adapter, 1.0)],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("shape"));
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,5 +1,26 @@
+pub mod cache;
+pub mod context;
+pub(crate) mod decoder;
+pub mod discovery;
+pub mod embedding;
+pub(crate) mod gguf_writer;
+pub mod hf_tokenizer;
+pub mod imatrix;
+pub mod inspect;
+pub(crate) mod layers;
 pub mod loader;
+pub mod lora;
+pub mod quantize;
+pub mod shards;
 pub mod tokenizer;
 
-pub use loader::{GgufMetadata, Model};
-pub use tokenizer::TokenizerWrapper;
+pub use context::{ContextOverrides, RopeScaling};
+pub use discovery::ModelEntry;
+pub use embedding::{EmbedOptions, Embedder, Pooling};
+pub use hf_tokenizer::TokenizerConfig;
+pub use imatrix::{Imatrix, ImatrixChunk, ImatrixOptions};
+pub use inspect::GgufInspection;
+pub use loader::{GgufMetadata, Model, RopeParams};
+pub use lora::LoraSpec;
+pub use quantize::{quantize, QuantType, QuantizeOptions, QuantizeReport, TensorProgress};
+pub use tokenizer::{TokenInfo, TokenizerWrapper};
@@ -0,0 +1,659 @@
+//! GGUF Requantisation
+//!
+//! Rewrites a GGUF model with its weights quantised to one of llama.cpp's
+//! file types. Types and mixes follow `llama_tensor_get_type`: norms, biases
+//! and MoE routers are copied unchanged; the output projection and token
+//! embeddings are kept at Q6_K (or the target type, if higher); the `_M` and
+//! `_L` mixes give `attn_v`, `ffn_down` and `attn_output` more bits in some
+//! layers. Tensors whose rows do not fit the target's block size fall back
+//! to the nearest legacy type, as llama.cpp does. Metadata is copied with
+//! `general.file_type` updated, and tensors are written one at a time.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use candle_core::quantized::gguf_file::Value;
+use candle_core::quantized::{GgmlDType, QTensor};
+use candle_core::Device;
+use serde::Serialize;
+
+use super::gguf_writer::{GgufWriter, TensorSpec, DEFAULT_ALIGNMENT};
+use super::imatrix::Imatrix;
+use super::inspect::dtype_name;
+use super::shards::ShardedGguf;
+
+/// ggml's `GGML_QNT_VERSION`.
+const QUANTIZATION_VERSION: u32 = 2;
+
+/// Metadata keys that describe the input's split and are not copied.
+const SPLIT_KEYS: &[&str] = &["split.no", "split.count", "split.tensors.count"];
+
+/// A llama.cpp model file type (`LLAMA_FTYPE_*`).
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantType {
+    F32,
+    F16,
+    BF16,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q2_K,
+    Q3_K_S,
+    Q3_K_M,
+    Q3_K_L,
+    Q4_K_S,
+    Q4_K_M,
+    Q5_K_S,
+    Q5_K_M,
+    Q6_K,
+}
+
+const QUANT_TYPES: &[(QuantType, &str, u32)] = &[
+    (QuantType::F32, "f32", 0),
+    (QuantType::F16, "f16", 1),
+    (QuantType::BF16, "bf16", 32),
+    (QuantType::Q4_0, "q4_0", 2),
+    (QuantType::Q4_1, "q4_1", 3),
+    (QuantType::Q5_0, "q5_0", 8),
+    (QuantType::Q5_1, "q5_1", 9),
+    (QuantType::Q8_0, "q8_0", 7),
+    (QuantType::Q2_K, "q2_k", 10),
+    (QuantType::Q3_K_S, "q3_k_s", 11),
+    (QuantType::Q3_K_M, "q3_k_m", 12),
+    (QuantType::Q3_K_L, "q3_k_l", 13),
+    (QuantType::Q4_K_S, "q4_k_s", 14),
+    (QuantType::Q4_K_M, "q4_k_m", 15),
+    (QuantType::Q5_K_S, "q5_k_s", 16),
+    (QuantType::Q5_K_M, "q5_k_m", 17),
+    (QuantType::Q6_K, "q6_k", 18),
+];
+
+impl QuantType {
+    /// Value of `general.file_type`.
+    pub fn file_type(self) -> u32 {
+        QUANT_TYPES.iter().find(|(t, _, _)| *t == self).unwrap().2
+    }
+
+    /// The type with `general.file_type` value `file_type`, if supported.
+    pub fn from_file_type(file_type: u32) -> Option<Self> {
+        QUANT_TYPES
+            .iter()
+            .find(|(_, _, id)| *id == file_type)
+            .map(|(t, _, _)| *t)
+    }
+
+    /// Type of the bulk of the weights.
+    fn base(self) -> GgmlDType {
+        match self {
+            Self::F32 => GgmlDType::F32,
+            Self::F16 => GgmlDType::F16,
+            Self::BF16 => GgmlDType::BF16,
+            Self::Q4_0 => GgmlDType::Q4_0,
+            Self::Q4_1 => GgmlDType::Q4_1,
+            Self::Q5_0 => GgmlDType::Q5_0,
+            Self::Q5_1 => GgmlDType::Q5_1,
+            Self::Q8_0 => GgmlDType::Q8_0,
+            Self::Q2_K => GgmlDType::Q2K,
+            Self::Q3_K_S | Self::Q3_K_M | Self::Q3_K_L => GgmlDType::Q3K,
+            Self::Q4_K_S | Self::Q4_K_M => GgmlDType::Q4K,
+            Self::Q5_K_S | Self::Q5_K_M => GgmlDType::Q5K,
+            Self::Q6_K => GgmlDType::Q6K,
+        }
+    }
+
+    /// Whether the weights are stored unquantised.
+    fn is_float(self) -> bool {
+        matches!(self, Self::F32 | Self::F16 | Self::BF16)
+    }
+}
+
+impl FromStr for QuantType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let name = s.to_lowercase().replace('-', "_");
+        QUANT_TYPES
+            .iter()
+            .find(|(_, n, _)| *n == name)
+            .map(|(t, _, _)| *t)
+            .ok_or_else(|| {
+                let names: Vec<&str> = QUANT_TYPES.iter().map(|(_, n, _)| *n).collect();
+                format!(
+                    "unknown quantization type '{}' (expected one of {})",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl fmt::Display for QuantType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = QUANT_TYPES.iter().find(|(t, _, _)| t == self).unwrap().1;
+        f.write_str(&name.to_uppercase())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QuantizeOptions {
+    /// Quantise tensors that are already quantised. Quality suffers, so this
+    /// must be asked for, as with llama.cpp's `--allow-requantize`.
+    pub allow_requantize: bool,
+    /// Importance matrix (llama.cpp `imatrix.dat`) that weights the
+    /// rounding error of k-quant tensors by how much each column is used.
+    pub imatrix: Option<PathBuf>,
+}
+
+/// One tensor of a [`quantize`] run, passed to its progress callback.
+#[derive(Debug, Clone)]
+pub struct TensorProgress<'a> {
+    pub index: usize,
+    pub total: usize,
+    pub name: &'a str,
+    pub shape: &'a [usize],
+    /// Type names, e.g. `F16` and `Q4_K`.
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuantizeReport {
+    pub file_type: String,
+    pub tensors: usize,
+    /// Tensors that were converted to a different type.
+    pub quantized: usize,
+    /// Tensors quantised with importance-matrix weights.
+    pub imatrix_tensors: usize,
+    pub input_size: u64,
+    pub output_size: u64,
+    /// Number of tensors of each type in the output.
+    pub dtype_counts: BTreeMap<String, usize>,
+}
+
+/// Quantise the model at `input` (any shard of a split model) to `file_type`
+/// and write it to `output` as a single file.
+pub fn quantize<F>(
+    input: &Path,
+    output: &Path,
+    file_type: QuantType,
+    options: &QuantizeOptions,
+    mut on_tensor: F,
+) -> Result<QuantizeReport>
+where
+    F: FnMut(&TensorProgress),
+{
+    let imatrix = options.imatrix.as_deref().map(Imatrix::load).transpose()?;
+    let model = ShardedGguf::open(input)?;
+    let content = &model.content;
+    let arch = content
+        .metadata
+        .get("general.architecture")
+        .and_then(|v| v.to_string().ok())
+        .cloned()
+        .unwrap_or_default();
+    let n_layer = content
+        .metadata
+        .get(&format!("{}.block_count", arch))
+        .and_then(|v| v.to_u32().ok())
+        .unwrap_or(0) as usize;
+    let has_output = content.tensor_infos.contains_key("output.weight");
+
+    let mut names: Vec<&String> = content.tensor_infos.keys().collect();
+    names.sort_by_key(|name| content.tensor_infos[*name].offset);
+
+    let mut specs = Vec::with_capacity(names.len());
+    for name in &names {
+        let info = &content.tensor_infos[*name];
+        let shape = info.shape.dims().to_vec();
+        let dtype = match target_type(name, &shape, file_type, n_layer, has_output) {
+            Some(dtype) => fit_block_size(dtype, shape.last().copied().unwrap_or(0)),
+            None => info.ggml_dtype,
+        };
+        if dtype != info.ggml_dtype && !is_float(info.ggml_dtype) && !options.allow_requantize {
+            anyhow::bail!(
+                "{} is already quantized as {}; requantizing loses quality \
+                 (use --allow-requantize to do it anyway)",
+                name,
+                dtype_name(info.ggml_dtype)
+            );
+        }
+        specs.push(TensorSpec {
+            name: name.to_string(),
+            shape,
+            dtype,
+        });
+    }
+
+    let mut metadata: Vec<(String, Value)> = content
+        .metadata
+        .iter()
+        .filter(|(key, _)| !SPLIT_KEYS.contains(&key.as_str()))
+        .filter(|(key, _)| *key != "general.file_type" && *key != "general.quantization_version")
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    metadata.push((
+        "general.quantization_version".to_string(),
+        Value::U32(QUANTIZATION_VERSION),
+    ));
+    metadata.push((
+        "general.file_type".to_string(),
+        Value::U32(file_type.file_type()),
+    ));
+    metadata.sort_by(|a, b| a.0.cmp(&b.0));
+    let alignment = content
+        .metadata
+        .get("general.alignment")
+        .and_then(|v| v.to_u32().ok())
+        .map_or(DEFAULT_ALIGNMENT, u64::from);
+
+    let file = File::create(output).with_context(|| format!("Failed to create {:?}", output))?;
+    let mut writer = GgufWriter::new(BufWriter::new(file), &metadata, specs.clone(), alignment)?;
+
+    let device = Device::Cpu;
+    let mut reader = model.reader();
+    let mut report = QuantizeReport {
+        file_type: file_type.to_string(),
+        tensors: specs.len(),
+        quantized: 0,
+        imatrix_tensors: 0,
+        input_size: model.mmaps.iter().map(|m| m.len() as u64).sum(),
+        output_size: 0,
+        dtype_counts: BTreeMap::new(),
+    };
+    for (index, spec) in specs.iter().enumerate() {
+        let tensor = content.tensor(&mut reader, &spec.name, &device)?;
+        on_tensor(&TensorProgress {
+            index,
+            total: specs.len(),
+            name: &spec.name,
+            shape: &spec.shape,
+            from: dtype_name(tensor.dtype()),
+            to: dtype_name(spec.dtype),
+        });
+
+        let tensor = if tensor.dtype() == spec.dtype {
+            tensor
+        } else {
+            report.quantized += 1;
+            let weights = tensor.dequantize(&device)?;
+            let importance = match &imatrix {
+                Some(imatrix) if is_k_quant(spec.dtype) => {
+                    let columns = spec.shape.last().copied().unwrap_or(0);
+                    match imatrix.weights(&spec.name) {
+                        Some(w) if w.len() == columns => Some(w),
+                        Some(w) => anyhow::bail!(
+                            "The imatrix has {} columns for {}, the model {}",
+                            w.len(),
+                            spec.name,
+                            columns
+                        ),
+                        None => {
+                            tracing::warn!("No imatrix entry for {}", spec.name);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+            match importance {
+                Some(importance) => {
+                    report.imatrix_tensors += 1;
+                    QTensor::quantize_imatrix(&weights, &importance, spec.dtype)
+                }
+                None => QTensor::quantize(&weights, spec.dtype),
+            }
+            .with_context(|| format!("Failed to quantize {}", spec.name))?
+        };
+        writer.write_tensor(&tensor.data()?)?;
+        *report
+            .dtype_counts
+            .entry(dtype_name(spec.dtype))
+            .or_default() += 1;
+    }
+    report.output_size = writer.finish()?;
+
+    tracing::info!(
+        "Quantized {:?} to {} ({} -> {} bytes)",
+        input,
+        file_type,
+        report.input_size,
+        report.output_size
+    );
+    Ok(report)
+}
+
+fn is_float(dtype: GgmlDType) -> bool {
+    matches!(dtype, GgmlDType::F32 | GgmlDType::F16 | GgmlDType::BF16)
+}
+
+/// Types candle can quantise with importance weights.
+fn is_k_quant(dtype: GgmlDType) -> bool {
+    matches!(
+        dtype,
+        GgmlDType::Q2K | GgmlDType::Q3K | GgmlDType::Q4K | GgmlDType::Q5K | GgmlDType::Q6K
+    )
+}
+
+/// Type for the tensor `name`, or `None` to copy it unchanged.
+fn target_type(
+    name: &str,
+    shape: &[usize],
+    file_type: QuantType,
+    n_layer: usize,
+    has_output: bool,
+) -> Option<GgmlDType> {
+    use QuantType::*;
+
+    let is_weight = name.ends_with(".weight") && shape.len() >= 2;
+    if !is_weight || name.contains("norm") || name.contains("ffn_gate_inp") {
+        return None;
+    }
+
+    let base = file_type.base();
+    let is_output = name == "output.weight" || (!has_output && name == "token_embd.weight");
+    if is_output || name == "token_embd.weight" {
+        return Some(match file_type {
+            F32 | F16 | BF16 | Q8_0 | Q6_K => base,
+            _ => GgmlDType::Q6K,
+        });
+    }
+    if file_type.is_float() {
+        return Some(base);
+    }
+
+    let layer = layer_index(name);
+    let i = layer.unwrap_or(0);
+    let more_bits = layer.is_some_and(|i| use_more_bits(i, n_layer));
+
+    let dtype = if name.contains("attn_v.weight") {
+        match file_type {
+            Q2_K => GgmlDType::Q3K,
+            Q3_K_M if i < 2 => GgmlDType::Q5K,
+            Q3_K_M => GgmlDType::Q4K,
+            Q3_K_L => GgmlDType::Q5K,
+            Q4_K_S if i < 4 => GgmlDType::Q5K,
+            Q4_K_M | Q5_K_M if more_bits => GgmlDType::Q6K,
+            _ => base,
+        }
+    } else if name.contains("ffn_down") {
+        match file_type {
+            Q2_K => GgmlDType::Q3K,
+            Q3_K_M if i < n_layer / 16 => GgmlDType::Q5K,
+            Q3_K_M => GgmlDType::Q4K,
+            Q3_K_L => GgmlDType::Q5K,
+            Q4_K_S if i < n_layer / 8 => GgmlDType::Q5K,
+            Q4_K_M | Q5_K_M if more_bits => GgmlDType::Q6K,
+            _ => base,
+        }
+    } else if name.contains("attn_output.weight") {
+        match file_type {
+            Q2_K => GgmlDType::Q3K,
+            Q3_K_M => GgmlDType::Q4K,
+            Q3_K_L => GgmlDType::Q5K,
+            _ => base,
+        }
+    } else if name.contains("attn_qkv.weight") {
+        match file_type {
+            Q3_K_M | Q3_K_L => GgmlDType::Q4K,
+            Q4_K_M => GgmlDType::Q5K,
+            Q5_K_M => GgmlDType::Q6K,
+            _ => base,
+        }
+    } else {
+        base
+    };
+    Some(dtype)
+}
+
+/// `N` of a `blk.N.` tensor name.
+fn layer_index(name: &str) -> Option<usize> {
+    name.strip_prefix("blk.")?.split('.').next()?.parse().ok()
+}
+
+/// llama.cpp's choice of layers that get more bits in `_M` mixes: the first
+/// and last eighth, and every third layer in between.
+fn use_more_bits(layer: usize, n_layer: usize) -> bool {
+    layer < n_layer / 8 || layer >= 7 * n_layer / 8 || (layer - n_layer / 8) % 3 == 2
+}
+
+/// `dtype`, or a fallback if rows of `row_len` values do not split into its
+/// blocks.
+fn fit_block_size(dtype: GgmlDType, row_len: usize) -> GgmlDType {
+    if row_len % dtype.block_size() == 0 {
+        return dtype;
+    }
+    let fallback = match dtype {
+        GgmlDType::Q2K | GgmlDType::Q3K => GgmlDType::Q4_0,
+        GgmlDType::Q4K => GgmlDType::Q5_0,
+        GgmlDType::Q5K => GgmlDType::Q5_1,
+        GgmlDType::Q6K => GgmlDType::Q8_0,
+        _ => GgmlDType::F16,
+    };
+    if row_len % fallback.block_size() == 0 {
+        fallback
+    } else {
+        GgmlDType::F16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::gguf_file;
+    use candle_core::Tensor;
+
+    #[test]
+    fn test_quant_type_names() {
+        assert_eq!("Q4_K_M".parse::<QuantType>().unwrap(), QuantType::Q4_K_M);
+        assert_eq!("q5-k-s".parse::<QuantType>().unwrap(), QuantType::Q5_K_S);
+        assert_eq!(QuantType::Q8_0.to_string(), "Q8_0");
+        assert_eq!(QuantType::Q4_K_M.file_type(), 15);
+        assert!("q4_k_x".parse::<QuantType>().is_err());
+    }
+
+    #[test]
+    fn test_mix_rules() {
+        let w = [256, 256];
+        let t = |name, ft| target_type(name, &w, ft, 32, true);
+        assert_eq!(t("blk.0.attn_norm.weight", QuantType::Q4_K_M), None);
+        assert_eq!(t("output.weight", QuantType::Q4_K_M), Some(GgmlDType::Q6K));
+        assert_eq!(t("output.weight", QuantType::Q8_0), Some(GgmlDType::Q8_0));
+        assert_eq!(
+            t("blk.5.attn_q.weight", QuantType::Q4_K_M),
+            Some(GgmlDType::Q4K)
+        );
+        assert_eq!(
+            t("blk.0.attn_v.weight", QuantType::Q4_K_M),
+            Some(GgmlDType::Q6K)
+        );
+        assert_eq!(
+            t("blk.5.attn_v.weight", QuantType::Q4_K_M),
+            Some(GgmlDType::Q4K)
+        );
+        assert_eq!(
+            t("blk.6.ffn_down.weight", QuantType::Q5_K_M),
+            Some(GgmlDType::Q6K)
+        );
+        assert_eq!(
+            t("blk.6.ffn_down.weight", QuantType::Q5_K_S),
+            Some(GgmlDType::Q5K)
+        );
+        assert_eq!(
+            target_type("blk.0.ffn_gate_inp.weight", &w, QuantType::Q4_K_M, 32, true),
+            None
+        );
+        assert_eq!(
+            target_type("token_embd.weight", &w, QuantType::Q4_0, 32, false),
+            Some(GgmlDType::Q6K)
+        );
+
+        assert_eq!(fit_block_size(GgmlDType::Q4K, 4096), GgmlDType::Q4K);
+        assert_eq!(fit_block_size(GgmlDType::Q4K, 96), GgmlDType::Q5_0);
+        assert_eq!(fit_block_size(GgmlDType::Q6K, 100), GgmlDType::F16);
+    }
+
+    fn write_model(path: &Path, weight_dtype: GgmlDType) {
+        let device = Device::Cpu;
+        let weight = Tensor::randn(0f32, 1.0, (64, 256), &device).unwrap();
+        let norm = Tensor::ones(256, candle_core::DType::F32, &device).unwrap();
+        let tensors = [
+            (
+                "token_embd.weight",
+                QTensor::quantize(&weight, GgmlDType::F16).unwrap(),
+            ),
+            (
+                "blk.0.attn_q.weight",
+                QTensor::quantize(&weight, weight_dtype).unwrap(),
+            ),
+            (
+                "blk.0.attn_norm.weight",
+                QTensor::quantize(&norm, GgmlDType::F32).unwrap(),
+            ),
+        ];
+        let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(n, t)| (*n, t)).collect();
+        let metadata = [
+            ("general.architecture", Value::String("llama".into())),
+            ("general.file_type", Value::U32(1)),
+            ("llama.block_count", Value::U32(1)),
+        ];
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let mut file = File::create(path).unwrap();
+        gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+    }
+
+    #[test]
+    fn test_quantize_model() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("oxide-quant-in-{}.gguf", std::process::id()));
+        let output = dir.join(format!("oxide-quant-out-{}.gguf", std::process::id()));
+        write_model(&input, GgmlDType::F16);
+
+        let mut seen = Vec::new();
+        let report = quantize(
+            &input,
+            &output,
+            QuantType::Q4_K_M,
+            &QuantizeOptions::default(),
+            |t| seen.push(t.name.to_string()),
+        )
+        .unwrap();
+        assert_eq!(seen.len(), 3);
+        assert_eq!(report.quantized, 2);
+        assert!(report.output_size < report.input_size);
+
+        let mut file = File::open(&output).unwrap();
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        assert_eq!(content.metadata["general.file_type"].to_u32().unwrap(), 15);
+        let dtype = |name: &str| content.tensor_infos[name].ggml_dtype;
+        assert_eq!(dtype("token_embd.weight"), GgmlDType::Q6K);
+        assert_eq!(dtype("blk.0.attn_q.weight"), GgmlDType::Q4K);
+        assert_eq!(dtype("blk.0.attn_norm.weight"), GgmlDType::F32);
+
+        let q = content
+            .tensor(&mut file, "blk.0.attn_q.weight", &Device::Cpu)
+            .unwrap();
+        assert_eq!(q.shape().dims(), &[64, 256]);
+        assert!(q.dequantize(&Device::Cpu).is_ok());
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&output).ok();
+    }
+
+    /// An imatrix.dat with one entry.
+    fn write_imatrix(path: &Path, name: &str, values: &[f32]) {
+        let mut buf = Vec::new();
+        for v in [1, name.len()] {
+            buf.extend_from_slice(&(v as i32).to_le_bytes());
+        }
+        buf.extend_from_slice(name.as_bytes());
+        for v in [1, values.len()] {
+            buf.extend_from_slice(&(v as i32).to_le_bytes());
+        }
+        for v in values {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        std::fs::write(path, buf).unwrap();
+    }
+
+    #[test]
+    fn test_quantize_with_imatrix() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("oxide-imat-in-{}.gguf", std::process::id()));
+        let output = dir.join(format!("oxide-imat-out-{}.gguf", std::process::id()));
+        let matrix = dir.join(format!("oxide-imat-{}.dat", std::process::id()));
+        write_model(&input, GgmlDType::F16);
+
+        // A few columns dominate, so weighted rounding differs from plain.
+        let importance: Vec<f32> = (0..256)
+            .map(|i| if i % 16 == 0 { 1000.0 } else { 0.001 })
+            .collect();
+        write_imatrix(&matrix, "blk.0.attn_q.weight", &importance);
+        let options = QuantizeOptions {
+            imatrix: Some(matrix.clone()),
+            ..Default::default()
+        };
+        let report = quantize(&input, &output, QuantType::Q4_K_M, &options, |_| {}).unwrap();
+        assert_eq!(report.imatrix_tensors, 1);
+
+        let mut file = File::open(&input).unwrap();
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let weights = content
+            .tensor(&mut file, "blk.0.attn_q.weight", &Device::Cpu)
+            .unwrap()
+            .dequantize(&Device::Cpu)
+            .unwrap();
+        let weighted = QTensor::quantize_imatrix(&weights, &importance, GgmlDType::Q4K).unwrap();
+        let plain = QTensor::quantize(&weights, GgmlDType::Q4K).unwrap();
+        assert_ne!(weighted.data().unwrap(), plain.data().unwrap());
+
+        let mut file = File::open(&output).unwrap();
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let written = content
+            .tensor(&mut file, "blk.0.attn_q.weight", &Device::Cpu)
+            .unwrap();
+        assert_eq!(written.data().unwrap(), weighted.data().unwrap());
+
+        write_imatrix(&matrix, "blk.0.attn_q.weight", &importance[..128]);
+        let err = quantize(&input, &output, QuantType::Q4_K_M, &options, |_| {}).unwrap_err();
+        assert!(err.to_string().contains("128 columns"), "{}", err);
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&output).ok();
+        std::fs::remove_file(&matrix).ok();
+    }
+
+    #[test]
+    fn test_requantize_needs_opt_in() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("oxide-requant-in-{}.gguf", std::process::id()));
+        let output = dir.join(format!("oxide-requant-out-{}.gguf", std::process::id()));
+        write_model(&input, GgmlDType::Q8_0);
+
+        let run = |allow_requantize| {
+            quantize(
+                &input,
+                &output,
+                QuantType::Q4_0,
+                &QuantizeOptions {
+                    allow_requantize,
+                    ..Default::default()
+                },
+                |_| {},
+            )
+        };
+        let err = run(false).unwrap_err();
+        assert!(err.to_string().contains("--allow-requantize"));
+        assert!(run(true).is_ok());
+
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&output).ok();
+    }
+}
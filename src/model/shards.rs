@@ -0,0 +1,405 @@
+//! Split GGUF Files
+//!
+//! Large models ship as `name-00001-of-00003.gguf`, `name-00002-of-00003.gguf`
+//! and so on, as written by llama.cpp's `gguf-split`. The first shard holds
+//! the model metadata; every shard records `split.no` and `split.count`, and
+//! the first also records `split.tensors.count`.
+//!
+//! [`ShardedGguf`] maps every shard and presents them as one GGUF: a single
+//! `Content` whose tensor offsets point into the shards laid end to end, and
+//! a reader over that concatenation, so candle's `from_gguf` loaders work
+//! unchanged.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use candle_core::quantized::gguf_file;
+use memmap2::Mmap;
+
+const SPLIT_COUNT_KEY: &str = "split.count";
+const SPLIT_NO_KEY: &str = "split.no";
+const SPLIT_TENSORS_KEY: &str = "split.tensors.count";
+
+/// A shard file name split into its parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SplitName {
+    prefix: String,
+    index: usize,
+    count: usize,
+}
+
+impl SplitName {
+    /// Parse `<prefix>-NNNNN-of-MMMMM.gguf`.
+    fn parse(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let stem = name.strip_suffix(".gguf")?;
+        let (rest, count) = stem.rsplit_once("-of-")?;
+        let (prefix, index) = rest.rsplit_once('-')?;
+
+        let is_number = |s: &str| s.len() == 5 && s.bytes().all(|b| b.is_ascii_digit());
+        if !is_number(index) || !is_number(count) {
+            return None;
+        }
+
+        let index: usize = index.parse().ok()?;
+        let count: usize = count.parse().ok()?;
+        if index == 0 || index > count {
+            return None;
+        }
+
+        Some(Self {
+            prefix: prefix.to_string(),
+            index,
+            count,
+        })
+    }
+
+    fn file_name(&self, index: usize) -> String {
+        format!("{}-{:05}-of-{:05}.gguf", self.prefix, index, self.count)
+    }
+}
+
+/// All files that make up the model at `path`, in order.
+///
+/// Returns just `path` for a model that is not split. Any shard may be
+/// given; missing shards are an error.
+pub fn shard_paths(path: &Path) -> Result<Vec<PathBuf>> {
+    let split = match SplitName::parse(path) {
+        Some(split) if split.count > 1 => split,
+        _ => return Ok(vec![path.to_path_buf()]),
+    };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let paths: Vec<PathBuf> = (1..=split.count)
+        .map(|i| dir.join(split.file_name(i)))
+        .collect();
+
+    let missing: Vec<String> = paths
+        .iter()
+        .filter(|p| !p.exists())
+        .map(|p| p.display().to_string())
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Split model is missing {} of {} shards: {}",
+            missing.len(),
+            split.count,
+            missing.join(", ")
+        );
+    }
+
+    Ok(paths)
+}
+
+/// The shard that carries the model metadata (the first one).
+pub fn first_shard(path: &Path) -> PathBuf {
+    match SplitName::parse(path) {
+        Some(split) if split.count > 1 && split.index != 1 => path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(split.file_name(1)),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// The file name without `.gguf` and any shard suffix, e.g. `llama-70b` for
+/// `llama-70b-00001-of-00002.gguf`.
+pub fn model_stem(path: &Path) -> String {
+    if let Some(split) = SplitName::parse(path) {
+        return split.prefix;
+    }
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    name.strip_suffix(".gguf").unwrap_or(name).to_string()
+}
+
+/// Total size in bytes of all shards of the model at `path`.
+pub fn total_size(path: &Path) -> Result<u64> {
+    shard_paths(path)?
+        .iter()
+        .map(|p| {
+            std::fs::metadata(p)
+                .map(|m| m.len())
+                .with_context(|| format!("Failed to open model file: {:?}", p))
+        })
+        .sum()
+}
+
+/// One or more memory-mapped GGUF files presented as a single model.
+pub struct ShardedGguf {
+    pub paths: Vec<PathBuf>,
+    pub mmaps: Vec<Mmap>,
+    /// Metadata from the first shard and the tensors of every shard, with
+    /// offsets relative to the start of the concatenated shards.
+    pub content: gguf_file::Content,
+}
+
+impl ShardedGguf {
+    pub fn open(path: &Path) -> Result<Self> {
+        let paths = shard_paths(path)?;
+
+        let mut mmaps = Vec::with_capacity(paths.len());
+        let mut contents = Vec::with_capacity(paths.len());
+        for shard in &paths {
+            let file = File::open(shard)
+                .with_context(|| format!("Failed to open model file: {:?}", shard))?;
+            let mmap = unsafe { Mmap::map(&file)? };
+            let content = gguf_file::Content::read(&mut Cursor::new(&mmap))
+                .with_context(|| format!("Failed to read GGUF file: {:?}", shard))?;
+            mmaps.push(mmap);
+            contents.push(content);
+        }
+
+        if paths.len() > 1 {
+            validate_shards(&paths, &contents)?;
+            tracing::info!("Loading split model from {} shards", paths.len());
+        }
+
+        let content = merge_contents(contents, &mmaps)?;
+        Ok(Self {
+            paths,
+            mmaps,
+            content,
+        })
+    }
+
+    /// A reader over all shards laid end to end.
+    pub fn reader(&self) -> ShardReader<'_> {
+        ShardReader::new(self.mmaps.iter().map(|m| &m[..]).collect())
+    }
+}
+
+fn split_value(content: &gguf_file::Content, key: &str) -> Option<u64> {
+    content.metadata.get(key).and_then(|v| match v {
+        gguf_file::Value::U16(n) => Some(*n as u64),
+        gguf_file::Value::U32(n) => Some(*n as u64),
+        gguf_file::Value::I32(n) => Some(*n as u64),
+        gguf_file::Value::U64(n) => Some(*n),
+        _ => None,
+    })
+}
+
+fn validate_shards(paths: &[PathBuf], contents: &[gguf_file::Content]) -> Result<()> {
+    let count = paths.len() as u64;
+
+    for (i, (path, content)) in paths.iter().zip(contents).enumerate() {
+        if let Some(n) = split_value(content, SPLIT_COUNT_KEY) {
+            if n != count {
+                anyhow::bail!(
+                    "{:?} says the model has {} shards, but {} were found",
+                    path,
+                    n,
+                    count
+                );
+            }
+        }
+        if let Some(no) = split_value(content, SPLIT_NO_KEY) {
+            if no != i as u64 {
+                anyhow::bail!(
+                    "{:?} is shard {} but was expected at {}",
+                    path,
+                    no + 1,
+                    i + 1
+                );
+            }
+        }
+    }
+
+    let found: usize = contents.iter().map(|c| c.tensor_infos.len()).sum();
+    if let Some(expected) = split_value(&contents[0], SPLIT_TENSORS_KEY) {
+        if found as u64 != expected {
+            anyhow::bail!(
+                "Split model has {} tensors across {} shards, expected {}",
+                found,
+                count,
+                expected
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn merge_contents(contents: Vec<gguf_file::Content>, mmaps: &[Mmap]) -> Result<gguf_file::Content> {
+    let mut merged: Option<gguf_file::Content> = None;
+    let mut tensor_infos = HashMap::new();
+    let mut base = 0u64;
+
+    for (mut content, mmap) in contents.into_iter().zip(mmaps) {
+        for (name, mut info) in std::mem::take(&mut content.tensor_infos) {
+            info.offset += base + content.tensor_data_offset;
+            if tensor_infos.insert(name.clone(), info).is_some() {
+                anyhow::bail!("Tensor {} appears in more than one shard", name);
+            }
+        }
+        base += mmap.len() as u64;
+        merged.get_or_insert(content);
+    }
+
+    let mut merged = merged.context("No GGUF shards to load")?;
+    merged.tensor_infos = tensor_infos;
+    merged.tensor_data_offset = 0;
+    Ok(merged)
+}
+
+/// `Read + Seek` over several byte slices as if they were one.
+pub struct ShardReader<'a> {
+    shards: Vec<&'a [u8]>,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a> ShardReader<'a> {
+    pub fn new(shards: Vec<&'a [u8]>) -> Self {
+        let len = shards.iter().map(|s| s.len() as u64).sum();
+        Self {
+            shards,
+            len,
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ShardReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut start = 0u64;
+        for shard in &self.shards {
+            let end = start + shard.len() as u64;
+            if self.pos < end {
+                let offset = (self.pos - start) as usize;
+                let n = buf.len().min(shard.len() - offset);
+                buf[..n].copy_from_slice(&shard[offset..offset + n]);
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            start = end;
+        }
+        Ok(0)
+    }
+}
+
+impl Seek for ShardReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        match target {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::{GgmlDType, QTensor};
+    use candle_core::{Device, Tensor};
+    use gguf_file::Value;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("oxide-shards-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_shard(path: &Path, no: u16, count: u16, total: i32, tensors: &[(&str, f32)]) {
+        let no = Value::U16(no);
+        let count = Value::U16(count);
+        let total = Value::I32(total);
+        let arch = Value::String("llama".to_string());
+        let mut metadata = vec![(SPLIT_NO_KEY, &no), (SPLIT_COUNT_KEY, &count)];
+        if matches!(no, Value::U16(0)) {
+            metadata.push((SPLIT_TENSORS_KEY, &total));
+            metadata.push(("general.architecture", &arch));
+        }
+
+        let tensors: Vec<(&str, QTensor)> = tensors
+            .iter()
+            .map(|&(name, value)| {
+                let t = Tensor::full(value, 8, &Device::Cpu).unwrap();
+                (name, QTensor::quantize(&t, GgmlDType::F32).unwrap())
+            })
+            .collect();
+        let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(n, t)| (*n, t)).collect();
+
+        let mut file = File::create(path).unwrap();
+        gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+    }
+
+    #[test]
+    fn test_split_name_parsing() {
+        let split = SplitName::parse(Path::new("/m/qwen-7b-q4_k_m-00002-of-00003.gguf")).unwrap();
+        assert_eq!(split.prefix, "qwen-7b-q4_k_m");
+        assert_eq!((split.index, split.count), (2, 3));
+        assert_eq!(split.file_name(1), "qwen-7b-q4_k_m-00001-of-00003.gguf");
+
+        assert!(SplitName::parse(Path::new("model.gguf")).is_none());
+        assert!(SplitName::parse(Path::new("model-1-of-3.gguf")).is_none());
+        assert!(SplitName::parse(Path::new("model-00004-of-00003.gguf")).is_none());
+        assert_eq!(
+            first_shard(Path::new("/m/a-00003-of-00003.gguf")),
+            PathBuf::from("/m/a-00001-of-00003.gguf")
+        );
+    }
+
+    #[test]
+    fn test_open_merges_shards() {
+        let dir = temp_dir("merge");
+        write_shard(
+            &dir.join("m-00001-of-00002.gguf"),
+            0,
+            2,
+            3,
+            &[("a", 1.0), ("b", 2.0)],
+        );
+        write_shard(&dir.join("m-00002-of-00002.gguf"), 1, 2, 3, &[("c", 3.0)]);
+
+        // Any shard can be given.
+        let gguf = ShardedGguf::open(&dir.join("m-00002-of-00002.gguf")).unwrap();
+        assert_eq!(gguf.paths.len(), 2);
+        assert_eq!(gguf.content.tensor_infos.len(), 3);
+        assert!(gguf.content.metadata.contains_key("general.architecture"));
+
+        let mut reader = gguf.reader();
+        for (name, value) in [("a", 1.0f32), ("b", 2.0), ("c", 3.0)] {
+            let t = gguf
+                .content
+                .tensor(&mut reader, name, &Device::Cpu)
+                .unwrap()
+                .dequantize(&Device::Cpu)
+                .unwrap();
+            assert_eq!(t.to_vec1::<f32>().unwrap(), vec![value; 8]);
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_open_validates_shards() {
+        let dir = temp_dir("validate");
+        let first = dir.join("m-00001-of-00002.gguf");
+        write_shard(&first, 0, 2, 3, &[("a", 1.0)]);
+
+        let err = ShardedGguf::open(&first).err().unwrap().to_string();
+        assert!(err.contains("missing 1 of 2"), "{}", err);
+
+        write_shard(&dir.join("m-00002-of-00002.gguf"), 1, 2, 3, &[("b", 2.0)]);
+        let err = ShardedGguf::open(&first).err().unwrap().to_string();
+        assert!(err.contains("expected 3"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
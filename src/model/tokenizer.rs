@@ -1,142 +1,351 @@
-use std::fs::{self, File};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use candle_core::quantized::gguf_file;
-use memmap2::Mmap;
-use sha2::{Digest, Sha256};
 use shimmytok::Tokenizer as ShimmyTokenizer;
+use tokenizers::Tokenizer as HfTokenizer;
 
-const CACHE_DIR: &str = ".cache/oxide";
+use super::cache::TokenizerCache;
+use super::hf_tokenizer::{self, TokenizerConfig};
+use super::shards;
 
 pub struct TokenizerWrapper {
-    inner: ShimmyTokenizer,
+    inner: Backend,
     eos_token_id: u32,
-    pending_tokens: Vec<u32>,
-    cached_decoded: String,
-    encode_buffer: Vec<u32>,
-    decode_buffer: String,
+    bos_token_id: Option<u32>,
+    special_tokens: HashSet<u32>,
+    config: Option<TokenizerConfig>,
+    stream: DecodeStream,
 }
 
-fn get_cache_path(model_path: &PathBuf) -> Result<PathBuf> {
-    let mut hasher = Sha256::new();
+enum Backend {
+    Gguf(Box<ShimmyTokenizer>),
+    HuggingFace(Box<HfTokenizer>),
+}
 
-    let mut file = File::open(model_path)?;
-    let mut header = vec![0u8; 65536];
-    use std::io::Read;
-    file.read_exact(&mut header)?;
-    hasher.update(&header);
+impl Backend {
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        match self {
+            Backend::Gguf(t) => t
+                .encode(text, add_special_tokens)
+                .map_err(|e| anyhow::anyhow!("Encode failed: {}", e)),
+            Backend::HuggingFace(t) => t
+                .encode(text, add_special_tokens)
+                .map(|enc| enc.get_ids().to_vec())
+                .map_err(|e| anyhow::anyhow!("Encode failed: {}", e)),
+        }
+    }
 
-    let file_size = file.metadata()?.len();
-    hasher.update(file_size.to_le_bytes());
+    fn piece(&self, token: u32) -> Option<String> {
+        match self {
+            Backend::Gguf(t) => t.token_to_piece(token).ok(),
+            Backend::HuggingFace(t) => t.id_to_token(token),
+        }
+    }
 
-    let hash = format!("{:x}", hasher.finalize());
+    fn vocab_size(&self) -> usize {
+        match self {
+            Backend::Gguf(t) => t.vocab_size(),
+            Backend::HuggingFace(t) => t.get_vocab_size(true),
+        }
+    }
 
-    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-    let cache_dir = PathBuf::from(home).join(CACHE_DIR);
-    fs::create_dir_all(&cache_dir)?;
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        match self {
+            Backend::Gguf(t) => t
+                .decode(tokens, false)
+                .map_err(|e| anyhow::anyhow!("Decode failed: {}", e)),
+            Backend::HuggingFace(t) => t
+                .decode(tokens, false)
+                .map_err(|e| anyhow::anyhow!("Decode failed: {}", e)),
+        }
+    }
+}
 
-    Ok(cache_dir.join(format!("{}.tokenizer_cache", hash)))
+/// A token with its vocabulary piece and the byte range of `text` it covers.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct TokenInfo {
+    pub id: u32,
+    pub piece: String,
+    pub start: usize,
+    pub end: usize,
+    pub special: bool,
 }
 
-fn extract_tokenizer_json(path: &PathBuf) -> Result<Option<String>> {
-    let file = File::open(path)?;
-    let mmap = unsafe { Mmap::map(&file)? };
-    let mut cursor = std::io::Cursor::new(&mmap);
+/// Incremental detokenizer state for streaming output.
+///
+/// Decoding tokens one at a time splits multi-byte characters that span
+/// byte-fallback tokens and loses context-dependent spacing. Instead, the
+/// running sequence is decoded over a small window and only the new,
+/// complete UTF-8 suffix is emitted, so the concatenation of all emitted
+/// chunks equals the decoded text of the whole sequence.
+#[derive(Debug, Default, Clone)]
+pub struct DecodeStream {
+    tokens: Vec<u32>,
+    prefix_offset: usize,
+    read_offset: usize,
+}
+
+impl DecodeStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Push a token and return the newly completed text, if any.
+    ///
+    /// Returns `None` while the tail of the sequence is an incomplete
+    /// UTF-8 character. On decode failure the token is discarded.
+    pub fn step<F>(&mut self, token: u32, decode: F) -> Result<Option<String>>
+    where
+        F: Fn(&[u32]) -> Result<String>,
+    {
+        self.tokens.push(token);
+
+        let (prefix_text, new_text) = match self.decode_window(&decode) {
+            Ok(texts) => texts,
+            Err(e) => {
+                self.tokens.pop();
+                return Err(e);
+            }
+        };
 
-    let content = gguf_file::Content::read(&mut cursor)
-        .map_err(|e| anyhow::anyhow!("Failed to read GGUF: {}", e))?;
+        if new_text.len() <= prefix_text.len() || new_text.ends_with(char::REPLACEMENT_CHARACTER) {
+            return Ok(None);
+        }
+
+        let suffix = new_text.get(prefix_text.len()..).map(str::to_string);
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.tokens.len();
 
-    let md = &content.metadata;
+        Ok(suffix.filter(|s| !s.is_empty()))
+    }
 
-    if let Some(tokenizer_json) = md.get("tokenizer.json.json") {
-        if let Ok(s) = tokenizer_json.to_string() {
-            tracing::debug!("Found embedded tokenizer.json in GGUF");
-            return Ok(Some(s.clone()));
+    /// Emit whatever text is still held back, even if it ends in an
+    /// incomplete character.
+    pub fn flush<F>(&mut self, decode: F) -> Result<Option<String>>
+    where
+        F: Fn(&[u32]) -> Result<String>,
+    {
+        if self.read_offset == self.tokens.len() {
+            return Ok(None);
         }
+
+        let (prefix_text, new_text) = self.decode_window(&decode)?;
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.tokens.len();
+
+        Ok(new_text
+            .get(prefix_text.len()..)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string))
     }
 
-    Ok(None)
+    pub fn reset(&mut self) {
+        self.tokens.clear();
+        self.prefix_offset = 0;
+        self.read_offset = 0;
+    }
+
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    fn decode_window<F>(&self, decode: &F) -> Result<(String, String)>
+    where
+        F: Fn(&[u32]) -> Result<String>,
+    {
+        let prefix_text = decode(&self.tokens[self.prefix_offset..self.read_offset])?;
+        let new_text = decode(&self.tokens[self.prefix_offset..])?;
+        Ok((prefix_text, new_text))
+    }
 }
 
 impl TokenizerWrapper {
-    pub fn from_gguf(path: &PathBuf) -> Result<Self> {
-        let cache_path = get_cache_path(path)?;
-        let json_cache_path = cache_path.with_extension("tokenizer_json");
-
-        let inner = if json_cache_path.exists() {
-            tracing::info!("Loading tokenizer from JSON cache: {:?}", json_cache_path);
-            match ShimmyTokenizer::from_gguf_file(&json_cache_path) {
-                Ok(tok) => tok,
-                Err(e) => {
-                    tracing::warn!("JSON cache corrupted ({}), loading from GGUF", e);
-                    ShimmyTokenizer::from_gguf_file(path)?
+    pub fn from_gguf(path: &Path) -> Result<Self> {
+        // Split models keep the tokenizer in the first shard.
+        let path = &shards::first_shard(path);
+        let cache = TokenizerCache::open();
+
+        let cached = cache.lookup(path).and_then(|entry| {
+            tracing::info!("Loading tokenizer from cache: {:?}", entry);
+            ShimmyTokenizer::from_gguf_file(&entry)
+                .map_err(|e| tracing::warn!("Cached tokenizer unusable ({}), loading from GGUF", e))
+                .ok()
+        });
+
+        let inner = match cached {
+            Some(tokenizer) => tokenizer,
+            None => {
+                tracing::info!("Loading tokenizer from GGUF...");
+                let tokenizer = ShimmyTokenizer::from_gguf_file(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to load tokenizer: {}", e))?;
+
+                if let Err(e) = cache.store(path) {
+                    tracing::warn!("Failed to cache tokenizer: {}", e);
                 }
+
+                tokenizer
             }
-        } else if cache_path.exists() {
-            tracing::info!("Loading tokenizer from legacy cache: {:?}", cache_path);
-            ShimmyTokenizer::from_gguf_file(&cache_path)
-                .map_err(|e| {
-                    tracing::warn!("Legacy cache corrupted ({}), loading from GGUF", e);
-                    e
-                })
-                .or_else(|_| ShimmyTokenizer::from_gguf_file(path))?
-        } else {
-            tracing::info!("Loading tokenizer from GGUF (first time)...");
+        };
+
+        tracing::info!("Loaded tokenizer, EOS={}", inner.eos_token());
 
-            let tokenizer = ShimmyTokenizer::from_gguf_file(path)
+        Ok(Self::from_shimmy(inner))
+    }
+
+    /// Load a tokenizer from a standalone file.
+    ///
+    /// GGUF files are read with the embedded llama.cpp vocabulary; anything
+    /// else is treated as a Hugging Face `tokenizer.json`, with
+    /// `tokenizer_config.json` picked up from the same directory.
+    pub fn from_file(path: &PathBuf) -> Result<Self> {
+        if hf_tokenizer::is_gguf_file(path)? {
+            let inner = ShimmyTokenizer::from_gguf_file(path)
                 .map_err(|e| anyhow::anyhow!("Failed to load tokenizer: {}", e))?;
 
-            if let Ok(Some(tokenizer_json)) = extract_tokenizer_json(path) {
-                if let Err(e) = fs::write(&json_cache_path, &tokenizer_json) {
-                    tracing::warn!("Failed to cache tokenizer JSON: {}", e);
-                } else {
-                    tracing::info!("Tokenizer JSON cached to {:?}", json_cache_path);
-                }
-            } else if let Err(e) = fs::copy(path, &cache_path) {
-                tracing::warn!("Failed to cache tokenizer: {}", e);
-            } else {
-                tracing::info!("Tokenizer cached to {:?}", cache_path);
-            }
+            tracing::info!("Loaded tokenizer from file, EOS={}", inner.eos_token());
 
-            tokenizer
-        };
+            return Ok(Self::from_shimmy(inner));
+        }
 
-        let eos_token_id = inner.eos_token();
-        tracing::info!("Loaded tokenizer, EOS={}", eos_token_id);
+        Self::from_hf_json(path)
+    }
+
+    /// Load a Hugging Face `tokenizer.json`.
+    pub fn from_hf_json(path: &PathBuf) -> Result<Self> {
+        let inner = HfTokenizer::from_file(path)
+            .map_err(|e| anyhow::anyhow!("Failed to load tokenizer.json {:?}: {}", path, e))?;
+        let config = TokenizerConfig::load_beside(path)?;
+
+        let eos_token_id = match hf_tokenizer::resolve_eos_token(&inner, &config) {
+            Some(id) => id,
+            None => {
+                tracing::warn!(
+                    "No EOS token found in {:?}; generation stops at max tokens",
+                    path
+                );
+                u32::MAX
+            }
+        };
+        let bos_token_id = config
+            .bos_token
+            .as_deref()
+            .and_then(|t| inner.token_to_id(t));
+
+        let special_tokens = inner
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, _)| id)
+            .collect();
+
+        tracing::info!(
+            "Loaded tokenizer.json from {:?}, EOS={}",
+            path,
+            eos_token_id
+        );
 
         Ok(Self {
-            inner,
+            inner: Backend::HuggingFace(Box::new(inner)),
             eos_token_id,
-            pending_tokens: Vec::new(),
-            cached_decoded: String::new(),
-            encode_buffer: Vec::with_capacity(4096),
-            decode_buffer: String::with_capacity(4096),
+            bos_token_id,
+            special_tokens,
+            config: Some(config),
+            stream: DecodeStream::new(),
         })
     }
 
-    pub fn from_file(path: &PathBuf) -> Result<Self> {
-        let inner = ShimmyTokenizer::from_gguf_file(path)
-            .map_err(|e| anyhow::anyhow!("Failed to load tokenizer: {}", e))?;
+    fn from_shimmy(inner: ShimmyTokenizer) -> Self {
+        Self {
+            eos_token_id: inner.eos_token(),
+            bos_token_id: Some(inner.bos_token()),
+            inner: Backend::Gguf(Box::new(inner)),
+            special_tokens: HashSet::new(),
+            config: None,
+            stream: DecodeStream::new(),
+        }
+    }
 
-        let eos_token_id = inner.eos_token();
+    /// Settings from `tokenizer_config.json`, when loaded from Hugging Face files.
+    pub fn config(&self) -> Option<&TokenizerConfig> {
+        self.config.as_ref()
+    }
 
-        tracing::info!("Loaded tokenizer from file, EOS={}", eos_token_id);
+    /// Encode `text`, optionally adding the tokenizer's BOS/EOS tokens.
+    pub fn tokenize(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        self.inner.encode(text, add_special_tokens)
+    }
 
-        Ok(Self {
-            inner,
-            eos_token_id,
-            pending_tokens: Vec::new(),
-            cached_decoded: String::new(),
-            encode_buffer: Vec::with_capacity(4096),
-            decode_buffer: String::with_capacity(4096),
-        })
+    /// Encode `text` and report, for every token, its piece and the byte
+    /// range of `text` it was produced from.
+    ///
+    /// Special tokens added by the tokenizer get an empty range at the
+    /// position where they occur.
+    pub fn tokenize_with_offsets(
+        &self,
+        text: &str,
+        add_special_tokens: bool,
+    ) -> Result<Vec<TokenInfo>> {
+        let offsets = match &self.inner {
+            Backend::HuggingFace(t) => {
+                let encoding = t
+                    .encode(text, add_special_tokens)
+                    .map_err(|e| anyhow::anyhow!("Encode failed: {}", e))?;
+                encoding
+                    .get_ids()
+                    .iter()
+                    .copied()
+                    .zip(encoding.get_offsets().iter().copied())
+                    .collect::<Vec<_>>()
+            }
+            Backend::Gguf(_) => {
+                let ids = self.inner.encode(text, add_special_tokens)?;
+                self.decoded_offsets(text, &ids)?
+            }
+        };
+
+        Ok(offsets
+            .into_iter()
+            .map(|(id, (start, end))| TokenInfo {
+                id,
+                piece: self.token_piece(id).unwrap_or_default(),
+                start,
+                end,
+                special: self.is_special_token(id),
+            })
+            .collect())
+    }
+
+    fn decoded_offsets(&self, text: &str, ids: &[u32]) -> Result<Vec<(u32, (usize, usize))>> {
+        decoded_offsets(
+            text,
+            ids,
+            |t| self.inner.decode(t),
+            |id| self.is_special_token(id),
+        )
+    }
+
+    /// The vocabulary entry for a token id.
+    pub fn token_piece(&self, token_id: u32) -> Option<String> {
+        self.inner.piece(token_id)
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.inner.vocab_size()
     }
 
     pub fn encode(&self, text: &str) -> Result<Vec<u32>> {
-        self.inner
-            .encode(text, true)
-            .map_err(|e| anyhow::anyhow!("Encode failed: {}", e))
+        let mut tokens = self.tokenize(text, true)?;
+
+        // Chat templates that render `bos_token` themselves would otherwise
+        // get a second BOS from the tokenizer's post-processor.
+        if let Some(bos) = self.bos_token_id {
+            if tokens.len() >= 2 && tokens[0] == bos && tokens[1] == bos {
+                tokens.remove(0);
+            }
+        }
+
+        Ok(tokens)
     }
 
     pub fn encode_batch(&self, texts: &[&str]) -> Result<Vec<Vec<u32>>> {
@@ -148,9 +357,7 @@ impl TokenizerWrapper {
     }
 
     pub fn decode(&self, tokens: &[u32]) -> Result<String> {
-        self.inner
-            .decode(tokens, false)
-            .map_err(|e| anyhow::anyhow!("Decode failed: {}", e))
+        self.inner.decode(tokens)
     }
 
     pub fn decode_batch(&self, tokens_batch: &[Vec<u32>]) -> Result<Vec<String>> {
@@ -165,42 +372,277 @@ impl TokenizerWrapper {
         self.eos_token_id
     }
 
+    pub fn bos_token_id(&self) -> Option<u32> {
+        self.bos_token_id
+    }
+
     pub fn is_special_token(&self, token_id: u32) -> bool {
-        self.inner.is_special_token(token_id)
+        match &self.inner {
+            Backend::Gguf(t) => t.is_special_token(token_id),
+            Backend::HuggingFace(_) => self.special_tokens.contains(&token_id),
+        }
     }
 
     pub fn clear_cache(&mut self) {
-        self.pending_tokens.clear();
-        self.cached_decoded.clear();
+        self.stream.reset();
     }
 
+    /// Feed one generated token to the streaming detokenizer and return the
+    /// text it completes, if any.
     pub fn decode_next(&mut self, token: u32) -> Result<Option<String>> {
-        self.pending_tokens.push(token);
+        let inner = &self.inner;
+        self.stream.step(token, |tokens| inner.decode(tokens))
+    }
 
-        let new_token_decoded = self.decode(&[token])?;
+    /// Flush text held back by [`decode_next`](Self::decode_next) at the end
+    /// of a stream.
+    pub fn decode_rest(&mut self) -> Result<Option<String>> {
+        let inner = &self.inner;
+        self.stream.flush(|tokens| inner.decode(tokens))
+    }
+}
 
-        self.cached_decoded.push_str(&new_token_decoded);
+/// Recover byte ranges by decoding the sequence prefix by prefix, for
+/// tokenizers that do not track offsets while encoding.
+fn decoded_offsets<F, S>(
+    text: &str,
+    ids: &[u32],
+    decode: F,
+    is_special: S,
+) -> Result<Vec<(u32, (usize, usize))>>
+where
+    F: Fn(&[u32]) -> Result<String>,
+    S: Fn(u32) -> bool,
+{
+    let mut stream = DecodeStream::new();
+    let mut decoded = String::new();
+    let mut spans = Vec::with_capacity(ids.len());
+
+    for &id in ids {
+        let start = decoded.len();
+        if !is_special(id) {
+            if let Some(chunk) = stream.step(id, &decode)? {
+                decoded.push_str(&chunk);
+            }
+        }
+        spans.push((id, (start, decoded.len())));
+    }
 
-        let result = if new_token_decoded.is_empty() {
-            None
-        } else {
-            Some(new_token_decoded)
-        };
+    // SentencePiece vocabularies prepend a space the input never had.
+    let shift = decoded.len().saturating_sub(text.len());
+    let shift = if decoded.ends_with(text) { shift } else { 0 };
+
+    Ok(spans
+        .into_iter()
+        .map(|(id, (start, end))| {
+            (
+                id,
+                (
+                    start.saturating_sub(shift).min(text.len()),
+                    end.saturating_sub(shift).min(text.len()),
+                ),
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
 
-        Ok(result)
+    // Byte-level vocabulary: token id == byte value, like byte-fallback tokens.
+    fn decode_bytes(tokens: &[u32]) -> Result<String> {
+        let bytes: Vec<u8> = tokens.iter().map(|&t| t as u8).collect();
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
     }
 
-    pub fn decode_rest(&mut self) -> Result<Option<String>> {
-        if self.pending_tokens.is_empty() {
-            return Ok(None);
+    fn stream_all(text: &str) -> Vec<String> {
+        let mut stream = DecodeStream::new();
+        let mut chunks = Vec::new();
+        for b in text.bytes() {
+            if let Some(chunk) = stream.step(b as u32, decode_bytes).unwrap() {
+                chunks.push(chunk);
+            }
+        }
+        if let Some(rest) = stream.flush(decode_bytes).unwrap() {
+            chunks.push(rest);
         }
+        chunks
+    }
+
+    #[test]
+    fn test_decode_stream_multibyte() {
+        for text in [
+            "héllo",
+            "日本語のテキスト",
+            "emoji 🦀🚀 done",
+            "fn main() {\n    x\n}",
+        ] {
+            let chunks = stream_all(text);
+            assert_eq!(chunks.concat(), text);
+            assert!(chunks
+                .iter()
+                .all(|c| !c.contains(char::REPLACEMENT_CHARACTER)));
+        }
+    }
+
+    #[test]
+    fn test_decode_stream_holds_incomplete_char() {
+        let mut stream = DecodeStream::new();
+        let crab = "🦀".as_bytes();
+        for &b in &crab[..3] {
+            assert_eq!(stream.step(b as u32, decode_bytes).unwrap(), None);
+        }
+        assert_eq!(
+            stream
+                .step(crab[3] as u32, decode_bytes)
+                .unwrap()
+                .as_deref(),
+            Some("🦀")
+        );
+        assert_eq!(stream.flush(decode_bytes).unwrap(), None);
+    }
 
-        let result = if self.cached_decoded.is_empty() {
-            None
-        } else {
-            Some(std::mem::take(&mut self.cached_decoded))
+    #[test]
+    fn test_decode_stream_flush_and_reset() {
+        let mut stream = DecodeStream::new();
+        stream.step(0xE6, decode_bytes).unwrap();
+        assert_eq!(
+            stream.flush(decode_bytes).unwrap().as_deref(),
+            Some("\u{FFFD}")
+        );
+
+        stream.reset();
+        assert!(stream.tokens().is_empty());
+        assert_eq!(
+            stream.step(b'a' as u32, decode_bytes).unwrap().as_deref(),
+            Some("a")
+        );
+    }
+
+    #[test]
+    fn test_decode_stream_keeps_leading_space() {
+        // SentencePiece pieces; decoding drops the leading space of the
+        // sequence, so "▁world" on its own decodes to "world".
+        let vocab = ["▁Hello", ",", "▁world", "!"];
+        let decode = |tokens: &[u32]| -> Result<String> {
+            let text: String = tokens
+                .iter()
+                .map(|&t| vocab[t as usize].replace('▁', " "))
+                .collect();
+            Ok(text.strip_prefix(' ').unwrap_or(&text).to_string())
         };
+        assert_eq!(decode(&[2]).unwrap(), "world");
+
+        let mut stream = DecodeStream::new();
+        let mut out = String::new();
+        for t in 0..vocab.len() as u32 {
+            if let Some(chunk) = stream.step(t, decode).unwrap() {
+                out.push_str(&chunk);
+            }
+        }
+        assert_eq!(out, "Hello, world!");
+    }
 
-        Ok(result)
+    #[test]
+    fn test_decoded_offsets_multibyte() {
+        // A BOS token, two SentencePiece words and "🦀" as byte-fallback
+        // tokens, one byte each.
+        let vocab: Vec<Vec<u8>> = vec![
+            b"<s>".to_vec(),
+            "▁héllo".as_bytes().to_vec(),
+            "▁wörld".as_bytes().to_vec(),
+            vec![0xF0],
+            vec![0x9F],
+            vec![0xA6],
+            vec![0x80],
+            b"!".to_vec(),
+        ];
+        let text = "héllo wörld🦀!";
+        let ids: Vec<u32> = (0..vocab.len() as u32).collect();
+        let expected = vec![
+            (0, (0, 0)),
+            (1, (0, 6)),
+            (2, (6, 13)),
+            (3, (13, 13)),
+            (4, (13, 13)),
+            (5, (13, 13)),
+            (6, (13, 17)),
+            (7, (17, 18)),
+        ];
+
+        for strip_leading_space in [true, false] {
+            let decode = |tokens: &[u32]| -> Result<String> {
+                let bytes: Vec<u8> = tokens
+                    .iter()
+                    .flat_map(|&t| vocab[t as usize].clone())
+                    .collect();
+                let text = String::from_utf8_lossy(&bytes).replace('▁', " ");
+                Ok(match text.strip_prefix(' ') {
+                    Some(rest) if strip_leading_space => rest.to_string(),
+                    _ => text,
+                })
+            };
+            let spans = decoded_offsets(text, &ids, decode, |id| id == 0).unwrap();
+            assert_eq!(spans, expected, "strip = {}", strip_leading_space);
+            for (_, (start, end)) in spans {
+                assert!(text.get(start..end).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_file_loads_hf_tokenizer_json() {
+        let dir = std::env::temp_dir().join(format!("oxide-hf-tok-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let tokenizer_json = r#"{
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [
+                {"id": 0, "content": "<s>", "single_word": false, "lstrip": false,
+                 "rstrip": false, "normalized": false, "special": true},
+                {"id": 1, "content": "</s>", "single_word": false, "lstrip": false,
+                 "rstrip": false, "normalized": false, "special": true}
+            ],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"<s>": 0, "</s>": 1, "hello": 2, "world": 3, "[UNK]": 4},
+                "unk_token": "[UNK]"
+            }
+        }"#;
+        let path = dir.join("tokenizer.json");
+        fs::write(&path, tokenizer_json).unwrap();
+        fs::write(
+            dir.join("tokenizer_config.json"),
+            r#"{"bos_token": "<s>", "eos_token": "</s>", "chat_template": "{{ bos_token }}"}"#,
+        )
+        .unwrap();
+
+        let tokenizer = TokenizerWrapper::from_file(&path).unwrap();
+        assert_eq!(tokenizer.eos_token_id(), 1);
+        assert_eq!(tokenizer.bos_token_id(), Some(0));
+        assert!(tokenizer.is_special_token(1));
+        assert!(!tokenizer.is_special_token(2));
+        assert_eq!(
+            tokenizer.encode("<s> <s> hello world").unwrap(),
+            vec![0, 2, 3]
+        );
+        let tokens = tokenizer.tokenize_with_offsets("wörld hello", false).unwrap();
+        let spans: Vec<(u32, usize, usize)> =
+            tokens.iter().map(|t| (t.id, t.start, t.end)).collect();
+        assert_eq!(spans, vec![(4, 0, 6), (2, 7, 12)]);
+        assert_eq!(
+            tokenizer.config().unwrap().chat_template.as_deref(),
+            Some("{{ bos_token }}")
+        );
+
+        fs::remove_dir_all(&dir).ok();
     }
 }
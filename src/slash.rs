@@ -0,0 +1,95 @@
+//! Arguments of the REPL's slash commands.
+
+/// `value` parsed as a `T` that `valid` accepts.
+pub fn parse_setting<T: std::str::FromStr + Copy>(
+    value: &str,
+    valid: impl Fn(T) -> bool,
+) -> Result<T, ()> {
+    match value.parse::<T>() {
+        Ok(v) if valid(v) => Ok(v),
+        _ => Err(()),
+    }
+}
+
+/// Like [`parse_setting`], with `off` or `none` for `None`.
+pub fn parse_optional<T: std::str::FromStr + Copy>(
+    value: &str,
+    valid: impl Fn(T) -> bool,
+) -> Result<Option<T>, ()> {
+    match value {
+        "off" | "none" => Ok(None),
+        _ => parse_setting(value, valid).map(Some),
+    }
+}
+
+/// `args` split at whitespace, with single- or double-quoted words kept
+/// whole, e.g. `"my notes.txt" b.rs`.
+pub fn split_words(args: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    for c in args.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(q) = quote {
+        return Err(format!("Missing closing {}", q));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_setting() {
+        assert_eq!(parse_setting("0.7", |t: f64| t >= 0.0), Ok(0.7));
+        assert_eq!(parse_setting("0", |t: f64| t >= 0.0), Ok(0.0));
+        assert_eq!(parse_setting("-1", |t: f64| t >= 0.0), Err(()));
+        assert_eq!(parse_setting("warm", |t: f64| t >= 0.0), Err(()));
+        assert_eq!(parse_setting("", |_: u64| true), Err(()));
+        assert_eq!(parse_setting("42", |_: u64| true), Ok(42));
+        assert_eq!(parse_setting("-42", |_: u64| true), Err(()));
+    }
+
+    #[test]
+    fn test_parse_optional() {
+        let top_k = |value| parse_optional(value, |k: usize| k > 0);
+        assert_eq!(top_k("40"), Ok(Some(40)));
+        assert_eq!(top_k("off"), Ok(None));
+        assert_eq!(top_k("none"), Ok(None));
+        assert_eq!(top_k("0"), Err(()));
+        assert_eq!(top_k("Off"), Err(()));
+        assert_eq!(top_k("many"), Err(()));
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            split_words("a.rs  b.rs"),
+            Ok(vec!["a.rs".into(), "b.rs".into()])
+        );
+        assert_eq!(
+            split_words("\"my notes.txt\" '~/x y.md'"),
+            Ok(vec!["my notes.txt".into(), "~/x y.md".into()])
+        );
+        assert_eq!(
+            split_words("dir/\"a b\".txt"),
+            Ok(vec!["dir/a b.txt".into()])
+        );
+        assert_eq!(split_words("\"it's\""), Ok(vec!["it's".into()]));
+        assert_eq!(split_words("\"\""), Ok(vec![String::new()]));
+        assert_eq!(split_words(""), Ok(vec![]));
+        assert!(split_words("\"a b").is_err());
+    }
+}